@@ -0,0 +1,115 @@
+//! Compare the allocations and time of wrapping BAM records with a decoded
+//! copy of their sequence (`BamRecord::new`) against decoding it on demand
+//! into a per-thread buffer (`BamRecord::without_seq_copy`, `--no-seq-copy`),
+//! for batches of records that are wrapped and then searched for a UMI.
+//!
+//! Run with `cargo bench --bench bam_record`.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use rust_htslib::bam;
+use umi_checker::io::{BamRecord, BioRecord};
+use umi_checker::matcher::is_umi_in_read;
+
+const NUM_READS: usize = 200_000;
+const READ_LEN: usize = 150;
+const BATCH_SIZE: usize = 10_000;
+
+/// The system allocator, counting allocations and allocated bytes.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Build deterministic pseudo-random records, planting the UMI in every tenth.
+fn synthetic_records(umi: &[u8]) -> Vec<bam::Record> {
+    let mut state: u64 = 0x5eed;
+    let mut next_base = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        b"ACGT"[(state % 4) as usize]
+    };
+    let qual = vec![30; READ_LEN];
+    (0..NUM_READS)
+        .map(|i| {
+            let mut seq: Vec<u8> = (0..READ_LEN).map(|_| next_base()).collect();
+            if i % 10 == 0 {
+                seq[20..20 + umi.len()].copy_from_slice(umi);
+            }
+            let mut rec = bam::Record::new();
+            rec.set(format!("read{}", i).as_bytes(), None, &seq, &qual);
+            rec
+        })
+        .collect()
+}
+
+/// Wrap every batch of `records` with `wrap` and search it for `umi`, the
+/// way the BAM processor does, returning the number of matches, the
+/// allocations and the allocated bytes.
+fn run(
+    records: &[bam::Record],
+    umi: &[u8],
+    wrap: fn(bam::Record) -> BamRecord,
+) -> (usize, usize, usize) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    let mut matched = 0;
+    for chunk in records.chunks(BATCH_SIZE) {
+        // Cloning stands in for reading the records, for both variants alike
+        let batch: Vec<BamRecord> = chunk.iter().cloned().map(wrap).collect();
+        matched += batch
+            .iter()
+            .filter(|rec| rec.with_seq(|seq| is_umi_in_read(black_box(umi), seq, 1)))
+            .count();
+    }
+    (
+        matched,
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        ALLOCATED.load(Ordering::Relaxed) - allocated,
+    )
+}
+
+fn main() {
+    let umi = b"ACGTACGTACGT";
+    let records = synthetic_records(umi);
+
+    let start = Instant::now();
+    let (copied, copy_allocations, copy_bytes) = run(&records, umi, BamRecord::new);
+    let copy_elapsed = start.elapsed();
+
+    let start = Instant::now();
+    let (decoded, decode_allocations, decode_bytes) =
+        run(&records, umi, BamRecord::without_seq_copy);
+    let decode_elapsed = start.elapsed();
+
+    assert_eq!(copied, decoded);
+    println!(
+        "sequence copy     {:>8.2?}  {:>9} allocations  {:>6.1} MB",
+        copy_elapsed,
+        copy_allocations,
+        copy_bytes as f64 / 1e6
+    );
+    println!(
+        "decode on demand  {:>8.2?}  {:>9} allocations  {:>6.1} MB",
+        decode_elapsed,
+        decode_allocations,
+        decode_bytes as f64 / 1e6
+    );
+}
@@ -0,0 +1,110 @@
+//! Compare scanning reads window by window (`is_umi_in_read`) against
+//! looking the UMI up in a k-mer index of the read (`--long-read`), for one
+//! UMI per 10 kb read, for several candidate UMIs sharing one index, and for
+//! one UMI per 150 bp read.
+//!
+//! Run with `cargo bench --bench long_read`.
+use std::hint::black_box;
+use std::time::Instant;
+
+use umi_checker::matcher::{is_umi_in_long_read, is_umi_in_read, seed_len, KmerIndex};
+
+const NUM_READS: usize = 500;
+const READ_LEN: usize = 10_000;
+const NUM_SHORT_READS: usize = 200_000;
+const SHORT_READ_LEN: usize = 150;
+const UMI_LEN: usize = 12;
+const CANDIDATES: usize = 16;
+const MAX_MISMATCHES: u32 = 2;
+
+/// Deterministic pseudo-random bases.
+fn bases(state: &mut u64, n: usize) -> Vec<u8> {
+    (0..n)
+        .map(|_| {
+            *state ^= *state << 13;
+            *state ^= *state >> 7;
+            *state ^= *state << 17;
+            b"ACGT"[(*state % 4) as usize]
+        })
+        .collect()
+}
+
+fn main() {
+    let mut state: u64 = 0x5eed;
+    let reads: Vec<Vec<u8>> = (0..NUM_READS)
+        .map(|_| bases(&mut state, READ_LEN))
+        .collect();
+    let umis: Vec<Vec<u8>> = (0..CANDIDATES)
+        .map(|_| bases(&mut state, UMI_LEN))
+        .collect();
+
+    let start = Instant::now();
+    let scanned = reads
+        .iter()
+        .filter(|read| is_umi_in_read(black_box(&umis[0]), read, MAX_MISMATCHES))
+        .count();
+    let scan_elapsed = start.elapsed();
+
+    let start = Instant::now();
+    let indexed = reads
+        .iter()
+        .filter(|read| is_umi_in_long_read(black_box(&umis[0]), read, MAX_MISMATCHES))
+        .count();
+    let index_elapsed = start.elapsed();
+    assert_eq!(scanned, indexed);
+    println!(
+        "1 UMI per read    scan {:>8.2?}  k-mer index {:>8.2?}",
+        scan_elapsed, index_elapsed
+    );
+
+    let start = Instant::now();
+    let scanned: usize = reads
+        .iter()
+        .map(|read| {
+            umis.iter()
+                .filter(|umi| is_umi_in_read(black_box(umi), read, MAX_MISMATCHES))
+                .count()
+        })
+        .sum();
+    let scan_elapsed = start.elapsed();
+
+    let start = Instant::now();
+    let indexed: usize = reads
+        .iter()
+        .map(|read| {
+            let index = KmerIndex::new(read, seed_len(UMI_LEN, MAX_MISMATCHES));
+            umis.iter()
+                .filter(|umi| index.contains(black_box(umi), MAX_MISMATCHES))
+                .count()
+        })
+        .sum();
+    let index_elapsed = start.elapsed();
+    assert_eq!(scanned, indexed);
+    println!(
+        "{} UMIs per read  scan {:>8.2?}  k-mer index {:>8.2?}",
+        CANDIDATES, scan_elapsed, index_elapsed
+    );
+
+    let short_reads: Vec<Vec<u8>> = (0..NUM_SHORT_READS)
+        .map(|_| bases(&mut state, SHORT_READ_LEN))
+        .collect();
+
+    let start = Instant::now();
+    let scanned = short_reads
+        .iter()
+        .filter(|read| is_umi_in_read(black_box(&umis[0]), read, MAX_MISMATCHES))
+        .count();
+    let scan_elapsed = start.elapsed();
+
+    let start = Instant::now();
+    let indexed = short_reads
+        .iter()
+        .filter(|read| is_umi_in_long_read(black_box(&umis[0]), read, MAX_MISMATCHES))
+        .count();
+    let index_elapsed = start.elapsed();
+    assert_eq!(scanned, indexed);
+    println!(
+        "1 UMI per {} bp read  scan {:>8.2?}  k-mer index {:>8.2?}",
+        SHORT_READ_LEN, scan_elapsed, index_elapsed
+    );
+}
@@ -0,0 +1,245 @@
+//! Compare searching reads with a precompiled `CompiledUmi` against calling
+//! `is_umi_in_read` for every read, and `is_umi_in_read` (which stops
+//! confirming a candidate window once it exceeds the mismatch budget) against
+//! a pigeonhole search that computes the full Hamming distance of every
+//! candidate, on random reads and on reads full of near-misses of the UMI.
+//! Also compares the generic two-chunk pigeonhole search against the
+//! `is_umi_in_read_1mm` specialization for a single mismatch, and
+//! `is_umi_in_read_with_chunks`, which re-checks the whole candidate window,
+//! against extending the exactly matching chunk and comparing only the bases
+//! outside the extended run, for UMIs of several lengths. The extension is
+//! not used by the library: it measured no faster, since confirming a window
+//! is already a few SWAR words.
+//!
+//! Run with `cargo bench --bench matcher`.
+use std::hint::black_box;
+use std::time::Instant;
+
+use umi_checker::matcher::{
+    hamming_distance, hamming_distance_capped, is_umi_in_read, is_umi_in_read_1mm,
+    is_umi_in_read_with_chunks, CompiledUmi,
+};
+
+const NUM_READS: usize = 200_000;
+const READ_LEN: usize = 150;
+
+/// Build deterministic pseudo-random reads, planting the UMI in every tenth.
+fn synthetic_reads(umi: &[u8]) -> Vec<Vec<u8>> {
+    let mut state: u64 = 0x5eed;
+    let mut next_base = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        b"ACGT"[(state % 4) as usize]
+    };
+    (0..NUM_READS)
+        .map(|i| {
+            let mut read: Vec<u8> = (0..READ_LEN).map(|_| next_base()).collect();
+            if i % 10 == 0 {
+                read[20..20 + umi.len()].copy_from_slice(umi);
+            }
+            read
+        })
+        .collect()
+}
+
+/// Pigeonhole search that confirms every window with a matching chunk using
+/// the full, uncapped Hamming distance.
+fn pigeonhole_full_distance(umi: &[u8], read: &[u8], max_mismatches: u32) -> bool {
+    let num_chunks = (max_mismatches + 1) as usize;
+    let chunk_size = umi.len() / num_chunks;
+    read.windows(umi.len()).any(|window| {
+        (0..num_chunks).any(|c| {
+            let end = if c == num_chunks - 1 {
+                umi.len()
+            } else {
+                (c + 1) * chunk_size
+            };
+            umi[c * chunk_size..end] == window[c * chunk_size..end]
+        }) && hamming_distance(umi, window) <= max_mismatches
+    })
+}
+
+/// Pigeonhole search that extends the first exactly matching chunk of a
+/// window 8 bytes at a time and compares only the bases outside the extended
+/// run. Expects a UMI without 'N'.
+fn pigeonhole_extended_run(umi: &[u8], read: &[u8], max_mismatches: u32) -> bool {
+    let num_chunks = (max_mismatches + 1) as usize;
+    let chunk_size = umi.len() / num_chunks;
+    let budget = max_mismatches as usize;
+    read.windows(umi.len()).any(|window| {
+        let Some((start, end)) = (0..num_chunks)
+            .map(|c| {
+                let end = if c == num_chunks - 1 {
+                    umi.len()
+                } else {
+                    (c + 1) * chunk_size
+                };
+                (c * chunk_size, end)
+            })
+            .find(|&(start, end)| umi[start..end] == window[start..end])
+        else {
+            return false;
+        };
+        let diff = |i: usize| {
+            u64::from_le_bytes(umi[i..i + 8].try_into().unwrap())
+                ^ u64::from_le_bytes(window[i..i + 8].try_into().unwrap())
+        };
+        let mut right = end;
+        while right + 8 <= umi.len() {
+            let d = diff(right);
+            if d != 0 {
+                right += (d.trailing_zeros() / 8) as usize;
+                break;
+            }
+            right += 8;
+        }
+        while right < umi.len() && umi[right] == window[right] {
+            right += 1;
+        }
+        let mut left = start;
+        while left >= 8 {
+            let d = diff(left - 8);
+            if d != 0 {
+                left -= (d.leading_zeros() / 8) as usize;
+                break;
+            }
+            left -= 8;
+        }
+        while left > 0 && umi[left - 1] == window[left - 1] {
+            left -= 1;
+        }
+        if left + (umi.len() - right) <= budget {
+            return true;
+        }
+        let before = hamming_distance_capped(&umi[..left], &window[..left], max_mismatches);
+        before <= max_mismatches
+            && before
+                + hamming_distance_capped(&umi[right..], &window[right..], max_mismatches - before)
+                <= max_mismatches
+    })
+}
+
+/// Reads tiled with copies of the UMI, every `stride`-th base replaced by
+/// noise, so most windows share a chunk with the UMI.
+fn near_miss_reads(umi: &[u8], stride: usize) -> Vec<Vec<u8>> {
+    synthetic_reads(umi)
+        .into_iter()
+        .map(|noise| {
+            let mut read: Vec<u8> = umi.iter().copied().cycle().take(READ_LEN).collect();
+            for (i, base) in read.iter_mut().enumerate().step_by(stride) {
+                *base = noise[i];
+            }
+            read
+        })
+        .collect()
+}
+
+fn main() {
+    let umi = b"ACGTACGTACGT";
+    let reads = synthetic_reads(umi);
+
+    for max_mismatches in 0..=3 {
+        let start = Instant::now();
+        let per_call = reads
+            .iter()
+            .filter(|read| is_umi_in_read(black_box(umi), read, max_mismatches))
+            .count();
+        let per_call_elapsed = start.elapsed();
+
+        let start = Instant::now();
+        let compiled = CompiledUmi::new(umi, max_mismatches);
+        let precompiled = reads
+            .iter()
+            .filter(|read| black_box(&compiled).matches(read))
+            .count();
+        let compiled_elapsed = start.elapsed();
+
+        assert_eq!(per_call, precompiled);
+        println!(
+            "mismatches={}: per-call {:>8.2?}  compiled {:>8.2?}  ({} / {} reads matched)",
+            max_mismatches, per_call_elapsed, compiled_elapsed, precompiled, NUM_READS
+        );
+    }
+
+    // Reads tiled with near-misses of the UMI: most windows share a chunk
+    // with it, so candidate windows are confirmed far more often.
+    let near_misses = near_miss_reads(umi, 3);
+
+    for (name, reads) in [("random", &reads), ("near-miss", &near_misses)] {
+        for max_mismatches in 1..=3 {
+            let start = Instant::now();
+            let full = reads
+                .iter()
+                .filter(|read| pigeonhole_full_distance(black_box(umi), read, max_mismatches))
+                .count();
+            let full_elapsed = start.elapsed();
+
+            let start = Instant::now();
+            let capped = reads
+                .iter()
+                .filter(|read| is_umi_in_read(black_box(umi), read, max_mismatches))
+                .count();
+            let capped_elapsed = start.elapsed();
+
+            assert_eq!(full, capped);
+            println!(
+                "{} reads, mismatches={}: full distance {:>8.2?}  capped {:>8.2?}",
+                name, max_mismatches, full_elapsed, capped_elapsed
+            );
+        }
+
+        let start = Instant::now();
+        let generic = reads
+            .iter()
+            .filter(|read| is_umi_in_read_with_chunks(black_box(umi), read, 1, 2))
+            .count();
+        let generic_elapsed = start.elapsed();
+
+        let start = Instant::now();
+        let specialized = reads
+            .iter()
+            .filter(|read| is_umi_in_read_1mm(black_box(umi), read))
+            .count();
+        let specialized_elapsed = start.elapsed();
+
+        assert_eq!(generic, specialized);
+        println!(
+            "{} reads, mismatches=1: generic {:>8.2?}  specialized {:>8.2?}",
+            name, generic_elapsed, specialized_elapsed
+        );
+    }
+
+    // Longer UMIs leave more bases around the exact chunk for the extension
+    // to skip when confirming a candidate window; it still measures no faster
+    // than re-checking the whole window.
+    let long_umi = b"ACGTTGCAGATCCTAGGCATTACGCAGTCAGT";
+    for umi_len in [12, 24, 32] {
+        let umi = &long_umi[..umi_len];
+        let reads = near_miss_reads(umi, 11);
+        for max_mismatches in 1..=3 {
+            let num_chunks = max_mismatches as usize + 1;
+            let start = Instant::now();
+            let whole = reads
+                .iter()
+                .filter(|read| {
+                    is_umi_in_read_with_chunks(black_box(umi), read, max_mismatches, num_chunks)
+                })
+                .count();
+            let whole_elapsed = start.elapsed();
+
+            let start = Instant::now();
+            let extended = reads
+                .iter()
+                .filter(|read| pigeonhole_extended_run(black_box(umi), read, max_mismatches))
+                .count();
+            let extended_elapsed = start.elapsed();
+
+            assert_eq!(whole, extended);
+            println!(
+                "{}bp UMI, mismatches={}: whole window {:>8.2?}  outside exact run {:>8.2?}",
+                umi_len, max_mismatches, whole_elapsed, extended_elapsed
+            );
+        }
+    }
+}
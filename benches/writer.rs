@@ -0,0 +1,86 @@
+//! Compare writing FASTQ records through a `BufWriter` on the matching
+//! thread against handing them to an `AsyncWriter`, with an output that is
+//! artificially slow (as compressed or network outputs can be).
+//!
+//! Run with `cargo bench --bench writer`.
+use std::hint::black_box;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
+
+use umi_checker::io::AsyncWriter;
+use umi_checker::matcher::is_umi_in_read;
+
+const NUM_READS: usize = 200_000;
+const READ_LEN: usize = 150;
+const BATCH_SIZE: usize = 10_000;
+/// Delay of every write reaching the slow output.
+const WRITE_DELAY: Duration = Duration::from_millis(2);
+
+/// Output that sleeps on every write and discards the data.
+struct SlowOutput;
+
+impl Write for SlowOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        std::thread::sleep(WRITE_DELAY);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Build deterministic pseudo-random reads.
+fn synthetic_reads() -> Vec<Vec<u8>> {
+    let mut state: u64 = 0x5eed;
+    let mut next_base = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        b"ACGT"[(state % 4) as usize]
+    };
+    (0..NUM_READS)
+        .map(|_| (0..READ_LEN).map(|_| next_base()).collect())
+        .collect()
+}
+
+/// Match every batch of `reads` and write it out, the way the FASTQ
+/// processor alternates between matching and writing.
+fn run(reads: &[Vec<u8>], out: &mut dyn Write) -> usize {
+    let umi = b"ACGTACGTACGT";
+    let qual = vec![b'I'; READ_LEN];
+    let mut matched = 0;
+    for batch in reads.chunks(BATCH_SIZE) {
+        let verdicts: Vec<bool> = batch
+            .iter()
+            .map(|read| is_umi_in_read(black_box(umi), read, 2))
+            .collect();
+        for (i, (read, found)) in batch.iter().zip(verdicts).enumerate() {
+            matched += usize::from(found);
+            writeln!(out, "@read{}", i).unwrap();
+            out.write_all(read).unwrap();
+            out.write_all(b"\n+\n").unwrap();
+            out.write_all(&qual).unwrap();
+            out.write_all(b"\n").unwrap();
+        }
+    }
+    out.flush().unwrap();
+    matched
+}
+
+fn main() {
+    let reads = synthetic_reads();
+
+    let start = Instant::now();
+    let blocking = run(&reads, &mut BufWriter::with_capacity(64 * 1024, SlowOutput));
+    let blocking_elapsed = start.elapsed();
+
+    let start = Instant::now();
+    let overlapped = run(&reads, &mut AsyncWriter::new(SlowOutput));
+    let overlapped_elapsed = start.elapsed();
+
+    assert_eq!(blocking, overlapped);
+    println!(
+        "blocking writes {:>8.2?}  writer thread {:>8.2?}",
+        blocking_elapsed, overlapped_elapsed
+    );
+}
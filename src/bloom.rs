@@ -0,0 +1,111 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// A fixed-size Bloom filter over byte strings, used to estimate how many
+/// items were seen before in memory independent of the number of distinct
+/// items.
+///
+/// The filter is sized for `capacity` items at a target false-positive rate;
+/// once more items are inserted the real rate rises above the target.
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    inserted: usize,
+    capacity: usize,
+}
+
+impl BloomFilter {
+    /// Create a filter for `capacity` items with false-positive rate
+    /// `fp_rate` (in `(0, 1)`), using the optimal bit and hash counts.
+    pub fn new(capacity: usize, fp_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let n = capacity.max(1) as f64;
+        let num_bits = (-n * fp_rate.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / n) * ln2).round().max(1.0) as u32;
+        Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+            inserted: 0,
+            capacity,
+        }
+    }
+
+    /// Insert `item` and return whether it was (probably) already present.
+    /// A `true` may be a false positive; a `false` is always correct.
+    pub fn insert(&mut self, item: &[u8]) -> bool {
+        let mut present = true;
+        for (word, mask) in self.positions(item) {
+            present &= self.bits[word] & mask != 0;
+            self.bits[word] |= mask;
+        }
+        if !present {
+            self.inserted += 1;
+        }
+        present
+    }
+
+    /// Whether `item` was (probably) inserted before, without inserting it.
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.positions(item)
+            .all(|(word, mask)| self.bits[word] & mask != 0)
+    }
+
+    /// `(word, mask)` of each bit of `item`, by double hashing: the i-th bit
+    /// is `h1 + i * h2`.
+    fn positions(&self, item: &[u8]) -> impl Iterator<Item = (usize, u64)> {
+        let (h1, h2) = Self::hashes(item);
+        let num_bits = self.num_bits;
+        (0..u64::from(self.num_hashes)).map(move |i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % num_bits;
+            ((bit / 64) as usize, 1u64 << (bit % 64))
+        })
+    }
+
+    /// Whether more distinct items were inserted than the filter was sized
+    /// for, so that its false-positive rate exceeds the target.
+    pub fn is_over_capacity(&self) -> bool {
+        self.inserted > self.capacity
+    }
+
+    fn hashes(item: &[u8]) -> (u64, u64) {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        let h1 = hasher.finish();
+        // A second, independent hash; odd so that the indices cycle through all bits
+        0x9E37_79B9_7F4A_7C15u64.hash(&mut hasher);
+        (h1, hasher.finish() | 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter_has_no_false_negatives() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+        for i in 0..1000u32 {
+            filter.insert(format!("item{}", i).as_bytes());
+        }
+        for i in 0..1000u32 {
+            assert!(filter.insert(format!("item{}", i).as_bytes()));
+        }
+        assert!(!filter.is_over_capacity());
+    }
+
+    #[test]
+    fn test_bloom_filter_false_positive_rate() {
+        let mut filter = BloomFilter::new(10_000, 0.01);
+        for i in 0..10_000u32 {
+            filter.insert(format!("seen{}", i).as_bytes());
+        }
+        let false_positives = (0..10_000u32)
+            .filter(|i| filter.contains(format!("new{}", i).as_bytes()))
+            .count();
+        // 1% target; allow for sampling noise
+        assert!(false_positives < 200, "{} false positives", false_positives);
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+/// Index of `base` in a vote column: A, C, G, T, or 4 for anything else.
+fn base_index(base: u8) -> usize {
+    match base.to_ascii_uppercase() {
+        b'A' => 0,
+        b'C' => 1,
+        b'G' => 2,
+        b'T' => 3,
+        _ => 4,
+    }
+}
+
+const BASES: [u8; 5] = *b"ACGTN";
+
+/// Majority-vote consensus of the reads of one UMI group, built up one read
+/// at a time so only per-position vote counts are kept, not the reads.
+#[derive(Debug, Clone, Default)]
+pub struct Consensus {
+    /// Header of the first read of the group.
+    first_header: Vec<u8>,
+    reads: usize,
+    /// Votes for A, C, G, T and other bases at each position.
+    votes: Vec<[u32; 5]>,
+    /// Highest quality seen for each base at each position, if any read of
+    /// the group had qualities.
+    quals: Option<Vec<[u8; 5]>>,
+}
+
+impl Consensus {
+    /// Add a read with sequence `seq` and optional Phred+33 `qual`.
+    pub fn add(&mut self, header: &[u8], seq: &[u8], qual: Option<&[u8]>) {
+        if self.reads == 0 {
+            self.first_header = header.to_vec();
+        }
+        self.reads += 1;
+        if self.votes.len() < seq.len() {
+            self.votes.resize(seq.len(), [0; 5]);
+        }
+        for (column, &base) in self.votes.iter_mut().zip(seq) {
+            column[base_index(base)] += 1;
+        }
+        if let Some(qual) = qual {
+            let quals = self.quals.get_or_insert_with(Vec::new);
+            if quals.len() < seq.len() {
+                quals.resize(seq.len(), [0; 5]);
+            }
+            for ((column, &base), &q) in quals.iter_mut().zip(seq).zip(qual) {
+                let best = &mut column[base_index(base)];
+                *best = (*best).max(q);
+            }
+        }
+    }
+
+    /// Number of reads in the group.
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+
+    /// Header of the first read of the group.
+    pub fn first_header(&self) -> &[u8] {
+        &self.first_header
+    }
+
+    /// The consensus sequence and, if the reads had qualities, its
+    /// qualities. Each base is the most frequent one at its position (ties go
+    /// to A, C, G, T, N in that order) with the best quality it was read
+    /// with. The consensus ends where fewer than half of the reads reach.
+    pub fn call(&self) -> (Vec<u8>, Option<Vec<u8>>) {
+        let len = self
+            .votes
+            .iter()
+            .take_while(|column| 2 * column.iter().sum::<u32>() as usize >= self.reads)
+            .count();
+        let winners: Vec<usize> = self.votes[..len]
+            .iter()
+            .map(|column| {
+                // First maximum, so ties resolve in `BASES` order
+                (0..5).fold(0, |best, i| if column[i] > column[best] { i } else { best })
+            })
+            .collect();
+        let seq = winners.iter().map(|&i| BASES[i]).collect();
+        let qual = self.quals.as_ref().map(|quals| {
+            winners
+                .iter()
+                .zip(quals)
+                .map(|(&i, column)| column[i].max(b'!'))
+                .collect()
+        });
+        (seq, qual)
+    }
+}
+
+/// Consensus groups keyed by UMI, in order of first appearance.
+#[derive(Debug, Clone, Default)]
+pub struct ConsensusGroups {
+    index: HashMap<Vec<u8>, usize>,
+    groups: Vec<(Vec<u8>, Consensus)>,
+}
+
+impl ConsensusGroups {
+    /// Add a read to the group of `umi`.
+    pub fn add(&mut self, umi: &[u8], header: &[u8], seq: &[u8], qual: Option<&[u8]>) {
+        let i = match self.index.get(umi) {
+            Some(&i) => i,
+            None => {
+                self.groups.push((umi.to_vec(), Consensus::default()));
+                self.index.insert(umi.to_vec(), self.groups.len() - 1);
+                self.groups.len() - 1
+            }
+        };
+        self.groups[i].1.add(header, seq, qual);
+    }
+
+    /// The `(umi, consensus)` groups in order of first appearance.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &Consensus)> {
+        self.groups.iter().map(|(umi, c)| (umi.as_slice(), c))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_majority_vote_corrects_single_error() {
+        let mut consensus = Consensus::default();
+        consensus.add(b"r1", b"ACGTACGT", Some(b"IIIIIIII"));
+        consensus.add(b"r2", b"ACGAACGT", Some(b"IIII5III"));
+        consensus.add(b"r3", b"ACGTACGTAA", Some(b"II#IIIIIII"));
+        let (seq, qual) = consensus.call();
+        assert_eq!(seq, b"ACGTACGT");
+        // The position only reached by one of three reads is dropped
+        assert_eq!(qual.unwrap(), b"IIIIIIII");
+        assert_eq!(consensus.reads(), 3);
+        assert_eq!(consensus.first_header(), b"r1");
+    }
+
+    #[test]
+    fn test_groups_keep_first_appearance_order() {
+        let mut groups = ConsensusGroups::default();
+        groups.add(b"TTTT", b"a", b"AC", None);
+        groups.add(b"GGGG", b"b", b"AC", None);
+        groups.add(b"TTTT", b"c", b"AG", None);
+        let umis: Vec<&[u8]> = groups.iter().map(|(umi, _)| umi).collect();
+        assert_eq!(umis, [b"TTTT", b"GGGG"]);
+        let (_, first) = groups.iter().next().unwrap();
+        assert_eq!(first.reads(), 2);
+        // A one-one tie goes to the earlier base in ACGT order
+        assert_eq!(first.call(), (b"AC".to_vec(), None));
+    }
+}
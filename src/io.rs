@@ -1,10 +1,16 @@
 use anyhow::{Context, Result};
+use flate2::read::MultiGzDecoder;
 use flate2::write::GzEncoder;
-use flate2::Compression;
+use flate2::{Compression, GzBuilder};
 use rust_htslib::bam;
-use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::path::Path;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::OnceLock;
+use std::thread::{self, JoinHandle};
 
 /// Generic writer abstraction that can be either a FASTQ writer, a BAM writer,
 /// or a no-op sink used when the user did not request any output files.
@@ -15,26 +21,88 @@ use std::path::Path;
 pub enum GenericWriter {
     Fastq(Box<dyn Write>),
     Bam(bam::Writer),
+    /// An output whose file is only created once the first record is written
+    /// to it, so that outputs without records leave no file behind.
+    Deferred(DeferredOutput),
+    /// FASTQ outputs that BAM records are converted to and split into by mate.
+    Mates(Box<MateWriters>),
     /// No-op sink: used when output was not requested (no files should be written).
     Sink,
+    /// No-op sink that counts the records written to it, to check where
+    /// records are routed without writing any output.
+    Counting(usize),
+}
+
+/// The file a `GenericWriter::Deferred` creates on its first record.
+pub enum DeferredOutput {
+    Fastq(PathBuf),
+    Bam(PathBuf, bam::Header, bam::Format),
 }
 
 impl GenericWriter {
-    /// Write a BAM record to the underlying BAM writer.
+    /// Create the file of a `Deferred` writer and become the writer for it.
+    fn open_deferred(&mut self) -> Result<()> {
+        if let Self::Deferred(output) = self {
+            *self = match output {
+                DeferredOutput::Fastq(path) => Self::Fastq(create_fastq_writer(path)?),
+                DeferredOutput::Bam(path, header, format) => {
+                    Self::Bam(create_bam_writer(path, header, *format)?)
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// Write a BAM record to the underlying BAM writer, or as FASTQ to the
+    /// writer of its mate.
     ///
-    /// No-op when the `GenericWriter` is not a BAM writer.
+    /// No-op when the `GenericWriter` is not a BAM or mate writer.
     pub fn write_bam(&mut self, rec: &bam::Record) -> Result<()> {
+        match self {
+            Self::Mates(mates) => return mates.write(rec),
+            Self::Counting(records) => *records += 1,
+            _ => {}
+        }
+        self.open_deferred()?;
         if let Self::Bam(ref mut w) = self {
             w.write(rec).context("Failed to write BAM record")?;
         }
         Ok(())
     }
 
+    /// Flush buffered FASTQ output to its file, surfacing write errors that
+    /// would otherwise be lost when the writer is dropped. A no-op for BAM
+    /// outputs, which htslib flushes when they are closed.
+    pub fn flush(&mut self) -> Result<()> {
+        match self {
+            Self::Fastq(w) => w.flush().context("Failed to flush FASTQ output"),
+            Self::Mates(mates) => {
+                mates.unpaired.flush()?;
+                mates.r1.flush()?;
+                mates.r2.flush()
+            }
+            Self::Bam(_) | Self::Deferred(_) | Self::Sink | Self::Counting(_) => Ok(()),
+        }
+    }
+
+    /// Number of records written to a `Counting` sink, `None` for other
+    /// writers.
+    pub fn records_written(&self) -> Option<usize> {
+        match self {
+            Self::Counting(records) => Some(*records),
+            _ => None,
+        }
+    }
+
     /// Write a FASTQ-formatted record to the underlying writer.
     ///
     /// This writes a single `@<header>\n<seq>\n+\n<qual>` entry; if `qual` is
     /// `None`, a placeholder `+` line is still emitted.
     pub fn write_fastq(&mut self, head: &[u8], seq: &[u8], qual: Option<&[u8]>) -> Result<()> {
+        if let Self::Counting(records) = self {
+            *records += 1;
+        }
+        self.open_deferred()?;
         if let Self::Fastq(ref mut w) = self {
             w.write_all(b"@")?;
             w.write_all(head)?;
@@ -50,11 +118,154 @@ impl GenericWriter {
     }
 }
 
+/// FASTQ writers for BAM records, split by mate like `samtools fastq`:
+/// first and last mates of paired records go to `r1` and `r2`, everything
+/// else to `unpaired`. Records are written in sequencing orientation, so
+/// reverse-strand records are reverse complemented.
+pub struct MateWriters {
+    pub unpaired: GenericWriter,
+    pub r1: GenericWriter,
+    pub r2: GenericWriter,
+}
+
+impl MateWriters {
+    /// Writers for `path` split into `<base>.R1.fq` and `<base>.R2.fq` (see
+    /// `mate_path`), with unpaired records in `path` itself. The unpaired
+    /// file is only created if a record goes to it, the mate files only if
+    /// `deferred` is set.
+    pub fn new(path: &Path, deferred: bool) -> Result<Self> {
+        let open = |path: PathBuf| -> Result<GenericWriter> {
+            Ok(if deferred {
+                GenericWriter::Deferred(DeferredOutput::Fastq(path))
+            } else {
+                GenericWriter::Fastq(create_fastq_writer(&path)?)
+            })
+        };
+        Ok(Self {
+            unpaired: GenericWriter::Deferred(DeferredOutput::Fastq(path.to_path_buf())),
+            r1: open(mate_path(path, "R1"))?,
+            r2: open(mate_path(path, "R2"))?,
+        })
+    }
+
+    /// Write `rec` as FASTQ to the writer of its mate. Secondary and
+    /// supplementary alignments repeat a primary record and are skipped.
+    fn write(&mut self, rec: &bam::Record) -> Result<()> {
+        if rec.is_secondary() || rec.is_supplementary() {
+            return Ok(());
+        }
+        let writer = match (
+            rec.is_paired(),
+            rec.is_first_in_template(),
+            rec.is_last_in_template(),
+        ) {
+            (true, true, false) => &mut self.r1,
+            (true, false, true) => &mut self.r2,
+            _ => &mut self.unpaired,
+        };
+        let mut seq = rec.seq().as_bytes();
+        // BAM marks missing qualities with 0xff
+        let mut qual: Option<Vec<u8>> = match rec.qual() {
+            [0xff, ..] => None,
+            qual => Some(qual.iter().map(|q| q.saturating_add(33)).collect()),
+        };
+        if rec.is_reverse() {
+            seq = crate::matcher::reverse_complement(&seq);
+            if let Some(ref mut qual) = qual {
+                qual.reverse();
+            }
+        }
+        writer.write_fastq(rec.qname(), &seq, qual.as_deref())
+    }
+}
+
+/// `path` with `.<mate>` inserted before its FASTQ suffix, e.g.
+/// `out.removed.fq` becomes `out.removed.R1.fq`.
+pub fn mate_path(path: &Path, mate: &str) -> PathBuf {
+    let name = path.to_string_lossy();
+    let suffix = [".fq.gz", ".fastq.gz", ".fq", ".fastq"]
+        .into_iter()
+        .find(|s| name.ends_with(s))
+        .unwrap_or_default();
+    let base = &name[..name.len() - suffix.len()];
+    PathBuf::from(format!("{}.{}{}", base, mate, suffix))
+}
+
 /// The common interface for any sequence record.
 pub trait BioRecord: Send + Sync {
     fn seq(&self) -> &[u8];
+    /// Call `f` with the sequence. Records that do not keep a decoded copy
+    /// of their sequence decode it into a reusable buffer instead of
+    /// allocating one per record.
+    fn with_seq<T>(&self, f: impl FnOnce(&[u8]) -> T) -> T {
+        f(self.seq())
+    }
+    /// Number of bases of the sequence.
+    fn seq_len(&self) -> usize {
+        self.seq().len()
+    }
     fn header(&self) -> &[u8];
     fn write_to(self, writer: &mut GenericWriter) -> Result<()>;
+    /// Value of the SAM/BAM string tag `tag`, if the record carries it.
+    /// Formats without tags always return `None`.
+    fn tag(&self, _tag: &[u8]) -> Option<&[u8]> {
+        None
+    }
+    /// Phred+33 base qualities, if the record has them in text form. Only
+    /// FASTQ records provide them.
+    fn qual(&self) -> Option<&[u8]> {
+        None
+    }
+    /// Shorten the sequence (and qualities) to `len` bases. Only supported
+    /// for FASTQ records; other formats are left unchanged.
+    fn truncate(&mut self, _len: usize) {}
+    /// Annotate the record with the mismatch count of its UMI match.
+    fn annotate_mismatches(&mut self, _mismatches: u32) -> Result<()> {
+        Ok(())
+    }
+    /// Mean Phred quality of the bases `start..end`, or `None` if the record
+    /// has no qualities.
+    fn window_quality(&self, _start: usize, _end: usize) -> Option<f64> {
+        None
+    }
+    /// Annotate the record with the mean quality of its UMI match.
+    fn annotate_window_quality(&mut self, _quality: f64) -> Result<()> {
+        Ok(())
+    }
+    /// Remove the UMI field `field` from the read name (see
+    /// `crate::strip_umi_field`), keeping it as a comment or tag if
+    /// `keep_umi`. Names without that field are left unchanged.
+    fn strip_header_umi(&mut self, _field: isize, _keep_umi: bool) -> Result<()> {
+        Ok(())
+    }
+    /// Read name shared by both mates, for the primary record of a paired
+    /// read. Formats without mate information return `None`.
+    fn mate_name(&self) -> Option<&[u8]> {
+        None
+    }
+    /// Reference interval covered by the read bases
+    /// `start..end`, for aligned records. Unaligned formats return `None`.
+    fn reference_interval(&self, _start: usize, _end: usize) -> Option<ReferenceInterval> {
+        None
+    }
+}
+
+/// Mean of Phred `scores`, or `None` if there are none.
+fn mean_phred(scores: impl ExactSizeIterator<Item = u8>) -> Option<f64> {
+    let n = scores.len();
+    let sum: u64 = scores.map(u64::from).sum();
+    (n > 0).then(|| sum as f64 / n as f64)
+}
+
+/// Zero-based, half-open interval on a reference sequence of the BAM header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReferenceInterval {
+    /// Index of the reference sequence in the BAM header.
+    pub tid: u32,
+    pub start: i64,
+    pub end: i64,
+    /// Whether the read is aligned to the reverse strand.
+    pub reverse: bool,
 }
 
 /// A FASTQ-style in-memory record used for batching and processing.
@@ -77,19 +288,128 @@ impl BioRecord for FastqRecord {
     fn write_to(self, writer: &mut GenericWriter) -> Result<()> {
         writer.write_fastq(&self.head, &self.seq, self.qual.as_deref())
     }
+    fn qual(&self) -> Option<&[u8]> {
+        self.qual.as_deref()
+    }
+    fn truncate(&mut self, len: usize) {
+        self.seq.truncate(len);
+        if let Some(ref mut q) = self.qual {
+            q.truncate(len);
+        }
+    }
+    /// Appends an `mm:<n>` comment to the header.
+    fn annotate_mismatches(&mut self, mismatches: u32) -> Result<()> {
+        self.head
+            .extend_from_slice(format!(" mm:{}", mismatches).as_bytes());
+        Ok(())
+    }
+    /// Qualities are decoded as Phred+33.
+    fn window_quality(&self, start: usize, end: usize) -> Option<f64> {
+        let window = self.qual.as_ref()?.get(start..end)?;
+        mean_phred(window.iter().map(|q| q.saturating_sub(33)))
+    }
+    /// Appends a `wq:<q>` comment to the header.
+    fn annotate_window_quality(&mut self, quality: f64) -> Result<()> {
+        self.head
+            .extend_from_slice(format!(" wq:{:.1}", quality).as_bytes());
+        Ok(())
+    }
+    /// Keeps the UMI as a `umi:<UMI>` comment, as `--collapse-output` does.
+    fn strip_header_umi(&mut self, field: isize, keep_umi: bool) -> Result<()> {
+        if let Some((mut head, umi)) = crate::strip_umi_field(&self.head, field) {
+            if keep_umi {
+                head.extend_from_slice(b" umi:");
+                head.extend_from_slice(&umi);
+            }
+            self.head = head;
+        }
+        Ok(())
+    }
 }
 
 /// A small wrapper for a BAM record that also stores a copy of the sequence
 /// bytes so it can implement `BioRecord` without lifetime issues.
+///
+/// Records created with `without_seq_copy` skip the copy: `with_seq` decodes
+/// the packed sequence into a per-thread scratch buffer on every call, and
+/// only `seq` still caches a copy on first use.
+///
+/// `seq` keeps the orientation stored in the BAM file, which for
+/// reverse-strand reads is the reverse complement of the sequenced read. The
+/// qualities are stored in the same orientation, so read offsets (e.g. of a
+/// UMI match) index both alike. Anything that reverse-complements `seq` must
+/// reverse the qualities too.
 pub struct BamRecord {
     pub rec: bam::Record,
-    #[allow(dead_code)] // The seq is read via the trait
-    pub seq: Vec<u8>,
+    /// Decoded sequence, filled on creation or on the first call to `seq`.
+    seq: OnceLock<Vec<u8>>,
+}
+
+thread_local! {
+    /// Scratch buffer `BamRecord::with_seq` decodes sequences into.
+    static SEQ_SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Decode the sequence of `rec` into `buf`, replacing its contents.
+///
+/// htslib packs bases as 4-bit codes of `=ACMGRSVTWYHKDBN`. `=` stands for
+/// the reference base, which is unknown without the reference, and the
+/// IUPAC ambiguity codes are uncertain calls, so both are reported as `N`,
+/// which the matcher always counts as a mismatch.
+fn decode_seq_into(rec: &bam::Record, buf: &mut Vec<u8>) {
+    let seq = rec.seq();
+    buf.clear();
+    buf.extend((0..seq.len()).map(|i| match seq.encoded_base(i) {
+        1 => b'A',
+        2 => b'C',
+        4 => b'G',
+        8 => b'T',
+        _ => b'N',
+    }));
+}
+
+impl BamRecord {
+    /// Wrap `rec` with its decoded sequence (see `decode_seq_into`). The
+    /// record itself is written out unchanged.
+    pub fn new(rec: bam::Record) -> Self {
+        let mut seq = Vec::new();
+        decode_seq_into(&rec, &mut seq);
+        Self {
+            rec,
+            seq: OnceLock::from(seq),
+        }
+    }
+
+    /// Wrap `rec` without decoding its sequence up front.
+    pub fn without_seq_copy(rec: bam::Record) -> Self {
+        Self {
+            rec,
+            seq: OnceLock::new(),
+        }
+    }
 }
 
 impl BioRecord for BamRecord {
     fn seq(&self) -> &[u8] {
-        &self.seq
+        self.seq.get_or_init(|| {
+            let mut seq = Vec::new();
+            decode_seq_into(&self.rec, &mut seq);
+            seq
+        })
+    }
+    fn with_seq<T>(&self, f: impl FnOnce(&[u8]) -> T) -> T {
+        if let Some(seq) = self.seq.get() {
+            return f(seq);
+        }
+        // Taken out of the cell so that `f` may itself call `with_seq`
+        let mut buf = SEQ_SCRATCH.with(|scratch| std::mem::take(&mut *scratch.borrow_mut()));
+        decode_seq_into(&self.rec, &mut buf);
+        let result = f(&buf);
+        SEQ_SCRATCH.with(|scratch| *scratch.borrow_mut() = buf);
+        result
+    }
+    fn seq_len(&self) -> usize {
+        self.rec.seq().len()
     }
     fn header(&self) -> &[u8] {
         self.rec.qname()
@@ -97,29 +417,552 @@ impl BioRecord for BamRecord {
     fn write_to(self, writer: &mut GenericWriter) -> Result<()> {
         writer.write_bam(&self.rec)
     }
+    fn tag(&self, tag: &[u8]) -> Option<&[u8]> {
+        match self.rec.aux(tag) {
+            Ok(bam::record::Aux::String(value)) => Some(value.as_bytes()),
+            _ => None,
+        }
+    }
+    /// Sets the `mm:i` tag, replacing any existing one.
+    fn annotate_mismatches(&mut self, mismatches: u32) -> Result<()> {
+        // Fails only if the tag is absent, which is fine
+        let _ = self.rec.remove_aux(b"mm");
+        self.rec
+            .push_aux(b"mm", bam::record::Aux::U32(mismatches))
+            .context("Failed to set mm tag")
+    }
+    fn window_quality(&self, start: usize, end: usize) -> Option<f64> {
+        let window = self.rec.qual().get(start..end)?;
+        // BAM marks missing qualities with 0xff
+        if window.first() == Some(&0xff) {
+            return None;
+        }
+        mean_phred(window.iter().copied())
+    }
+    /// Sets the `wq:f` tag, replacing any existing one.
+    fn annotate_window_quality(&mut self, quality: f64) -> Result<()> {
+        let _ = self.rec.remove_aux(b"wq");
+        self.rec
+            .push_aux(b"wq", bam::record::Aux::Float(quality as f32))
+            .context("Failed to set wq tag")
+    }
+    /// Keeps the UMI in the `RX:Z` tag, replacing any existing one.
+    fn strip_header_umi(&mut self, field: isize, keep_umi: bool) -> Result<()> {
+        let Some((qname, umi)) = crate::strip_umi_field(self.rec.qname(), field) else {
+            return Ok(());
+        };
+        self.rec.set_qname(&qname);
+        if keep_umi {
+            let _ = self.rec.remove_aux(b"RX");
+            let umi = String::from_utf8_lossy(&umi);
+            self.rec
+                .push_aux(b"RX", bam::record::Aux::String(&umi))
+                .context("Failed to set RX tag")?;
+        }
+        Ok(())
+    }
+    /// Secondary and supplementary alignments repeat a mate and are skipped.
+    fn mate_name(&self) -> Option<&[u8]> {
+        let primary = !self.rec.is_secondary() && !self.rec.is_supplementary();
+        (self.rec.is_paired() && primary).then(|| self.rec.qname())
+    }
+    /// Soft-clipped bases are projected onto the reference as if aligned,
+    /// so a UMI in a clip still gets the coordinates it would occupy.
+    fn reference_interval(&self, start: usize, end: usize) -> Option<ReferenceInterval> {
+        if self.rec.is_unmapped() || self.rec.tid() < 0 || start >= end {
+            return None;
+        }
+        let cigar = self.rec.cigar();
+        let (first, _) = reference_position(&cigar, self.rec.pos(), start);
+        let (last, aligned) = reference_position(&cigar, self.rec.pos(), end - 1);
+        let last = last + i64::from(aligned);
+        Some(ReferenceInterval {
+            tid: self.rec.tid() as u32,
+            start: first.max(0),
+            end: last.max(first).max(0),
+            reverse: self.rec.is_reverse(),
+        })
+    }
+}
+
+/// Reference position of the read base at `offset` for an alignment starting
+/// at `pos` with `cigar`, and whether that base consumes the reference.
+/// Soft clips are treated like matches and extend the alignment past `pos`
+/// and its end; inserted bases sit at the next reference position.
+fn reference_position(cigar: &[bam::record::Cigar], pos: i64, offset: usize) -> (i64, bool) {
+    use bam::record::Cigar;
+    let leading_clip: i64 = cigar
+        .iter()
+        .take_while(|op| matches!(op, Cigar::HardClip(_) | Cigar::SoftClip(_)))
+        .filter(|op| matches!(op, Cigar::SoftClip(_)))
+        .map(|op| i64::from(op.len()))
+        .sum();
+    let mut ref_pos = pos - leading_clip;
+    let mut read_pos = 0;
+    for op in cigar {
+        let len = op.len() as usize;
+        let (consumes_read, consumes_ref) = match op {
+            Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_) | Cigar::SoftClip(_) => (true, true),
+            Cigar::Ins(_) => (true, false),
+            Cigar::Del(_) | Cigar::RefSkip(_) => (false, true),
+            Cigar::HardClip(_) | Cigar::Pad(_) => (false, false),
+        };
+        if consumes_read && offset < read_pos + len {
+            let within = if consumes_ref { offset - read_pos } else { 0 };
+            return (ref_pos + within as i64, consumes_ref);
+        }
+        if consumes_read {
+            read_pos += len;
+        }
+        if consumes_ref {
+            ref_pos += len as i64;
+        }
+    }
+    // Past the end of the alignment
+    (ref_pos + (offset - read_pos) as i64, true)
 }
 
 /// Create a writer for FASTQ output. If `path` ends with `.gz`, returns a
 /// gzip-wrapped writer.
 pub fn create_fastq_writer(path: &Path) -> Result<Box<dyn Write>> {
+    create_fastq_writer_with_level(path, Compression::default())
+}
+
+/// Like `create_fastq_writer`, compressing gzip output at `level`.
+pub fn create_fastq_writer_with_level(path: &Path, level: Compression) -> Result<Box<dyn Write>> {
     let file =
         File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    Ok(wrap_fastq_file(file, path, level))
+}
+
+/// Open a FASTQ writer for a checkpointed run. When resuming, `path` is cut
+/// back to the `resume_len` bytes recorded in the checkpoint, dropping
+/// anything an interrupted run wrote after it, and appended to; otherwise it
+/// is created afresh.
+///
+/// Gzip output ends its gzip member on every flush (see `GzMembers`), so the
+/// file is complete at each checkpoint and a resumed run adds new members.
+pub fn checkpointed_fastq_writer(
+    path: &Path,
+    level: Compression,
+    resume_len: Option<u64>,
+) -> Result<Box<dyn Write>> {
+    let file = match resume_len {
+        Some(len) => {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(path)
+                .with_context(|| format!("Failed to open {} for resuming", path.display()))?;
+            if file.metadata()?.len() < len {
+                anyhow::bail!(
+                    "{} is shorter than recorded in the checkpoint; it was modified since",
+                    path.display()
+                );
+            }
+            file.set_len(len)?;
+            file.seek(SeekFrom::End(0))?;
+            file
+        }
+        None => {
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?
+        }
+    };
+    let writer = BufWriter::new(file);
+    Ok(if path.extension().is_some_and(|e| e == "gz") {
+        Box::new(AsyncWriter::new(GzMembers::new(writer, level)))
+    } else {
+        Box::new(AsyncWriter::new(writer))
+    })
+}
+
+/// Gzip writer that ends the current gzip member on every flush, so the
+/// output is a complete multi-member gzip file after each flush. Members
+/// start lazily, so flushes without new data add no empty members.
+struct GzMembers<W: Write> {
+    /// Encoder of the current member, if data was written since the last flush.
+    encoder: Option<GzEncoder<W>>,
+    /// The underlying writer between members.
+    idle: Option<W>,
+    level: Compression,
+}
+
+impl<W: Write> GzMembers<W> {
+    fn new(writer: W, level: Compression) -> Self {
+        Self {
+            encoder: None,
+            idle: Some(writer),
+            level,
+        }
+    }
+}
+
+impl<W: Write> Write for GzMembers<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if let Some(writer) = self.idle.take() {
+            self.encoder = Some(reproducible_gz_encoder(writer, self.level));
+        }
+        self.encoder
+            .as_mut()
+            .expect("a member is open after starting one")
+            .write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(encoder) = self.encoder.take() {
+            self.idle = Some(encoder.finish()?);
+        }
+        self.idle
+            .as_mut()
+            .expect("no member is open after finishing it")
+            .flush()
+    }
+}
+
+/// Create a writer for a plain-text report, gzip-compressed if `path` ends
+/// with `.gz`.
+pub fn create_report_writer(path: &Path) -> Result<Box<dyn Write>> {
+    create_fastq_writer(path)
+}
+
+/// Gzip header OS byte meaning "unknown", used instead of the build platform
+/// so outputs do not differ between operating systems.
+const GZIP_OS_UNKNOWN: u8 = 255;
+
+/// Buffer `file` and gzip-wrap it at `level` if `path` ends with `.gz`.
+///
+/// The gzip header carries no timestamp and a fixed OS byte, so repeated runs
+/// produce byte-identical outputs for checksum-based pipeline caching.
+///
+/// Writing (and compressing) happens on an `AsyncWriter` thread so that it
+/// overlaps with matching the next batch.
+fn wrap_fastq_file(file: File, path: &Path, level: Compression) -> Box<dyn Write> {
     let writer = BufWriter::new(file);
     if path.extension().is_some_and(|e| e == "gz") {
-        Ok(Box::new(GzEncoder::new(writer, Compression::default())))
+        Box::new(AsyncWriter::new(reproducible_gz_encoder(writer, level)))
     } else {
-        Ok(Box::new(writer))
+        Box::new(AsyncWriter::new(writer))
     }
 }
 
-/// Create a BAM writer from `path` using `header` as a template.
-pub fn create_bam_writer(path: &Path, header: &bam::Header) -> Result<bam::Writer> {
-    bam::Writer::from_path(path, header, bam::Format::Bam).context("Failed to create BAM writer")
+/// Gzip encoder with a zero mtime and fixed OS byte in its header.
+fn reproducible_gz_encoder<W: Write>(writer: W, level: Compression) -> GzEncoder<W> {
+    GzBuilder::new()
+        .mtime(0)
+        .operating_system(GZIP_OS_UNKNOWN)
+        .write(writer, level)
+}
+
+/// Bytes buffered by `AsyncWriter` before they are handed to its thread.
+const ASYNC_CHUNK_BYTES: usize = 64 * 1024;
+/// Chunks queued for the writer thread before `AsyncWriter` blocks, bounding
+/// memory when the output is slower than matching.
+const ASYNC_QUEUE_CHUNKS: usize = 16;
+
+/// Work sent to the thread of an `AsyncWriter`.
+enum WriteAction {
+    Data(Vec<u8>),
+    /// Flush the inner writer and report the result.
+    Flush(mpsc::Sender<io::Result<()>>),
+}
+
+/// Writer that hands its data to a dedicated thread owning `inner`, so slow
+/// (e.g. compressed or remote) outputs do not stall the caller.
+///
+/// Chunks travel over a single FIFO channel and are written in the order they
+/// were produced. `flush` waits until everything written so far has reached
+/// `inner`; write errors of the thread are reported by the next `write` or
+/// `flush`. Dropping the writer writes the remaining data and waits for the
+/// thread, which drops (and so finishes) `inner`.
+pub struct AsyncWriter {
+    buf: Vec<u8>,
+    tx: Option<SyncSender<WriteAction>>,
+    handle: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl AsyncWriter {
+    pub fn new<W: Write + Send + 'static>(mut inner: W) -> Self {
+        let (tx, rx) = mpsc::sync_channel(ASYNC_QUEUE_CHUNKS);
+        let handle = thread::spawn(move || {
+            for action in rx {
+                match action {
+                    WriteAction::Data(data) => inner.write_all(&data)?,
+                    WriteAction::Flush(done) => {
+                        let _ = done.send(inner.flush());
+                    }
+                }
+            }
+            inner.flush()
+        });
+        Self {
+            buf: Vec::with_capacity(ASYNC_CHUNK_BYTES),
+            tx: Some(tx),
+            handle: Some(handle),
+        }
+    }
+
+    fn send(&mut self, action: WriteAction) -> io::Result<()> {
+        match self.tx.as_ref().map(|tx| tx.send(action)) {
+            Some(Ok(())) => Ok(()),
+            _ => Err(self.thread_error()),
+        }
+    }
+
+    fn send_buffered(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let data = std::mem::replace(&mut self.buf, Vec::with_capacity(ASYNC_CHUNK_BYTES));
+        self.send(WriteAction::Data(data))
+    }
+
+    /// The error that stopped the writer thread.
+    fn thread_error(&mut self) -> io::Error {
+        self.tx = None;
+        match self.handle.take().map(JoinHandle::join) {
+            Some(Ok(Err(e))) => e,
+            Some(Err(_)) => io::Error::other("output writer thread panicked"),
+            _ => io::Error::other("output writer thread stopped"),
+        }
+    }
+}
+
+impl Write for AsyncWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= ASYNC_CHUNK_BYTES {
+            self.send_buffered()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffered()?;
+        let (done_tx, done_rx) = mpsc::channel();
+        self.send(WriteAction::Flush(done_tx))?;
+        match done_rx.recv() {
+            Ok(result) => result,
+            Err(_) => Err(self.thread_error()),
+        }
+    }
+}
+
+impl Drop for AsyncWriter {
+    fn drop(&mut self) {
+        // Errors here have no caller to go to, like those of a dropped BufWriter
+        let _ = self.send_buffered();
+        self.tx = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Write `counts` as a two-column TSV (`<key_name>\tcount` header row) sorted
+/// by descending count, ties broken by key so the output is deterministic.
+pub fn write_count_table(
+    path: &Path,
+    key_name: &str,
+    counts: &HashMap<Vec<u8>, usize>,
+) -> Result<()> {
+    let mut rows: Vec<(&Vec<u8>, &usize)> = counts.iter().collect();
+    rows.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut w = BufWriter::new(file);
+    writeln!(w, "{}\tcount", key_name)?;
+    for (key, count) in rows {
+        w.write_all(key)?;
+        writeln!(w, "\t{}", count)?;
+    }
+    w.flush()
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Write a histogram of UMI match start offsets as a TSV (`offset\treads`
+/// header row) in ascending offset order.
+pub fn write_position_histogram(path: &Path, histogram: &BTreeMap<usize, usize>) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut w = BufWriter::new(file);
+    writeln!(w, "offset\treads")?;
+    for (offset, reads) in histogram {
+        writeln!(w, "{}\t{}", offset, reads)?;
+    }
+    w.flush()
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Write per-group read counts as a TSV with a
+/// `<key_name>\ttotal\twith_umi\tperc_with\twithout_umi\tperc_without` header
+/// row, mirroring the summary line. `groups` maps each group to its
+/// `(with_umi, without_umi)` counts; rows are sorted by group.
+pub fn write_group_table(
+    path: &Path,
+    key_name: &str,
+    groups: &HashMap<Vec<u8>, (usize, usize)>,
+) -> Result<()> {
+    let mut rows: Vec<(&Vec<u8>, &(usize, usize))> = groups.iter().collect();
+    rows.sort_by(|a, b| a.0.cmp(b.0));
+
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut w = BufWriter::new(file);
+    writeln!(
+        w,
+        "{}\ttotal\twith_umi\tperc_with\twithout_umi\tperc_without",
+        key_name
+    )?;
+    for (key, &(with_umi, without_umi)) in rows {
+        let total = with_umi + without_umi;
+        w.write_all(key)?;
+        writeln!(
+            w,
+            "\t{}\t{}\t{:.2}\t{}\t{:.2}",
+            total,
+            with_umi,
+            crate::percentage(with_umi, total),
+            without_umi,
+            crate::percentage(without_umi, total)
+        )?;
+    }
+    w.flush()
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Load a per-read report written with `--per-read-report`: a TSV with a
+/// `read_id\tfound` header row followed by one `true`/`false` verdict per read.
+/// Gzipped reports (written to a `.gz` path) are decompressed.
+pub fn read_verdicts(path: &Path) -> Result<HashMap<Vec<u8>, bool>> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut magic = [0; 2];
+    let gzipped = file.read(&mut magic)? == 2 && magic == [0x1f, 0x8b];
+    file.seek(SeekFrom::Start(0))?;
+    let mut content = String::new();
+    if gzipped {
+        MultiGzDecoder::new(file).read_to_string(&mut content)
+    } else {
+        file.read_to_string(&mut content)
+    }
+    .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut verdicts = HashMap::new();
+    for (i, line) in content.lines().enumerate().skip(1) {
+        // Columns after `found` (e.g. the matched candidate) are ignored
+        let mut columns = line.split('\t');
+        let found = match (columns.next(), columns.next()) {
+            (Some(id), Some("true")) => (id, true),
+            (Some(id), Some("false")) => (id, false),
+            _ => anyhow::bail!("Malformed line {} in {}", i + 1, path.display()),
+        };
+        verdicts.insert(found.0.as_bytes().to_vec(), found.1);
+    }
+    Ok(verdicts)
+}
+
+/// One line of a `--manifest`: an input and the parameters overriding the
+/// command-line defaults for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub umi_length: Option<usize>,
+    pub mismatches: Option<u32>,
+}
+
+/// Load a `--manifest`: a TSV with one input path per line, optionally
+/// followed by `umi_length` and `mismatches` columns. Missing, empty or `.`
+/// columns keep the command-line value; blank lines and `#` comments are
+/// skipped. Relative paths are resolved against the manifest's directory.
+pub fn read_manifest(path: &Path) -> Result<Vec<ManifestEntry>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut entries = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut columns = line.split('\t');
+        let input = columns.next().unwrap_or_default();
+        let umi_length = manifest_column(columns.next(), "umi_length", i, path)?;
+        let mismatches = manifest_column(columns.next(), "mismatches", i, path)?;
+        if columns.next().is_some() {
+            anyhow::bail!("Too many columns on line {} of {}", i + 1, path.display());
+        }
+        entries.push(ManifestEntry {
+            path: dir.join(input),
+            umi_length,
+            mismatches,
+        });
+    }
+    Ok(entries)
+}
+
+/// Parse an optional manifest column, `None` if missing, empty or `.`.
+fn manifest_column<T: std::str::FromStr>(
+    value: Option<&str>,
+    name: &str,
+    line: usize,
+    path: &Path,
+) -> Result<Option<T>> {
+    match value.map(str::trim) {
+        None | Some("") | Some(".") => Ok(None),
+        Some(v) => v.parse().map(Some).map_err(|_| {
+            anyhow::anyhow!(
+                "Invalid {} '{}' on line {} of {}",
+                name,
+                v,
+                line + 1,
+                path.display()
+            )
+        }),
+    }
+}
+
+/// The empty BGZF block every complete BGZF file (e.g. BAM) ends with.
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Whether `path` is BGZF-compressed but lacks the end-of-file marker block,
+/// i.e. was likely truncated. Uncompressed files (e.g. SAM) are never
+/// reported as truncated.
+pub fn is_truncated_bgzf(path: &Path) -> Result<bool> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut magic = [0u8; 4];
+    if file.read_exact(&mut magic).is_err() || magic != BGZF_EOF[..4] {
+        return Ok(false);
+    }
+    let len = file.metadata()?.len();
+    if len < BGZF_EOF.len() as u64 {
+        return Ok(true);
+    }
+    let mut tail = [0u8; 28];
+    file.seek(SeekFrom::End(-(BGZF_EOF.len() as i64)))
+        .and_then(|_| file.read_exact(&mut tail))
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(tail != BGZF_EOF)
+}
+
+/// Create a BAM (or, with `bam::Format::Sam`, SAM) writer from `path` using
+/// `header` as a template.
+///
+/// The writer serializes its own copy of `header`, so several writers
+/// created from the same header share no htslib state.
+pub fn create_bam_writer(
+    path: &Path,
+    header: &bam::Header,
+    format: bam::Format,
+) -> Result<bam::Writer> {
+    bam::Writer::from_path(path, header, format).context("Failed to create BAM writer")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::matcher::find_umi_in_read;
     use std::io::{Result as IoResult, Write};
     use std::sync::{Arc, Mutex};
 
@@ -135,6 +978,137 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_read_manifest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("manifest.tsv");
+        std::fs::write(
+            &path,
+            "# path\tumi_length\tmismatches\na.fq\nb.fq\t8\n\n/data/c.bam\t.\t2\n",
+        )
+        .unwrap();
+        let entries = read_manifest(&path).unwrap();
+        assert_eq!(
+            entries,
+            [
+                ManifestEntry {
+                    path: tmp.path().join("a.fq"),
+                    umi_length: None,
+                    mismatches: None,
+                },
+                ManifestEntry {
+                    path: tmp.path().join("b.fq"),
+                    umi_length: Some(8),
+                    mismatches: None,
+                },
+                ManifestEntry {
+                    path: PathBuf::from("/data/c.bam"),
+                    umi_length: None,
+                    mismatches: Some(2),
+                },
+            ]
+        );
+
+        std::fs::write(&path, "a.fq\teight\n").unwrap();
+        let err = read_manifest(&path).unwrap_err().to_string();
+        assert!(
+            err.contains("Invalid umi_length 'eight' on line 1"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_is_truncated_bgzf() {
+        let tmp = tempfile::tempdir().unwrap();
+        let bam = std::fs::read("tests/data/example.bam").unwrap();
+        assert!(!is_truncated_bgzf(Path::new("tests/data/example.bam")).unwrap());
+        assert!(!is_truncated_bgzf(Path::new("tests/data/single_cell.sam")).unwrap());
+
+        let truncated = tmp.path().join("truncated.bam");
+        std::fs::write(&truncated, &bam[..bam.len() - BGZF_EOF.len()]).unwrap();
+        assert!(is_truncated_bgzf(&truncated).unwrap());
+    }
+
+    #[test]
+    fn test_bam_record_reports_reference_and_ambiguity_bases_as_n() {
+        let mut rec = bam::Record::new();
+        rec.set(b"r1", None, b"TTAC=TAMGTTT", &[30; 12]);
+        let record = BamRecord::new(rec);
+        assert_eq!(record.seq(), b"TTACNTANGTTT");
+        // Each decoded base counts as a mismatch, never as a match
+        let umi = b"ACGTACGT";
+        assert!(find_umi_in_read(umi, record.seq(), 1).is_none());
+        assert_eq!(
+            find_umi_in_read(umi, record.seq(), 2).map(|m| (m.position, m.mismatches)),
+            Some((2, 2))
+        );
+        // The record itself keeps its bases
+        assert_eq!(record.rec.seq().as_bytes(), b"TTAC=TAMGTTT");
+    }
+
+    #[test]
+    fn test_bam_record_without_seq_copy_decodes_on_demand() {
+        let mut rec = bam::Record::new();
+        rec.set(b"r1", None, b"TTAC=TAMGTTT", &[30; 12]);
+        let record = BamRecord::without_seq_copy(rec);
+        assert_eq!(record.seq_len(), 12);
+        assert_eq!(record.with_seq(<[u8]>::to_vec), b"TTACNTANGTTT");
+        assert!(record.seq.get().is_none());
+        assert_eq!(record.seq(), b"TTACNTANGTTT");
+    }
+
+    #[test]
+    fn test_mate_path() {
+        assert_eq!(
+            mate_path(Path::new("out.removed.fq"), "R1"),
+            PathBuf::from("out.removed.R1.fq")
+        );
+        assert_eq!(
+            mate_path(Path::new("dir.v2/out.fastq.gz"), "R2"),
+            PathBuf::from("dir.v2/out.R2.fastq.gz")
+        );
+    }
+
+    #[test]
+    fn test_window_quality_of_reverse_strand_bam_record() {
+        let bam_record = |flags: u16| {
+            let mut rec = bam::Record::new();
+            rec.set(
+                b"r1",
+                None,
+                b"TTACGTACGTTT",
+                &[40, 40, 5, 5, 5, 5, 35, 35, 35, 35, 40, 40],
+            );
+            rec.set_flags(flags);
+            BamRecord::new(rec)
+        };
+        let forward = bam_record(0);
+        let reverse = bam_record(0x10);
+        assert!(reverse.rec.is_reverse());
+        // Sequence and qualities share the stored orientation on both strands
+        assert_eq!(reverse.seq(), forward.seq());
+        assert_eq!(forward.window_quality(2, 10), Some(20.0));
+        assert_eq!(reverse.window_quality(2, 10), forward.window_quality(2, 10));
+    }
+
+    #[test]
+    fn test_reference_position_follows_cigar() {
+        use bam::record::Cigar::*;
+        // 3S4M2I3M2D4M starting at reference position 100
+        let cigar = [SoftClip(3), Match(4), Ins(2), Match(3), Del(2), Match(4)];
+        // Soft-clipped bases are projected before the alignment start
+        assert_eq!(reference_position(&cigar, 100, 0), (97, true));
+        assert_eq!(reference_position(&cigar, 100, 3), (100, true));
+        // Inserted bases sit at the next reference position
+        assert_eq!(reference_position(&cigar, 100, 7), (104, false));
+        assert_eq!(reference_position(&cigar, 100, 9), (104, true));
+        // The deletion shifts the following bases
+        assert_eq!(reference_position(&cigar, 100, 12), (109, true));
+        // Beyond the alignment the projection continues
+        assert_eq!(reference_position(&cigar, 100, 17), (114, true));
+    }
+
     #[test]
     fn test_write_fastq_format() {
         let buf = Arc::new(Mutex::new(Vec::new()));
@@ -149,4 +1123,91 @@ mod tests {
         assert!(s.starts_with("@read1\n"));
         assert!(s.contains("ACGT\n+\n!!!!"));
     }
+
+    #[test]
+    fn test_flush_makes_fastq_output_visible() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("out.fq");
+        let mut writer = GenericWriter::Fastq(create_fastq_writer(&path).unwrap());
+        writer
+            .write_fastq(b"read1", b"ACGT", Some(b"IIII"))
+            .unwrap();
+        // Still buffered until flushed
+        assert!(std::fs::read(&path).unwrap().is_empty());
+        writer.flush().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "@read1\nACGT\n+\nIIII\n"
+        );
+        drop(writer);
+    }
+
+    /// Writer that sleeps before every write to mimic a slow output.
+    struct SlowWriter(Arc<Mutex<Vec<u8>>>);
+    impl Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_async_writer_preserves_order() {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let mut writer = GenericWriter::Fastq(Box::new(AsyncWriter::new(SlowWriter(buf.clone()))));
+        let mut expected = Vec::new();
+        for i in 0..20_000 {
+            let head = format!("read{}", i);
+            writer
+                .write_fastq(head.as_bytes(), b"ACGT", Some(b"IIII"))
+                .unwrap();
+            expected.extend_from_slice(format!("@{}\nACGT\n+\nIIII\n", head).as_bytes());
+        }
+        if let GenericWriter::Fastq(ref mut w) = writer {
+            w.flush().unwrap();
+        }
+        // Everything written before the flush has arrived, in order
+        assert_eq!(*buf.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_async_writer_reports_write_errors() {
+        struct FailingWriter;
+        impl Write for FailingWriter {
+            fn write(&mut self, _buf: &[u8]) -> IoResult<usize> {
+                Err(std::io::Error::other("disk full"))
+            }
+            fn flush(&mut self) -> IoResult<()> {
+                Ok(())
+            }
+        }
+        let mut writer = AsyncWriter::new(FailingWriter);
+        writer.write_all(b"@read\nACGT\n+\nIIII\n").unwrap();
+        let err = writer.flush().unwrap_err();
+        assert_eq!(err.to_string(), "disk full");
+    }
+
+    #[test]
+    fn test_gzip_output_is_reproducible() {
+        let tmp = tempfile::tempdir().unwrap();
+        let write = |name: &str| {
+            let path = tmp.path().join(name);
+            let mut w = GenericWriter::Fastq(create_fastq_writer(&path).unwrap());
+            w.write_fastq(b"read1:ACGT", b"ACGTACGT", Some(b"IIIIIIII"))
+                .unwrap();
+            drop(w);
+            std::fs::read(path).unwrap()
+        };
+
+        let first = write("first.fq.gz");
+        let second = write("second.fq.gz");
+        assert_eq!(first, second);
+        // No timestamp and an "unknown" OS byte in the gzip header
+        assert_eq!(&first[4..8], &[0, 0, 0, 0]);
+        assert_eq!(first[9], GZIP_OS_UNKNOWN);
+    }
 }
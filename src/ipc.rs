@@ -0,0 +1,176 @@
+//! Reporting summaries to an orchestrating process over a Unix domain socket.
+//!
+//! With `--ipc-socket` every input's summary is sent as one JSON object per
+//! line (`{"file":"a.fq.gz","total":100,"with_umi":..}`) as soon as the input
+//! is processed, so a daemon driving many runs does not have to parse stdout.
+//! Statistics only gathered with `--verbose`, such as the mean GC content, are
+//! `null` without it.
+
+use crate::processing::{Mean, ProcessStats};
+use crate::{json_string, percentage};
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+/// A connection to the socket summaries are sent to.
+#[derive(Debug)]
+pub struct SummarySocket {
+    stream: UnixStream,
+    path: PathBuf,
+}
+
+impl SummarySocket {
+    /// Connect to the socket listening at `path`.
+    pub fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("Failed to connect to IPC socket {}", path.display()))?;
+        Ok(Self {
+            stream,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Send the summary of input `file` as one line of JSON.
+    pub fn send(&mut self, file: &str, stats: &ProcessStats) -> Result<()> {
+        writeln!(self.stream, "{}", summary_json(file, stats))
+            .and_then(|_| self.stream.flush())
+            .with_context(|| format!("Failed to send summary to {}", self.path.display()))
+    }
+}
+
+/// The summary of input `file` as a single-line JSON object, with the same
+/// fields as the tab-separated summary plus the read pairs with the UMI in
+/// at least one mate, the mean GC content of reads with and without it, the
+/// most frequent extracted UMIs and the read category counts.
+pub fn summary_json(file: &str, stats: &ProcessStats) -> String {
+    let gc_pct = |mean: Mean| {
+        mean.get()
+            .map_or("null".to_string(), |gc| format!("{:.2}", gc * 100.0))
+    };
+    let top_umis = stats.top_umis.as_ref().map_or("null".to_string(), |top| {
+        let umis: Vec<String> = top
+            .items
+            .iter()
+            .map(|(umi, count)| {
+                format!(
+                    "{{\"umi\":{},\"count\":{}}}",
+                    json_string(&String::from_utf8_lossy(umi)),
+                    count
+                )
+            })
+            .collect();
+        format!(
+            "{{\"seen\":{},\"max_undercount\":{},\"umis\":[{}]}}",
+            top.seen,
+            top.max_undercount,
+            umis.join(",")
+        )
+    });
+    format!(
+        "{{\"file\":{},\"total\":{},\"with_umi\":{},\"with_umi_pct\":{:.2},\"without_umi\":{},\"without_umi_pct\":{:.2},\"pairs\":{},\"pairs_with_umi\":{},\"pairs_with_umi_pct\":{:.2},\"mean_gc_with_umi_pct\":{},\"mean_gc_without_umi_pct\":{},\"top_umis\":{},\"no_umi\":{},\"shorter_than_umi\":{},\"adapter_present\":{},\"low_complexity_umi\":{},\"duplicate_id\":{},\"malformed\":{}}}",
+        json_string(file),
+        stats.total,
+        stats.removed,
+        percentage(stats.removed, stats.total),
+        stats.kept,
+        percentage(stats.kept, stats.total),
+        stats.pairs,
+        stats.pairs_with_umi,
+        percentage(stats.pairs_with_umi, stats.pairs),
+        gc_pct(stats.gc.0),
+        gc_pct(stats.gc.1),
+        top_umis,
+        stats.no_umi,
+        stats.shorter_than_umi,
+        stats.adapter_present,
+        stats.low_complexity_umi,
+        stats.duplicate_id,
+        stats.malformed
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topk::TopItems;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_summary_socket_sends_json_lines() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("summary.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            BufReader::new(stream)
+                .lines()
+                .collect::<std::io::Result<Vec<_>>>()
+                .unwrap()
+        });
+
+        let mut socket = SummarySocket::connect(&path).unwrap();
+        let mut gc_with_umi = Mean::default();
+        gc_with_umi.add(0.25);
+        gc_with_umi.add(0.5);
+        let stats = ProcessStats {
+            total: 4,
+            removed: 1,
+            kept: 3,
+            pairs: 2,
+            pairs_with_umi: 1,
+            gc: (gc_with_umi, Mean::default()),
+            top_umis: None,
+            no_umi: 2,
+            shorter_than_umi: 1,
+            adapter_present: 5,
+            low_complexity_umi: 4,
+            duplicate_id: 3,
+            malformed: 1,
+        };
+        socket.send("a.fq", &stats).unwrap();
+        let stats = ProcessStats {
+            top_umis: Some(TopItems {
+                items: vec![(b"ACGT".to_vec(), 3), (b"GGCC".to_vec(), 1)],
+                seen: 4,
+                max_undercount: 0,
+            }),
+            ..Default::default()
+        };
+        socket.send("b.bam", &stats).unwrap();
+        drop(socket);
+
+        let lines = server.join().unwrap();
+        assert_eq!(lines.len(), 2);
+        // A flat object of scalar fields without commas inside the strings
+        let fields: HashMap<&str, &str> = lines[0]
+            .strip_prefix('{')
+            .and_then(|l| l.strip_suffix('}'))
+            .unwrap()
+            .split(',')
+            .map(|kv| kv.split_once(':').unwrap())
+            .collect();
+        assert_eq!(fields["\"file\""], "\"a.fq\"");
+        assert_eq!(fields["\"total\""], "4");
+        assert_eq!(fields["\"with_umi\""], "1");
+        assert_eq!(fields["\"with_umi_pct\""], "25.00");
+        assert_eq!(fields["\"without_umi\""], "3");
+        assert_eq!(fields["\"without_umi_pct\""], "75.00");
+        assert_eq!(fields["\"pairs_with_umi_pct\""], "50.00");
+        assert_eq!(fields["\"mean_gc_with_umi_pct\""], "37.50");
+        assert_eq!(fields["\"mean_gc_without_umi_pct\""], "null");
+        assert_eq!(fields["\"top_umis\""], "null");
+        assert_eq!(fields["\"no_umi\""], "2");
+        assert_eq!(fields["\"shorter_than_umi\""], "1");
+        assert_eq!(fields["\"adapter_present\""], "5");
+        assert_eq!(fields["\"low_complexity_umi\""], "4");
+        assert_eq!(fields["\"duplicate_id\""], "3");
+        assert_eq!(fields["\"malformed\""], "1");
+        assert!(lines[1].contains("\"total\":0"));
+        assert!(lines[1].contains(
+            "\"top_umis\":{\"seen\":4,\"max_undercount\":0,\"umis\":[{\"umi\":\"ACGT\",\"count\":3},{\"umi\":\"GGCC\",\"count\":1}]},"
+        ));
+    }
+}
@@ -1,6 +1,17 @@
+pub mod bloom;
+pub mod consensus;
 pub mod io;
+#[cfg(feature = "ipc")]
+pub mod ipc;
 pub mod matcher;
+pub mod multiqc;
 pub mod processing;
+pub mod topk;
+
+use regex::bytes::Regex;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::ops::RangeInclusive;
 
 /// Extract the UMI from a read header.
 ///
@@ -10,31 +21,500 @@ pub mod processing;
 /// found. **Note:** the function will panic if a UMI is found but its length
 /// does not equal `expected_length` to enforce caller invariants.
 pub fn extract_umi_from_header(header: &[u8], expected_length: usize) -> Option<Vec<u8>> {
-    let header_str = std::str::from_utf8(header).ok()?;
+    extract_umi_field(header, expected_length, -1)
+}
 
-    // Try to find UMI after last ':' or '_' but before any whitespace
-    let umi_str = header_str
-        .split_whitespace()
-        .next()?
-        .rsplit([':', '_'])
-        .next()?;
+/// Percentage of `count` in `total`, guarded so that an empty total (an empty
+/// input or an empty group) reports `0.0` instead of `NaN`. Every percentage in
+/// the summary output and per-group tables should go through this helper.
+pub fn percentage(count: usize, total: usize) -> f64 {
+    if total > 0 {
+        (count as f64 / total as f64) * 100.0
+    } else {
+        0.0
+    }
+}
+
+/// Standard normal quantile of a two-sided 95% confidence level.
+const Z_95: f64 = 1.959964;
 
-    if umi_str.len() != expected_length {
+/// 95% Wilson score interval of the percentage of `count` in `total`, as
+/// `(lower, upper)` percentages. Unlike the normal approximation it stays
+/// within 0-100 and is not degenerate for rates of 0% or 100%. An empty total
+/// says nothing about the rate and gets the whole range.
+pub fn wilson_interval(count: usize, total: usize) -> (f64, f64) {
+    if total == 0 {
+        return (0.0, 100.0);
+    }
+    let n = total as f64;
+    let p = count as f64 / n;
+    let z2 = Z_95 * Z_95;
+    let center = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+    let half_width = Z_95 / (1.0 + z2 / n) * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+    (
+        ((center - half_width) * 100.0).max(0.0),
+        ((center + half_width) * 100.0).min(100.0),
+    )
+}
+
+/// Quote `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Reasons why a UMI could not be extracted from a read header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UmiParseError {
+    /// The header is not valid UTF-8.
+    InvalidUtf8,
+    /// The header has no token at the requested field index.
+    MissingField,
+    /// The header does not match the UMI regex.
+    NoRegexMatch,
+    /// The extracted token does not have the expected UMI length.
+    LengthMismatch { expected: usize, found: usize },
+    /// The extracted token is outside the accepted range of UMI lengths.
+    LengthOutOfRange {
+        min: usize,
+        max: usize,
+        found: usize,
+    },
+}
+
+impl std::fmt::Display for UmiParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidUtf8 => write!(f, "header is not valid UTF-8"),
+            Self::MissingField => write!(f, "header has no UMI field"),
+            Self::NoRegexMatch => write!(f, "header does not match the UMI regex"),
+            Self::LengthMismatch { expected, found } => write!(
+                f,
+                "UMI length does not match expected length: expected {}, found {}",
+                expected, found
+            ),
+            Self::LengthOutOfRange { min, max, found } => write!(
+                f,
+                "UMI length outside expected range: expected {}-{}, found {}",
+                min, max, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UmiParseError {}
+
+/// Extract the UMI from a specific delimiter-separated field of a read header.
+///
+/// The first whitespace-delimited token of the header is split on `:` and `_`
+/// and `field` selects the UMI: non-negative indices count from the start,
+/// negative indices from the end (`-1` is the last field, as used by
+/// `extract_umi_from_header`). This handles tools that append extra fields
+/// after the UMI, e.g. `READ:ACGTACGTACGT:3` with `field = -2`. Returns `None`
+/// if the header is not UTF-8 or the field does not exist, and panics on a
+/// length mismatch just like `extract_umi_from_header`.
+pub fn extract_umi_field(header: &[u8], expected_length: usize, field: isize) -> Option<Vec<u8>> {
+    extract_umi_field_in_range(header, expected_length..=expected_length, field)
+}
+
+/// Like `extract_umi_field` but accepts any UMI length within `lengths`, for
+/// inputs whose header UMIs vary in length. Panics on a UMI outside `lengths`.
+pub fn extract_umi_field_in_range(
+    header: &[u8],
+    lengths: RangeInclusive<usize>,
+    field: isize,
+) -> Option<Vec<u8>> {
+    match parse_umi_field_in_range(header, lengths, field) {
+        Ok(umi) => Some(umi),
         // Throw an exception if UMI length does not match expected length
-        panic!(
-            "UMI length does not match expected length: expected {}, found {}",
-            expected_length,
-            umi_str.len()
-        );
+        Err(
+            e @ (UmiParseError::LengthMismatch { .. } | UmiParseError::LengthOutOfRange { .. }),
+        ) => panic!("{}", e),
+        Err(_) => None,
+    }
+}
+
+/// Fallible counterpart of `extract_umi_field` that reports why a header
+/// could not yield a UMI instead of returning `None` or panicking.
+pub fn parse_umi_field(
+    header: &[u8],
+    expected_length: usize,
+    field: isize,
+) -> Result<Vec<u8>, UmiParseError> {
+    parse_umi_field_in_range(header, expected_length..=expected_length, field)
+}
+
+/// Fallible counterpart of `extract_umi_field_in_range`.
+pub fn parse_umi_field_in_range(
+    header: &[u8],
+    lengths: RangeInclusive<usize>,
+    field: isize,
+) -> Result<Vec<u8>, UmiParseError> {
+    parse_umi_field_in_range_as_is(header, lengths, field).map(|umi| umi.to_ascii_uppercase())
+}
+
+/// `parse_umi_field_in_range` without uppercasing the UMI, for alphabets
+/// where case is significant.
+pub(crate) fn parse_umi_field_in_range_as_is(
+    header: &[u8],
+    lengths: RangeInclusive<usize>,
+    field: isize,
+) -> Result<Vec<u8>, UmiParseError> {
+    let umi_str = header_field(header, field)?;
+    check_umi_length(umi_str, lengths)?;
+    Ok(umi_str.as_bytes().to_vec())
+}
+
+/// Like `parse_umi_field_in_range` for a field holding several candidate
+/// UMIs separated by `delim` (e.g. `READ:ACGTAC,ACGTTC` with `delim = b','`
+/// when an ambiguous base call was expanded). Every candidate must have an
+/// accepted length; the whole field is returned, to be split on `delim`.
+pub fn parse_umi_field_candidates(
+    header: &[u8],
+    lengths: RangeInclusive<usize>,
+    field: isize,
+    delim: u8,
+) -> Result<Vec<u8>, UmiParseError> {
+    parse_umi_field_candidates_as_is(header, lengths, field, delim)
+        .map(|umi| umi.to_ascii_uppercase())
+}
+
+/// `parse_umi_field_candidates` without uppercasing the candidates.
+pub(crate) fn parse_umi_field_candidates_as_is(
+    header: &[u8],
+    lengths: RangeInclusive<usize>,
+    field: isize,
+    delim: u8,
+) -> Result<Vec<u8>, UmiParseError> {
+    let field_str = header_field(header, field)?;
+    for candidate in field_str.split(char::from(delim)) {
+        check_umi_length(candidate, lengths.clone())?;
     }
+    Ok(field_str.as_bytes().to_vec())
+}
+
+/// Use the whole first whitespace-delimited token of `header` as the UMI,
+/// for formats where the read name is the UMI (e.g. `ACGTACGTACGT 1:N:0`).
+pub fn parse_umi_whole_header(
+    header: &[u8],
+    lengths: RangeInclusive<usize>,
+) -> Result<Vec<u8>, UmiParseError> {
+    parse_umi_whole_header_as_is(header, lengths).map(|umi| umi.to_ascii_uppercase())
+}
+
+/// `parse_umi_whole_header` without uppercasing the UMI.
+pub(crate) fn parse_umi_whole_header_as_is(
+    header: &[u8],
+    lengths: RangeInclusive<usize>,
+) -> Result<Vec<u8>, UmiParseError> {
+    let header_str = std::str::from_utf8(header).map_err(|_| UmiParseError::InvalidUtf8)?;
+    let token = header_str
+        .split_whitespace()
+        .next()
+        .ok_or(UmiParseError::MissingField)?;
+    check_umi_length(token, lengths)?;
+    Ok(token.as_bytes().to_vec())
+}
+
+/// The `field`-th `:`/`_`-separated field of the first whitespace-delimited
+/// token of `header`; negative indices count from the end.
+fn header_field(header: &[u8], field: isize) -> Result<&str, UmiParseError> {
+    let header_str = std::str::from_utf8(header).map_err(|_| UmiParseError::InvalidUtf8)?;
+    let token = header_str
+        .split_whitespace()
+        .next()
+        .ok_or(UmiParseError::MissingField)?;
 
-    Some(umi_str.as_bytes().to_ascii_uppercase())
+    if field < 0 {
+        token.rsplit([':', '_']).nth(field.unsigned_abs() - 1)
+    } else {
+        token.split([':', '_']).nth(field as usize)
+    }
+    .ok_or(UmiParseError::MissingField)
+}
+
+/// Remove the UMI field selected by `field` (as in `extract_umi_field`) from
+/// the first token of `header`, together with one delimiter next to it: the
+/// one before the field, or the one after it for the first field. Returns
+/// the new header and the removed field, or `None` if the header has no such
+/// field or the field is the whole read name.
+///
+/// `READ:ACGT 1:N:0` becomes `READ 1:N:0`, and `READ:ACGT:3` with
+/// `field = -2` becomes `READ:3`.
+pub fn strip_umi_field(header: &[u8], field: isize) -> Option<(Vec<u8>, Vec<u8>)> {
+    let header_str = std::str::from_utf8(header).ok()?;
+    let token = header_str.split_whitespace().next()?;
+    let umi = header_field(header, field).ok()?;
+    // Both are slices of `header`
+    let offset = |s: &str| s.as_ptr() as usize - header.as_ptr() as usize;
+    let (token_start, umi_start) = (offset(token), offset(umi));
+    let (token_end, umi_end) = (token_start + token.len(), umi_start + umi.len());
+    let cut = if umi_start > token_start {
+        umi_start - 1..umi_end
+    } else if umi_end < token_end {
+        umi_start..umi_end + 1
+    } else {
+        return None;
+    };
+    let mut stripped = header[..cut.start].to_vec();
+    stripped.extend_from_slice(&header[cut.end..]);
+    Some((stripped, umi.as_bytes().to_vec()))
+}
+
+fn check_umi_length(umi_str: &str, lengths: RangeInclusive<usize>) -> Result<(), UmiParseError> {
+    if !lengths.contains(&umi_str.len()) {
+        let (min, max) = lengths.into_inner();
+        return Err(if min == max {
+            UmiParseError::LengthMismatch {
+                expected: min,
+                found: umi_str.len(),
+            }
+        } else {
+            UmiParseError::LengthOutOfRange {
+                min,
+                max,
+                found: umi_str.len(),
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Extract the UMI from the named `umi` capture group of `re` matched
+/// against the whole header, for header formats that fields cannot describe.
+///
+/// The pattern defines the UMI, so its length is not checked. Reports
+/// `NoRegexMatch` if the header does not match and `MissingField` if the
+/// match did not take part in the `umi` group.
+pub fn parse_umi_regex(header: &[u8], re: &Regex) -> Result<Vec<u8>, UmiParseError> {
+    parse_umi_regex_as_is(header, re).map(|umi| umi.to_ascii_uppercase())
+}
+
+/// `parse_umi_regex` without uppercasing the UMI.
+pub(crate) fn parse_umi_regex_as_is(header: &[u8], re: &Regex) -> Result<Vec<u8>, UmiParseError> {
+    let caps = re.captures(header).ok_or(UmiParseError::NoRegexMatch)?;
+    let umi = caps.name("umi").ok_or(UmiParseError::MissingField)?;
+    Ok(umi.as_bytes().to_vec())
+}
+
+/// UMI length recorded in the `@CO` or `@PG` lines of a SAM/BAM header text,
+/// if any: an explicit setting such as `umi_length=10`, `UMI length: 10` or
+/// `--umi-length 10`, or a UMI-tools `--bc-pattern` (one `N` per UMI base).
+pub fn umi_length_from_sam_header(header: &[u8]) -> Option<usize> {
+    let setting = Regex::new(r"(?i)\bumi[ _-]?len(?:gth)?\s*[=:]?\s*(\d+)").unwrap();
+    let bc_pattern = Regex::new(r"bc-pattern[= ]([NCX]+)").unwrap();
+    header
+        .split(|&b| b == b'\n')
+        .filter(|line| line.starts_with(b"@CO") || line.starts_with(b"@PG"))
+        .find_map(|line| match setting.captures(line) {
+            Some(caps) => std::str::from_utf8(&caps[1]).ok()?.parse().ok(),
+            None => {
+                let pattern = bc_pattern.captures(line)?;
+                Some(pattern[1].iter().filter(|&&b| b == b'N').count())
+            }
+        })
+        .filter(|&len| len > 0)
+}
+
+/// Summary of the header formats in a sample of reads, produced by
+/// `diagnose_headers` to help pick `--umi-field` and `--umi-length`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderDiagnosis {
+    /// Number of headers sampled.
+    pub sampled: usize,
+    /// Number of headers per count of `:`/`_`-separated fields.
+    pub field_counts: BTreeMap<usize, usize>,
+    /// The field that most often holds a UMI-like token, if any does in
+    /// most headers.
+    pub umi: Option<InferredUmiField>,
+}
+
+/// Header field inferred to hold the UMI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InferredUmiField {
+    /// Field index counted from the end, as accepted by `--umi-field`.
+    pub field: isize,
+    /// Number of sampled headers whose field is a UMI-like token.
+    pub headers: usize,
+    /// Shortest and longest UMI-like token in the field.
+    pub lengths: RangeInclusive<usize>,
+    /// Number of UMI-like tokens in the field per token length.
+    pub length_counts: BTreeMap<usize, usize>,
+}
+
+impl InferredUmiField {
+    /// The most common UMI length, the shorter one on ties.
+    pub fn recommended_length(&self) -> usize {
+        self.length_counts
+            .iter()
+            .fold(
+                (0, 0),
+                |best, (&len, &count)| {
+                    if count > best.1 {
+                        (len, count)
+                    } else {
+                        best
+                    }
+                },
+            )
+            .0
+    }
+}
+
+/// Tokenize the first whitespace-delimited token of each header like
+/// `parse_umi_field` does and infer which field holds the UMI: the one (counted
+/// from the end) that is a nucleotide token in the most headers, preferring
+/// fields nearer the end on ties. A field must look like a UMI in more than
+/// half of the headers to be reported.
+pub fn diagnose_headers<H: AsRef<[u8]>>(headers: &[H]) -> HeaderDiagnosis {
+    let tokenized: Vec<Vec<&[u8]>> = headers
+        .iter()
+        .map(|h| {
+            h.as_ref()
+                .split(u8::is_ascii_whitespace)
+                .next()
+                .unwrap_or_default()
+                .split(|&b| b == b':' || b == b'_')
+                .collect()
+        })
+        .collect();
+
+    let mut field_counts = BTreeMap::new();
+    for fields in &tokenized {
+        *field_counts.entry(fields.len()).or_insert(0) += 1;
+    }
+
+    let is_umi_like = |token: &&[u8]| {
+        !token.is_empty()
+            && token
+                .iter()
+                .all(|b| matches!(b.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T' | b'N'))
+    };
+    let max_fields = field_counts.keys().copied().max().unwrap_or(0);
+    let mut umi: Option<InferredUmiField> = None;
+    for from_end in 1..=max_fields {
+        let tokens: Vec<&[u8]> = tokenized
+            .iter()
+            .filter_map(|fields| fields.len().checked_sub(from_end).map(|i| fields[i]))
+            .filter(is_umi_like)
+            .collect();
+        let mut length_counts = BTreeMap::new();
+        for token in &tokens {
+            *length_counts.entry(token.len()).or_insert(0) += 1;
+        }
+        let (Some((&min, _)), Some((&max, _))) = (
+            length_counts.first_key_value(),
+            length_counts.last_key_value(),
+        ) else {
+            continue;
+        };
+        if tokens.len() * 2 > headers.len() && umi.as_ref().is_none_or(|u| tokens.len() > u.headers)
+        {
+            umi = Some(InferredUmiField {
+                field: -(from_end as isize),
+                headers: tokens.len(),
+                lengths: min..=max,
+                length_counts,
+            });
+        }
+    }
+
+    HeaderDiagnosis {
+        sampled: headers.len(),
+        field_counts,
+        umi,
+    }
+}
+
+impl std::fmt::Display for HeaderDiagnosis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Sampled headers: {}", self.sampled)?;
+        writeln!(f, "Fields per header (split on ':' and '_'):")?;
+        for (fields, count) in &self.field_counts {
+            writeln!(
+                f,
+                "  {} fields: {} headers ({:.2}%)",
+                fields,
+                count,
+                percentage(*count, self.sampled)
+            )?;
+        }
+        match self.umi {
+            Some(ref umi) => {
+                let (min, max) = (*umi.lengths.start(), *umi.lengths.end());
+                write!(
+                    f,
+                    "Inferred UMI: field {} in {} headers ({:.2}%), length {}",
+                    umi.field,
+                    umi.headers,
+                    percentage(umi.headers, self.sampled),
+                    if min == max {
+                        min.to_string()
+                    } else {
+                        format!("{}-{}", min, max)
+                    }
+                )?;
+                write!(f, "\nSuggested options: --umi-field {} --umi-length {}", umi.field, min)?;
+                if min != max {
+                    write!(f, " --umi-length-max {}", max)?;
+                }
+                Ok(())
+            }
+            None => write!(
+                f,
+                "Inferred UMI: none; no field is a nucleotide token in most headers (see --umi-regex)"
+            ),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_percentage_guards_zero_total() {
+        assert_eq!(format!("{:.2}", percentage(0, 0)), "0.00");
+        assert_eq!(format!("{:.2}", percentage(3, 0)), "0.00");
+        assert_eq!(format!("{:.2}", percentage(1, 3)), "33.33");
+    }
+
+    #[test]
+    fn test_wilson_interval_brackets_rate_and_narrows() {
+        let (lower, upper) = wilson_interval(30, 100);
+        assert!(lower < percentage(30, 100) && percentage(30, 100) < upper);
+        assert_eq!(format!("{:.2} {:.2}", lower, upper), "21.89 39.58");
+        let (lower_large, upper_large) = wilson_interval(3000, 10000);
+        assert!(lower_large < 30.0 && 30.0 < upper_large);
+        assert!(upper_large - lower_large < upper - lower);
+        // Rates of 0% and 100% keep a one-sided interval
+        let (lower, upper) = wilson_interval(0, 10);
+        assert!(lower == 0.0 && upper > 0.0);
+        let (lower, upper) = wilson_interval(10, 10);
+        assert!(lower < 100.0 && (upper - 100.0).abs() < 1e-9);
+        assert_eq!(wilson_interval(0, 0), (0.0, 100.0));
+    }
+
+    #[test]
+    fn test_json_string_escapes() {
+        assert_eq!(json_string("a.fq"), "\"a.fq\"");
+        assert_eq!(json_string("a\"b\\c\n\x01"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+
     #[test]
     fn test_extract_umi_from_header() {
         let header = b"READ_12345:ACGTACGTACGT";
@@ -71,4 +551,189 @@ mod tests {
         let umi2 = extract_umi_from_header(header2, 6).unwrap();
         assert_eq!(umi2, b"GGGTTT");
     }
+
+    #[test]
+    fn test_extract_umi_field_with_trailing_count() {
+        let header = b"READ:ACGTACGTACGT:3";
+        let umi = extract_umi_field(header, 12, -2).unwrap();
+        assert_eq!(umi, b"ACGTACGTACGT");
+
+        // Positive indices count from the start of the token
+        let umi = extract_umi_field(header, 12, 1).unwrap();
+        assert_eq!(umi, b"ACGTACGTACGT");
+
+        // Out-of-range fields yield no UMI
+        assert!(extract_umi_field(header, 12, -4).is_none());
+    }
+
+    #[test]
+    fn test_parse_umi_field_reports_errors() {
+        assert_eq!(
+            parse_umi_field(b"READ:ACGT", 6, -1),
+            Err(UmiParseError::LengthMismatch {
+                expected: 6,
+                found: 4
+            })
+        );
+        assert_eq!(
+            parse_umi_field(b"READ:ACGTAC", 6, 3),
+            Err(UmiParseError::MissingField)
+        );
+        assert_eq!(
+            parse_umi_field(b"\xff\xfe", 6, -1),
+            Err(UmiParseError::InvalidUtf8)
+        );
+    }
+
+    #[test]
+    fn test_parse_umi_field_in_range() {
+        let umi = parse_umi_field_in_range(b"READ:ACGTACGTAC", 10..=12, -1).unwrap();
+        assert_eq!(umi, b"ACGTACGTAC");
+        let umi = parse_umi_field_in_range(b"READ:ACGTACGTACGT", 10..=12, -1).unwrap();
+        assert_eq!(umi, b"ACGTACGTACGT");
+        assert_eq!(
+            parse_umi_field_in_range(b"READ:ACGTACGT", 10..=12, -1),
+            Err(UmiParseError::LengthOutOfRange {
+                min: 10,
+                max: 12,
+                found: 8
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_umi_field_candidates() {
+        let umis = parse_umi_field_candidates(b"READ:acgtac,ACGTTC 1:N", 6..=6, -1, b',');
+        assert_eq!(umis.unwrap(), b"ACGTAC,ACGTTC");
+        assert_eq!(
+            parse_umi_field_candidates(b"READ:ACGTAC,ACG", 6..=6, -1, b','),
+            Err(UmiParseError::LengthMismatch {
+                expected: 6,
+                found: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_strip_umi_field() {
+        let strip = |header: &[u8], field| {
+            strip_umi_field(header, field).map(|(h, umi)| {
+                (
+                    String::from_utf8(h).unwrap(),
+                    String::from_utf8(umi).unwrap(),
+                )
+            })
+        };
+        let pair = |h: &str, umi: &str| Some((h.to_string(), umi.to_string()));
+        assert_eq!(strip(b"READ:ACGT 1:N:0", -1), pair("READ 1:N:0", "ACGT"));
+        assert_eq!(strip(b"READ:ACGT:3", -2), pair("READ:3", "ACGT"));
+        // The first field takes the delimiter after it
+        assert_eq!(strip(b"ACGT_READ", 0), pair("READ", "ACGT"));
+        // Never leave an empty read name or strip a missing field
+        assert_eq!(strip(b"ACGT 1:N", -1), None);
+        assert_eq!(strip(b"READ:ACGT", 5), None);
+    }
+
+    #[test]
+    fn test_parse_umi_whole_header() {
+        let umi = parse_umi_whole_header(b"acgtACGTACGT 1:N:0:1", 12..=12).unwrap();
+        assert_eq!(umi, b"ACGTACGTACGT");
+        assert_eq!(
+            parse_umi_whole_header(b"READ:ACGTACGTACGT", 12..=12),
+            Err(UmiParseError::LengthMismatch {
+                expected: 12,
+                found: 17
+            })
+        );
+        assert_eq!(
+            parse_umi_whole_header(b"", 12..=12),
+            Err(UmiParseError::MissingField)
+        );
+    }
+
+    #[test]
+    fn test_umi_length_from_sam_header() {
+        let header = b"@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100\n@CO\tumi_length=10\n";
+        assert_eq!(umi_length_from_sam_header(header), Some(10));
+        let header = b"@HD\tVN:1.6\n@CO\tLibrary prep: UMI length: 8 bp\n";
+        assert_eq!(umi_length_from_sam_header(header), Some(8));
+        let header = b"@PG\tID:umi_tools\tCL:umi_tools extract --bc-pattern=NNNNNNXXX\n";
+        assert_eq!(umi_length_from_sam_header(header), Some(6));
+        // Only comment and program lines are searched
+        let header = b"@HD\tVN:1.6\n@SQ\tSN:umi_length=9\tLN:100\n@CO\tno structure\n";
+        assert_eq!(umi_length_from_sam_header(header), None);
+    }
+
+    #[test]
+    fn test_parse_umi_regex() {
+        let re = Regex::new(r"^\S+_(?P<umi>[ACGTN]+)").unwrap();
+        assert_eq!(parse_umi_regex(b"READ_ACGTN 1:N:0", &re).unwrap(), b"ACGTN");
+
+        // UMI embedded in a key=value list of an instrument header
+        let re = Regex::new(r"\bUMI=(?P<umi>[ACGTN]+)(?:;|$)").unwrap();
+        let header = b"M0123:45:000-ABC:1:1101:15589:1331 1:N:0 BC=TTAGGC;UMI=ACGTACGTACGT;RG=x";
+        assert_eq!(parse_umi_regex(header, &re).unwrap(), b"ACGTACGTACGT");
+        assert_eq!(
+            parse_umi_regex(b"READ BC=TTAGGC", &re),
+            Err(UmiParseError::NoRegexMatch)
+        );
+
+        // The match can skip an optional `umi` group
+        let re = Regex::new(r"^READ(?::(?P<umi>[ACGT]+))?$").unwrap();
+        assert_eq!(
+            parse_umi_regex(b"READ", &re),
+            Err(UmiParseError::MissingField)
+        );
+    }
+
+    #[test]
+    fn test_diagnose_headers_finds_umi_field() {
+        let headers = [
+            &b"READ1:ACGTACGTACGT:3 1:N:0"[..],
+            b"READ2:TTGCAACGTACC:1 1:N:0",
+            b"READ3:GGGGCCCCAAAA:12",
+            b"READ4_2",
+        ];
+        let diagnosis = diagnose_headers(&headers);
+        assert_eq!(diagnosis.sampled, 4);
+        assert_eq!(diagnosis.field_counts, BTreeMap::from([(2, 1), (3, 3)]));
+        assert_eq!(
+            diagnosis.umi,
+            Some(InferredUmiField {
+                field: -2,
+                headers: 3,
+                lengths: 12..=12,
+                length_counts: BTreeMap::from([(12, 3)]),
+            })
+        );
+        assert!(diagnosis
+            .to_string()
+            .ends_with("Suggested options: --umi-field -2 --umi-length 12"));
+
+        // Numeric fields are not mistaken for a UMI
+        let diagnosis = diagnose_headers(&[b"READ:1:2", b"READ:3:4"]);
+        assert_eq!(diagnosis.umi, None);
+    }
+
+    #[test]
+    fn test_recommended_length_is_most_common() {
+        let headers = [
+            &b"R1:ACGTACGTAC"[..],
+            b"R2:ACGTACGTAC",
+            b"R3:ACGTACGTA",
+            b"R4:ACGTACGTACGT",
+        ];
+        let umi = diagnose_headers(&headers).umi.unwrap();
+        assert_eq!(
+            umi.length_counts,
+            BTreeMap::from([(9, 1), (10, 2), (12, 1)])
+        );
+        assert_eq!(umi.recommended_length(), 10);
+
+        // Ties go to the shorter length
+        let umi = diagnose_headers(&[&b"R1:ACGT"[..], b"R2:ACGTA"])
+            .umi
+            .unwrap();
+        assert_eq!(umi.recommended_length(), 4);
+    }
 }
@@ -1,8 +1,19 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, Parser};
+use std::ffi::OsString;
+use std::io::{ErrorKind, IsTerminal, Write};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use umi_checker::processing::{process_bam, process_fastq};
+use rayon::prelude::*;
+use umi_checker::io::read_manifest;
+use umi_checker::matcher::{is_umi_in_read, CompiledUmi};
+use umi_checker::processing::{
+    bam_header_umi_length, process_bam, process_fastq, process_fastq_mates, sample_bam_headers,
+    sample_fastq_headers, ProcessOptions, ProcessStats, BATCH_SIZE,
+};
+use umi_checker::{diagnose_headers, percentage, wilson_interval};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -11,33 +22,670 @@ use umi_checker::processing::{process_bam, process_fastq};
     about = "UMI presence validator - checks if UMI from header exists in read"
 )]
 struct Args {
-    /// Input file (FASTQ, FASTQ.gz, BAM, or SAM)
-    #[arg(short, long)]
-    input: PathBuf,
+    /// Input file(s) (FASTQ, FASTQ.gz, BAM, or SAM); the type of each is detected separately
+    #[arg(short, long, num_args = 1.., required_unless_present_any = ["manifest", "list_supported_formats", "check_install"])]
+    input: Vec<PathBuf>,
+
+    /// TSV listing the inputs, one per line, with optional umi_length and mismatches columns overriding --umi-length and --mismatches for that input
+    #[arg(long, conflicts_with = "input")]
+    manifest: Option<PathBuf>,
+
+    /// Treat the two FASTQ inputs as the R1 and R2 mates of paired reads: count pairs and also find UMIs split across the end of R1 and the reverse-complemented R2, joined where the mates overlap (no output)
+    #[arg(long, default_value_t = false, conflicts_with_all = ["manifest", "output", "shard", "umi_candidates_delim", "umi_template", "spacer_motif", "internal_linker"])]
+    mates: bool,
 
     /// Maximum number of mismatches allowed when finding UMI in read (<=3)
     #[arg(short, long, default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=3))]
     mismatches: u32,
 
-    /// UMI length in base pairs
-    #[arg(short = 'l', long, default_value_t = 12)]
-    umi_length: usize,
+    /// Split the UMI into this many chunks for the mismatch search (at least mismatches + 1)
+    // The motif, template and scan searches do not use chunks
+    #[arg(long, conflicts_with_all = ["spacer_motif", "umi_template", "internal_linker", "scan_only"])]
+    chunks: Option<usize>,
+
+    /// Look UMIs up in a k-mer index of each read instead of scanning every window; faster on long reads, and shared by the --umi-candidates-delim candidates of a read
+    #[arg(long, default_value_t = false, conflicts_with_all = ["chunks", "spacer_motif", "umi_template", "internal_linker", "scan_only", "ignore_positions", "alphabet"])]
+    long_read: bool,
+
+    /// UMI length in base pairs, or from-header[:N] to read it from BAM/SAM header comments, falling back to N; a comma-separated list gives one length per input (e.g. 8,10 for R1 and R2)
+    #[arg(short = 'l', long, default_value = "12", value_delimiter = ',', value_parser = parse_umi_length)]
+    umi_length: Vec<UmiLength>,
+
+    /// Accept header UMIs of any length from --umi-length up to this value
+    #[arg(long)]
+    umi_length_max: Option<usize>,
+
+    /// Search every read for this UMI instead of extracting it from the read header
+    #[arg(long, conflicts_with = "strict")]
+    umi: Option<String>,
+
+    /// Header field holding the UMI, split on ':' and '_' (negative counts from the end)
+    #[arg(long, default_value_t = -1, allow_negative_numbers = true)]
+    umi_field: isize,
+
+    /// Use the whole read name (up to the first whitespace) as the UMI
+    #[arg(long, default_value_t = false, conflicts_with_all = ["umi_field", "umi", "umi_tag", "umi_regex", "umi_candidates_delim", "single_cell"])]
+    umi_whole_header: bool,
+
+    /// Regex matched against the read header; the UMI is its named group, e.g. '_(?P<umi>[ACGTN]+)'
+    #[arg(long, conflicts_with_all = ["umi", "umi_tag", "single_cell"])]
+    umi_regex: Option<String>,
+
+    /// Split the header UMI into candidate UMIs on this character; a read has its UMI if any candidate is found
+    #[arg(long, conflicts_with = "umi")]
+    umi_candidates_delim: Option<char>,
+
+    /// Treat the two --umi-candidates-delim parts as dual UMIs that must both be found, the first at the start and the second at the end of the read
+    #[arg(long, default_value_t = false, requires = "umi_candidates_delim", conflicts_with_all = ["scan_only", "spacer_motif"])]
+    require_both_umis_same_position: bool,
+
+    /// Read the UMI from this SAM/BAM tag (e.g. UB) instead of the read header
+    #[arg(long, value_parser = parse_tag, conflicts_with = "umi")]
+    umi_tag: Option<[u8; 2]>,
+
+    /// Group the per-tag counts written to --tag-counts by this SAM/BAM tag (e.g. CB)
+    #[arg(long, value_parser = parse_tag, requires = "tag_counts")]
+    count_by_tag: Option<[u8; 2]>,
+
+    /// Write per-group counts for --count-by-tag (or --single-cell) to this TSV
+    #[arg(long)]
+    tag_counts: Option<PathBuf>,
+
+    /// Single-cell (10x) preset: UMI from the UB tag, counts grouped by the CB tag
+    #[arg(long, default_value_t = false, conflicts_with_all = ["umi", "umi_tag", "count_by_tag"])]
+    single_cell: bool,
+
+    /// Count reads whose ID repeats an earlier read's (FASTQ only; an error with --strict)
+    #[arg(long, default_value_t = false)]
+    check_duplicate_ids: bool,
+
+    /// Also search the reverse complement of reads whose UMI is not found on the forward strand
+    #[arg(long, default_value_t = false)]
+    check_revcomp: bool,
+
+    /// Let N positions in the UMI match any read base instead of counting as mismatches
+    #[arg(long, default_value_t = false, conflicts_with_all = ["spacer_motif", "scan_only"])]
+    tolerate_n_in_umi: bool,
+
+    /// Leave these window positions (0-based offsets from the UMI start, e.g. known-bad cycles) out of the UMI comparison
+    #[arg(long, value_name = "POS", value_delimiter = ',', conflicts_with_all = ["tolerate_n_in_umi", "spacer_motif", "umi_template", "internal_linker", "scan_only", "chunks", "long_read", "require_both_umis_same_position"])]
+    ignore_positions: Vec<usize>,
+
+    /// Alphabet of the UMI: dna counts N as a mismatch, raw compares arbitrary barcode symbols byte for byte
+    #[arg(long, value_enum, default_value_t = Alphabet::Dna)]
+    alphabet: Alphabet,
+
+    /// Abort on the first read header that does not yield a UMI of the expected length, on truncated BAM files and on FASTQ records whose quality and sequence lengths differ (otherwise skipped)
+    #[arg(long, default_value_t = false)]
+    strict: bool,
+
+    /// Known motif downstream of the UMI; the UMI must end within --spacer-max bases of it
+    #[arg(long)]
+    spacer_motif: Option<String>,
+
+    /// Structured UMI layout, e.g. NNNNGGNNNN: N positions come from the header UMI, other bases must match the read exactly
+    #[arg(long, value_name = "PATTERN", conflicts_with_all = ["umi", "umi_length_max", "spacer_motif", "tolerate_n_in_umi", "scan_only", "umi_candidates_delim"])]
+    umi_template: Option<String>,
+
+    /// Constant sequence splitting the UMI in the read (UMI_A + SEQ + UMI_B) while the header stores the halves concatenated; the linker must match exactly
+    #[arg(long, value_name = "SEQ", conflicts_with_all = ["umi_template", "umi", "umi_length_max", "spacer_motif", "tolerate_n_in_umi", "scan_only", "umi_candidates_delim"])]
+    internal_linker: Option<String>,
+
+    /// Number of UMI bases before --internal-linker [default: half the UMI length]
+    #[arg(long, value_name = "K", requires = "internal_linker")]
+    linker_split: Option<usize>,
+
+    /// Maximum number of arbitrary spacer bases between the UMI and --spacer-motif
+    #[arg(long, default_value_t = 0, requires = "spacer_motif")]
+    spacer_max: usize,
+
+    /// Do not search for low-complexity UMIs (e.g. poly-A); their reads count as without UMI
+    #[arg(long, default_value_t = false)]
+    skip_low_complexity: bool,
+
+    /// Reject UMI matches in regions with a dinucleotide entropy below BITS (0-4), e.g. simple repeats
+    #[arg(long, value_name = "BITS", conflicts_with = "scan_only")]
+    min_match_complexity: Option<f64>,
+
+    /// Examine at most N UMI windows per read, from the start of the searched region; matches further in are not counted
+    #[arg(long, value_name = "N")]
+    max_window_search: Option<usize>,
+
+    /// Only process shard I of N (I/N, counting from 0): the I-th of N contiguous record ranges, for splitting one input across invocations
+    #[arg(long, value_name = "I/N", value_parser = parse_shard, conflicts_with_all = ["checkpoint", "resume"])]
+    shard: Option<(usize, usize)>,
+
+    /// Only process reads overlapping this region, e.g. chr1:1000-2000 (indexed BAM only)
+    #[arg(long)]
+    region: Option<String>,
+
+    /// Only process BAM/SAM records with all of these flag bits set, like samtools view -f (decimal or 0x hex)
+    #[arg(short = 'f', long, value_parser = parse_flags, default_value = "0")]
+    require_flags: u16,
+
+    /// Skip BAM/SAM records with any of these flag bits set, like samtools view -F (decimal or 0x hex)
+    #[arg(short = 'F', long, value_parser = parse_flags, default_value = "0")]
+    exclude_flags: u16,
+
+    /// Also count reads containing this adapter sequence (same mismatch budget as the UMI) and adapter dimers among them
+    #[arg(long)]
+    adapter: Option<String>,
+
+    /// Exclude this many bases at the start of every read from the UMI search
+    #[arg(long, default_value_t = 0)]
+    skip_prefix: usize,
+
+    /// Exclude this many bases at the end of every read from the UMI search
+    #[arg(long, default_value_t = 0)]
+    skip_suffix: usize,
+
+    /// Only search for the UMI next to the first exact occurrence of this motif in the read; reads without it count as without UMI
+    #[arg(long, value_name = "SEQ", conflicts_with_all = ["spacer_motif", "require_both_umis_same_position"])]
+    anchor_motif: Option<String>,
+
+    /// Side of --anchor-motif the UMI is on
+    #[arg(long, value_enum, default_value_t = AnchorSide::After, requires = "anchor_motif")]
+    anchor_side: AnchorSide,
+
+    /// Maximum number of arbitrary bases between --anchor-motif and the UMI
+    #[arg(long, default_value_t = 0, requires = "anchor_motif")]
+    anchor_gap: usize,
+
+    /// Ignore trailing poly-G runs of at least this many bases when searching (default 10)
+    #[arg(long, num_args = 0..=1, default_missing_value = "10")]
+    trim_polyg: Option<usize>,
+
+    /// Also remove the poly-G tails from the written reads (FASTQ only)
+    #[arg(long, default_value_t = false, requires = "trim_polyg")]
+    trim_polyg_output: bool,
 
     /// Optional output file prefix (suffix will be derived from the input).
+    /// A `{name}` token is replaced by each input's file name without suffix.
     /// If not provided, no output files will be written.
     #[arg(short, long)]
     output: Option<PathBuf>,
 
+    /// Compression of FASTQ outputs: auto keeps the input's compression unless --output ends in .gz
+    #[arg(long, value_enum, default_value_t = OutputCompression::Auto)]
+    output_compression: OutputCompression,
+
+    /// Format of the outputs of BAM/SAM input: auto keeps the input's format, fastq splits reads by mate
+    #[arg(long, value_enum, default_value_t = OutputFormat::Auto)]
+    output_format: OutputFormat,
+
+    /// Create the directory of --output if it does not exist
+    #[arg(long, default_value_t = false, requires = "output")]
+    mkdir: bool,
+
+    /// Annotate removed reads with the mismatch count of their UMI match (FASTQ comment mm:<n>, BAM tag mm:i)
+    #[arg(long, default_value_t = false, requires = "output")]
+    count_mismatches_in_output: bool,
+
+    /// Remove the UMI field and its delimiter from the names of output reads
+    #[arg(long, default_value_t = false, requires = "output", conflicts_with_all = ["umi", "umi_tag", "umi_regex", "umi_whole_header", "single_cell"])]
+    normalize_output_headers: bool,
+
+    /// Keep the UMI removed by --normalize-output-headers as a comment umi:<UMI> (FASTQ) or tag RX:Z (BAM/SAM)
+    #[arg(long, default_value_t = false, requires = "normalize_output_headers")]
+    keep_umi_comment: bool,
+
+    /// Write this quality character for every base of reads without qualities (e.g. FASTA input) instead of an empty quality line
+    #[arg(long, value_name = "CHAR", num_args = 0..=1, default_missing_value = "I")]
+    fill_missing_quality: Option<char>,
+
+    /// Estimate the fraction of extracted UMIs seen before in the input, in bounded memory
+    // A fixed --umi is the same in every read
+    #[arg(long, default_value_t = false, conflicts_with = "umi")]
+    estimate_dup_rate: bool,
+
+    /// False-positive rate of the Bloom filter used by --estimate-dup-rate
+    #[arg(long, default_value_t = 0.01, requires = "estimate_dup_rate")]
+    bloom_fp: f64,
+
+    /// Track the K most frequent extracted UMIs with approximate counts, in memory bounded by K; listed with --verbose and in the IPC summary
+    #[arg(long, value_name = "K", conflicts_with = "umi")]
+    top_umis: Option<usize>,
+
+    /// Annotate removed reads with the mean base quality of their UMI match (FASTQ comment wq:<q>, BAM tag wq:f) and report the mean
+    #[arg(long, default_value_t = false, conflicts_with = "scan_only")]
+    window_quality: bool,
+
+    /// Record progress in <OUTPUT>.checkpoint after every batch (FASTQ only)
+    #[arg(long, default_value_t = false, requires = "output")]
+    checkpoint: bool,
+
+    /// Resume an interrupted run from <OUTPUT>.checkpoint, appending to its outputs
+    #[arg(long, default_value_t = false, requires = "output")]
+    resume: bool,
+
+    /// Write removed reads ordered by ascending mismatch count (buffers them all in memory)
+    #[arg(long, default_value_t = false, conflicts_with_all = ["checkpoint", "resume"])]
+    sort_by_mismatches: bool,
+
+    /// Write reads containing their UMI to <PREFIX>.mm0.fq, <PREFIX>.mm1.fq, ... by the mismatch count of their best match instead of to the removed output (FASTQ only)
+    #[arg(long, value_name = "PREFIX", conflicts_with_all = ["checkpoint", "resume", "sort_by_mismatches", "scan_only"])]
+    tag_output_by_mismatch: Option<PathBuf>,
+
+    /// Do not create output files that would not contain any reads
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "output",
+        conflicts_with = "resume"
+    )]
+    no_empty_output: bool,
+
+    /// Write a random sample of at most N reads without UMI instead of all of them (holds N reads in memory)
+    #[arg(long, value_name = "N", requires = "output", conflicts_with_all = ["checkpoint", "resume"])]
+    downsample_to: Option<usize>,
+
+    /// Seed of the --downsample-to sampler
+    #[arg(long, default_value_t = 0, requires = "downsample_to")]
+    seed: u64,
+
+    /// Write a table of extracted UMIs not found in their read (umi<TAB>count, most frequent first)
+    #[arg(long)]
+    unmatched_umi_table: Option<PathBuf>,
+
+    /// Write the verdict for every read (read_id<TAB>found, plus orientation<TAB>position with --check-revcomp) to this TSV
+    #[arg(long, conflicts_with = "resume")]
+    per_read_report: Option<PathBuf>,
+
+    /// Write a spreadsheet-friendly TSV with one row per read: read_id, umi, found, mismatches, read_length
+    #[arg(long, conflicts_with = "scan_only")]
+    qc_table: Option<PathBuf>,
+
+    /// Write the UMI, matched window and substituted positions (pos:from>to, 0-based) of every matched read to this TSV
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["scan_only", "umi_template", "internal_linker", "require_both_umis_same_position"])]
+    error_table: Option<PathBuf>,
+
+    /// Write a histogram of UMI match start offsets in the read to this TSV (offset<TAB>reads)
+    #[arg(long, conflicts_with = "scan_only")]
+    position_histogram: Option<PathBuf>,
+
+    /// Write one majority-vote consensus read per extracted UMI to this FASTQ file (FASTQ only; holds every UMI group in memory)
+    #[arg(long, conflicts_with_all = ["scan_only", "checkpoint", "resume"])]
+    collapse_output: Option<PathBuf>,
+
+    /// Also write reads with an empty sequence, a header UMI of the wrong length or none, or fewer bases than their UMI, and skipped malformed records to this FASTQ file, with the reason in the header comment (FASTQ only)
+    #[arg(long)]
+    quarantine: Option<PathBuf>,
+
+    /// Write the reference interval of each UMI match in aligned BAM/SAM reads to this BED file
+    #[arg(long, conflicts_with = "scan_only")]
+    match_bed: Option<PathBuf>,
+
+    /// Only count UMI occurrences per read into this TSV (read_id<TAB>umi<TAB>occurrences_in_read); no reads are written
+    #[arg(long, conflicts_with_all = ["output", "spacer_motif", "sort_by_mismatches"])]
+    scan_only: Option<PathBuf>,
+
+    /// Compare verdicts against a saved --per-read-report and print how many flipped
+    #[arg(long, conflicts_with = "resume")]
+    compare_baseline: Option<PathBuf>,
+
+    /// Approximate memory budget in MB for each batch of reads; shrinks batches of long reads
+    #[arg(long)]
+    max_memory: Option<usize>,
+
+    /// Decode BAM/SAM sequences on demand instead of keeping a copy per read; less memory, more decoding
+    #[arg(long, default_value_t = false)]
+    no_seq_copy: bool,
+
+    /// Use the defaults of a built-in preset (illumina, tenx); explicit options take precedence
+    #[arg(long)]
+    preset: Option<String>,
+
+    /// Read default option values from a TOML file of `option_name = value` lines;
+    /// explicit options take precedence
+    #[arg(long)]
+    config: Option<PathBuf>,
+
     /// Number of threads for parallel processing
     #[arg(short, long, default_value_t = 4)]
     threads: usize,
 
-    /// Verbose output (show elapsed time)
+    /// Pick threads, batch size and gzip level from the number of CPUs and the output compression (shown with --verbose)
+    #[arg(long, default_value_t = false, conflicts_with = "threads")]
+    auto: bool,
+
+    /// Write the summary to this file instead of stdout
+    #[arg(long)]
+    summary_to: Option<PathBuf>,
+
+    /// Column layout of the summary: native rows, or a table with the column names of another tool's schema
+    #[arg(long, value_enum, default_value_t = SummarySchema::Native)]
+    schema: SummarySchema,
+
+    /// Add the lower and upper bound of a 95% Wilson score confidence interval of the percentage with UMI to the summary
+    #[arg(long, default_value_t = false)]
+    ci: bool,
+
+    /// Also write each input's UMI-found rate to this MultiQC custom-content file (must end in `_mqc.json`)
+    #[arg(long, value_name = "PATH")]
+    multiqc: Option<PathBuf>,
+
+    /// Do not color the summary printed to a terminal (also set by the NO_COLOR environment variable)
+    #[arg(long, default_value_t = false)]
+    no_color: bool,
+
+    /// Also send each input's summary as a line of JSON to this Unix domain socket
+    #[cfg(feature = "ipc")]
+    #[arg(long, value_name = "PATH")]
+    ipc_socket: Option<PathBuf>,
+
+    /// Warn about every read that takes longer than this many milliseconds to match
+    #[arg(long, value_name = "MS")]
+    warn_slow_threshold: Option<u64>,
+
+    /// Print the Hamming distance of the UMI to every window of the read with this ID to stderr
+    #[arg(long, value_name = "READ_ID")]
+    dump_windows: Option<String>,
+
+    /// Report the header format of the first N reads of each input and the likely UMI field to stderr, then exit
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "1000")]
+    diagnose_headers: Option<usize>,
+
+    /// Print the UMI length distribution in the first N headers of each input and the recommended --umi-length, then exit
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "1000", conflicts_with = "diagnose_headers")]
+    detect_umi_length: Option<usize>,
+
+    /// Print the supported input and output formats and exit
+    #[arg(long, default_value_t = false)]
+    list_supported_formats: bool,
+
+    /// Run the matcher, threading, FASTQ and BAM code on a tiny built-in dataset, print PASS/FAIL for each and exit
+    #[arg(long, default_value_t = false)]
+    check_install: bool,
+
+    /// Verbose output (show elapsed time, GC content of reads with/without UMI, UMI presence per read pair and read categories)
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Built-in presets selectable with `--preset`, as `(option, value)` pairs in
+/// the same form as a `--config` file.
+const PRESETS: &[(&str, &[(&str, &str)])] = &[
+    // bcl-convert appends the UMI as the last `:`-separated header field
+    ("illumina", &[("umi_length", "8"), ("umi_field", "-1")]),
+    // 10x Chromium v3: 12bp UMI in UB, cell barcode in CB
+    ("tenx", &[("single_cell", "true"), ("umi_length", "12")]),
+];
+
+/// Parse a TOML file of top-level `key = value` pairs. Only plain strings,
+/// integers and booleans are supported, which covers every CLI option.
+fn parse_config(content: &str) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .with_context(|| format!("Line {}: expected `key = value`", i + 1))?;
+        let value = value.trim();
+        let value = match value.strip_prefix('"') {
+            Some(quoted) => quoted
+                .split_once('"')
+                .map(|(v, _)| v)
+                .with_context(|| format!("Line {}: unterminated string", i + 1))?,
+            None => value.split('#').next().unwrap_or_default().trim(),
+        };
+        pairs.push((key.trim().to_string(), value.to_string()));
+    }
+    Ok(pairs)
+}
+
+/// Append the option values from `--preset` and `--config` to `argv` for
+/// every option not given on the command line, so that explicit options
+/// always win. `--config` takes precedence over `--preset`.
+fn apply_config(mut argv: Vec<OsString>, matches: &ArgMatches) -> Result<Vec<OsString>> {
+    let mut pairs = Vec::new();
+    if let Some(path) = matches.get_one::<PathBuf>("config") {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        pairs.extend(
+            parse_config(&content).with_context(|| format!("Invalid config {}", path.display()))?,
+        );
+    }
+    if let Some(name) = matches.get_one::<String>("preset") {
+        let (_, preset) = PRESETS
+            .iter()
+            .find(|(n, _)| n == name)
+            .with_context(|| format!("Unknown preset '{}'", name))?;
+        pairs.extend(preset.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+    }
+
+    let command = Args::command();
+    let mut applied = Vec::new();
+    for (key, value) in pairs {
+        let arg = command
+            .get_arguments()
+            .find(|a| a.get_id() == key.as_str() && a.get_long().is_some())
+            .filter(|a| !matches!(a.get_id().as_str(), "config" | "preset"))
+            .with_context(|| format!("Unknown option '{}' in config", key))?;
+        if matches.value_source(&key) == Some(ValueSource::CommandLine) || applied.contains(&key) {
+            continue;
+        }
+        let flag = OsString::from(format!("--{}", arg.get_long().unwrap_or_default()));
+        if arg.get_action().takes_values() {
+            argv.push(flag);
+            argv.push(value.into());
+        } else if value == "true" {
+            argv.push(flag);
+        } else if value != "false" {
+            anyhow::bail!("Option '{}' expects true or false, found '{}'", key, value);
+        }
+        applied.push(key);
+    }
+    Ok(argv)
+}
+
+/// Parse a two-character SAM/BAM tag name such as `UB`.
+fn parse_tag(s: &str) -> Result<[u8; 2], String> {
+    s.as_bytes()
+        .try_into()
+        .map_err(|_| format!("'{}' is not a two-character SAM tag", s))
+}
+
+/// Parse a `--shard` value `I/N` into `(I, N)`.
+fn parse_shard(s: &str) -> Result<(usize, usize), String> {
+    let invalid = || format!("'{}' is not a shard I/N with I < N", s);
+    let (i, n) = s.split_once('/').ok_or_else(invalid)?;
+    let (i, n) = (
+        i.parse().map_err(|_| invalid())?,
+        n.parse().map_err(|_| invalid())?,
+    );
+    if i >= n {
+        return Err(invalid());
+    }
+    Ok((i, n))
+}
+
+/// A `--umi-length` value: a number of bases, or `from-header[:N]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UmiLength {
+    Bases(usize),
+    /// Read from the `@CO`/`@PG` lines of a BAM or SAM header, falling back
+    /// to the given length, or to `DEFAULT_UMI_LENGTH` with a warning.
+    FromHeader(Option<usize>),
+}
+
+/// UMI length used when `--umi-length from-header` finds none in the header
+/// and no fallback was given.
+const DEFAULT_UMI_LENGTH: usize = 12;
+
+/// Parse a `--umi-length` value.
+fn parse_umi_length(s: &str) -> Result<UmiLength, String> {
+    let invalid = || format!("'{}' is not a number of bases or from-header[:N]", s);
+    match s.strip_prefix("from-header") {
+        Some("") => Ok(UmiLength::FromHeader(None)),
+        Some(fallback) => fallback
+            .strip_prefix(':')
+            .and_then(|n| n.parse().ok())
+            .map(|n| UmiLength::FromHeader(Some(n)))
+            .ok_or_else(invalid),
+        None => s.parse().map(UmiLength::Bases).map_err(|_| invalid()),
+    }
+}
+
+/// The `--umi-template` equivalent of an `--internal-linker`: `split` UMI
+/// positions, the linker, then the rest of the UMI of the single
+/// `--umi-length`.
+fn linker_template(linker: &str, lengths: &[UmiLength], split: Option<usize>) -> Result<Vec<u8>> {
+    let linker = linker.as_bytes().to_ascii_uppercase();
+    if linker.is_empty() || !linker.iter().all(|b| b"ACGT".contains(b)) {
+        anyhow::bail!("--internal-linker must consist of A, C, G and T");
+    }
+    let [UmiLength::Bases(len)] = lengths else {
+        anyhow::bail!("--internal-linker requires a single --umi-length in bases");
+    };
+    let split = split.unwrap_or(len / 2);
+    if split == 0 || split >= *len {
+        anyhow::bail!(
+            "--linker-split must leave UMI bases on both sides of the linker (1 to {})",
+            len.saturating_sub(1)
+        );
+    }
+    let mut template = vec![b'N'; split];
+    template.extend_from_slice(&linker);
+    template.resize(template.len() + len - split, b'N');
+    Ok(template)
+}
+
+/// Parse a SAM flag mask given in decimal or as `0x` hexadecimal.
+fn parse_flags(s: &str) -> Result<u16, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("'{}' is not a SAM flag mask", s))
+}
+
+/// How FASTQ outputs are compressed, selected with `--output-compression`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputCompression {
+    /// Same as the input, or gzip if `--output` ends in `.gz`
+    Auto,
+    /// Always gzip
+    Gzip,
+    /// Never compress
+    None,
+}
+
+/// Format of the outputs written for BAM and SAM inputs.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Same as the input
+    Auto,
+    /// Compressed BAM
+    Bam,
+    /// Uncompressed, human-readable SAM
+    Sam,
+    /// Uncompressed FASTQ, split by mate into .R1.fq and .R2.fq (unpaired reads in .fq)
+    Fastq,
+}
+
+/// Side of `--anchor-motif` the UMI is searched on.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum AnchorSide {
+    /// The UMI precedes the motif
+    Before,
+    /// The UMI follows the motif
+    After,
+}
+
+/// Symbols the UMI and reads are compared as, selected with `--alphabet`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Alphabet {
+    /// Nucleotides; N counts as a mismatch even against N
+    Dna,
+    /// Arbitrary bytes compared as they are
+    Raw,
+}
+
+/// Column layout of the summary printed by `run`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum SummarySchema {
+    /// One headerless row per input: file name, total, with UMI, %, without UMI, %
+    Native,
+    /// MultiQC custom-content table: a header row, then one row per sample named by its input stem
+    Multiqc,
+}
+
+/// Header row of the `--schema multiqc` table.
+const MULTIQC_COLUMNS: &str =
+    "Sample\ttotal_reads\treads_with_umi\tpercent_with_umi\treads_without_umi\tpercent_without_umi";
+
+/// Column names of the `--ci` bounds in the MultiQC schema.
+const MULTIQC_CI_COLUMNS: &str = "percent_with_umi_ci_lower\tpercent_with_umi_ci_upper";
+
+/// Settings chosen by `--auto`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AutoProfile {
+    cpus: usize,
+    /// Threads for matching; the rest is left to the output writer threads.
+    threads: usize,
+    batch_size: usize,
+    /// Gzip level of FASTQ outputs, if any are compressed.
+    gzip_level: Option<u32>,
+}
+
+impl AutoProfile {
+    /// Balance matching against compression on `cpus` CPUs. Every compressed
+    /// output (kept and removed) is compressed on a thread of its own, so
+    /// those threads are taken from matching, and fewer CPUs get a faster,
+    /// lower gzip level. Batches grow with the threads so each gets enough
+    /// reads per batch.
+    fn new(cpus: usize, compressed: bool) -> Self {
+        let cpus = cpus.max(1);
+        let writer_threads = if compressed { 2.min(cpus / 2) } else { 0 };
+        let threads = (cpus - writer_threads).max(1);
+        Self {
+            cpus,
+            threads,
+            batch_size: (threads * 2_500).clamp(BATCH_SIZE, 100_000),
+            gzip_level: compressed.then_some(match cpus {
+                0..=2 => 1,
+                3..=8 => 4,
+                _ => 6,
+            }),
+        }
+    }
+
+    /// The profile for `args` on this machine.
+    fn detect(args: &Args) -> Result<Self> {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let mut compressed = false;
+        if let Some(ref out) = args.output {
+            for input in &args.input {
+                compressed |= FileType::from_path(input)?
+                    .output_type(out, args.output_compression)?
+                    == FileType::FastqGz;
+            }
+        }
+        Ok(Self::new(cpus, compressed))
+    }
+}
+
+impl std::fmt::Display for AutoProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Auto profile: {} CPUs, {} matching threads, batch size {}, gzip level {}",
+            self.cpus,
+            self.threads,
+            self.batch_size,
+            self.gzip_level
+                .map_or("none (uncompressed output)".to_string(), |l| l.to_string())
+        )
+    }
+}
+
+/// Token in `--output` replaced by the input's file name without suffix.
+const NAME_TOKEN: &str = "{name}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum FileType {
     Fastq,
     FastqGz,
@@ -46,6 +694,14 @@ enum FileType {
 }
 
 impl FileType {
+    /// Every supported file type, in the order they are listed to users.
+    const ALL: [FileType; 4] = [
+        FileType::Fastq,
+        FileType::FastqGz,
+        FileType::Bam,
+        FileType::Sam,
+    ];
+
     /// Determine the input `FileType` from the filename suffix.
     ///
     /// Supports `.fq`, `.fastq`, `.fq.gz`, `.fastq.gz`, `.bam`, and `.sam`.
@@ -75,6 +731,16 @@ impl FileType {
         anyhow::bail!("Unsupported file type: {}", fname)
     }
 
+    /// Human-readable format name.
+    fn name(&self) -> &'static str {
+        match self {
+            FileType::Fastq => "fastq",
+            FileType::FastqGz => "fastq.gz",
+            FileType::Bam => "bam",
+            FileType::Sam => "sam",
+        }
+    }
+
     /// Returns the canonical suffix and acceptable suffix variants for this file type.
     fn suffix_info(&self) -> (&'static str, &'static [&'static str]) {
         match self {
@@ -85,18 +751,103 @@ impl FileType {
         }
     }
 
+    /// File name of `path` without this file type's suffix, used to give each
+    /// input of a multi-input run its own output prefix.
+    fn input_stem(&self, path: &Path) -> String {
+        let (_, candidates) = self.suffix_info();
+        let fname = path
+            .file_name()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let lower = fname.to_lowercase();
+        match candidates.iter().find(|s| lower.ends_with(*s)) {
+            Some(s) => fname[..fname.len() - s.len()].to_string(),
+            None => fname,
+        }
+    }
+
+    /// Type of the outputs written for an input of this type. BAM and SAM
+    /// inputs produce BAM/SAM outputs (see `with_output_format` for FASTQ
+    /// ones); FASTQ outputs follow
+    /// `compression`, where `Auto` inherits the input's compression unless
+    /// `output` explicitly ends in `.gz`.
+    fn output_type(&self, output: &Path, compression: OutputCompression) -> Result<Self> {
+        match (self, compression) {
+            (FileType::Bam | FileType::Sam, OutputCompression::Auto) => Ok(*self),
+            (FileType::Bam | FileType::Sam, _) => {
+                anyhow::bail!("--output-compression only applies to FASTQ input")
+            }
+            (_, OutputCompression::Gzip) => Ok(FileType::FastqGz),
+            (_, OutputCompression::None) => Ok(FileType::Fastq),
+            (_, OutputCompression::Auto) => {
+                if output.to_string_lossy().to_lowercase().ends_with(".gz") {
+                    Ok(FileType::FastqGz)
+                } else {
+                    Ok(*self)
+                }
+            }
+        }
+    }
+
+    /// Apply `--output-format` to the output type of a BAM or SAM input.
+    fn with_output_format(self, format: OutputFormat) -> Result<Self> {
+        match (self, format) {
+            (_, OutputFormat::Auto) => Ok(self),
+            (FileType::Bam | FileType::Sam, OutputFormat::Bam) => Ok(FileType::Bam),
+            (FileType::Bam | FileType::Sam, OutputFormat::Sam) => Ok(FileType::Sam),
+            (FileType::Bam | FileType::Sam, OutputFormat::Fastq) => Ok(FileType::Fastq),
+            _ => anyhow::bail!("--output-format only applies to BAM or SAM input"),
+        }
+    }
+
+    /// Suffixes an output prefix of this type may end in and that are
+    /// replaced by the canonical suffix.
+    fn prefix_suffixes(&self) -> &'static [&'static str] {
+        match self {
+            // A FASTQ prefix may carry any FASTQ suffix, compressed or not
+            FileType::Fastq | FileType::FastqGz => &[".fq.gz", ".fastq.gz", ".gz", ".fq", ".fastq"],
+            _ => self.suffix_info().1,
+        }
+    }
+
+    /// Suffix of another format that `out_prefix` ends in, e.g. `.bam` for
+    /// FASTQ outputs, which would otherwise end up inside the output names.
+    fn foreign_suffix(&self, out_prefix: &Path) -> Option<&'static str> {
+        let prefix_str = out_prefix.to_string_lossy();
+        if self
+            .prefix_suffixes()
+            .iter()
+            .any(|s| prefix_str.ends_with(s))
+        {
+            return None;
+        }
+        FileType::ALL
+            .iter()
+            .flat_map(|t| t.suffix_info().1)
+            .find(|s| prefix_str.ends_with(*s))
+            .copied()
+    }
+
     /// Build output file paths for the matched and removed sets based on the
     /// provided `out_prefix` and this file type's suffix. The returned pair is
     /// `(matched_path, removed_path)`.
+    ///
+    /// The canonical suffix is always appended, so the names end in the
+    /// format written: a suffix of this format or of another sequence format
+    /// (see `foreign_suffix`) at the end of the prefix is replaced, anything
+    /// else is kept (`out.txt` gives `out.txt.fq`).
     fn build_output_paths(&self, out_prefix: &Path) -> (PathBuf, PathBuf) {
-        let (suffix, candidates) = self.suffix_info();
+        let (suffix, _) = self.suffix_info();
         let prefix_str = out_prefix.to_string_lossy();
 
         // If the prefix ends with any of the acceptable variants, trim that variant.
-        let base = candidates
+        let base = self
+            .prefix_suffixes()
             .iter()
+            .copied()
             .find(|s| prefix_str.ends_with(*s))
-            .map(|s| prefix_str.trim_end_matches(*s).to_string())
+            .or_else(|| self.foreign_suffix(out_prefix))
+            .map(|s| prefix_str[..prefix_str.len() - s.len()].to_string())
             .unwrap_or_else(|| prefix_str.to_string());
 
         let matched = PathBuf::from(format!("{}.{}", base, suffix));
@@ -106,99 +857,979 @@ impl FileType {
     }
 }
 
+/// Table of supported formats for `--list-supported-formats`: each input
+/// format with its accepted suffixes and the suffix of its outputs.
+fn supported_formats() -> String {
+    let mut table = String::from("format\tinput_suffixes\toutput_suffix");
+    for file_type in FileType::ALL {
+        let (suffix, candidates) = file_type.suffix_info();
+        table.push_str(&format!(
+            "\n{}\t{}\t.{}",
+            file_type.name(),
+            candidates.join(","),
+            suffix
+        ));
+    }
+    table
+}
+
 /// Extracted business logic - now testable!
 /// Returns formatted summary string instead of printing directly.
 fn run(args: Args) -> Result<String> {
+    if args.list_supported_formats {
+        return Ok(supported_formats());
+    }
+
     // Validate mismatches
     if args.mismatches > 3 {
         anyhow::bail!("Maximum allowed mismatches is 3");
     }
+    if let Some(chunks) = args.chunks {
+        if chunks < args.mismatches as usize + 1 {
+            anyhow::bail!(
+                "--chunks must be at least --mismatches + 1 ({}) to find every match",
+                args.mismatches + 1
+            );
+        }
+    }
 
-    // Determine file type and process
-    let file_type: FileType = FileType::from_path(&args.input)?;
+    if args
+        .min_match_complexity
+        .is_some_and(|bits| !(0.0..=4.0).contains(&bits))
+    {
+        anyhow::bail!("--min-match-complexity must be between 0 and 4 bits");
+    }
+    if args.max_window_search == Some(0) {
+        anyhow::bail!("--max-window-search must be greater than 0");
+    }
+    // MultiQC only picks up custom content from files with this suffix
+    if let Some(path) = &args.multiqc {
+        if !path.to_string_lossy().ends_with("_mqc.json") {
+            anyhow::bail!("--multiqc file name must end in _mqc.json");
+        }
+    }
+    if !(args.bloom_fp > 0.0 && args.bloom_fp < 1.0) {
+        anyhow::bail!("--bloom-fp must be between 0 and 1");
+    }
+    if let Some(c) = args.fill_missing_quality {
+        // Phred+33 qualities range from '!' to '~'
+        if !('!'..='~').contains(&c) {
+            anyhow::bail!("--fill-missing-quality must be a quality character between '!' and '~'");
+        }
+    }
+    if let Some(delim) = args.umi_candidates_delim {
+        if !delim.is_ascii() || delim.is_ascii_whitespace() || delim == ':' || delim == '_' {
+            anyhow::bail!(
+                "--umi-candidates-delim must be an ASCII character other than whitespace, ':' and '_'"
+            );
+        }
+    }
 
-    // Build output file paths (matched + removed) based on input suffix and provided prefix.
-    // If --output is not provided we won't write output files (use None).
-    let (clean_output, removed_output) = if let Some(ref out) = args.output {
-        let (c, r) = file_type.build_output_paths(out);
-        (Some(c), Some(r))
+    // Validate UMI length: an empty UMI would trivially match every read
+    if args
+        .umi_length
+        .iter()
+        .any(|&len| matches!(len, UmiLength::Bases(0) | UmiLength::FromHeader(Some(0))))
+    {
+        anyhow::bail!("UMI length must be greater than 0");
+    }
+    let too_long = |len: usize| args.umi_length_max.is_some_and(|max| max < len);
+    if args
+        .umi_length
+        .iter()
+        .any(|&len| matches!(len, UmiLength::Bases(n) if too_long(n)))
+    {
+        anyhow::bail!("--umi-length-max must not be smaller than --umi-length");
+    }
+
+    // Inputs listed in a manifest may override the UMI length and mismatches
+    let manifest = match args.manifest {
+        Some(ref path) => {
+            let entries = read_manifest(path)?;
+            if entries.is_empty() {
+                anyhow::bail!("Manifest {} lists no inputs", path.display());
+            }
+            entries
+        }
+        None => Vec::new(),
+    };
+    let paths: Vec<&Path> = if args.manifest.is_some() {
+        manifest.iter().map(|e| e.path.as_path()).collect()
     } else {
-        (None, None)
+        args.input.iter().map(PathBuf::as_path).collect()
+    };
+    let mismatches: Vec<u32> = (0..paths.len())
+        .map(|i| manifest.get(i).and_then(|e| e.mismatches))
+        .map(|mm| mm.unwrap_or(args.mismatches))
+        .collect();
+    for (path, &mm) in paths.iter().zip(&mismatches) {
+        if mm > 3 {
+            anyhow::bail!(
+                "Maximum allowed mismatches is 3 (manifest entry {})",
+                path.display()
+            );
+        }
+        if args.chunks.is_some_and(|chunks| chunks < mm as usize + 1) {
+            anyhow::bail!(
+                "--chunks must be at least the mismatches + 1 ({}) of manifest entry {}",
+                mm + 1,
+                path.display()
+            );
+        }
+    }
+
+    // Detect every input's type up front so a bad input fails before any work
+    let inputs = paths
+        .iter()
+        .map(|&p| Ok((p, FileType::from_path(p)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let multi_input = inputs.len() > 1;
+    if args.mates {
+        if inputs.len() != 2
+            || inputs
+                .iter()
+                .any(|(_, t)| matches!(t, FileType::Bam | FileType::Sam))
+        {
+            anyhow::bail!("--mates requires exactly two FASTQ inputs, R1 and R2");
+        }
+        if args.umi_length.len() > 1 {
+            anyhow::bail!(
+                "--mates searches both mates for the same UMI; give a single --umi-length"
+            );
+        }
+    }
+    if args.checkpoint || args.resume {
+        if let Some((path, _)) = inputs
+            .iter()
+            .find(|(_, t)| matches!(t, FileType::Bam | FileType::Sam))
+        {
+            anyhow::bail!(
+                "--checkpoint and --resume only support FASTQ input, but {} is BAM/SAM",
+                path.display()
+            );
+        }
+    }
+    if args.umi_length.len() > 1 && args.umi_length.len() != inputs.len() {
+        anyhow::bail!(
+            "--umi-length has {} values for {} inputs; give one length or one per input",
+            args.umi_length.len(),
+            inputs.len()
+        );
+    }
+    if multi_input {
+        for (flag, set) in [
+            ("--unmatched-umi-table", args.unmatched_umi_table.is_some()),
+            ("--per-read-report", args.per_read_report.is_some()),
+            ("--match-bed", args.match_bed.is_some()),
+            ("--qc-table", args.qc_table.is_some()),
+            ("--error-table", args.error_table.is_some()),
+            ("--collapse-output", args.collapse_output.is_some()),
+            ("--quarantine", args.quarantine.is_some()),
+            (
+                "--tag-output-by-mismatch",
+                args.tag_output_by_mismatch.is_some(),
+            ),
+            ("--position-histogram", args.position_histogram.is_some()),
+            ("--compare-baseline", args.compare_baseline.is_some()),
+            ("--tag-counts", args.tag_counts.is_some()),
+            ("--scan-only", args.scan_only.is_some()),
+        ] {
+            if set {
+                anyhow::bail!("{} cannot be combined with multiple inputs", flag);
+            }
+        }
+    }
+
+    // Searches built around nucleotides (motifs, reverse complements, N
+    // wildcards, pigeonhole and k-mer indexes) have no raw counterpart
+    if args.alphabet == Alphabet::Raw {
+        for (flag, set) in [
+            ("--check-revcomp", args.check_revcomp),
+            ("--tolerate-n-in-umi", args.tolerate_n_in_umi),
+            ("--ignore-positions", !args.ignore_positions.is_empty()),
+            ("--spacer-motif", args.spacer_motif.is_some()),
+            ("--umi-template", args.umi_template.is_some()),
+            ("--internal-linker", args.internal_linker.is_some()),
+            ("--scan-only", args.scan_only.is_some()),
+            ("--chunks", args.chunks.is_some()),
+            ("--mates", args.mates),
+            (
+                "--require-both-umis-same-position",
+                args.require_both_umis_same_position,
+            ),
+        ] {
+            if set {
+                anyhow::bail!("{} cannot be combined with --alphabet raw", flag);
+            }
+        }
+    }
+
+    // Output prefix per input: with several inputs each gets `<OUTPUT>.<stem>`
+    // so that outputs of different inputs never overwrite each other.
+    let prefixes: Vec<Option<PathBuf>> = inputs
+        .iter()
+        .map(|(path, file_type)| {
+            args.output.as_ref().map(|out| {
+                let out_str = out.to_string_lossy();
+                if out_str.contains(NAME_TOKEN) {
+                    PathBuf::from(out_str.replace(NAME_TOKEN, &file_type.input_stem(path)))
+                } else if multi_input {
+                    PathBuf::from(format!("{}.{}", out.display(), file_type.input_stem(path)))
+                } else {
+                    out.clone()
+                }
+            })
+        })
+        .collect();
+    let output_types = inputs
+        .iter()
+        .map(|(_, file_type)| match args.output {
+            Some(ref out) => file_type
+                .output_type(out, args.output_compression)?
+                .with_output_format(args.output_format),
+            None => Ok(*file_type),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for (prefix, output_type) in prefixes.iter().zip(&output_types) {
+        let Some(prefix) = prefix else { continue };
+        if let Some(suffix) = output_type.foreign_suffix(prefix) {
+            let (matched, removed) = output_type.build_output_paths(prefix);
+            eprintln!(
+                "Warning: --output {} ends in {}, but {} is written; writing {} and {}",
+                prefix.display(),
+                suffix,
+                output_type.name(),
+                matched.display(),
+                removed.display()
+            );
+        }
+    }
+    // Inputs of different types sharing a stem get distinct suffixes, but a
+    // shared checkpoint file would still clash.
+    let output_key = |i: usize| {
+        prefixes[i].as_ref().map(|p| {
+            if args.checkpoint || args.resume {
+                p.clone()
+            } else {
+                output_types[i].build_output_paths(p).0
+            }
+        })
+    };
+    for i in 0..inputs.len() {
+        let key = output_key(i);
+        if let Some(j) = (0..i).find(|&j| key.is_some() && output_key(j) == key) {
+            anyhow::bail!(
+                "Inputs {} and {} would write to the same output files",
+                inputs[j].0.display(),
+                inputs[i].0.display()
+            );
+        }
+    }
+
+    // Writers would otherwise fail with a bare "No such file or directory"
+    for dir in prefixes
+        .iter()
+        .flatten()
+        .filter_map(|out| out.parent())
+        .filter(|dir| !dir.as_os_str().is_empty() && !dir.is_dir())
+    {
+        if args.mkdir {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create output directory {}", dir.display()))?;
+        } else {
+            anyhow::bail!(
+                "Output directory {} does not exist (use --mkdir to create it)",
+                dir.display()
+            );
+        }
+    }
+
+    // Raw alphabets compare bytes as they are, so keep the case of --umi
+    let fixed_umi = args.umi.as_ref().map(|u| {
+        if args.alphabet == Alphabet::Raw {
+            u.as_bytes().to_vec()
+        } else {
+            u.as_bytes().to_ascii_uppercase()
+        }
+    });
+    if fixed_umi.as_ref().is_some_and(|u| u.is_empty()) {
+        anyhow::bail!("--umi must not be empty");
+    }
+    if args.anchor_motif.as_ref().is_some_and(|m| m.is_empty()) {
+        anyhow::bail!("--anchor-motif must not be empty");
+    }
+    let umi_template = match (&args.umi_template, &args.internal_linker) {
+        (Some(template), _) => Some(template.as_bytes().to_ascii_uppercase()),
+        // A split UMI is a template with the linker as its literal bases
+        (None, Some(linker)) => Some(linker_template(
+            linker,
+            &args.umi_length,
+            args.linker_split,
+        )?),
+        (None, None) => None,
     };
+    if let Some(ref template) = umi_template {
+        if !template.iter().all(|b| b"ACGTN".contains(b)) || !template.contains(&b'N') {
+            anyhow::bail!("--umi-template must consist of A, C, G, T and N with at least one N");
+        }
+    }
+    let manifest_length = |i: usize| manifest.get(i).and_then(|e| e.umi_length);
+    if (fixed_umi.is_some() || umi_template.is_some())
+        && (0..inputs.len()).any(|i| manifest_length(i).is_some())
+    {
+        anyhow::bail!("Manifest UMI lengths cannot be combined with --umi or --umi-template");
+    }
+    // UMI length per input, e.g. different lengths for the R1 and R2 files
+    let umi_lengths: Vec<usize> = match (&fixed_umi, &umi_template) {
+        (Some(umi), _) => vec![umi.len(); inputs.len()],
+        // The header UMI fills the N positions of the template
+        (None, Some(template)) => {
+            vec![template.iter().filter(|&&b| b == b'N').count(); inputs.len()]
+        }
+        (None, None) => inputs
+            .iter()
+            .enumerate()
+            .map(|(i, (path, file_type))| {
+                if let Some(n) = manifest_length(i) {
+                    if n == 0 || too_long(n) {
+                        anyhow::bail!(
+                            "Manifest UMI length {} of {} must be between 1 and --umi-length-max",
+                            n,
+                            path.display()
+                        );
+                    }
+                    return Ok(n);
+                }
+                let n = match args.umi_length[i.min(args.umi_length.len() - 1)] {
+                    UmiLength::Bases(n) => return Ok(n),
+                    UmiLength::FromHeader(fallback) => match file_type {
+                        FileType::Bam | FileType::Sam => bam_header_umi_length(path)?.or(fallback),
+                        FileType::Fastq | FileType::FastqGz => {
+                            anyhow::bail!("--umi-length from-header requires BAM or SAM input")
+                        }
+                    },
+                };
+                let n = n.unwrap_or_else(|| {
+                    eprintln!(
+                        "Warning: no UMI length found in the header of {}; using {}",
+                        path.display(),
+                        DEFAULT_UMI_LENGTH
+                    );
+                    DEFAULT_UMI_LENGTH
+                });
+                if too_long(n) {
+                    anyhow::bail!(
+                        "--umi-length-max must not be smaller than the UMI length {} in the header of {}",
+                        n,
+                        path.display()
+                    );
+                }
+                Ok(n)
+            })
+            .collect::<Result<_>>()?,
+    };
+
+    let umi_regex = args
+        .umi_regex
+        .as_deref()
+        .map(|pattern| {
+            let re = regex::bytes::Regex::new(pattern).context("Invalid --umi-regex")?;
+            if !re.capture_names().any(|name| name == Some("umi")) {
+                anyhow::bail!("--umi-regex must contain a named capture group (?P<umi>...)");
+            }
+            Ok(re)
+        })
+        .transpose()?;
+
+    let adapter = args
+        .adapter
+        .as_ref()
+        .map(|a| a.as_bytes().to_ascii_uppercase());
+    if adapter.as_ref().is_some_and(|a| a.is_empty()) {
+        anyhow::bail!("--adapter must not be empty");
+    }
+    let compile = |umi: &[u8], mismatches: u32| match args.chunks {
+        Some(chunks) => CompiledUmi::with_chunks(umi, mismatches, chunks),
+        None => CompiledUmi::new(umi, mismatches),
+    };
+
+    let profile = args.auto.then(|| AutoProfile::detect(&args)).transpose()?;
+    if let (true, Some(profile)) = (args.verbose, profile) {
+        eprintln!("{}", profile);
+    }
+
+    let opts = ProcessOptions {
+        max_mismatches: args.mismatches,
+        umi_len: umi_lengths[0],
+        umi_len_max: args.umi_length_max,
+        umi_field: args.umi_field,
+        strict: args.strict,
+        spacer_motif: args
+            .spacer_motif
+            .as_ref()
+            .map(|m| m.as_bytes().to_ascii_uppercase()),
+        spacer_max: args.spacer_max,
+        umi_template,
+        skip_prefix: args.skip_prefix,
+        skip_suffix: args.skip_suffix,
+        anchor_motif: args
+            .anchor_motif
+            .as_ref()
+            .map(|m| m.as_bytes().to_ascii_uppercase()),
+        anchor_before: args.anchor_side == AnchorSide::Before,
+        anchor_gap: args.anchor_gap,
+        trim_polyg: args.trim_polyg,
+        trim_polyg_output: args.trim_polyg_output,
+        checkpoint: None,
+        resume: args.resume,
+        sort_by_mismatches: args.sort_by_mismatches,
+        no_empty_output: args.no_empty_output,
+        downsample: args.downsample_to,
+        seed: args.seed,
+        // Compiled per input below for its mismatches
+        fixed_umi: None,
+        unmatched_umi_table: args.unmatched_umi_table.clone(),
+        per_read_report: args.per_read_report.clone(),
+        match_bed: args.match_bed.clone(),
+        qc_table: args.qc_table.clone(),
+        error_table: args.error_table.clone(),
+        collapse_output: args.collapse_output.clone(),
+        quarantine: args.quarantine.clone(),
+        tag_output_by_mismatch: args.tag_output_by_mismatch.clone(),
+        position_histogram: args.position_histogram.clone(),
+        compare_baseline: args.compare_baseline.clone(),
+        scan_only: args.scan_only.clone(),
+        umi_tag: args.umi_tag.or(args.single_cell.then_some(*b"UB")),
+        count_by_tag: args
+            .count_by_tag
+            .or(args.single_cell.then_some(*b"CB"))
+            .filter(|_| args.tag_counts.is_some()),
+        tag_counts: args.tag_counts.clone(),
+        max_memory: args.max_memory.map(|mb| mb.saturating_mul(1 << 20)),
+        batch_size: profile.map_or(BATCH_SIZE, |p| p.batch_size),
+        gzip_level: profile.and_then(|p| p.gzip_level),
+        report_gc: args.verbose,
+        adapter: None,
+        skip_low_complexity: args.skip_low_complexity,
+        min_match_complexity: args.min_match_complexity,
+        max_window_search: args.max_window_search,
+        shard: args.shard,
+        sam_output: false,
+        fastq_output: false,
+        umi_regex,
+        annotate_mismatches: args.count_mismatches_in_output,
+        normalize_output_headers: args.normalize_output_headers,
+        keep_header_umi: args.keep_umi_comment,
+        window_quality: args.window_quality,
+        fill_quality: args.fill_missing_quality.map(|c| c as u8),
+        estimate_dup_rate: args.estimate_dup_rate.then_some(args.bloom_fp),
+        top_umis: args.top_umis,
+        report_top_umis: args.verbose,
+        check_duplicate_ids: args.check_duplicate_ids,
+        region: args.region.clone(),
+        require_flags: args.require_flags,
+        exclude_flags: args.exclude_flags,
+        tolerate_n_in_umi: args.tolerate_n_in_umi,
+        ignore_positions: args.ignore_positions.clone(),
+        raw_alphabet: args.alphabet == Alphabet::Raw,
+        no_seq_copy: args.no_seq_copy,
+        check_revcomp: args.check_revcomp,
+        chunks: args.chunks,
+        long_read: args.long_read,
+        umi_candidates_delim: args.umi_candidates_delim.map(|c| c as u8),
+        require_both_umis_same_position: args.require_both_umis_same_position,
+        umi_whole_header: args.umi_whole_header,
+        warn_slow: args.warn_slow_threshold.map(Duration::from_millis),
+        dump_windows: args.dump_windows.as_ref().map(|id| id.as_bytes().to_vec()),
+    };
+
+    // Report missing inputs by name rather than as a bare OS error; other
+    // errors (e.g. permissions) surface when the input is opened
+    for (path, _) in &inputs {
+        if let Err(e) = std::fs::metadata(path) {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                anyhow::bail!("Input file not found: {}", path.display());
+            }
+        }
+    }
 
     // Start timer
     let start = std::time::Instant::now();
 
-    let (total, with_umi, without_umi) = match file_type {
+    // Connect before processing so an unreachable socket fails fast
+    #[cfg(feature = "ipc")]
+    let mut socket = args
+        .ipc_socket
+        .as_deref()
+        .map(umi_checker::ipc::SummarySocket::connect)
+        .transpose()?;
+
+    let mut lines = Vec::with_capacity(inputs.len() + 1);
+    if args.schema == SummarySchema::Multiqc {
+        lines.push(if args.ci {
+            format!("{}\t{}", MULTIQC_COLUMNS, MULTIQC_CI_COLUMNS)
+        } else {
+            MULTIQC_COLUMNS.to_string()
+        });
+    }
+    let mut samples = Vec::with_capacity(inputs.len());
+    for (((((input, file_type), prefix), output_type), &umi_len), &mm) in inputs
+        .iter()
+        .zip(&prefixes)
+        .zip(&output_types)
+        .zip(&umi_lengths)
+        .zip(&mismatches)
+        // The R2 mate is processed along with R1
+        .take(if args.mates { 1 } else { inputs.len() })
+    {
+        let opts = ProcessOptions {
+            umi_len,
+            max_mismatches: mm,
+            fixed_umi: fixed_umi.as_ref().map(|u| compile(u, mm)),
+            adapter: adapter.as_ref().map(|a| compile(a, mm)),
+            checkpoint: prefix
+                .as_ref()
+                .filter(|_| args.checkpoint || args.resume)
+                .map(|out| PathBuf::from(format!("{}.checkpoint", out.display()))),
+            sam_output: *output_type == FileType::Sam,
+            fastq_output: matches!(file_type, FileType::Bam | FileType::Sam)
+                && *output_type == FileType::Fastq,
+            ..opts.clone()
+        };
+        let outputs = prefix.as_ref().map(|p| output_type.build_output_paths(p));
+        let stats = if args.mates {
+            process_fastq_mates(input, inputs[1].0, &opts)?
+        } else {
+            run_input(input, file_type, outputs, &opts)?
+        };
+        #[cfg(feature = "ipc")]
+        if let Some(socket) = socket.as_mut() {
+            socket.send(&input_name(input), &stats)?;
+        }
+        lines.push(match args.schema {
+            SummarySchema::Native => summary_line(input, &stats, args.ci),
+            // MultiQC matches sample names without file extensions
+            SummarySchema::Multiqc => summary_row(&file_type.input_stem(input), &stats, args.ci),
+        });
+        samples.push((file_type.input_stem(input), stats));
+    }
+    if let Some(path) = &args.multiqc {
+        umi_checker::multiqc::write_multiqc_json(path, &samples)?;
+    }
+
+    let elapsed = start.elapsed();
+    let mut output = lines.join("\n");
+
+    if args.verbose {
+        // Paired inputs answer two questions: how many reads carry the UMI,
+        // and how many fragments have it in either mate
+        for (sample, stats) in samples.iter().filter(|(_, stats)| stats.pairs > 0) {
+            output.push_str(&format!(
+                "\n{}: UMI found in {:.2}% of reads and in at least one mate of {:.2}% of {} pairs",
+                sample,
+                percentage(stats.removed, stats.total),
+                percentage(stats.pairs_with_umi, stats.pairs),
+                stats.pairs
+            ));
+        }
+        for (sample, stats) in &samples {
+            output.push_str(&format!("\n{}", category_line(sample, stats)));
+        }
+        output.push_str(&format!("\nElapsed: {:.3}s", elapsed.as_secs_f64()));
+    }
+
+    Ok(output)
+}
+
+/// Process a single input with the processor for its `file_type`, writing
+/// the `(matched, removed)` outputs if given, and return its counts.
+fn run_input(
+    input: &Path,
+    file_type: &FileType,
+    outputs: Option<(PathBuf, PathBuf)>,
+    opts: &ProcessOptions,
+) -> Result<ProcessStats> {
+    // If --output is not provided we won't write output files (use None).
+    let (clean_output, removed_output) = match outputs {
+        Some((c, r)) => (Some(c), Some(r)),
+        None => (None, None),
+    };
+
+    match file_type {
         FileType::Fastq | FileType::FastqGz => process_fastq(
-            &args.input,
+            input,
             clean_output.as_deref(),
             removed_output.as_deref(),
-            args.mismatches,
-            args.umi_length,
-        )?,
+            opts,
+        ),
         FileType::Bam | FileType::Sam => process_bam(
-            &args.input,
+            input,
             clean_output.as_deref(),
             removed_output.as_deref(),
-            args.mismatches,
-            args.umi_length,
-        )?,
-    };
-
-    let elapsed = start.elapsed();
-
-    // Output concise tab-separated summary
-    let perc_with = if total > 0 {
-        (with_umi as f64 / total as f64) * 100.0
-    } else {
-        0.0
-    };
-    let perc_without = if total > 0 {
-        (without_umi as f64 / total as f64) * 100.0
-    } else {
-        0.0
-    };
+            opts,
+        ),
+    }
+}
 
-    // Include input filename as first column for easier aggregation in shell loops
-    let fname = args
-        .input
+/// The name `input` is reported under: its file name.
+fn input_name(input: &Path) -> String {
+    input
         .file_name()
         .and_then(|s| s.to_str())
         .map(|s| s.to_string())
-        .unwrap_or_else(|| args.input.to_string_lossy().to_string());
+        .unwrap_or_else(|| input.to_string_lossy().to_string())
+}
+
+/// Concise tab-separated summary line of `input`.
+fn summary_line(input: &Path, stats: &ProcessStats, ci: bool) -> String {
+    // Include input filename as first column for easier aggregation in shell loops
+    summary_row(&input_name(input), stats, ci)
+}
 
-    let mut output = format!(
+/// Verbose line with the read category counts of `stats` under `name`,
+/// named like the fields of the IPC summary.
+fn category_line(name: &str, stats: &ProcessStats) -> String {
+    format!(
+        "{} read categories: no_umi {}, shorter_than_umi {}, adapter_present {}, low_complexity_umi {}, duplicate_id {}, malformed {}",
+        name, stats.no_umi, stats.shorter_than_umi, stats.adapter_present, stats.low_complexity_umi, stats.duplicate_id, stats.malformed
+    )
+}
+
+/// Tab-separated summary row of `stats` under `name`, followed by the
+/// confidence interval of the percentage with UMI if `ci` is set.
+fn summary_row(name: &str, stats: &ProcessStats, ci: bool) -> String {
+    let perc_with = percentage(stats.removed, stats.total);
+    let perc_without = percentage(stats.kept, stats.total);
+
+    let mut row = format!(
         "{}\t{}\t{}\t{:.2}\t{}\t{:.2}",
-        fname, total, with_umi, perc_with, without_umi, perc_without
+        name, stats.total, stats.removed, perc_with, stats.kept, perc_without
     );
-
-    if args.verbose {
-        output.push_str(&format!("\nElapsed: {:.3}s", elapsed.as_secs_f64()));
+    if ci {
+        let (lower, upper) = wilson_interval(stats.removed, stats.total);
+        row.push_str(&format!("\t{:.2}\t{:.2}", lower, upper));
     }
-
-    Ok(output)
+    row
 }
 
 /// CLI entry point: parse args, configure threading, and delegate to run().
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let matches = Args::command().get_matches_from(&argv);
+    let mut args = Args::parse_from(apply_config(argv, &matches)?);
+    if args.auto && !args.input.is_empty() {
+        args.threads = AutoProfile::detect(&args)?.threads;
+    }
 
     // Set up thread pool
     rayon::ThreadPoolBuilder::new()
         .num_threads(args.threads)
         .build_global()?;
 
+    if args.check_install {
+        let (report, passed) = check_install();
+        print_summary(&report)?;
+        if !passed {
+            anyhow::bail!("Installation check failed");
+        }
+        return Ok(());
+    }
+    if let Some(n) = args.diagnose_headers {
+        return diagnose_inputs(&args.input, n);
+    }
+    if let Some(n) = args.detect_umi_length {
+        return print_summary(&detect_umi_lengths(&args.input, n)?);
+    }
+
+    let summary_to = args.summary_to.clone();
+    // Scripts reading stdout through a pipe keep getting the plain TSV, and
+    // another tool's schema is always printed as is
+    let human = std::io::stdout().is_terminal() && args.schema == SummarySchema::Native;
+    let color = !args.no_color && std::env::var_os("NO_COLOR").is_none();
     let output = run(args)?;
-    println!("{}", output);
+    match summary_to {
+        Some(path) => std::fs::write(&path, format!("{}\n", output))
+            .with_context(|| format!("Failed to write summary to {}", path.display()))?,
+        None if human => print_summary(&human_summary(&output, color))?,
+        None => print_summary(&output)?,
+    }
 
     Ok(())
 }
 
+/// Dataset for `--check-install`: the UMI of `r1` is in its read, the one of
+/// `r2` is not.
+const CHECK_FASTQ: &str = "@r1:ACGTACGTACGT\nTTACGTACGTACGTTT\n+\nIIIIIIIIIIIIIIII\n\
+                           @r2:ACGTACGTACGT\nTTTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIII\n";
+/// The same reads as `CHECK_FASTQ`, aligned.
+const CHECK_SAM: &str = "@HD\tVN:1.6\n@SQ\tSN:check\tLN:100\n\
+    r1:ACGTACGTACGT\t0\tcheck\t1\t60\t16M\t*\t0\t0\tTTACGTACGTACGTTT\tIIIIIIIIIIIIIIII\n\
+    r2:ACGTACGTACGT\t0\tcheck\t1\t60\t16M\t*\t0\t0\tTTTTTTTTTTTTTTTT\tIIIIIIIIIIIIIIII\n";
+
+/// A `--check-install` check, given a scratch directory.
+type CheckFn = fn(&Path) -> Result<()>;
+
+/// Run every subsystem on the built-in dataset, in a scratch directory, and
+/// return a `PASS`/`FAIL` line per subsystem and whether all of them passed.
+fn check_install() -> (String, bool) {
+    let dir = std::env::temp_dir().join(format!("umi-checker-check-{}", std::process::id()));
+    let checks: [(&str, CheckFn); 4] = [
+        ("matcher", check_matcher),
+        ("threads", check_threads),
+        ("fastq", check_fastq),
+        ("htslib", check_htslib),
+    ];
+    let mut lines = Vec::with_capacity(checks.len());
+    let mut passed = true;
+    for (name, check) in checks {
+        match std::fs::create_dir_all(&dir)
+            .context("Failed to create scratch directory")
+            .and_then(|_| check(&dir))
+        {
+            Ok(()) => lines.push(format!("PASS\t{}", name)),
+            Err(e) => {
+                passed = false;
+                lines.push(format!("FAIL\t{}\t{:#}", name, e));
+            }
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+    (lines.join("\n"), passed)
+}
+
+fn check_matcher(_dir: &Path) -> Result<()> {
+    let umi = b"ACGTACGTACGT";
+    let read = b"TTACGTACCTACGTTT";
+    anyhow::ensure!(
+        !is_umi_in_read(umi, read, 0) && is_umi_in_read(umi, read, 1),
+        "is_umi_in_read gave a wrong verdict"
+    );
+    anyhow::ensure!(
+        CompiledUmi::new(umi, 1).matches(read),
+        "CompiledUmi gave a wrong verdict"
+    );
+    Ok(())
+}
+
+fn check_threads(_dir: &Path) -> Result<()> {
+    let sum: u64 = (0..10_000u64).into_par_iter().sum();
+    anyhow::ensure!(sum == 49_995_000, "parallel sum gave {}", sum);
+    Ok(())
+}
+
+fn check_fastq(dir: &Path) -> Result<()> {
+    let input = dir.join("check.fastq");
+    std::fs::write(&input, CHECK_FASTQ)?;
+    let (kept, removed) = (dir.join("check.fq"), dir.join("check.removed.fq"));
+    let stats = process_fastq(
+        &input,
+        Some(&kept),
+        Some(&removed),
+        &ProcessOptions::default(),
+    )?;
+    anyhow::ensure!(
+        (stats.total, stats.removed, stats.kept) == (2, 1, 1),
+        "unexpected counts {:?}",
+        stats
+    );
+    anyhow::ensure!(
+        std::fs::read_to_string(&removed)?.starts_with("@r1:"),
+        "wrong read in {}",
+        removed.display()
+    );
+    Ok(())
+}
+
+fn check_htslib(dir: &Path) -> Result<()> {
+    use rust_htslib::bam::{self, Read};
+    let input = dir.join("check.sam");
+    std::fs::write(&input, CHECK_SAM)?;
+    let (kept, removed) = (dir.join("check.bam"), dir.join("check.removed.bam"));
+    let stats = process_bam(
+        &input,
+        Some(&kept),
+        Some(&removed),
+        &ProcessOptions::default(),
+    )?;
+    anyhow::ensure!(
+        (stats.total, stats.removed, stats.kept) == (2, 1, 1),
+        "unexpected counts {:?}",
+        stats
+    );
+    let mut reader = bam::Reader::from_path(&removed)?;
+    let names = reader
+        .records()
+        .map(|r| Ok(r?.qname().to_vec()))
+        .collect::<Result<Vec<_>>>()?;
+    anyhow::ensure!(
+        names == [b"r1:ACGTACGTACGT".to_vec()],
+        "wrong reads in {}",
+        removed.display()
+    );
+    Ok(())
+}
+
+/// Print a `diagnose_headers` report for the first `n` headers of each input
+/// to stderr.
+fn diagnose_inputs(inputs: &[PathBuf], n: usize) -> Result<()> {
+    for input in inputs {
+        let headers = sample_headers(input, n)?;
+        eprintln!("{}\n{}", input.display(), diagnose_headers(&headers));
+    }
+    Ok(())
+}
+
+/// The first `n` read headers of `input`.
+fn sample_headers(input: &Path, n: usize) -> Result<Vec<Vec<u8>>> {
+    match FileType::from_path(input)? {
+        FileType::Fastq | FileType::FastqGz => sample_fastq_headers(input, n),
+        FileType::Bam | FileType::Sam => sample_bam_headers(input, n),
+    }
+}
+
+/// Report of the UMI lengths in the first `n` headers of each input: the
+/// length distribution in the inferred UMI field and the most common length
+/// as the recommended `--umi-length`.
+fn detect_umi_lengths(inputs: &[PathBuf], n: usize) -> Result<String> {
+    let mut report = Vec::new();
+    for input in inputs {
+        let headers = sample_headers(input, n)?;
+        let Some(umi) = diagnose_headers(&headers).umi else {
+            anyhow::bail!(
+                "No UMI field found in the headers of {} (see --diagnose-headers)",
+                input.display()
+            );
+        };
+        report.push(format!(
+            "{}\nUMI lengths in field {} of {} sampled headers:",
+            input_name(input),
+            umi.field,
+            headers.len()
+        ));
+        for (len, count) in &umi.length_counts {
+            report.push(format!(
+                "  {}\t{} headers ({:.2}%)",
+                len,
+                count,
+                percentage(*count, headers.len())
+            ));
+        }
+        report.push(format!(
+            "Recommended: --umi-length {}",
+            umi.recommended_length()
+        ));
+    }
+    Ok(report.join("\n"))
+}
+
+/// Width in characters of the bars of `human_summary`.
+const SUMMARY_BAR_WIDTH: usize = 30;
+
+/// Render the tab-separated summary `output` for reading on a terminal: a
+/// block per input with its counts and a bar of the reads with (green) and
+/// without (yellow) UMI. Lines that are not per-input summaries (e.g. the
+/// elapsed time) are kept as they are.
+fn human_summary(output: &str, color: bool) -> String {
+    let paint = |code: &str, text: String| {
+        if color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text
+        }
+    };
+    let mut blocks = Vec::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        // Six columns, or eight with the `--ci` bounds
+        let (name, total, with, perc_with, without, perc_without, ci) = match fields[..] {
+            [name, total, with, perc_with, without, perc_without] => {
+                (name, total, with, perc_with, without, perc_without, None)
+            }
+            [name, total, with, perc_with, without, perc_without, lower, upper] => (
+                name,
+                total,
+                with,
+                perc_with,
+                without,
+                perc_without,
+                Some((lower, upper)),
+            ),
+            _ => {
+                blocks.push(line.to_string());
+                continue;
+            }
+        };
+        let filled = perc_with
+            .parse::<f64>()
+            .map_or(0, |p| {
+                (p / 100.0 * SUMMARY_BAR_WIDTH as f64).round() as usize
+            })
+            .min(SUMMARY_BAR_WIDTH);
+        let bar =
+            paint("32", "█".repeat(filled)) + &paint("33", "█".repeat(SUMMARY_BAR_WIDTH - filled));
+        let ci = ci.map_or(String::new(), |(lower, upper)| {
+            format!("\n  95% CI        {}-{}%", lower, upper)
+        });
+        blocks.push(format!(
+            "{}\n  Total reads   {}\n  With UMI      {}{}\n  Without UMI   {}\n  {}",
+            paint("1", name.to_string()),
+            total,
+            paint("32", format!("{} ({}%)", with, perc_with)),
+            ci,
+            paint("33", format!("{} ({}%)", without, perc_without)),
+            bar
+        ));
+    }
+    blocks.join("\n")
+}
+
+/// Print the summary to stdout. A reader that closed the pipe early (e.g.
+/// `umi-checker ... | head -0`) is not an error: the run is complete and
+/// there is nobody left to report to, so exit cleanly instead of panicking.
+fn print_summary(output: &str) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    match writeln!(stdout, "{}", output).and_then(|_| stdout.flush()) {
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        result => result.context("Failed to write summary"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_auto_profile() {
+        let single = AutoProfile::new(1, true);
+        assert_eq!((single.threads, single.gzip_level), (1, Some(1)));
+        let plain = AutoProfile::new(16, false);
+        assert_eq!((plain.threads, plain.gzip_level), (16, None));
+        assert_eq!(plain.batch_size, 40_000);
+        // Compression threads are taken from matching
+        let compressed = AutoProfile::new(16, true);
+        assert_eq!((compressed.threads, compressed.gzip_level), (14, Some(6)));
+        assert_eq!(AutoProfile::new(4, false).batch_size, BATCH_SIZE);
+    }
+
+    #[test]
+    fn test_human_summary() {
+        let output = "a.fq\t4\t1\t25.00\t3\t75.00\nElapsed: 0.001s";
+        let plain = human_summary(output, false);
+        assert_eq!(
+            plain,
+            format!(
+                "a.fq\n  Total reads   4\n  With UMI      1 (25.00%)\n  Without UMI   3 (75.00%)\n  {}{}\nElapsed: 0.001s",
+                "█".repeat(8),
+                "█".repeat(22)
+            )
+        );
+        assert!(!plain.contains('\x1b'));
+        assert!(human_summary(output, true).contains("\x1b[32m"));
+
+        let with_ci = human_summary("a.fq\t4\t1\t25.00\t3\t75.00\t4.56\t69.94", false);
+        assert!(with_ci.contains("  With UMI      1 (25.00%)\n  95% CI        4.56-69.94%\n"));
+    }
+
     #[test]
     fn test_file_type_from_path() {
         assert_eq!(
@@ -244,6 +1875,24 @@ mod tests {
         assert_eq!(removed, PathBuf::from("output.removed.fq"));
     }
 
+    #[test]
+    fn test_build_output_paths_normalizes_other_extensions() {
+        let ft = FileType::Fastq;
+        // Not a sequence format: kept as part of the prefix
+        assert_eq!(ft.foreign_suffix(Path::new("output.txt")), None);
+        let (matched, removed) = ft.build_output_paths(Path::new("output.txt"));
+        assert_eq!(matched, PathBuf::from("output.txt.fq"));
+        assert_eq!(removed, PathBuf::from("output.txt.removed.fq"));
+        // Another sequence format: replaced
+        assert_eq!(ft.foreign_suffix(Path::new("output.bam")), Some(".bam"));
+        let (matched, removed) = ft.build_output_paths(Path::new("output.bam"));
+        assert_eq!(matched, PathBuf::from("output.fq"));
+        assert_eq!(removed, PathBuf::from("output.removed.fq"));
+        let (matched, _) = FileType::Bam.build_output_paths(Path::new("output.fastq.gz"));
+        assert_eq!(matched, PathBuf::from("output.bam"));
+        assert_eq!(ft.foreign_suffix(Path::new("output.fastq")), None);
+    }
+
     #[test]
     fn test_build_output_paths_bam() {
         let ft = FileType::Bam;
@@ -252,16 +1901,199 @@ mod tests {
         assert_eq!(removed, PathBuf::from("output.removed.bam"));
     }
 
+    #[test]
+    fn test_output_type_inherits_or_overrides_compression() {
+        let auto = OutputCompression::Auto;
+        let out = Path::new("out");
+        assert_eq!(
+            FileType::FastqGz.output_type(out, auto).unwrap(),
+            FileType::FastqGz
+        );
+        assert_eq!(
+            FileType::Fastq.output_type(out, auto).unwrap(),
+            FileType::Fastq
+        );
+        // A .gz prefix asks for gzip output even for plain input
+        let (matched, removed) = FileType::Fastq
+            .output_type(Path::new("out.fq.gz"), auto)
+            .unwrap()
+            .build_output_paths(Path::new("out.fq.gz"));
+        assert_eq!(matched, PathBuf::from("out.fq.gz"));
+        assert_eq!(removed, PathBuf::from("out.removed.fq.gz"));
+        assert_eq!(
+            FileType::FastqGz
+                .output_type(out, OutputCompression::None)
+                .unwrap(),
+            FileType::Fastq
+        );
+        assert!(FileType::Bam
+            .output_type(out, OutputCompression::Gzip)
+            .is_err());
+    }
+
+    #[test]
+    fn test_with_output_format() {
+        assert_eq!(
+            FileType::Bam.with_output_format(OutputFormat::Sam).unwrap(),
+            FileType::Sam
+        );
+        assert_eq!(
+            FileType::Sam
+                .with_output_format(OutputFormat::Auto)
+                .unwrap(),
+            FileType::Sam
+        );
+        assert_eq!(
+            FileType::Bam
+                .with_output_format(OutputFormat::Fastq)
+                .unwrap(),
+            FileType::Fastq
+        );
+        assert!(FileType::Fastq
+            .with_output_format(OutputFormat::Sam)
+            .is_err());
+    }
+
+    #[test]
+    fn test_input_stem_strips_type_suffix() {
+        assert_eq!(
+            FileType::FastqGz.input_stem(Path::new("dir/sample.FASTQ.gz")),
+            "sample"
+        );
+        assert_eq!(FileType::Bam.input_stem(Path::new("sample.bam")), "sample");
+    }
+
+    #[test]
+    fn test_conflicting_options_name_both() {
+        for (first, second) in [
+            (&["--chunks", "3"][..], &["--spacer-motif", "GG"][..]),
+            (&["--chunks", "3"], &["--umi-template", "NNGGNN"]),
+            (&["--long-read"], &["--scan-only", "scan.tsv"]),
+            (&["--long-read"], &["--internal-linker", "GG"]),
+            (&["--long-read"], &["--ignore-positions", "1"]),
+            (&["--long-read"], &["--alphabet", "raw"]),
+            (&["--anchor-motif", "TATA"], &["--spacer-motif", "GG"]),
+            (
+                &["--anchor-motif", "TATA"],
+                &["--require-both-umis-same-position"],
+            ),
+            (&["--estimate-dup-rate"], &["--umi", "ACGT"]),
+        ] {
+            let argv = ["umi-checker", "-i", "a.fq"]
+                .iter()
+                .chain(first)
+                .chain(second);
+            let err = Args::try_parse_from(argv).unwrap_err();
+            assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+            let message = err.to_string();
+            assert!(message.contains(first[0]), "{}", message);
+            assert!(message.contains(second[0]), "{}", message);
+        }
+    }
+
+    #[test]
+    fn test_long_read_with_and_without_candidates() {
+        assert!(Args::try_parse_from(["umi-checker", "-i", "a.fq", "--long-read"]).is_ok());
+        assert!(Args::try_parse_from([
+            "umi-checker",
+            "-i",
+            "a.fq",
+            "--long-read",
+            "--umi-candidates-delim",
+            "+"
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn test_linker_template() {
+        let eight = [UmiLength::Bases(8)];
+        assert_eq!(linker_template("gg", &eight, None).unwrap(), b"NNNNGGNNNN");
+        assert_eq!(
+            linker_template("GG", &eight, Some(3)).unwrap(),
+            b"NNNGGNNNNN"
+        );
+        assert!(linker_template("GG", &eight, Some(8)).is_err());
+        assert!(linker_template("GNG", &eight, None).is_err());
+        assert!(linker_template("GG", &[UmiLength::FromHeader(None)], None).is_err());
+    }
+
+    #[test]
+    fn test_run_rejects_colliding_multi_input_outputs() {
+        let mut args = test_args("a/sample.fastq");
+        args.input.push(PathBuf::from("b/sample.fq"));
+        args.output = Some(PathBuf::from("out"));
+        let err = run(args).unwrap_err().to_string();
+        assert!(err.contains("would write to the same output files"));
+    }
+
+    #[test]
+    fn test_run_expands_name_token_in_output() {
+        let tmp = tempfile::tempdir().unwrap();
+        let data_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/example.fastq");
+        for name in ["a.fastq", "b.fastq"] {
+            std::fs::copy(&data_path, tmp.path().join(name)).unwrap();
+        }
+
+        let mut args = test_args(tmp.path().join("a.fastq"));
+        args.input.push(tmp.path().join("b.fastq"));
+        args.output = Some(tmp.path().join("out/{name}"));
+        args.mkdir = true;
+        run(args).unwrap();
+        for name in ["a", "b"] {
+            assert!(tmp.path().join(format!("out/{}.fq", name)).exists());
+            assert!(tmp.path().join(format!("out/{}.removed.fq", name)).exists());
+        }
+    }
+
+    /// Parse `argv` the way `main` does, applying `--preset`/`--config`.
+    fn parse_with_config(argv: &[&str]) -> Result<Args> {
+        let argv: Vec<OsString> = argv.iter().map(OsString::from).collect();
+        let matches = Args::command().try_get_matches_from(&argv)?;
+        Ok(Args::try_parse_from(apply_config(argv, &matches)?)?)
+    }
+
+    #[test]
+    fn test_config_sets_defaults_without_overriding_cli() {
+        use std::io::Write;
+        let mut config = tempfile::NamedTempFile::new().unwrap();
+        writeln!(config, "# lab defaults\numi_length = 10\nstrict = true").unwrap();
+        let path = config.path().to_str().unwrap();
+
+        let args = parse_with_config(&["umi-checker", "-i", "x.fastq", "--config", path]).unwrap();
+        assert_eq!(args.umi_length, [UmiLength::Bases(10)]);
+        assert!(args.strict);
+
+        let args =
+            parse_with_config(&["umi-checker", "-i", "x.fastq", "--config", path, "-l", "8"])
+                .unwrap();
+        assert_eq!(args.umi_length, [UmiLength::Bases(8)]);
+    }
+
+    #[test]
+    fn test_preset_and_unknown_config_keys() {
+        let args = parse_with_config(&["umi-checker", "-i", "x.bam", "--preset", "tenx"]).unwrap();
+        assert!(args.single_cell);
+        assert_eq!(args.umi_length, [UmiLength::Bases(12)]);
+
+        assert!(parse_with_config(&["umi-checker", "-i", "x.bam", "--preset", "nope"]).is_err());
+        assert!(parse_config("umi_length").is_err());
+    }
+
+    /// Parse `Args` for `input` with all other options at their CLI defaults.
+    fn test_args(input: impl AsRef<Path>) -> Args {
+        Args::parse_from([
+            std::ffi::OsStr::new("umi-checker"),
+            std::ffi::OsStr::new("-i"),
+            input.as_ref().as_os_str(),
+        ])
+    }
+
     #[test]
     fn test_run_validates_mismatches() {
-        let args = Args {
-            input: PathBuf::from("test.fastq"),
-            mismatches: 4,
-            umi_length: 12,
-            output: None,
-            threads: 1,
-            verbose: false,
-        };
+        let mut args = test_args("test.fastq");
+        args.mismatches = 4;
+        args.threads = 1;
 
         let result = run(args);
         assert!(result.is_err());
@@ -271,16 +2103,24 @@ mod tests {
             .contains("Maximum allowed mismatches is 3"));
     }
 
+    #[test]
+    fn test_run_rejects_zero_umi_length() {
+        let mut args = test_args("test.fastq");
+        args.umi_length = vec![UmiLength::Bases(0)];
+
+        let result = run(args);
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("UMI length must be greater than 0"));
+    }
+
     #[test]
     fn test_run_invalid_file_type() {
-        let args = Args {
-            input: PathBuf::from("test.txt"),
-            mismatches: 1,
-            umi_length: 12,
-            output: None,
-            threads: 1,
-            verbose: false,
-        };
+        let mut args = test_args("test.txt");
+        args.mismatches = 1;
+        args.threads = 1;
 
         let result = run(args);
         assert!(result.is_err());
@@ -290,6 +2130,39 @@ mod tests {
             .contains("Unsupported file type"));
     }
 
+    #[test]
+    fn test_run_rejects_umi_regex_without_umi_group() {
+        let mut args = test_args("test.fastq");
+        args.umi_regex = Some("_([ACGT]+)$".to_string());
+        let err = run(args).unwrap_err().to_string();
+        assert!(err.contains("named capture group"));
+
+        let mut args = test_args("test.fastq");
+        args.umi_regex = Some("(?P<umi>[ACGT".to_string());
+        let err = run(args).unwrap_err().to_string();
+        assert!(err.contains("Invalid --umi-regex"));
+    }
+
+    #[test]
+    fn test_run_reports_missing_output_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let out_dir = tmp.path().join("missing/nested");
+        let data_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/example.fastq");
+
+        let mut args = test_args(&data_path);
+        args.output = Some(out_dir.join("out"));
+        let err = run(args).unwrap_err().to_string();
+        assert!(err.contains("does not exist"));
+        assert!(err.contains(&out_dir.display().to_string()));
+
+        let mut args = test_args(&data_path);
+        args.output = Some(out_dir.join("out"));
+        args.mkdir = true;
+        run(args).unwrap();
+        assert!(out_dir.join("out.fq").exists());
+        assert!(out_dir.join("out.removed.fq").exists());
+    }
+
     #[test]
     fn test_run_with_real_data() {
         use tempfile::NamedTempFile;
@@ -305,14 +2178,11 @@ mod tests {
         let matched_tmp = NamedTempFile::new().expect("create temp file");
         let out_prefix = matched_tmp.path().parent().unwrap().join("test_output");
 
-        let args = Args {
-            input: data_path,
-            mismatches: 1,
-            umi_length: 12,
-            output: Some(out_prefix),
-            threads: 1,
-            verbose: true,
-        };
+        let mut args = test_args(&data_path);
+        args.mismatches = 1;
+        args.output = Some(out_prefix);
+        args.threads = 1;
+        args.verbose = true;
 
         let result = run(args);
         assert!(result.is_ok());
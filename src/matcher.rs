@@ -19,19 +19,26 @@ fn count_nonzero_bytes(mut x: u64) -> u32 {
     (x.wrapping_mul(0x0101010101010101) >> 56) as u32
 }
 
-/// Produce a mask with the high bit set for each zero byte in `x`.
+/// Produce a mask with the high bit set for each 'N' or 'n' byte in `x`.
 ///
-/// The returned word has 0x80 in each byte position that was zero in `x` and
-/// 0x00 otherwise. This bit-hack is useful for detecting 'N' characters when
-/// packed as 8-byte words.
+/// The returned word has 0x80 in each byte position that holds 'N' or 'n' in
+/// `x` and 0x00 otherwise, for detecting 'N' characters packed as 8-byte
+/// words.
 #[inline(always)]
 fn is_n_mask(x: u64) -> u64 {
-    const N_MASK: u64 = 0x4E4E4E4E4E4E4E4E; // 'N' repeated
-                                            // Standard bit-hack to find bytes equal to 0x4E
-    let diff = x ^ N_MASK;
+    const CASE_BIT: u64 = 0x2020202020202020; // Lowercases 'N', only 'N'/'n' become 'n'
+    const N_MASK: u64 = 0x6E6E6E6E6E6E6E6E; // 'n' repeated
+                                            // Standard bit-hack to find bytes equal to 0x6E
+    let diff = (x | CASE_BIT) ^ N_MASK;
     diff.wrapping_sub(0x0101010101010101) & !diff & 0x8080808080808080
 }
 
+/// Whether `b` is 'N' or 'n', the byte-wise counterpart of `is_n_mask`.
+#[inline(always)]
+fn is_n(b: u8) -> bool {
+    (b | 0x20) == b'n'
+}
+
 /// Compute the Hamming distance between `seq1` and `seq2`.
 ///
 /// This function treats 'N' and 'n' in *either* sequence as a mismatch and is
@@ -68,7 +75,7 @@ pub fn hamming_distance(seq1: &[u8], seq2: &[u8]) -> u32 {
     let remainder2 = seq2.chunks_exact(8).remainder();
 
     for (&a, &b) in remainder1.iter().zip(remainder2) {
-        if a != b || a == b'N' || b == b'N' {
+        if a != b || is_n(a) || is_n(b) {
             distance += 1;
         }
     }
@@ -76,6 +83,165 @@ pub fn hamming_distance(seq1: &[u8], seq2: &[u8]) -> u32 {
     distance
 }
 
+/// Hamming distance between `seq1` and `seq2` comparing bytes as they are,
+/// for barcode alphabets other than DNA: unlike `hamming_distance`, 'N' is
+/// an ordinary symbol that matches itself. Uses the same SWAR comparison
+/// without the 'N' mask.
+pub fn raw_hamming_distance(seq1: &[u8], seq2: &[u8]) -> u32 {
+    assert_eq!(seq1.len(), seq2.len());
+
+    let blocks: u32 = seq1
+        .chunks_exact(8)
+        .zip(seq2.chunks_exact(8))
+        .map(|(s1, s2)| {
+            let c1 = u64::from_ne_bytes(s1.try_into().unwrap());
+            let c2 = u64::from_ne_bytes(s2.try_into().unwrap());
+            count_nonzero_bytes(c1 ^ c2)
+        })
+        .sum();
+
+    let remainder1 = seq1.chunks_exact(8).remainder();
+    let remainder2 = seq2.chunks_exact(8).remainder();
+    blocks
+        + remainder1
+            .iter()
+            .zip(remainder2)
+            .filter(|(a, b)| a != b)
+            .count() as u32
+}
+
+/// Hamming distance between `seq1` and `seq2` (with the same 'N' handling
+/// as `hamming_distance`), giving up once it exceeds `cap`.
+///
+/// Returns the exact distance if it is at most `cap`, otherwise some value
+/// greater than `cap`. Saves work on UMIs longer than 8 bases whose first
+/// blocks already exceed the budget.
+pub fn hamming_distance_capped(seq1: &[u8], seq2: &[u8], cap: u32) -> u32 {
+    assert_eq!(seq1.len(), seq2.len());
+
+    let mut distance = 0;
+    for (s1, s2) in seq1.chunks_exact(8).zip(seq2.chunks_exact(8)) {
+        let c1 = u64::from_ne_bytes(s1.try_into().unwrap());
+        let c2 = u64::from_ne_bytes(s2.try_into().unwrap());
+        distance += count_nonzero_bytes((c1 ^ c2) | is_n_mask(c1) | is_n_mask(c2));
+        if distance > cap {
+            return distance;
+        }
+    }
+
+    let remainder1 = seq1.chunks_exact(8).remainder();
+    let remainder2 = seq2.chunks_exact(8).remainder();
+    for (&a, &b) in remainder1.iter().zip(remainder2) {
+        if a != b || is_n(a) || is_n(b) {
+            distance += 1;
+            if distance > cap {
+                break;
+            }
+        }
+    }
+    distance
+}
+
+/// Reverse complement of `seq`. Bases other than A, C, G and T (in either
+/// case) are kept as they are.
+pub fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|&b| match b {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            b'a' => b't',
+            b'c' => b'g',
+            b'g' => b'c',
+            b't' => b'a',
+            other => other,
+        })
+        .collect()
+}
+
+/// Shannon entropy of the base composition of `seq`, in bits.
+///
+/// Ranges from 0 for a homopolymer such as `AAAAAAAAAAAA` to 2 for equal
+/// amounts of A, C, G and T. Any other byte is counted as a fifth symbol.
+pub fn shannon_entropy(seq: &[u8]) -> f64 {
+    if seq.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 5];
+    for b in seq {
+        let idx = match b.to_ascii_uppercase() {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => 4,
+        };
+        counts[idx] += 1;
+    }
+    let len = seq.len() as f64;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Shannon entropy of the overlapping dinucleotides of `seq`, in bits.
+///
+/// Ranges from 0 for a homopolymer to 4 for equal amounts of all 16 ACGT
+/// dinucleotides. Unlike `shannon_entropy` it is also low for simple repeats
+/// such as `ACACACAC` (1 bit). Pairs with any other byte count as one extra
+/// symbol. Sequences shorter than two bases have entropy 0.
+pub fn dinucleotide_entropy(seq: &[u8]) -> f64 {
+    if seq.len() < 2 {
+        return 0.0;
+    }
+    let idx = |b: u8| match b.to_ascii_uppercase() {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    };
+    let mut counts = [0usize; 17];
+    for pair in seq.windows(2) {
+        let i = match (idx(pair[0]), idx(pair[1])) {
+            (Some(a), Some(b)) => 4 * a + b,
+            _ => 16,
+        };
+        counts[i] += 1;
+    }
+    let pairs = (seq.len() - 1) as f64;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / pairs;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Boundaries `(start, end)` of pigeonhole chunk `chunk_idx` when a UMI of
+/// `umi_len` bases is split into `num_chunks` chunks; the last chunk absorbs
+/// the remainder.
+#[inline(always)]
+fn chunk_range(chunk_idx: usize, num_chunks: usize, umi_len: usize) -> (usize, usize) {
+    let chunk_size = umi_len / num_chunks;
+    let start = chunk_idx * chunk_size;
+    let end = if chunk_idx == num_chunks - 1 {
+        umi_len
+    } else {
+        (chunk_idx + 1) * chunk_size
+    };
+    (start, end)
+}
+
 /// Check whether `umi` occurs in `read` allowing up to `max_mismatches`.
 ///
 /// Behavior:
@@ -86,7 +252,123 @@ pub fn hamming_distance(seq1: &[u8], seq2: &[u8]) -> u32 {
 ///   heap allocations and prefers SIMD-like operations when possible.
 ///
 /// Returns `true` if a window in `read` is within `max_mismatches` of `umi`.
+/// A budget of one mismatch, the most common setting, is dispatched to
+/// `is_umi_in_read_1mm`.
 pub fn is_umi_in_read(umi: &[u8], read: &[u8], max_mismatches: u32) -> bool {
+    if max_mismatches == 1 {
+        return is_umi_in_read_1mm(umi, read);
+    }
+    is_umi_in_read_with_chunks(umi, read, max_mismatches, max_mismatches as usize + 1)
+}
+
+/// 2-bit code of each byte for `is_umi_in_read_1mm`: 0 to 3 for an uppercase
+/// A, C, G or T, and 4 for any other byte. Unlike `base_code` this is
+/// case-sensitive, as the window comparisons of the pigeonhole search are.
+const PACKED_CODES: [u8; 256] = {
+    let mut codes = [4; 256];
+    codes[b'A' as usize] = 0;
+    codes[b'C' as usize] = 1;
+    codes[b'G' as usize] = 2;
+    codes[b'T' as usize] = 3;
+    codes
+};
+
+/// `seq` packed two bits per base, first base in the highest bits, if it is
+/// at most 32 uppercase A/C/G/T bases.
+fn pack_bases(seq: &[u8]) -> Option<u64> {
+    if seq.len() > 32 {
+        return None;
+    }
+    seq.iter().try_fold(0u64, |code, &base| {
+        let packed = PACKED_CODES[base as usize];
+        (packed < 4).then_some(code << 2 | u64::from(packed))
+    })
+}
+
+/// Mask keeping the lowest `bits` bits of a `u64`.
+#[inline(always)]
+fn low_bits(bits: usize) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+/// `is_umi_in_read` specialized for a budget of one mismatch.
+///
+/// With one mismatch allowed, a matching window agrees exactly with one of
+/// the two halves of the UMI. Instead of comparing both halves against every
+/// window, a single pass over the read keeps the last 32 bases packed two
+/// bits each, so each half is checked with one masked integer comparison per
+/// position; only windows where a half matches get the full distance check.
+///
+/// UMIs with bases other than uppercase A/C/G/T, shorter than 2 bases or with
+/// halves longer than 32 bases are left to the generic pigeonhole search.
+/// Either way the result is that of `is_umi_in_read_with_chunks(umi, read, 1, 2)`.
+pub fn is_umi_in_read_1mm(umi: &[u8], read: &[u8]) -> bool {
+    let umi_len = umi.len();
+    if read.len() < umi_len {
+        return false;
+    }
+
+    // Same halves as `chunk_range` with two chunks
+    let (left, right) = umi.split_at(umi_len / 2);
+    let (Some(left_code), Some(right_code)) = (pack_bases(left), pack_bases(right)) else {
+        return is_umi_in_read_with_chunks(umi, read, 1, 2);
+    };
+    if left.is_empty() {
+        return is_umi_in_read_with_chunks(umi, read, 1, 2);
+    }
+    let (left_mask, right_mask) = (low_bits(2 * left.len()), low_bits(2 * right.len()));
+    let (left_bits, right_bits) = (low_bits(left.len()), low_bits(right.len()));
+
+    let confirm =
+        |start: usize| hamming_distance_capped(umi, &read[start..start + umi_len], 1) <= 1;
+
+    let mut code = 0u64;
+    // One bit per position, most recent lowest, set for bytes that are not
+    // A/C/G/T; positions before the read count as such
+    let mut invalid = u64::MAX;
+    for (end, &base) in read.iter().enumerate() {
+        let packed = PACKED_CODES[base as usize];
+        code = code << 2 | u64::from(packed & 3);
+        invalid = invalid << 1 | u64::from(packed >> 2);
+        // The bases up to `end` are the left half of the window starting at
+        // `end + 1 - left.len()` and the right half of the one starting at
+        // `end + 1 - umi_len`
+        if code & left_mask == left_code && invalid & left_bits == 0 {
+            let start = end + 1 - left.len();
+            if start + umi_len <= read.len() && confirm(start) {
+                return true;
+            }
+        }
+        if code & right_mask == right_code
+            && invalid & right_bits == 0
+            && end + 1 >= umi_len
+            && confirm(end + 1 - umi_len)
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// Like `is_umi_in_read` but splits the UMI into `num_chunks` pigeonhole
+/// chunks instead of `max_mismatches + 1`.
+///
+/// Any count of at least `max_mismatches + 1` keeps the guarantee that a
+/// window within the budget has an exactly matching chunk; smaller counts are
+/// raised to that minimum. More chunks are shorter and cheaper to compare, but
+/// also match by chance more often, sending more windows to the full distance
+/// check. Which count is fastest depends on the UMI length and read
+/// composition, so it is best found by timing a sample of the input.
+pub fn is_umi_in_read_with_chunks(
+    umi: &[u8],
+    read: &[u8],
+    max_mismatches: u32,
+    num_chunks: usize,
+) -> bool {
     let umi_len = umi.len();
     let read_len = read.len();
 
@@ -100,7 +382,7 @@ pub fn is_umi_in_read(umi: &[u8], read: &[u8], max_mismatches: u32) -> bool {
     }
 
     // Fallback: If UMI is very short or mismatches are high not worth chunking
-    let num_chunks = (max_mismatches + 1) as usize;
+    let num_chunks = num_chunks.max(max_mismatches as usize + 1);
     if umi_len < num_chunks {
         return read
             .windows(umi_len)
@@ -113,43 +395,663 @@ pub fn is_umi_in_read(umi: &[u8], read: &[u8], max_mismatches: u32) -> bool {
     //
     // We want to speedup the search by avoiding comparing every possible window fully.
     // So we split it up into chunks and check if any chunk matches exactly first.
-    // If no chunks match, we can be certain that we have at least `max_mismatches + 1` mismatches
+    // If no chunks match, we can be certain that we have at least `num_chunks` mismatches
     //
-    // If we split up the UMI into #mismatches + 1 chunks.
+    // If we split up the UMI into (at least) #mismatches + 1 chunks.
     // We slide over the read and for each window of UMI length:
     // - Check if any chunk matches exactly.
-    // - If so, compute full Hamming distance to confirm.
-
-    let chunk_size = umi_len / num_chunks;
-
-    // Helper to get chunk boundaries
-    let get_chunk_range = |chunk_idx: usize| -> (usize, usize) {
-        let start = chunk_idx * chunk_size;
-        let end = if chunk_idx == num_chunks - 1 {
-            umi_len
-        } else {
-            (chunk_idx + 1) * chunk_size
-        };
-        (start, end)
-    };
+    // - If so, compute the Hamming distance to confirm, stopping once it exceeds the budget.
 
     // Check if any chunk matches at this position
     let has_matching_chunk = |window: &[u8]| -> bool {
         (0..num_chunks).any(|chunk_idx| {
-            let (start, end) = get_chunk_range(chunk_idx);
+            let (start, end) = chunk_range(chunk_idx, num_chunks, umi_len);
             umi[start..end] == window[start..end]
         })
     };
 
     // Iterate through all possible windows in the read
-    read.windows(umi_len)
-        .any(|window| has_matching_chunk(window) && hamming_distance(umi, window) <= max_mismatches)
+    read.windows(umi_len).any(|window| {
+        has_matching_chunk(window)
+            && hamming_distance_capped(umi, window, max_mismatches) <= max_mismatches
+    })
+}
+
+/// A UMI prepared for repeated searches with a fixed mismatch budget.
+///
+/// `is_umi_in_read` re-derives the pigeonhole chunk boundaries on every call.
+/// When the same UMI is searched in many reads (e.g. with `--umi`) they can be
+/// computed once up front; `matches` then gives the same answer as
+/// `is_umi_in_read` with the same arguments.
+#[derive(Debug, Clone)]
+pub struct CompiledUmi {
+    umi: Vec<u8>,
+    max_mismatches: u32,
+    /// Pigeonhole chunk boundaries; empty when the exact-match or brute-force
+    /// path is used instead.
+    chunks: Vec<(usize, usize)>,
+}
+
+impl CompiledUmi {
+    /// Precompute the search strategy for `umi` with up to `max_mismatches`.
+    pub fn new(umi: &[u8], max_mismatches: u32) -> Self {
+        Self::with_chunks(umi, max_mismatches, max_mismatches as usize + 1)
+    }
+
+    /// Like `new` but with `num_chunks` pigeonhole chunks, see
+    /// `is_umi_in_read_with_chunks`.
+    pub fn with_chunks(umi: &[u8], max_mismatches: u32, num_chunks: usize) -> Self {
+        let num_chunks = num_chunks.max(max_mismatches as usize + 1);
+        let chunks = if max_mismatches > 0 && umi.len() >= num_chunks {
+            (0..num_chunks)
+                .map(|chunk_idx| chunk_range(chunk_idx, num_chunks, umi.len()))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Self {
+            umi: umi.to_vec(),
+            max_mismatches,
+            chunks,
+        }
+    }
+
+    /// The UMI sequence being searched for.
+    pub fn umi(&self) -> &[u8] {
+        &self.umi
+    }
+
+    /// Check whether the UMI occurs in `read` within the mismatch budget.
+    pub fn matches(&self, read: &[u8]) -> bool {
+        let umi = self.umi.as_slice();
+        if read.len() < umi.len() {
+            return false;
+        }
+
+        if self.max_mismatches == 1 && self.chunks.len() == 2 {
+            return is_umi_in_read_1mm(umi, read);
+        }
+
+        if self.max_mismatches == 0 {
+            return read.windows(umi.len()).any(|window| window == umi);
+        }
+
+        if self.chunks.is_empty() {
+            return read
+                .windows(umi.len())
+                .any(|window| hamming_distance(umi, window) <= self.max_mismatches);
+        }
+
+        read.windows(umi.len()).any(|window| {
+            self.chunks
+                .iter()
+                .any(|&(start, end)| umi[start..end] == window[start..end])
+                && hamming_distance_capped(umi, window, self.max_mismatches) <= self.max_mismatches
+        })
+    }
+}
+
+/// Location and quality of a UMI occurrence in a read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UmiMatch {
+    /// Zero-based start offset of the matching window in the read.
+    pub position: usize,
+    /// Hamming distance between the UMI and the matching window.
+    pub mismatches: u32,
+}
+
+/// Keep whichever of `best` and the window at `position` has fewer mismatches,
+/// preferring the earlier (already stored) one on ties.
+#[inline(always)]
+fn keep_best(best: &mut Option<UmiMatch>, position: usize, mismatches: u32) {
+    if best.is_none_or(|b| mismatches < b.mismatches) {
+        *best = Some(UmiMatch {
+            position,
+            mismatches,
+        });
+    }
+}
+
+/// Find the best occurrence of `umi` in `read` allowing up to `max_mismatches`.
+///
+/// Unlike `is_umi_in_read`, which stops at the first acceptable window, this
+/// returns the window with the fewest mismatches (the leftmost one on ties),
+/// stopping early once an exact match is found. Returns `None` if no window is
+/// within `max_mismatches`.
+pub fn find_umi_in_read(umi: &[u8], read: &[u8], max_mismatches: u32) -> Option<UmiMatch> {
+    if read.len() < umi.len() {
+        return None;
+    }
+
+    let mut best = None;
+    for (position, window) in read.windows(umi.len()).enumerate() {
+        let mismatches = hamming_distance(umi, window);
+        if mismatches <= max_mismatches {
+            keep_best(&mut best, position, mismatches);
+            if mismatches == 0 {
+                break;
+            }
+        }
+    }
+    best
+}
+
+/// Like `find_umi_in_read`, but comparing bytes as they are (see
+/// `raw_hamming_distance`).
+pub fn find_umi_in_read_raw(umi: &[u8], read: &[u8], max_mismatches: u32) -> Option<UmiMatch> {
+    if read.len() < umi.len() {
+        return None;
+    }
+
+    let mut best = None;
+    for (position, window) in read.windows(umi.len()).enumerate() {
+        let mismatches = raw_hamming_distance(umi, window);
+        if mismatches <= max_mismatches {
+            keep_best(&mut best, position, mismatches);
+            if mismatches == 0 {
+                break;
+            }
+        }
+    }
+    best
+}
+
+/// Hamming distance between `umi` and `window` where an 'N' in the UMI is a
+/// wildcard matching any base. An 'N' in the window still counts as a
+/// mismatch against a called UMI base, as in `hamming_distance`.
+pub fn wildcard_hamming_distance(umi: &[u8], window: &[u8]) -> u32 {
+    assert_eq!(umi.len(), window.len());
+    umi.iter()
+        .zip(window)
+        .filter(|&(&u, &w)| u != b'N' && (u != w || is_n(w)))
+        .count() as u32
+}
+
+/// Like `find_umi_in_read`, but 'N' positions of `umi` match any read base
+/// (see `wildcard_hamming_distance`).
+pub fn find_umi_in_read_with_wildcards(
+    umi: &[u8],
+    read: &[u8],
+    max_mismatches: u32,
+) -> Option<UmiMatch> {
+    if read.len() < umi.len() {
+        return None;
+    }
+
+    let mut best = None;
+    for (position, window) in read.windows(umi.len()).enumerate() {
+        let mismatches = wildcard_hamming_distance(umi, window);
+        if mismatches <= max_mismatches {
+            keep_best(&mut best, position, mismatches);
+            if mismatches == 0 {
+                break;
+            }
+        }
+    }
+    best
+}
+
+/// Hamming distance between `umi` and `window` over the positions that are
+/// not in `ignored` (offsets from the window start). Ignored positions count
+/// neither as match nor as mismatch; the others compare as in
+/// `hamming_distance`.
+pub fn masked_hamming_distance(umi: &[u8], window: &[u8], ignored: &[usize]) -> u32 {
+    assert_eq!(umi.len(), window.len());
+    umi.iter()
+        .zip(window)
+        .enumerate()
+        .filter(|&(i, (&u, &w))| !ignored.contains(&i) && (u != w || is_n(u) || is_n(w)))
+        .count() as u32
+}
+
+/// Like `find_umi_in_read`, but the `ignored` window positions are left out
+/// of the comparison (see `masked_hamming_distance`).
+pub fn find_umi_in_read_masked(
+    umi: &[u8],
+    read: &[u8],
+    max_mismatches: u32,
+    ignored: &[usize],
+) -> Option<UmiMatch> {
+    if read.len() < umi.len() {
+        return None;
+    }
+
+    let mut best = None;
+    for (position, window) in read.windows(umi.len()).enumerate() {
+        let mismatches = masked_hamming_distance(umi, window, ignored);
+        if mismatches <= max_mismatches {
+            keep_best(&mut best, position, mismatches);
+            if mismatches == 0 {
+                break;
+            }
+        }
+    }
+    best
+}
+
+/// Fill the `N` positions of `template` (e.g. `NNNNGGNNNN`) with the bases of
+/// `umi` in order, or `None` unless `umi` has exactly one base per `N`.
+pub fn fill_umi_template(template: &[u8], umi: &[u8]) -> Option<Vec<u8>> {
+    if template.iter().filter(|&&t| t == b'N').count() != umi.len() {
+        return None;
+    }
+    let mut bases = umi.iter();
+    Some(
+        template
+            .iter()
+            .map(|&t| if t == b'N' { *bases.next().unwrap() } else { t })
+            .collect(),
+    )
+}
+
+/// Like `find_umi_in_read`, for a `filled` UMI built from `template` by
+/// `fill_umi_template`: the literal bases of the template must match the read
+/// exactly, and only the positions taken from the UMI may mismatch.
+pub fn find_templated_umi_in_read(
+    filled: &[u8],
+    template: &[u8],
+    read: &[u8],
+    max_mismatches: u32,
+) -> Option<UmiMatch> {
+    assert_eq!(filled.len(), template.len());
+    if read.len() < filled.len() {
+        return None;
+    }
+
+    let mut best = None;
+    for (position, window) in read.windows(filled.len()).enumerate() {
+        let fixed_ok = template
+            .iter()
+            .zip(window)
+            .all(|(&t, &w)| t == b'N' || (t == w.to_ascii_uppercase() && !is_n(w)));
+        if !fixed_ok {
+            continue;
+        }
+        let mismatches = hamming_distance(filled, window);
+        if mismatches <= max_mismatches {
+            keep_best(&mut best, position, mismatches);
+            if mismatches == 0 {
+                break;
+            }
+        }
+    }
+    best
+}
+
+/// Find every occurrence of `umi` in `read` allowing up to `max_mismatches`.
+///
+/// Every window within the mismatch budget is reported in read order,
+/// including overlapping ones, so the result's length is the number of
+/// occurrences of the UMI in the read.
+pub fn find_all_umi_in_read(umi: &[u8], read: &[u8], max_mismatches: u32) -> Vec<UmiMatch> {
+    if umi.is_empty() || read.len() < umi.len() {
+        return Vec::new();
+    }
+
+    read.windows(umi.len())
+        .enumerate()
+        .filter_map(|(position, window)| {
+            let mismatches = hamming_distance(umi, window);
+            (mismatches <= max_mismatches).then_some(UmiMatch {
+                position,
+                mismatches,
+            })
+        })
+        .collect()
+}
+
+/// Check whether `umi` occurs in `read` immediately upstream of `motif`,
+/// separated from it by a spacer of `0..=spacer_max` arbitrary bases.
+///
+/// `motif` must match exactly; the UMI window ending `s` bases before each
+/// motif occurrence is compared with `hamming_distance` for every spacer
+/// length `s`. Unlike `is_umi_in_read` this constrains where the UMI ends
+/// relative to a known downstream landmark.
+pub fn is_umi_before_motif(
+    umi: &[u8],
+    read: &[u8],
+    motif: &[u8],
+    spacer_max: usize,
+    max_mismatches: u32,
+) -> bool {
+    find_umi_before_motif(umi, read, motif, spacer_max, max_mismatches).is_some()
+}
+
+/// Find the best UMI window upstream of `motif` as described for
+/// `is_umi_before_motif`.
+pub fn find_umi_before_motif(
+    umi: &[u8],
+    read: &[u8],
+    motif: &[u8],
+    spacer_max: usize,
+    max_mismatches: u32,
+) -> Option<UmiMatch> {
+    let umi_len = umi.len();
+    if motif.is_empty() || read.len() < umi_len + motif.len() {
+        return None;
+    }
+
+    let mut best = None;
+    let motif_starts = read
+        .windows(motif.len())
+        .enumerate()
+        .filter(|(_, window)| *window == motif)
+        .map(|(start, _)| start);
+    for motif_start in motif_starts {
+        for spacer in (0..=spacer_max).take_while(|spacer| motif_start >= spacer + umi_len) {
+            let start = motif_start - spacer - umi_len;
+            let mismatches = hamming_distance(umi, &read[start..start + umi_len]);
+            if mismatches <= max_mismatches {
+                keep_best(&mut best, start, mismatches);
+            }
+        }
+    }
+    best
+}
+
+/// Shortest overlap between the end of R1 and the start of the reverse
+/// complemented R2 that `mate_overlap` accepts; shorter ones match by chance.
+const MIN_MATE_OVERLAP: usize = 10;
+
+/// Length of the longest suffix of `r1` that matches a prefix of `r2_rc`
+/// (R2 reverse complemented into the orientation of R1) with at most
+/// `max_mismatches` mismatches, or 0 if none of at least `MIN_MATE_OVERLAP`
+/// bases does.
+pub fn mate_overlap(r1: &[u8], r2_rc: &[u8], max_mismatches: u32) -> usize {
+    (MIN_MATE_OVERLAP..=r1.len().min(r2_rc.len()))
+        .rev()
+        .find(|&len| hamming_distance(&r1[r1.len() - len..], &r2_rc[..len]) <= max_mismatches)
+        .unwrap_or(0)
+}
+
+/// Check whether `umi` is split across the end of mate `r1` and the
+/// continuation of the insert in mate `r2`, as happens in short-insert
+/// libraries.
+///
+/// `r2` is given as sequenced and is reverse complemented into the
+/// orientation of `r1`. Where the mates overlap (see `mate_overlap`) the
+/// overlapping bases are dropped from R2, so the two are joined into the
+/// insert; otherwise they are joined as adjacent. Only windows that straddle
+/// the junction are searched: the last `umi.len() - 1` bases of `r1` are
+/// concatenated with the next `umi.len() - 1` bases of the insert. UMIs
+/// fully inside one mate are found by `is_umi_in_read`.
+pub fn is_umi_spanning_mates(umi: &[u8], r1: &[u8], r2: &[u8], max_mismatches: u32) -> bool {
+    let Some(overhang) = umi.len().checked_sub(1) else {
+        return false;
+    };
+    let r2_rc = reverse_complement(r2);
+    let rest = &r2_rc[mate_overlap(r1, &r2_rc, max_mismatches)..];
+    let tail = &r1[r1.len().saturating_sub(overhang)..];
+    let head = &rest[..rest.len().min(overhang)];
+    let junction = [tail, head].concat();
+    junction
+        .windows(umi.len())
+        .any(|window| hamming_distance(umi, window) <= max_mismatches)
+}
+
+/// Longest seed a `KmerIndex` is built for, which keeps its table of 4^k
+/// buckets at 64 Ki entries. Longer chunks are seeded by their prefix.
+const MAX_SEED_LEN: usize = 8;
+
+/// 2-bit code of every byte, 4 for anything but A, C, G and T in either case.
+const BASE_CODES: [u8; 256] = {
+    let mut codes = [4; 256];
+    let mut code = 0;
+    while code < 4 {
+        codes[b"ACGT"[code] as usize] = code as u8;
+        codes[b"acgt"[code] as usize] = code as u8;
+        code += 1;
+    }
+    codes
+};
+
+/// 2-bit code of `base`, or `None` for anything but A, C, G and T.
+#[inline(always)]
+fn base_code(base: u8) -> Option<usize> {
+    let code = BASE_CODES[base as usize];
+    (code < 4).then_some(code as usize)
+}
+
+/// 2-bit code of the k-mer `seq`, or `None` if it has a base other than ACGT.
+fn kmer_code(seq: &[u8]) -> Option<usize> {
+    seq.iter()
+        .try_fold(0, |code, &base| Some((code << 2) | base_code(base)?))
+}
+
+/// Seed length for a UMI of `umi_len` bases split into `max_mismatches + 1`
+/// pigeonhole chunks: the shortest chunk, capped at `MAX_SEED_LEN`.
+pub fn seed_len(umi_len: usize, max_mismatches: u32) -> usize {
+    (umi_len / (max_mismatches as usize + 1)).min(MAX_SEED_LEN)
+}
+
+/// Positions of every k-mer of a read, for searching long reads.
+///
+/// Scanning a window at a time compares the pigeonhole chunks at every
+/// position of the read. The index is instead built with a rolling 2-bit
+/// code and a counting sort into one bucket per k-mer (two cheap passes over
+/// the read, so it beats a scan even for a single UMI), after which each
+/// chunk of a UMI jumps straight to the positions where its first `k` bases
+/// occur, and only the windows those seeds point at are confirmed with the
+/// Hamming distance. K-mers with a base other than ACGT are not indexed; a
+/// chunk holding one never matches exactly anyway, since `hamming_distance`
+/// counts 'N' as a mismatch.
+#[derive(Debug, Clone)]
+pub struct KmerIndex<'a> {
+    read: &'a [u8],
+    k: usize,
+    /// Start of the bucket of each k-mer code in `positions`, plus the end.
+    offsets: Vec<u32>,
+    /// K-mer start positions, grouped by k-mer code in read order.
+    positions: Vec<u32>,
+}
+
+impl<'a> KmerIndex<'a> {
+    /// Index the k-mers of `read`; `k` is clamped to `1..=MAX_SEED_LEN` and
+    /// to log4 of the read length, so that short reads do not pay for a
+    /// table of 4^k buckets that would mostly stay empty.
+    pub fn new(read: &'a [u8], k: usize) -> Self {
+        let fits = (read.len().max(1).ilog2() / 2) as usize;
+        let k = k.min(fits).clamp(1, MAX_SEED_LEN);
+        let mask = (1 << (2 * k)) - 1;
+        // Calls `f` with the start and code of every all-ACGT k-mer
+        let each_kmer = |f: &mut dyn FnMut(usize, usize)| {
+            let (mut code, mut run) = (0, 0);
+            for (end, &base) in read.iter().enumerate() {
+                let c = BASE_CODES[base as usize] as usize;
+                if c < 4 {
+                    code = ((code << 2) | c) & mask;
+                    run += 1;
+                    if run >= k {
+                        f(end + 1 - k, code);
+                    }
+                } else {
+                    run = 0;
+                }
+            }
+        };
+        let mut offsets = vec![0u32; mask + 2];
+        each_kmer(&mut |_, code| offsets[code + 1] += 1);
+        for i in 1..offsets.len() {
+            offsets[i] += offsets[i - 1];
+        }
+        let mut next = offsets.clone();
+        let mut positions = vec![0; offsets[mask + 1] as usize];
+        each_kmer(&mut |start, code| {
+            positions[next[code] as usize] = start as u32;
+            next[code] += 1;
+        });
+        Self {
+            read,
+            k,
+            offsets,
+            positions,
+        }
+    }
+
+    /// Seed length of the index.
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// Starts of the windows of `umi` seeded by an exact occurrence of the
+    /// first `k` bases of one of its chunks, in read order, or `None` if the
+    /// chunks are shorter than `k` and the index cannot be used.
+    fn candidates(&self, umi: &[u8], max_mismatches: u32) -> Option<Vec<usize>> {
+        let num_chunks = max_mismatches as usize + 1;
+        if seed_len(umi.len(), max_mismatches) < self.k {
+            return None;
+        }
+        let mut starts = Vec::new();
+        for chunk_idx in 0..num_chunks {
+            let (start, _) = chunk_range(chunk_idx, num_chunks, umi.len());
+            let Some(code) = kmer_code(&umi[start..start + self.k]) else {
+                continue;
+            };
+            let hits =
+                &self.positions[self.offsets[code] as usize..self.offsets[code + 1] as usize];
+            starts.extend(
+                hits.iter()
+                    .map(|&pos| pos as usize)
+                    .filter(|&pos| pos >= start && pos - start + umi.len() <= self.read.len())
+                    .map(|pos| pos - start),
+            );
+        }
+        starts.sort_unstable();
+        starts.dedup();
+        Some(starts)
+    }
+
+    /// Like `find_umi_in_read` on the indexed read.
+    pub fn find(&self, umi: &[u8], max_mismatches: u32) -> Option<UmiMatch> {
+        if umi.is_empty() || self.read.len() < umi.len() {
+            return None;
+        }
+        let Some(starts) = self.candidates(umi, max_mismatches) else {
+            return find_umi_in_read(umi, self.read, max_mismatches);
+        };
+        let mut best = None;
+        for position in starts {
+            let mismatches = hamming_distance(umi, &self.read[position..position + umi.len()]);
+            if mismatches <= max_mismatches {
+                keep_best(&mut best, position, mismatches);
+                if mismatches == 0 {
+                    break;
+                }
+            }
+        }
+        best
+    }
+
+    /// Like `is_umi_in_read` on the indexed read.
+    pub fn contains(&self, umi: &[u8], max_mismatches: u32) -> bool {
+        if umi.is_empty() || self.read.len() < umi.len() {
+            return false;
+        }
+        match self.candidates(umi, max_mismatches) {
+            Some(starts) => starts.into_iter().any(|position| {
+                let window = &self.read[position..position + umi.len()];
+                hamming_distance_capped(umi, window, max_mismatches) <= max_mismatches
+            }),
+            None => is_umi_in_read(umi, self.read, max_mismatches),
+        }
+    }
+}
+
+/// Like `is_umi_in_read`, through a `KmerIndex` of `read`. Reusing one
+/// index for several UMIs saves building it again; see `KmerIndex`.
+pub fn is_umi_in_long_read(umi: &[u8], read: &[u8], max_mismatches: u32) -> bool {
+    KmerIndex::new(read, seed_len(umi.len(), max_mismatches)).contains(umi, max_mismatches)
+}
+
+/// Like `find_umi_in_read`, through a `KmerIndex` of `read`.
+pub fn find_umi_in_long_read(umi: &[u8], read: &[u8], max_mismatches: u32) -> Option<UmiMatch> {
+    KmerIndex::new(read, seed_len(umi.len(), max_mismatches)).find(umi, max_mismatches)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_kmer_index_agrees_with_brute_force_on_long_read() {
+        let mut state: u64 = 0x5eed;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut read: Vec<u8> = (0..10_000)
+            .map(|_| b"ACGT"[(next() % 4) as usize])
+            .collect();
+        read[5_000] = b'N';
+        let mut umis: Vec<Vec<u8>> = Vec::new();
+        for _ in 0..40 {
+            // A random UMI, and one copied from the read with a few substitutions
+            umis.push((0..12).map(|_| b"ACGT"[(next() % 4) as usize]).collect());
+            let start = (next() % 9_988) as usize;
+            let mut planted = read[start..start + 12].to_vec();
+            for _ in 0..next() % 4 {
+                planted[(next() % 12) as usize] = b"ACGT"[(next() % 4) as usize];
+            }
+            umis.push(planted);
+        }
+        umis.push(b"ACGTNACGTACG".to_vec());
+        for max_mismatches in 0..=3 {
+            let index = KmerIndex::new(&read, seed_len(12, max_mismatches));
+            for umi in &umis {
+                assert_eq!(
+                    index.find(umi, max_mismatches),
+                    find_umi_in_read(umi, &read, max_mismatches),
+                    "{} with {} mismatches",
+                    String::from_utf8_lossy(umi),
+                    max_mismatches
+                );
+                let expected = is_umi_in_read(umi, &read, max_mismatches);
+                assert_eq!(index.contains(umi, max_mismatches), expected);
+                assert_eq!(is_umi_in_long_read(umi, &read, max_mismatches), expected);
+            }
+        }
+        // Too short to seed: falls back to scanning
+        assert_eq!(
+            find_umi_in_long_read(b"ACG", &read, 3),
+            find_umi_in_read(b"ACG", &read, 3)
+        );
+    }
+
+    #[test]
+    fn test_kmer_index_sizes_seeds_to_short_reads() {
+        let mut state: u64 = 0xbeef;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..200 {
+            let mut read: Vec<u8> = (0..150).map(|_| b"ACGT"[(next() % 4) as usize]).collect();
+            let start = (next() % 126) as usize;
+            let mut umi = read[start..start + 24].to_vec();
+            for _ in 0..next() % 4 {
+                umi[(next() % 24) as usize] = b"ACGT"[(next() % 4) as usize];
+            }
+            read[(next() % 150) as usize] = b'n';
+            // 24 bases with one mismatch seed 8-mers, but 150 bases only fit 3
+            let index = KmerIndex::new(&read, seed_len(24, 1));
+            assert_eq!(index.k(), 3);
+            for max_mismatches in 0..=3 {
+                assert_eq!(
+                    index.find(&umi, max_mismatches),
+                    find_umi_in_read(&umi, &read, max_mismatches)
+                );
+                assert_eq!(
+                    index.contains(&umi, max_mismatches),
+                    is_umi_in_read(&umi, &read, max_mismatches)
+                );
+            }
+        }
+    }
+
     #[test]
     fn test_hamming_distance_exact() {
         let a = b"ACGTACGT";
@@ -165,6 +1067,286 @@ mod tests {
         assert_eq!(hamming_distance(a, b), 2);
     }
 
+    #[test]
+    fn test_hamming_distance_lowercase_n() {
+        // Lowercase 'n' in the 8-byte (SWAR) head, on either side
+        assert_eq!(hamming_distance(b"ACGnACGTAC", b"ACGnACGTAC"), 1);
+        assert_eq!(hamming_distance(b"ACGTACGTAC", b"nCGTACGTAC"), 1);
+        // ... and in the tail handled byte by byte
+        assert_eq!(hamming_distance(b"ACGTACGTAn", b"ACGTACGTAn"), 1);
+        assert_eq!(hamming_distance(b"ACGTACGTAC", b"ACGTACGTnC"), 1);
+        // Both paths agree with the uppercase spelling
+        let (a, b) = (b"ACGnACGTAn", b"ACGTACGTAC");
+        let upper = a.to_ascii_uppercase();
+        assert_eq!(hamming_distance(a, b), hamming_distance(&upper, b));
+        assert_eq!(
+            hamming_distance_capped(a, b, 3),
+            hamming_distance(&upper, b)
+        );
+        // Neighbouring letters are not mistaken for 'N'
+        assert_eq!(hamming_distance(b"MOmoMOmo", b"MOmoMOmo"), 0);
+    }
+
+    #[test]
+    fn test_umi_n_as_wildcard() {
+        let umi = b"ACGTNCGT";
+        let read = b"TTACGTACGTTT";
+        // Under strict Hamming the N costs a mismatch
+        assert!(!is_umi_in_read(umi, read, 0));
+        assert_eq!(wildcard_hamming_distance(umi, b"ACGTACGT"), 0);
+        assert_eq!(
+            find_umi_in_read_with_wildcards(umi, read, 0),
+            Some(UmiMatch {
+                position: 2,
+                mismatches: 0
+            })
+        );
+        // A read N still mismatches a called UMI base
+        assert_eq!(wildcard_hamming_distance(umi, b"NCGTACGT"), 1);
+        assert_eq!(wildcard_hamming_distance(umi, b"ACGTNCGT"), 0);
+    }
+
+    #[test]
+    fn test_raw_hamming_distance_compares_bytes_as_is() {
+        // N matches itself; blocks of 8 and the remainder agree
+        assert_eq!(raw_hamming_distance(b"WKLMNPQRSTN", b"WKLMNPQRSTN"), 0);
+        assert_eq!(hamming_distance(b"WKLMNPQRSTN", b"WKLMNPQRSTN"), 2);
+        assert_eq!(raw_hamming_distance(b"WKLMNPQRSTN", b"WKLMNPQASTV"), 2);
+
+        // An amino-acid barcode with one substitution (R->S)
+        let read = b"GGWKLMNPQSGG";
+        assert_eq!(find_umi_in_read(b"WKLMNPQR", read, 1), None);
+        assert_eq!(
+            find_umi_in_read_raw(b"WKLMNPQR", read, 1),
+            Some(UmiMatch {
+                position: 2,
+                mismatches: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_masked_hamming_distance_skips_ignored_positions() {
+        assert_eq!(masked_hamming_distance(b"ACGTACGT", b"ACTTACGA", &[]), 2);
+        assert_eq!(masked_hamming_distance(b"ACGTACGT", b"ACTTACGA", &[2]), 1);
+        assert_eq!(
+            masked_hamming_distance(b"ACGTACGT", b"ACTTACGA", &[2, 7]),
+            0
+        );
+        // An ignored N is not a mismatch either; positions past the UMI are moot
+        assert_eq!(masked_hamming_distance(b"ACGT", b"ACNT", &[2, 9]), 0);
+        assert_eq!(masked_hamming_distance(b"ACGT", b"ACNT", &[]), 1);
+
+        // The UMI sits at offset 2 with a dark-cycle error at window offset 3
+        let read = b"GGACGAACGTGG";
+        assert_eq!(find_umi_in_read(b"ACGTACGT", read, 0), None);
+        assert_eq!(
+            find_umi_in_read_masked(b"ACGTACGT", read, 0, &[3]),
+            Some(UmiMatch {
+                position: 2,
+                mismatches: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_hamming_distance_capped() {
+        let a = b"ACGTACGTACGTNA";
+        let b = b"TCGTACGAACGTNC";
+        assert_eq!(hamming_distance(a, b), 4);
+        assert_eq!(hamming_distance_capped(a, b, 4), 4);
+        assert_eq!(hamming_distance_capped(a, b, 10), 4);
+        assert!(hamming_distance_capped(a, b, 1) > 1);
+        assert_eq!(hamming_distance_capped(b"", b"", 0), 0);
+    }
+
+    #[test]
+    fn test_is_umi_in_read_matches_brute_force() {
+        // Deterministic pseudo-random UMIs and reads built from a small LCG
+        let mut state: u64 = 7;
+        let mut next_base = || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            // Mostly ACGT with the occasional N
+            b"ACGTACGTACGTACGTACGN"[(state >> 33) as usize % 20]
+        };
+        for i in 0..500 {
+            let umi: Vec<u8> = (0..6 + i % 11).map(|_| next_base()).collect();
+            let mut read: Vec<u8> = (0..30).map(|_| next_base()).collect();
+            // Plant a mutated copy of the UMI in most reads
+            if i % 4 != 0 {
+                read[5..5 + umi.len()].copy_from_slice(&umi);
+                for k in 0..i % 5 {
+                    read[5 + (i * 3 + k * 5) % umi.len()] = next_base();
+                }
+            }
+            // The exact search (no mismatches) compares 'N' like any other base
+            let min_mismatches = u32::from(umi.contains(&b'N'));
+            for max_mismatches in min_mismatches..=3 {
+                let brute_force = read
+                    .windows(umi.len())
+                    .any(|window| hamming_distance(&umi, window) <= max_mismatches);
+                assert_eq!(
+                    is_umi_in_read(&umi, &read, max_mismatches),
+                    brute_force,
+                    "umi {:?} read {:?} m={}",
+                    String::from_utf8_lossy(&umi),
+                    String::from_utf8_lossy(&read),
+                    max_mismatches
+                );
+                assert_eq!(
+                    CompiledUmi::new(&umi, max_mismatches).matches(&read),
+                    brute_force
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_umi_in_read_1mm_matches_generic_search() {
+        let mut state: u64 = 13;
+        let mut next = || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) as usize
+        };
+        for i in 0..3000 {
+            // Mostly ACGT, sometimes with N or a lowercase base, and UMIs from
+            // a single base to longer than the packed halves can hold
+            let alphabet: &[u8] = match i % 10 {
+                0 => b"ACGTACGTN",
+                1 => b"ACGTACGTa",
+                _ => b"ACGT",
+            };
+            let umi_len = if i % 50 == 0 {
+                60 + next() % 10
+            } else {
+                1 + next() % 20
+            };
+            let umi: Vec<u8> = (0..umi_len)
+                .map(|_| alphabet[next() % alphabet.len()])
+                .collect();
+            let mut read: Vec<u8> = (0..umi_len + next() % 30)
+                .map(|_| alphabet[next() % alphabet.len()])
+                .collect();
+            if i % 3 != 0 {
+                let at = next() % (read.len() - umi_len + 1);
+                read[at..at + umi_len].copy_from_slice(&umi);
+                for _ in 0..i % 4 {
+                    read[at + next() % umi_len] = alphabet[next() % alphabet.len()];
+                }
+            }
+            assert_eq!(
+                is_umi_in_read_1mm(&umi, &read),
+                is_umi_in_read_with_chunks(&umi, &read, 1, 2),
+                "umi {:?} read {:?}",
+                String::from_utf8_lossy(&umi),
+                String::from_utf8_lossy(&read)
+            );
+        }
+        // Shorter reads never match
+        assert!(!is_umi_in_read_1mm(b"ACGT", b"ACG"));
+    }
+
+    #[test]
+    fn test_chunk_counts_match_brute_force() {
+        let mut state: u64 = 11;
+        let mut next_base = || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            b"ACGT"[(state >> 33) as usize % 4]
+        };
+        for i in 0..300 {
+            let umi: Vec<u8> = (0..6 + i % 11).map(|_| next_base()).collect();
+            let mut read: Vec<u8> = (0..30).map(|_| next_base()).collect();
+            if i % 3 != 0 {
+                read[4..4 + umi.len()].copy_from_slice(&umi);
+                for k in 0..i % 5 {
+                    read[4 + (i * 7 + k * 3) % umi.len()] = next_base();
+                }
+            }
+            for max_mismatches in 1..=3 {
+                let brute_force = read
+                    .windows(umi.len())
+                    .any(|window| hamming_distance(&umi, window) <= max_mismatches);
+                // From the minimum up to more chunks than UMI bases
+                for num_chunks in max_mismatches as usize + 1..=umi.len() + 2 {
+                    assert_eq!(
+                        is_umi_in_read_with_chunks(&umi, &read, max_mismatches, num_chunks),
+                        brute_force,
+                        "umi {:?} read {:?} m={} chunks={}",
+                        String::from_utf8_lossy(&umi),
+                        String::from_utf8_lossy(&read),
+                        max_mismatches,
+                        num_chunks
+                    );
+                    assert_eq!(
+                        CompiledUmi::with_chunks(&umi, max_mismatches, num_chunks).matches(&read),
+                        brute_force
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_umi_spanning_mates() {
+        let umi = b"ACGTACGTACGT";
+        let r1 = b"TTTTTTTTTTTTACGTAC";
+        // Sequenced from the other end: the insert continues GTACGTGGG...
+        let r2 = b"CCCCCCCCCCCCACGTAC";
+        // Neither mate holds the whole UMI
+        assert!(!is_umi_in_read(umi, r1, 0));
+        assert!(!is_umi_in_read(umi, &reverse_complement(r2), 0));
+        assert!(is_umi_spanning_mates(umi, r1, r2, 0));
+        // Not reverse complemented, R2 does not continue the UMI
+        assert!(!is_umi_spanning_mates(umi, r1, b"GTACGTGGGGGGGGGGGG", 0));
+        // Windows fully inside one mate are not part of the junction
+        assert!(!is_umi_spanning_mates(umi, b"ACGTACGTACGTTTTTTT", r2, 0));
+        assert!(is_umi_spanning_mates(umi, b"TTTTTTTTTTTTACGTAA", r2, 1));
+    }
+
+    #[test]
+    fn test_is_umi_spanning_overlapping_mates() {
+        let umi = b"AACCGGTTACGA";
+        let insert = b"GATTACAGATTACACCATGGTAACCGGTTACGATTTT";
+        let r1 = &insert[..24];
+        let r2 = reverse_complement(&insert[8..]);
+        assert_eq!(mate_overlap(r1, &insert[8..], 0), 16);
+        // The mates overlap by 16 bases, so R2 continues the insert after them
+        assert!(!is_umi_in_read(umi, r1, 0));
+        assert!(!is_umi_in_read(umi, &r2, 0));
+        assert!(is_umi_spanning_mates(umi, r1, &r2, 0));
+        // Too short to count as an overlap: the mates are joined as adjacent
+        assert_eq!(mate_overlap(&insert[..12], &insert[3..], 0), 0);
+    }
+
+    #[test]
+    fn test_reverse_complement() {
+        assert_eq!(reverse_complement(b"AACGTN"), b"NACGTT");
+        assert_eq!(reverse_complement(b"acgT"), b"Acgt");
+        assert!(reverse_complement(b"").is_empty());
+    }
+
+    #[test]
+    fn test_shannon_entropy() {
+        assert_eq!(shannon_entropy(b"AAAAAAAAAAAA"), 0.0);
+        assert_eq!(shannon_entropy(b"ACGTACGTACGT"), 2.0);
+        assert_eq!(shannon_entropy(b"AAAATTTT"), 1.0);
+        assert_eq!(shannon_entropy(b""), 0.0);
+    }
+
+    #[test]
+    fn test_dinucleotide_entropy() {
+        assert_eq!(dinucleotide_entropy(b"AAAAAAAAAAAA"), 0.0);
+        assert_eq!(dinucleotide_entropy(b"ACACACACA"), 1.0);
+        assert_eq!(dinucleotide_entropy(b"ACGTACGTA"), 2.0);
+        assert_eq!(dinucleotide_entropy(b"A"), 0.0);
+    }
+
     #[test]
     fn test_is_umi_in_read_exact_and_mismatch() {
         let umi = b"ACGTACGTACGT"; // 12
@@ -175,4 +1357,114 @@ mod tests {
         assert!(is_umi_in_read(umi, read2, 1));
         assert!(!is_umi_in_read(umi, read2, 0));
     }
+
+    #[test]
+    fn test_is_umi_before_motif_with_spacer() {
+        let umi = b"ACGTACGTACGT";
+        let motif = b"TTTAAA";
+        // Spacers of length 0, 2 and 4 between UMI and motif
+        let read0 = b"GGACGTACGTACGTTTTAAAGG";
+        let read2 = b"GGACGTACGTACGTCCTTTAAAGG";
+        let read4 = b"GGACGTACGTACGTCCCCTTTAAAGG";
+
+        assert!(is_umi_before_motif(umi, read0, motif, 0, 0));
+        assert!(!is_umi_before_motif(umi, read2, motif, 0, 0));
+        assert!(is_umi_before_motif(umi, read2, motif, 2, 0));
+        assert!(!is_umi_before_motif(umi, read4, motif, 3, 0));
+        assert!(is_umi_before_motif(umi, read4, motif, 4, 0));
+
+        // The UMI must sit upstream of the motif, not anywhere in the read
+        let downstream = b"TTTAAAGGACGTACGTACGT";
+        assert!(!is_umi_before_motif(umi, downstream, motif, 4, 0));
+    }
+
+    #[test]
+    fn test_find_umi_in_read_prefers_fewest_mismatches() {
+        let umi = b"ACGTACGTACGT";
+        // 1-mismatch copy at offset 2, exact copy at offset 18
+        let read = b"GGACGTACGAACGTGGGGACGTACGTACGTGG";
+        let best = find_umi_in_read(umi, read, 2).unwrap();
+        assert_eq!(
+            best,
+            UmiMatch {
+                position: 18,
+                mismatches: 0
+            }
+        );
+
+        let best = find_umi_in_read(umi, &read[..16], 2).unwrap();
+        assert_eq!(best.position, 2);
+        assert_eq!(best.mismatches, 1);
+        assert!(find_umi_in_read(umi, &read[..16], 0).is_none());
+    }
+
+    #[test]
+    fn test_find_templated_umi_in_read() {
+        let template = b"NNNNGGNNNN";
+        assert_eq!(fill_umi_template(template, b"ACGTACG"), None);
+        let filled = fill_umi_template(template, b"ACGTACGT").unwrap();
+        assert_eq!(filled, b"ACGTGGACGT");
+
+        assert_eq!(
+            find_templated_umi_in_read(&filled, template, b"TTACGTGGACGTTT", 0),
+            Some(UmiMatch {
+                position: 2,
+                mismatches: 0
+            })
+        );
+        // A mismatch in a UMI position is within budget...
+        assert!(find_templated_umi_in_read(&filled, template, b"TTACCTGGACGTTT", 1).is_some());
+        // ...but one in the fixed GG never is
+        assert!(find_templated_umi_in_read(&filled, template, b"TTACGTGCACGTTT", 1).is_none());
+        assert!(find_templated_umi_in_read(&filled, template, b"TTACGTGNACGTTT", 3).is_none());
+    }
+
+    #[test]
+    fn test_find_all_umi_in_read_counts_every_occurrence() {
+        let read = b"ACGTTTACGTTTACCT";
+        let exact = find_all_umi_in_read(b"ACGT", read, 0);
+        assert_eq!(exact.iter().map(|m| m.position).collect::<Vec<_>>(), [0, 6]);
+        let fuzzy = find_all_umi_in_read(b"ACGT", read, 1);
+        assert_eq!(
+            fuzzy.iter().map(|m| m.position).collect::<Vec<_>>(),
+            [0, 6, 12]
+        );
+        assert_eq!(fuzzy[2].mismatches, 1);
+        assert!(find_all_umi_in_read(b"ACGT", b"ACG", 1).is_empty());
+    }
+
+    #[test]
+    fn test_compiled_umi_matches_per_call_search() {
+        // Deterministic pseudo-random reads built from a small LCG
+        let mut state: u64 = 42;
+        let mut next_base = || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            b"ACGTN"[(state >> 33) as usize % 5]
+        };
+        let umi = b"ACGTACGTACGT";
+        let reads: Vec<Vec<u8>> = (0..200)
+            .map(|i| {
+                let mut read: Vec<u8> = (0..40).map(|_| next_base()).collect();
+                // Plant a mutated copy of the UMI in every other read
+                if i % 2 == 0 {
+                    read[10..22].copy_from_slice(umi);
+                    read[10 + i % 12] = next_base();
+                    read[10 + (i * 7) % 12] = next_base();
+                }
+                read
+            })
+            .collect();
+
+        for max_mismatches in 0..=4 {
+            let compiled = CompiledUmi::new(umi, max_mismatches);
+            for read in &reads {
+                assert_eq!(
+                    compiled.matches(read),
+                    is_umi_in_read(umi, read, max_mismatches)
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,64 @@
+//! MultiQC custom-content output.
+//!
+//! With `--multiqc` the UMI-found rate of every input is written to a
+//! `*_mqc.json` file that MultiQC picks up as a General Statistics section,
+//! keyed by sample name.
+
+use crate::processing::ProcessStats;
+use crate::{json_string, percentage};
+use anyhow::{Context, Result};
+use std::path::Path;
+
+/// The custom-content module for `samples`, `(sample name, stats)` pairs.
+pub fn multiqc_json(samples: &[(String, ProcessStats)]) -> String {
+    let data: Vec<String> = samples
+        .iter()
+        .map(|(sample, stats)| {
+            format!(
+                "{}:{{\"total\":{},\"with_umi\":{},\"with_umi_pct\":{:.2}}}",
+                json_string(sample),
+                stats.total,
+                stats.removed,
+                percentage(stats.removed, stats.total)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"id\":\"umi_checker\",\"section_name\":\"UMI checker\",\
+         \"description\":\"Reads in which the UMI was found.\",\
+         \"plot_type\":\"generalstats\",\
+         \"pconfig\":{{\"with_umi_pct\":{{\"title\":\"% UMI found\",\"min\":0,\"max\":100,\"suffix\":\"%\"}}}},\
+         \"data\":{{{}}}}}\n",
+        data.join(",")
+    )
+}
+
+/// Write the custom-content module for `samples` to `path`.
+pub fn write_multiqc_json(path: &Path, samples: &[(String, ProcessStats)]) -> Result<()> {
+    std::fs::write(path, multiqc_json(samples))
+        .with_context(|| format!("Failed to write MultiQC file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multiqc_json_keys_data_by_sample() {
+        let stats = ProcessStats {
+            total: 8,
+            removed: 2,
+            kept: 6,
+            ..Default::default()
+        };
+        let json = multiqc_json(&[
+            ("a".to_string(), stats),
+            ("b".to_string(), ProcessStats::default()),
+        ]);
+        assert!(json.starts_with("{\"id\":\"umi_checker\","));
+        assert!(json.contains(
+            "\"data\":{\"a\":{\"total\":8,\"with_umi\":2,\"with_umi_pct\":25.00},\
+             \"b\":{\"total\":0,\"with_umi\":0,\"with_umi_pct\":0.00}}}"
+        ));
+    }
+}
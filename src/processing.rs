@@ -1,186 +1,2543 @@
-use anyhow::{Context, Result};
-use needletail::parse_fastx_file;
+use anyhow::{anyhow, Context, Result};
+use flate2::read::MultiGzDecoder;
+use flate2::Compression;
+use needletail::errors::ParseErrorKind;
+use needletail::parser::FastxReader;
+use needletail::{parse_fastx_file, parse_fastx_reader};
 use rayon::prelude::*;
-use rust_htslib::{bam, bam::Read};
+use regex::bytes::Regex;
+use rust_htslib::bam;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use crate::bloom::BloomFilter;
+use crate::consensus::ConsensusGroups;
 use crate::io::{
-    create_bam_writer, create_fastq_writer, BamRecord, BioRecord, FastqRecord, GenericWriter,
+    checkpointed_fastq_writer, create_bam_writer, create_fastq_writer,
+    create_fastq_writer_with_level, create_report_writer, read_verdicts, write_count_table,
+    write_group_table, write_position_histogram, BamRecord, BioRecord, DeferredOutput, FastqRecord,
+    GenericWriter, MateWriters,
 };
-use crate::matcher::is_umi_in_read;
+use crate::matcher::{
+    dinucleotide_entropy, fill_umi_template, find_all_umi_in_read, find_templated_umi_in_read,
+    find_umi_before_motif, find_umi_in_long_read, find_umi_in_read, find_umi_in_read_masked,
+    find_umi_in_read_raw, find_umi_in_read_with_wildcards, hamming_distance, is_umi_before_motif,
+    is_umi_in_long_read, is_umi_in_read, is_umi_in_read_with_chunks, is_umi_spanning_mates,
+    masked_hamming_distance, raw_hamming_distance, reverse_complement, seed_len, shannon_entropy,
+    wildcard_hamming_distance, CompiledUmi, KmerIndex, UmiMatch,
+};
+use crate::topk::{TopItems, TopK};
+use crate::UmiParseError;
+
+pub const BATCH_SIZE: usize = 10_000;
+
+/// UMIs with a base composition entropy below this many bits (e.g. poly-A
+/// UMIs) match almost any low-complexity stretch and are flagged.
+const LOW_COMPLEXITY_ENTROPY: f64 = 1.0;
+
+/// Largest fraction of the read that may precede the adapter for the read to
+/// count as an adapter dimer: the adapter and its read-through then cover at
+/// least the rest, i.e. the insert is (nearly) empty.
+const ADAPTER_DIMER_MAX_INSERT_FRACTION: f64 = 0.2;
+
+/// Bases on either side of a UMI match included in the region whose
+/// complexity `min_match_complexity` checks.
+const MATCH_COMPLEXITY_FLANK: usize = 8;
+
+/// Fraction of reads shorter than their UMI above which the UMI length is
+/// reported as a likely misconfiguration.
+const UMI_LONGER_THAN_READ_WARN_FRACTION: f64 = 0.1;
+
+/// Number of buffered reads after which `--sort-by-mismatches` warns about
+/// memory use.
+const SORT_WARN_RECORDS: usize = 5_000_000;
+
+/// Number of distinct UMIs the `--estimate-dup-rate` Bloom filter is sized
+/// for (about 12 MB at a 1% false-positive rate).
+const DUP_RATE_CAPACITY: usize = 10_000_000;
+
+/// Counters kept per requested `--top-umis` entry. More counters than
+/// reported entries keep the counts of the reported UMIs close to exact.
+const TOP_UMI_COUNTERS_PER_ENTRY: usize = 10;
+
+/// Options controlling how UMIs are extracted from headers and searched for
+/// in reads. Shared by the FASTQ and BAM processors.
+#[derive(Debug, Clone)]
+pub struct ProcessOptions {
+    /// Maximum number of mismatches allowed when finding the UMI in the read.
+    pub max_mismatches: u32,
+    /// Expected UMI length used when extracting the UMI from the read header.
+    pub umi_len: usize,
+    /// Upper bound for header UMI lengths; when set, UMIs of any length in
+    /// `umi_len..=umi_len_max` are accepted and each read is searched with
+    /// its own UMI's length.
+    pub umi_len_max: Option<usize>,
+    /// Delimiter-separated header field holding the UMI; negative indices
+    /// count from the end (see `extract_umi_field`).
+    pub umi_field: isize,
+    /// Read the UMI from this SAM/BAM string tag (e.g. `UB`) instead of the
+    /// read header. Tag UMIs are taken as-is, without a length check.
+    pub umi_tag: Option<[u8; 2]>,
+    /// Group reads by the value of this SAM/BAM string tag (e.g. `CB`) and
+    /// write per-group counts to `tag_counts`. Reads without the tag are not
+    /// part of any group.
+    pub count_by_tag: Option<[u8; 2]>,
+    /// Destination of the per-group counts for `count_by_tag`.
+    pub tag_counts: Option<PathBuf>,
+    /// Abort on the first header that does not yield a UMI of the expected
+    /// length instead of counting the read as not containing its UMI.
+    pub strict: bool,
+    /// Known motif downstream of the UMI. When set, the UMI only matches if it
+    /// ends within `spacer_max` bases upstream of an exact motif occurrence.
+    pub spacer_motif: Option<Vec<u8>>,
+    /// Structured UMI layout such as `NNNNGGNNNN`: the `N` positions are
+    /// filled with the extracted UMI and the other bases must match the read
+    /// exactly. The UMI must have one base per `N`.
+    pub umi_template: Option<Vec<u8>>,
+    /// Maximum number of arbitrary bases between the UMI and `spacer_motif`.
+    pub spacer_max: usize,
+    /// Number of bases at the start of every read excluded from the search,
+    /// e.g. a constant primer preceding the UMI-bearing region.
+    pub skip_prefix: usize,
+    /// Number of bases at the end of every read excluded from the search.
+    pub skip_suffix: usize,
+    /// Constant landmark the UMI is tied to. When set, only the bases right
+    /// after (or, with `anchor_before`, right before) the first exact motif
+    /// occurrence are searched: the UMI length plus `anchor_gap` bases.
+    /// Reads without the motif count as not containing their UMI.
+    pub anchor_motif: Option<Vec<u8>>,
+    /// Search before `anchor_motif` instead of after it.
+    pub anchor_before: bool,
+    /// Maximum number of arbitrary bases between `anchor_motif` and the UMI.
+    pub anchor_gap: usize,
+    /// Ignore a trailing poly-G run of at least this many bases, as produced
+    /// by two-color chemistry, when searching for the UMI.
+    pub trim_polyg: Option<usize>,
+    /// Also remove the poly-G tail from the written reads (FASTQ only).
+    pub trim_polyg_output: bool,
+    /// Checkpoint file updated after every batch with the progress so far.
+    /// Only supported for FASTQ input.
+    pub checkpoint: Option<PathBuf>,
+    /// Resume from `checkpoint` (if it exists): continue the input after the
+    /// processed records, restore their counts and append to the outputs as
+    /// they were when the checkpoint was written.
+    pub resume: bool,
+    /// Buffer removed reads and write them ordered by ascending mismatch
+    /// count once the input is exhausted. This gives up streaming output:
+    /// every read containing its UMI is held in memory until the end.
+    pub sort_by_mismatches: bool,
+    /// Write reads containing their UMI to one FASTQ file per mismatch count
+    /// of their best match, `<prefix>.mm0.fq` to `<prefix>.mmN.fq` for
+    /// `max_mismatches` N (see `mismatch_output_path`), instead of to the
+    /// removed output. Only supported for FASTQ input.
+    pub tag_output_by_mismatch: Option<PathBuf>,
+    /// Only create an output file once the first read is written to it, so
+    /// that empty outputs leave no file. Not combined with `resume`.
+    pub no_empty_output: bool,
+    /// Write a uniform random sample of at most this many kept reads instead
+    /// of all of them (reservoir sampling). The sample is held in memory
+    /// until the input is exhausted, so memory grows with the sample size.
+    pub downsample: Option<usize>,
+    /// Seed of the `downsample` sampler; the same seed and input give the
+    /// same sample.
+    pub seed: u64,
+    /// Search every read for this UMI instead of extracting it from the
+    /// header. Compiled once since the same UMI is searched in every read.
+    pub fixed_umi: Option<CompiledUmi>,
+    /// Write a `umi\tcount` table of extracted UMIs that were not found in
+    /// their read, most frequent first.
+    pub unmatched_umi_table: Option<PathBuf>,
+    /// Write a `read_id\tfound` TSV with the verdict for every read. With
+    /// `umi_candidates_delim` a third column names the matched candidate;
+    /// with `check_revcomp` `orientation` and `position` columns follow.
+    pub per_read_report: Option<PathBuf>,
+    /// Write a BED6 file with the reference interval of every UMI match in
+    /// aligned BAM/SAM reads (chrom, start, end, read ID, mismatches,
+    /// strand). Unaligned reads are skipped.
+    pub match_bed: Option<PathBuf>,
+    /// Write a spreadsheet-friendly QC table with one row per read:
+    /// `read_id\tumi\tfound\tmismatches\tread_length`. `NA` marks a missing
+    /// UMI or, for reads without their UMI, the mismatch count.
+    pub qc_table: Option<PathBuf>,
+    /// Write the substitutions of every UMI match to this TSV for error
+    /// modelling, one row per matched read:
+    /// `read_id\tumi\twindow\tmismatches\tsubstitutions`, where
+    /// substitutions are `pos:from>to` (0-based offset in the UMI),
+    /// comma-separated, or `NA` for an exact match.
+    pub error_table: Option<PathBuf>,
+    /// Group reads by extracted UMI and write one majority-vote consensus
+    /// FASTQ record per group to this file. FASTQ has no alignment position,
+    /// so groups are by UMI alone. Per-position vote counts of every group
+    /// are held in memory until the input is exhausted. Only supported for
+    /// FASTQ input.
+    pub collapse_output: Option<PathBuf>,
+    /// Also write every problematic read (see `quarantine_reason`) to this
+    /// FASTQ file, with the reason appended to its header as
+    /// `quarantine:<reason>`. The reads are still counted and written as
+    /// without UMI, except malformed records, which are skipped. Only
+    /// supported for FASTQ input.
+    pub quarantine: Option<PathBuf>,
+    /// Write an `offset\treads` histogram of the start offset in the read of
+    /// every UMI match. A tight peak shows where the UMI sits; a diffuse one
+    /// suggests spurious matches.
+    pub position_histogram: Option<PathBuf>,
+    /// Compare this run's verdicts against a report written by
+    /// `per_read_report` and print how many of them flipped.
+    pub compare_baseline: Option<PathBuf>,
+    /// Write a `read_id\tumi\toccurrences_in_read` TSV counting every
+    /// (possibly overlapping) occurrence of each read's UMI. Reads without a
+    /// UMI are not listed.
+    pub scan_only: Option<PathBuf>,
+    /// Approximate memory budget in bytes for the records of an in-flight
+    /// batch. The batch size shrinks below `batch_size` once the observed
+    /// average record size would exceed it.
+    pub max_memory: Option<usize>,
+    /// Maximum number of records per batch.
+    pub batch_size: usize,
+    /// Gzip compression level (0-9) of compressed FASTQ outputs; `None` uses
+    /// the flate2 default.
+    pub gzip_level: Option<u32>,
+    /// Report the mean GC content of reads with and without their UMI.
+    pub report_gc: bool,
+    /// Known adapter searched for in every read (with the same mismatch
+    /// budget as the UMI); reads containing it are counted and reported.
+    pub adapter: Option<CompiledUmi>,
+    /// Do not search for low-complexity UMIs (entropy below
+    /// `LOW_COMPLEXITY_ENTROPY`); their reads count as not containing the UMI.
+    pub skip_low_complexity: bool,
+    /// Reject UMI matches whose region (the match plus
+    /// `MATCH_COMPLEXITY_FLANK` bases on either side) has a dinucleotide
+    /// entropy below this many bits; their reads count as not containing the
+    /// UMI. Rejections are counted separately.
+    pub min_match_complexity: Option<f64>,
+    /// Examine at most this many UMI windows per read, starting at the
+    /// beginning of the search region, to bound the search time of very long
+    /// reads. Matches further into the read are not counted.
+    pub max_window_search: Option<usize>,
+    /// Only process shard `i` of `n` (`(i, n)`): the `i`-th of `n`
+    /// contiguous, near-equal ranges of input records, counted before any
+    /// flag filters. Requires an extra pass over the input to count them.
+    pub shard: Option<(usize, usize)>,
+    /// Do not keep a decoded copy of the sequence of every BAM/SAM record
+    /// in a batch; decode it into a per-thread buffer whenever it is needed.
+    /// Saves an allocation and a sequence's worth of memory per record at
+    /// the cost of decoding again for options that look at the sequence
+    /// after matching (e.g. `report_gc`).
+    pub no_seq_copy: bool,
+    /// Write BAM/SAM outputs as uncompressed SAM instead of BAM.
+    pub sam_output: bool,
+    /// Write BAM/SAM inputs as FASTQ, split by mate into `<base>.R1.fq` and
+    /// `<base>.R2.fq` next to each output, with unpaired reads in the output
+    /// itself (see `MateWriters`).
+    pub fastq_output: bool,
+    /// Extract the UMI from the named `umi` capture group of this regex
+    /// instead of a header field.
+    pub umi_regex: Option<Regex>,
+    /// Annotate removed reads with the mismatch count of their UMI match
+    /// (`mm:<n>` FASTQ header comment, `mm:i` BAM tag).
+    pub annotate_mismatches: bool,
+    /// Remove the `umi_field` header field from the names of output reads.
+    pub normalize_output_headers: bool,
+    /// Keep the UMI removed by `normalize_output_headers` as a `umi:<UMI>`
+    /// FASTQ header comment or `RX:Z` BAM tag.
+    pub keep_header_umi: bool,
+    /// Annotate removed reads with the mean base quality of their UMI match
+    /// (`wq:<q>` FASTQ header comment, `wq:f` BAM tag) and report the mean
+    /// over all matches.
+    pub window_quality: bool,
+    /// Quality character written for every base of FASTQ records without
+    /// qualities (e.g. FASTA input); without it their quality line is left
+    /// empty, which strict FASTQ parsers reject.
+    pub fill_quality: Option<u8>,
+    /// Estimate the fraction of extracted UMIs that were seen before with a
+    /// Bloom filter of this false-positive rate, in bounded memory.
+    pub estimate_dup_rate: Option<f64>,
+    /// Track this many most frequent extracted UMIs, counted approximately
+    /// in memory bounded by the number tracked (see `TopK`), for
+    /// `ProcessStats::top_umis`.
+    pub top_umis: Option<usize>,
+    /// Also print the `top_umis` list to stderr at the end of the input.
+    pub report_top_umis: bool,
+    /// Count reads whose ID was already seen in the input (an error in
+    /// strict mode). Every read ID is kept in memory. Only supported for
+    /// FASTQ input, since BAM mates share their name.
+    pub check_duplicate_ids: bool,
+    /// Only process reads overlapping this region (`chr`, `chr:beg-end`),
+    /// fetched through the BAM index. Only supported for indexed BAM input.
+    pub region: Option<String>,
+    /// Only process BAM records with all of these flag bits set, like
+    /// `samtools view -f`. Other records are neither counted nor written.
+    pub require_flags: u16,
+    /// Only process BAM records with none of these flag bits set, like
+    /// `samtools view -F`.
+    pub exclude_flags: u16,
+    /// Treat 'N' positions of the UMI as wildcards matching any read base
+    /// instead of mismatches. Not combined with `spacer_motif` or `scan_only`.
+    pub tolerate_n_in_umi: bool,
+    /// Compare UMI and read bytes as they are (`raw_hamming_distance`) instead
+    /// of counting 'N' as a mismatch, for barcode alphabets other than DNA.
+    /// Not combined with the searches that assume DNA.
+    pub raw_alphabet: bool,
+    /// Window positions (0-based offsets from the window start) left out of
+    /// the UMI comparison, e.g. known-bad sequencing cycles. Not combined
+    /// with the motif, template, wildcard, chunked or k-mer index searches.
+    pub ignore_positions: Vec<usize>,
+    /// Number of pigeonhole chunks for the mismatch search; `None` uses
+    /// `max_mismatches + 1`. Must be at least `max_mismatches + 1`.
+    pub chunks: Option<usize>,
+    /// Search every read through a `KmerIndex` of the read instead of
+    /// scanning all windows. The `umi_candidates_delim` candidates of a read
+    /// share one index. Not combined with `chunks`.
+    pub long_read: bool,
+    /// Split the UMI into candidates on this byte and count the UMI as found
+    /// if any candidate is; with a header UMI the length of every candidate
+    /// is checked.
+    pub umi_candidates_delim: Option<u8>,
+    /// Treat the two `umi_candidates_delim` parts as dual UMIs flanking the
+    /// read: the UMI counts as found only if the first part matches at the
+    /// start and the second at the end of the search region, each within
+    /// `max_mismatches`. Headers with any other number of parts never match.
+    pub require_both_umis_same_position: bool,
+    /// Also search the reverse complement of the search region of reads
+    /// whose UMI is not found on the forward strand. Matches on the reverse
+    /// strand are reported with `-` orientation in `per_read_report`.
+    pub check_revcomp: bool,
+    /// Use the whole first token of the header as the UMI, for formats where
+    /// the read name is the UMI. Not combined with `umi_candidates_delim`.
+    pub umi_whole_header: bool,
+    /// Warn about every read whose UMI extraction and search takes longer
+    /// than this, e.g. a single very long read dragging down a run.
+    pub warn_slow: Option<Duration>,
+    /// Print the Hamming distance of the UMI to every window of the search
+    /// region of the read with this ID to stderr, for debugging its verdict.
+    pub dump_windows: Option<Vec<u8>>,
+}
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        Self {
+            max_mismatches: 0,
+            umi_len: 12,
+            umi_len_max: None,
+            umi_field: -1,
+            umi_tag: None,
+            count_by_tag: None,
+            tag_counts: None,
+            strict: false,
+            spacer_motif: None,
+            umi_template: None,
+            spacer_max: 0,
+            skip_prefix: 0,
+            skip_suffix: 0,
+            anchor_motif: None,
+            anchor_before: false,
+            anchor_gap: 0,
+            trim_polyg: None,
+            trim_polyg_output: false,
+            checkpoint: None,
+            resume: false,
+            sort_by_mismatches: false,
+            no_empty_output: false,
+            downsample: None,
+            seed: 0,
+            fixed_umi: None,
+            unmatched_umi_table: None,
+            per_read_report: None,
+            match_bed: None,
+            qc_table: None,
+            error_table: None,
+            collapse_output: None,
+            quarantine: None,
+            tag_output_by_mismatch: None,
+            position_histogram: None,
+            compare_baseline: None,
+            scan_only: None,
+            max_memory: None,
+            batch_size: BATCH_SIZE,
+            gzip_level: None,
+            report_gc: false,
+            adapter: None,
+            skip_low_complexity: false,
+            min_match_complexity: None,
+            max_window_search: None,
+            shard: None,
+            no_seq_copy: false,
+            sam_output: false,
+            fastq_output: false,
+            umi_regex: None,
+            annotate_mismatches: false,
+            normalize_output_headers: false,
+            keep_header_umi: false,
+            window_quality: false,
+            fill_quality: None,
+            estimate_dup_rate: None,
+            top_umis: None,
+            report_top_umis: false,
+            check_duplicate_ids: false,
+            region: None,
+            require_flags: 0,
+            exclude_flags: 0,
+            tolerate_n_in_umi: false,
+            raw_alphabet: false,
+            ignore_positions: Vec::new(),
+            chunks: None,
+            long_read: false,
+            umi_candidates_delim: None,
+            require_both_umis_same_position: false,
+            check_revcomp: false,
+            umi_whole_header: false,
+            warn_slow: None,
+            dump_windows: None,
+        }
+    }
+}
+
+/// Read counts of a processed input. Counts of several inputs (or batches)
+/// are merged with `+=`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessStats {
+    /// Number of reads processed.
+    pub total: usize,
+    /// Reads whose UMI was found in the sequence.
+    pub removed: usize,
+    /// Reads whose UMI was not found in the sequence.
+    pub kept: usize,
+    /// Read pairs both of whose mates were processed (BAM/SAM input only).
+    pub pairs: usize,
+    /// Pairs where the UMI was found in at least one mate.
+    pub pairs_with_umi: usize,
+    /// Mean GC fraction of reads with and without their UMI, tracked with
+    /// `report_gc`.
+    pub gc: (Mean, Mean),
+    /// Most frequent extracted UMIs of the input, tracked with `top_umis`.
+    pub top_umis: Option<TopItems>,
+    /// Reads without a usable UMI, e.g. from a header without one or with
+    /// one of the wrong length; they count as without UMI.
+    pub no_umi: usize,
+    /// Reads shorter than their UMI, which can never contain it.
+    pub shorter_than_umi: usize,
+    /// Reads containing the `adapter` sequence, whether or not their UMI was
+    /// found.
+    pub adapter_present: usize,
+    /// Reads whose UMI is low-complexity (e.g. poly-A).
+    pub low_complexity_umi: usize,
+    /// Reads whose ID repeats an earlier read's, counted with
+    /// `check_duplicate_ids`.
+    pub duplicate_id: usize,
+    /// FASTQ records whose quality and sequence lengths differ, skipped and
+    /// not counted in `total`.
+    pub malformed: usize,
+}
+
+impl std::ops::AddAssign for ProcessStats {
+    fn add_assign(&mut self, other: Self) {
+        self.total += other.total;
+        self.removed += other.removed;
+        self.kept += other.kept;
+        self.pairs += other.pairs;
+        self.pairs_with_umi += other.pairs_with_umi;
+        self.gc.0 += other.gc.0;
+        self.gc.1 += other.gc.1;
+        // Only the stats of a whole input carry the list
+        self.top_umis = self.top_umis.take().or(other.top_umis);
+        self.no_umi += other.no_umi;
+        self.shorter_than_umi += other.shorter_than_umi;
+        self.adapter_present += other.adapter_present;
+        self.low_complexity_umi += other.low_complexity_umi;
+        self.duplicate_id += other.duplicate_id;
+        self.malformed += other.malformed;
+    }
+}
+
+/// Progress snapshot persisted to the checkpoint file: the number of records
+/// consumed from the input and how many of them were removed or kept, where
+/// the input continues and how long both outputs were at that point.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub records: usize,
+    pub removed: usize,
+    pub kept: usize,
+    /// Decompressed input byte at which the first unprocessed record starts.
+    pub input_offset: u64,
+    /// Bytes of the kept output.
+    pub kept_bytes: u64,
+    /// Bytes of the removed output.
+    pub removed_bytes: u64,
+}
+
+impl Checkpoint {
+    /// Load a checkpoint written by `save`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read checkpoint {}", path.display()))?;
+        let fields: Vec<u64> = content
+            .trim()
+            .split('\t')
+            .map(|f| f.parse())
+            .collect::<std::result::Result<_, _>>()
+            .with_context(|| format!("Malformed checkpoint {}", path.display()))?;
+        match fields[..] {
+            [records, removed, kept, input_offset, kept_bytes, removed_bytes] => Ok(Self {
+                records: records as usize,
+                removed: removed as usize,
+                kept: kept as usize,
+                input_offset,
+                kept_bytes,
+                removed_bytes,
+            }),
+            _ => anyhow::bail!("Malformed checkpoint {}", path.display()),
+        }
+    }
+
+    /// Atomically write the checkpoint as a single tab-separated line of
+    /// `records`, `removed`, `kept`, `input_offset`, `kept_bytes` and
+    /// `removed_bytes`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("checkpoint.tmp");
+        fs::write(
+            &tmp,
+            format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\n",
+                self.records,
+                self.removed,
+                self.kept,
+                self.input_offset,
+                self.kept_bytes,
+                self.removed_bytes
+            ),
+        )
+        .and_then(|_| fs::rename(&tmp, path))
+        .with_context(|| format!("Failed to write checkpoint {}", path.display()))
+    }
+}
+
+/// Adapts the batch size to `--max-memory` from the running average size of
+/// the records seen so far.
+#[derive(Debug)]
+struct BatchSizer {
+    budget: Option<usize>,
+    limit: usize,
+    bytes: usize,
+    records: usize,
+}
+
+impl BatchSizer {
+    fn new(budget: Option<usize>) -> Self {
+        Self {
+            budget,
+            limit: BATCH_SIZE,
+            bytes: 0,
+            records: 0,
+        }
+    }
+
+    /// Hold at most `limit` records per batch instead of `BATCH_SIZE`.
+    fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = limit.max(1);
+        self
+    }
+
+    /// Account for a record of `bytes` bytes.
+    fn observe(&mut self, bytes: usize) {
+        self.bytes = self.bytes.saturating_add(bytes);
+        self.records += 1;
+    }
+
+    /// Number of records a batch may hold: the limit, capped so that
+    /// records of the average size so far fit the budget. Always at least 1.
+    fn capacity(&self) -> usize {
+        match self.budget {
+            Some(budget) if self.records > 0 => {
+                let average = (self.bytes / self.records).max(1);
+                (budget / average).clamp(1, self.limit)
+            }
+            _ => self.limit,
+        }
+    }
+}
+
+/// Search `seq` for `umi` using the strategy selected by `opts`, through
+/// `index` (a `KmerIndex` of `seq`) if one was built.
+fn umi_matches(umi: &[u8], seq: &[u8], index: Option<&KmerIndex>, opts: &ProcessOptions) -> bool {
+    match (&opts.spacer_motif, &opts.fixed_umi) {
+        (Some(motif), _) => {
+            is_umi_before_motif(umi, seq, motif, opts.spacer_max, opts.max_mismatches)
+        }
+        (None, _) if opts.long_read => match index {
+            Some(index) => index.contains(umi, opts.max_mismatches),
+            None => is_umi_in_long_read(umi, seq, opts.max_mismatches),
+        },
+        (None, Some(compiled)) => compiled.matches(seq),
+        (None, None) => match opts.chunks {
+            Some(chunks) => is_umi_in_read_with_chunks(umi, seq, opts.max_mismatches, chunks),
+            None => is_umi_in_read(umi, seq, opts.max_mismatches),
+        },
+    }
+}
+
+/// Locate the best occurrence of `umi` in `seq` like `umi_matches`. Slower
+/// than `umi_matches` for reads containing the UMI.
+fn locate_umi(
+    umi: &[u8],
+    seq: &[u8],
+    index: Option<&KmerIndex>,
+    opts: &ProcessOptions,
+) -> Option<UmiMatch> {
+    match opts.spacer_motif {
+        Some(ref motif) => {
+            find_umi_before_motif(umi, seq, motif, opts.spacer_max, opts.max_mismatches)
+        }
+        None if opts.long_read => match index {
+            Some(index) => index.find(umi, opts.max_mismatches),
+            None => find_umi_in_long_read(umi, seq, opts.max_mismatches),
+        },
+        None => find_umi_in_read(umi, seq, opts.max_mismatches),
+    }
+}
+
+/// Length of the trailing poly-G run of `seq` if it is at least `min_run`
+/// bases long, otherwise 0.
+fn polyg_tail_len(seq: &[u8], min_run: usize) -> usize {
+    let run = seq
+        .iter()
+        .rev()
+        .take_while(|b| b.eq_ignore_ascii_case(&b'G'))
+        .count();
+    if run >= min_run.max(1) {
+        run
+    } else {
+        0
+    }
+}
+
+/// The part of `seq` that is searched for a UMI of `umi_len` bases, with its
+/// offset in `seq`: what remains after removing a poly-G tail (if
+/// `trim_polyg` is set) and then the `skip_prefix` and `skip_suffix` bases,
+/// narrowed to the bases next to `anchor_motif` if one is set. Empty if the
+/// read is too short or lacks the anchor.
+fn search_region<'a>(seq: &'a [u8], umi_len: usize, opts: &ProcessOptions) -> (usize, &'a [u8]) {
+    let seq = match opts.trim_polyg {
+        Some(min_run) => &seq[..seq.len() - polyg_tail_len(seq, min_run)],
+        None => seq,
+    };
+    let end = seq.len().saturating_sub(opts.skip_suffix);
+    let region = seq.get(opts.skip_prefix..end).unwrap_or_default();
+    let Some(ref motif) = opts.anchor_motif else {
+        return (opts.skip_prefix, region);
+    };
+    let Some(motif_start) = region.windows(motif.len()).position(|w| w == &motif[..]) else {
+        return (opts.skip_prefix, &[]);
+    };
+    let span = umi_len + opts.anchor_gap;
+    let (start, end) = if opts.anchor_before {
+        (motif_start.saturating_sub(span), motif_start)
+    } else {
+        let start = motif_start + motif.len();
+        (start, (start + span).min(region.len()))
+    };
+    (opts.skip_prefix + start, &region[start..end])
+}
+
+/// Determine the UMI to search for in `rec`: the fixed `--umi` if given,
+/// otherwise the one from the UMI tag, the UMI regex or the read header, or
+/// why the read has none. UMIs from the read are uppercased unless
+/// `raw_alphabet` makes case significant.
+fn record_umi<'a, R: BioRecord>(
+    rec: &R,
+    opts: &'a ProcessOptions,
+) -> std::result::Result<Cow<'a, [u8]>, UmiParseError> {
+    if let Some(ref fixed) = opts.fixed_umi {
+        return Ok(Cow::Borrowed(fixed.umi()));
+    }
+    let cased = |umi: Vec<u8>| {
+        if opts.raw_alphabet {
+            umi
+        } else {
+            umi.to_ascii_uppercase()
+        }
+    };
+    if let Some(ref tag) = opts.umi_tag {
+        return match rec.tag(tag) {
+            Some(umi) => Ok(Cow::Owned(cased(umi.to_vec()))),
+            None => Err(UmiParseError::MissingField),
+        };
+    }
+    if let Some(ref re) = opts.umi_regex {
+        return crate::parse_umi_regex_as_is(rec.header(), re).map(|umi| Cow::Owned(cased(umi)));
+    }
+    let lengths = opts.umi_len..=opts.umi_len_max.unwrap_or(opts.umi_len);
+    let parsed = match opts.umi_candidates_delim {
+        Some(delim) => {
+            crate::parse_umi_field_candidates_as_is(rec.header(), lengths, opts.umi_field, delim)
+        }
+        None if opts.umi_whole_header => crate::parse_umi_whole_header_as_is(rec.header(), lengths),
+        None => crate::parse_umi_field_in_range_as_is(rec.header(), lengths, opts.umi_field),
+    };
+    parsed.map(|umi| Cow::Owned(cased(umi)))
+}
+
+/// Result of searching a single read for its UMI.
+#[derive(Debug, Default)]
+struct Outcome {
+    /// Whether the UMI was found within the allowed mismatches.
+    matched: bool,
+    /// Best match details; only computed when an option needs them.
+    best: Option<UmiMatch>,
+    /// Read coordinates of the best match, when it was located.
+    window: Option<Range<usize>>,
+    /// The extracted UMI, kept only when an option needs it after matching.
+    umi: Option<Vec<u8>>,
+    /// The candidate UMI that was found, for the per-read report.
+    candidate: Option<Vec<u8>>,
+    /// Value of the `count_by_tag` tag, if requested and present.
+    group: Option<Vec<u8>>,
+    /// Number of occurrences of the UMI in the read, for `scan_only`.
+    occurrences: Option<usize>,
+    /// The UMI (or matched candidate) and the read window it matched, in the
+    /// orientation they were compared, for `error_table`.
+    compared: Option<(Vec<u8>, Vec<u8>)>,
+    /// Whether the read contains `adapter`, if one was given.
+    adapter: bool,
+    /// Whether the read is an adapter dimer (see `is_adapter_dimer`).
+    adapter_dimer: bool,
+    /// Whether the UMI is low-complexity.
+    low_complexity: bool,
+    /// Whether a UMI match was rejected for `min_match_complexity`.
+    low_complexity_match: bool,
+    /// Whether the read had more windows than `max_window_search`.
+    window_capped: bool,
+    /// Whether the read is shorter than its UMI.
+    umi_longer_than_read: bool,
+    /// Whether no UMI could be determined for the read, e.g. from a header
+    /// without a parseable UMI.
+    no_umi: bool,
+    /// Whether the header UMI had a length outside the expected range.
+    wrong_umi_length: bool,
+    /// Whether the UMI was found in the reverse complement of the read.
+    reverse: bool,
+    /// Time spent on the read, if it exceeded `warn_slow`.
+    slow: Option<Duration>,
+}
+
+/// Search `seq` for each `delim`-separated candidate of `umi` in turn and
+/// return the outcome for the first candidate found (or for the last one if
+/// none is) together with that candidate.
+fn evaluate_candidates<'a>(
+    umi: &'a [u8],
+    delim: u8,
+    seq: &[u8],
+    opts: &ProcessOptions,
+) -> (Outcome, &'a [u8]) {
+    // One index of the read serves every candidate
+    let index = opts.long_read.then(|| {
+        let shortest = umi.split(|&b| b == delim).map(<[u8]>::len).min();
+        KmerIndex::new(seq, seed_len(shortest.unwrap_or(0), opts.max_mismatches))
+    });
+    let mut last = None;
+    for candidate in umi.split(|&b| b == delim) {
+        let outcome = evaluate(candidate, seq, index.as_ref(), opts);
+        if outcome.matched {
+            return (outcome, candidate);
+        }
+        last = Some((outcome, candidate));
+    }
+    last.expect("split yields at least one candidate")
+}
+
+/// Whether the two `delim`-separated parts of the dual `umi` flank `seq`:
+/// the first at its start and the second at its end.
+fn dual_umis_flank(umi: &[u8], delim: u8, seq: &[u8], opts: &ProcessOptions) -> bool {
+    let mut parts = umi.split(|&b| b == delim);
+    let (Some(first), Some(second), None) = (parts.next(), parts.next(), parts.next()) else {
+        return false;
+    };
+    if first.len() > seq.len() || second.len() > seq.len() {
+        return false;
+    }
+    let distance = |part: &[u8], window: &[u8]| {
+        if opts.tolerate_n_in_umi {
+            wildcard_hamming_distance(part, window)
+        } else {
+            hamming_distance(part, window)
+        }
+    };
+    distance(first, &seq[..first.len()]) <= opts.max_mismatches
+        && distance(second, &seq[seq.len() - second.len()..]) <= opts.max_mismatches
+}
+
+/// Search `seq` for `umi`, or for each of its candidates or dual UMIs as
+/// selected by `opts`, and return the outcome with the UMI that was found.
+fn search_umi<'a>(umi: &'a [u8], seq: &[u8], opts: &ProcessOptions) -> (Outcome, &'a [u8]) {
+    match opts.umi_candidates_delim {
+        Some(delim) if opts.require_both_umis_same_position => {
+            let matched = dual_umis_flank(umi, delim, seq, opts);
+            (
+                Outcome {
+                    matched,
+                    ..Default::default()
+                },
+                umi,
+            )
+        }
+        Some(delim) => evaluate_candidates(umi, delim, seq, opts),
+        None => (evaluate(umi, seq, None, opts), umi),
+    }
+}
+
+/// The `dump_windows` table of read `id`: the Hamming distance of each
+/// `delim`-separated candidate of `umi` to every window of `seq`, the search
+/// region starting at read offset `offset`.
+fn window_dump(id: &[u8], umi: &[u8], seq: &[u8], offset: usize, opts: &ProcessOptions) -> String {
+    let mut dump = format!("Windows of read '{}':\n", String::from_utf8_lossy(id));
+    let candidates: Vec<&[u8]> = match opts.umi_candidates_delim {
+        Some(delim) => umi.split(|&b| b == delim).collect(),
+        None => vec![umi],
+    };
+    for candidate in candidates {
+        dump.push_str(&format!(
+            "UMI {}\noffset\twindow\tdistance\n",
+            String::from_utf8_lossy(candidate)
+        ));
+        let mut min = None;
+        for (i, window) in seq.windows(candidate.len().max(1)).enumerate() {
+            let distance = if opts.tolerate_n_in_umi {
+                wildcard_hamming_distance(candidate, window)
+            } else if opts.raw_alphabet {
+                raw_hamming_distance(candidate, window)
+            } else if !opts.ignore_positions.is_empty() {
+                masked_hamming_distance(candidate, window, &opts.ignore_positions)
+            } else {
+                hamming_distance(candidate, window)
+            };
+            min = Some(min.map_or(distance, |m: u32| m.min(distance)));
+            dump.push_str(&format!(
+                "{}\t{}\t{}\n",
+                offset + i,
+                String::from_utf8_lossy(window),
+                distance
+            ));
+        }
+        dump.push_str(&match min {
+            Some(min) => format!("minimum distance {}\n", min),
+            None => "read shorter than the UMI\n".to_string(),
+        });
+    }
+    dump
+}
+
+/// Search the first `max_window_search` windows of `seq` for `umi`, through
+/// `index` (a `KmerIndex` of all of `seq`) if one was built.
+fn evaluate(umi: &[u8], seq: &[u8], index: Option<&KmerIndex>, opts: &ProcessOptions) -> Outcome {
+    let window_len = opts.umi_template.as_ref().map_or(umi.len(), Vec::len);
+    let windows = (seq.len() + 1).saturating_sub(window_len);
+    match opts.max_window_search {
+        // The index covers windows past the cap, so it cannot be used
+        Some(max) if windows > max => Outcome {
+            window_capped: true,
+            ..evaluate_windows(umi, &seq[..max + window_len - 1], None, opts)
+        },
+        _ => evaluate_windows(umi, seq, index, opts),
+    }
+}
+
+/// Search `seq` for `umi`, locating the best match only when needed.
+fn evaluate_windows(
+    umi: &[u8],
+    seq: &[u8],
+    index: Option<&KmerIndex>,
+    opts: &ProcessOptions,
+) -> Outcome {
+    if opts.scan_only.is_some() {
+        let occurrences = find_all_umi_in_read(umi, seq, opts.max_mismatches).len();
+        Outcome {
+            matched: occurrences > 0,
+            occurrences: Some(occurrences),
+            ..Default::default()
+        }
+    } else if let Some(ref template) = opts.umi_template {
+        let best = fill_umi_template(template, umi).and_then(|filled| {
+            find_templated_umi_in_read(&filled, template, seq, opts.max_mismatches)
+        });
+        Outcome {
+            matched: best.is_some(),
+            best,
+            ..Default::default()
+        }
+    } else if opts.tolerate_n_in_umi && umi.contains(&b'N') {
+        let best = find_umi_in_read_with_wildcards(umi, seq, opts.max_mismatches);
+        Outcome {
+            matched: best.is_some(),
+            best,
+            ..Default::default()
+        }
+    } else if opts.raw_alphabet {
+        let best = find_umi_in_read_raw(umi, seq, opts.max_mismatches);
+        Outcome {
+            matched: best.is_some(),
+            best,
+            ..Default::default()
+        }
+    } else if !opts.ignore_positions.is_empty() {
+        let best = find_umi_in_read_masked(umi, seq, opts.max_mismatches, &opts.ignore_positions);
+        Outcome {
+            matched: best.is_some(),
+            best,
+            ..Default::default()
+        }
+    } else if opts.sort_by_mismatches
+        || opts.tag_output_by_mismatch.is_some()
+        || opts.annotate_mismatches
+        || opts.window_quality
+        || opts.match_bed.is_some()
+        || opts.qc_table.is_some()
+        || opts.error_table.is_some()
+        || opts.position_histogram.is_some()
+        || opts.min_match_complexity.is_some()
+        || (opts.check_revcomp && opts.per_read_report.is_some())
+    {
+        let best = locate_umi(umi, seq, index, opts);
+        Outcome {
+            matched: best.is_some(),
+            best,
+            ..Default::default()
+        }
+    } else {
+        Outcome {
+            matched: umi_matches(umi, seq, index, opts),
+            ..Default::default()
+        }
+    }
+}
+
+/// Per-input mutable state shared by all batches: the output writers plus
+/// any records held back until the whole input has been read.
+struct RunState<R> {
+    kept_w: GenericWriter,
+    rem_w: GenericWriter,
+    /// Writers of `--tag-output-by-mismatch`, indexed by mismatch count.
+    mismatch_w: Vec<GenericWriter>,
+    /// Removed records buffered with their mismatch count for
+    /// `--sort-by-mismatches`.
+    held_removed: Vec<(u32, R)>,
+    /// Kept records sampled for `downsample`, with their index among kept reads.
+    reservoir: Vec<(usize, R)>,
+    /// Number of kept records offered to the reservoir.
+    kept_seen: usize,
+    /// State of the `downsample` random number generator.
+    rng: u64,
+    /// Tally of extracted UMIs among reads where the UMI was not found.
+    unmatched_umis: HashMap<Vec<u8>, usize>,
+    /// `(with_umi, without_umi)` counts per `--count-by-tag` group.
+    group_counts: HashMap<Vec<u8>, (usize, usize)>,
+    /// Destination of `--per-read-report` verdicts.
+    report: Option<Box<dyn Write>>,
+    /// Whether the report has a column for the matched candidate UMI.
+    report_candidates: bool,
+    /// Whether the report has orientation and position columns.
+    report_strand: bool,
+    /// Destination of the `--scan-only` occurrence table.
+    scan: Option<Box<dyn Write>>,
+    /// Destination of the `--match-bed` intervals.
+    match_bed: Option<Box<dyn Write>>,
+    /// Destination of the `--qc-table` rows.
+    qc_table: Option<Box<dyn Write>>,
+    /// Destination of the `--error-table` rows.
+    error_table: Option<Box<dyn Write>>,
+    /// Destination of `--quarantine` reads.
+    quarantine: Option<GenericWriter>,
+    /// UMI groups of `--collapse-output`.
+    consensus: Option<ConsensusGroups>,
+    /// Reads per UMI match start offset, for `--position-histogram`.
+    match_offsets: BTreeMap<usize, usize>,
+    /// Reference names of the BAM header, for `--match-bed`.
+    target_names: Vec<Vec<u8>>,
+    /// Verdicts of an earlier run loaded for `--compare-baseline`.
+    baseline: Option<HashMap<Vec<u8>, bool>>,
+    flips: VerdictFlips,
+    /// Mean per-read GC fraction of reads with (`.0`) and without (`.1`) their UMI.
+    gc: (Mean, Mean),
+    /// Number of reads that are adapter dimers.
+    adapter_dimers: usize,
+    /// Number of reads seen, for the adapter percentages.
+    reads: usize,
+    /// Number of UMI matches rejected for `min_match_complexity`.
+    low_complexity_matches: usize,
+    /// Reads with more windows than `max_window_search`.
+    window_capped: usize,
+    /// Read IDs seen so far, for `check_duplicate_ids`.
+    seen_ids: HashSet<Vec<u8>>,
+    /// Mean base quality of matched UMI windows, for `window_quality`.
+    window_quality: Mean,
+    /// UMIs seen so far, for `estimate_dup_rate`.
+    umi_filter: Option<BloomFilter>,
+    /// `(extracted, seen before)` UMI counts for `estimate_dup_rate`.
+    umi_repeats: (usize, usize),
+    /// Most frequent extracted UMIs, for `top_umis`.
+    top_umis: Option<TopK>,
+    /// Whether the UMI was found in mates whose other mate has not been seen
+    /// yet, by read name.
+    pending_mates: HashMap<Vec<u8>, bool>,
+    #[cfg(feature = "profiling")]
+    timing: BatchTiming,
+}
+
+/// Time spent in the parallel match step and the serial write step of
+/// `process_batch`, summed over batches, with the `profiling` feature.
+#[cfg(feature = "profiling")]
+#[derive(Debug, Clone, Copy, Default)]
+struct BatchTiming {
+    batches: usize,
+    matching: Duration,
+    writing: Duration,
+}
+
+/// Running mean of per-read values. Means of several batches are merged
+/// with `+=`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Mean {
+    sum: f64,
+    count: usize,
+}
+
+impl Mean {
+    pub fn add(&mut self, value: f64) {
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// The mean of all added values, or `None` if there were none.
+    pub fn get(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+}
+
+impl std::ops::AddAssign for Mean {
+    fn add_assign(&mut self, other: Self) {
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+/// Next value of the SplitMix64 generator with `state`: small, fast and
+/// reproducible across platforms, which is all `downsample` needs.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Fraction of G and C bases in `seq`, or `None` for an empty read.
+fn gc_fraction(seq: &[u8]) -> Option<f64> {
+    let gc = seq
+        .iter()
+        .filter(|b| matches!(b.to_ascii_uppercase(), b'G' | b'C'))
+        .count();
+    (!seq.is_empty()).then(|| gc as f64 / seq.len() as f64)
+}
+
+/// Verdict changes relative to a `--compare-baseline` report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VerdictFlips {
+    /// Reads whose UMI was found in the baseline but not in this run.
+    pub found_to_not_found: usize,
+    /// Reads whose UMI was not found in the baseline but is in this run.
+    pub not_found_to_found: usize,
+    /// Reads of this run that do not appear in the baseline at all.
+    pub missing_from_baseline: usize,
+}
+
+impl<R: BioRecord> RunState<R> {
+    fn new(kept_w: GenericWriter, rem_w: GenericWriter) -> Self {
+        Self {
+            kept_w,
+            rem_w,
+            mismatch_w: Vec::new(),
+            held_removed: Vec::new(),
+            reservoir: Vec::new(),
+            kept_seen: 0,
+            rng: 0,
+            unmatched_umis: HashMap::new(),
+            group_counts: HashMap::new(),
+            report: None,
+            report_candidates: false,
+            report_strand: false,
+            scan: None,
+            match_bed: None,
+            qc_table: None,
+            error_table: None,
+            quarantine: None,
+            consensus: None,
+            match_offsets: BTreeMap::new(),
+            target_names: Vec::new(),
+            baseline: None,
+            flips: VerdictFlips::default(),
+            gc: (Mean::default(), Mean::default()),
+            adapter_dimers: 0,
+            reads: 0,
+            low_complexity_matches: 0,
+            window_capped: 0,
+            seen_ids: HashSet::new(),
+            window_quality: Mean::default(),
+            umi_filter: None,
+            umi_repeats: (0, 0),
+            top_umis: None,
+            pending_mates: HashMap::new(),
+            #[cfg(feature = "profiling")]
+            timing: BatchTiming::default(),
+        }
+    }
+
+    /// Open the `--per-read-report` and `--scan-only` outputs and load the
+    /// `--compare-baseline` verdicts, if requested, and seed the sampler.
+    fn with_reports(mut self, opts: &ProcessOptions) -> Result<Self> {
+        self.rng = opts.seed;
+        if let Some(ref path) = opts.per_read_report {
+            let mut w = create_report_writer(path)?;
+            w.write_all(b"read_id\tfound")?;
+            if opts.umi_candidates_delim.is_some() {
+                w.write_all(b"\tcandidate")?;
+                self.report_candidates = true;
+            }
+            if opts.check_revcomp {
+                w.write_all(b"\torientation\tposition")?;
+                self.report_strand = true;
+            }
+            w.write_all(b"\n")?;
+            self.report = Some(w);
+        }
+        if let Some(ref path) = opts.scan_only {
+            let mut w = create_report_writer(path)?;
+            w.write_all(b"read_id\tumi\toccurrences_in_read\n")?;
+            self.scan = Some(w);
+        }
+        if let Some(ref path) = opts.compare_baseline {
+            self.baseline = Some(read_verdicts(path)?);
+        }
+        if let Some(ref path) = opts.match_bed {
+            self.match_bed = Some(create_report_writer(path)?);
+        }
+        if opts.collapse_output.is_some() {
+            self.consensus = Some(ConsensusGroups::default());
+        }
+        if let Some(ref path) = opts.quarantine {
+            self.quarantine = Some(GenericWriter::Fastq(create_fastq_writer(path)?));
+        }
+        if let Some(ref prefix) = opts.tag_output_by_mismatch {
+            self.mismatch_w = (0..=opts.max_mismatches)
+                .map(|mismatches| {
+                    let path = mismatch_output_path(prefix, mismatches);
+                    Ok(if opts.no_empty_output {
+                        GenericWriter::Deferred(DeferredOutput::Fastq(path))
+                    } else {
+                        GenericWriter::Fastq(create_fastq_writer(&path)?)
+                    })
+                })
+                .collect::<Result<_>>()?;
+        }
+        if let Some(fp_rate) = opts.estimate_dup_rate {
+            self.umi_filter = Some(BloomFilter::new(DUP_RATE_CAPACITY, fp_rate));
+        }
+        if let Some(k) = opts.top_umis {
+            self.top_umis = Some(TopK::new(k * TOP_UMI_COUNTERS_PER_ENTRY));
+        }
+        if let Some(ref path) = opts.qc_table {
+            let mut w = create_report_writer(path)?;
+            w.write_all(b"read_id\tumi\tfound\tmismatches\tread_length\n")?;
+            self.qc_table = Some(w);
+        }
+        if let Some(ref path) = opts.error_table {
+            let mut w = create_report_writer(path)?;
+            w.write_all(b"read_id\tumi\twindow\tmismatches\tsubstitutions\n")?;
+            self.error_table = Some(w);
+        }
+        Ok(self)
+    }
+
+    /// Report the verdict for the read with `header` and compare it against
+    /// the baseline. `strand` is the orientation the UMI was found in and its
+    /// start offset in the read as read in that orientation.
+    fn record_verdict(
+        &mut self,
+        header: &[u8],
+        found: bool,
+        candidate: Option<&[u8]>,
+        strand: Option<(char, usize)>,
+    ) -> Result<()> {
+        if self.report.is_none() && self.baseline.is_none() {
+            return Ok(());
+        }
+        let read_id = read_id(header);
+        if let Some(ref mut w) = self.report {
+            w.write_all(read_id)?;
+            write!(w, "\t{}", found)?;
+            if self.report_candidates {
+                w.write_all(b"\t")?;
+                w.write_all(candidate.unwrap_or(b"NA"))?;
+            }
+            if self.report_strand {
+                match strand {
+                    Some((orientation, position)) => write!(w, "\t{}\t{}", orientation, position)?,
+                    None => w.write_all(b"\tNA\tNA")?,
+                }
+            }
+            w.write_all(b"\n")?;
+        }
+        if let Some(ref baseline) = self.baseline {
+            match baseline.get(read_id) {
+                Some(true) if !found => self.flips.found_to_not_found += 1,
+                Some(false) if found => self.flips.not_found_to_found += 1,
+                Some(_) => {}
+                None => self.flips.missing_from_baseline += 1,
+            }
+        }
+        Ok(())
+    }
+
+    /// Offer a kept record to the `downsample` reservoir of size `n`: every
+    /// kept record ends up in it with the same probability (Algorithm R).
+    fn sample_kept(&mut self, n: usize, rec: R) {
+        let index = self.kept_seen;
+        self.kept_seen += 1;
+        if self.reservoir.len() < n {
+            self.reservoir.push((index, rec));
+        } else {
+            let j = (splitmix64(&mut self.rng) % (index as u64 + 1)) as usize;
+            if j < n {
+                self.reservoir[j] = (index, rec);
+            }
+        }
+    }
+
+    /// The `top_umis` most frequent extracted UMIs so far, if tracked.
+    fn top_umi_list(&self, opts: &ProcessOptions) -> Option<TopItems> {
+        Some(self.top_umis.as_ref()?.snapshot(opts.top_umis?))
+    }
+
+    /// Write out any records held back during processing, best matches first,
+    /// and any requested end-of-run tables.
+    fn finish(&mut self, opts: &ProcessOptions) -> Result<()> {
+        let mut held = std::mem::take(&mut self.held_removed);
+        // Stable sort: reads with equal mismatch counts keep their input order
+        held.sort_by_key(|(mismatches, _)| *mismatches);
+        for (_, rec) in held {
+            rec.write_to(&mut self.rem_w)?;
+        }
+        // The sample is written in input order
+        let mut sample = std::mem::take(&mut self.reservoir);
+        sample.sort_by_key(|(index, _)| *index);
+        for (_, rec) in sample {
+            rec.write_to(&mut self.kept_w)?;
+        }
+        // Surface write errors of the outputs instead of losing them on drop
+        self.kept_w.flush()?;
+        self.rem_w.flush()?;
+        for w in &mut self.mismatch_w {
+            w.flush()?;
+        }
+        if let Some(ref path) = opts.unmatched_umi_table {
+            write_count_table(path, "umi", &self.unmatched_umis)?;
+        }
+        if let Some(ref path) = opts.position_histogram {
+            write_position_histogram(path, &self.match_offsets)?;
+        }
+        if let (Some(tag), Some(path)) = (opts.count_by_tag, &opts.tag_counts) {
+            write_group_table(path, &String::from_utf8_lossy(&tag), &self.group_counts)?;
+        }
+        if let Some(ref mut w) = self.report {
+            w.flush().context("Failed to write per-read report")?;
+        }
+        if let Some(ref mut w) = self.scan {
+            w.flush().context("Failed to write scan table")?;
+        }
+        if let Some(ref mut w) = self.match_bed {
+            w.flush().context("Failed to write match BED file")?;
+        }
+        if let Some(ref mut w) = self.qc_table {
+            w.flush().context("Failed to write QC table")?;
+        }
+        if let Some(ref mut w) = self.error_table {
+            w.flush().context("Failed to write error table")?;
+        }
+        if let (Some(w), Some(path)) = (&mut self.quarantine, &opts.quarantine) {
+            w.flush()
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+        if let (Some(groups), Some(path)) = (&self.consensus, &opts.collapse_output) {
+            let mut w = GenericWriter::Fastq(create_fastq_writer(path)?);
+            for (umi, consensus) in groups.iter() {
+                let (seq, qual) = consensus.call();
+                let mut head = read_id(consensus.first_header()).to_vec();
+                head.extend_from_slice(b" umi:");
+                head.extend_from_slice(umi);
+                head.extend_from_slice(format!(" reads:{}", consensus.reads()).as_bytes());
+                w.write_fastq(&head, &seq, qual.as_deref())?;
+            }
+            w.flush()
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+        #[cfg(feature = "profiling")]
+        {
+            let BatchTiming {
+                batches,
+                matching,
+                writing,
+            } = self.timing;
+            let total = (matching + writing).as_secs_f64().max(f64::MIN_POSITIVE);
+            eprintln!(
+                "Batch timing: {} batches, matching {:.3}s ({:.1}%), writing {:.3}s ({:.1}%)",
+                batches,
+                matching.as_secs_f64(),
+                100.0 * matching.as_secs_f64() / total,
+                writing.as_secs_f64(),
+                100.0 * writing.as_secs_f64() / total
+            );
+        }
+        if !self.pending_mates.is_empty() {
+            eprintln!(
+                "Warning: {} paired reads had no mate in the input and were not counted as pairs",
+                self.pending_mates.len()
+            );
+        }
+        if let (Some(filter), Some(fp_rate)) = (&self.umi_filter, opts.estimate_dup_rate) {
+            let (extracted, repeated) = self.umi_repeats;
+            eprintln!(
+                "Estimated UMI duplication rate: {:.2}% of {} UMIs (Bloom filter, false-positive rate {})",
+                crate::percentage(repeated, extracted),
+                extracted,
+                fp_rate
+            );
+            if filter.is_over_capacity() {
+                eprintln!(
+                    "Warning: more than {} distinct UMIs; the duplication rate is overestimated",
+                    DUP_RATE_CAPACITY
+                );
+            }
+        }
+        if let Some(top) = self.top_umi_list(opts).filter(|_| opts.report_top_umis) {
+            eprintln!(
+                "Most common of {} extracted UMIs (counts may be up to {} low):",
+                top.seen, top.max_undercount
+            );
+            for (umi, count) in top.items {
+                eprintln!("  {}\t{}", String::from_utf8_lossy(&umi), count);
+            }
+        }
+        if opts.window_quality {
+            eprintln!(
+                "Mean quality of matched UMI windows: {}",
+                self.window_quality
+                    .get()
+                    .map_or("NA".to_string(), |q| format!("{:.2}", q))
+            );
+        }
+        if let Some(min) = opts.min_match_complexity {
+            eprintln!(
+                "UMI matches rejected in low-complexity regions (dinucleotide entropy < {} bit): {}",
+                min, self.low_complexity_matches
+            );
+        }
+        if let (Some(max), true) = (opts.max_window_search, self.window_capped > 0) {
+            eprintln!(
+                "Warning: the UMI search stopped after {} windows in {} reads; matches further into them were not counted",
+                max, self.window_capped
+            );
+        }
+        if opts.adapter.is_some() {
+            eprintln!(
+                "Adapter-dimer reads (adapter within the first {:.0}% of the read): {} ({:.2}%)",
+                ADAPTER_DIMER_MAX_INSERT_FRACTION * 100.0,
+                self.adapter_dimers,
+                crate::percentage(self.adapter_dimers, self.reads)
+            );
+        }
+        if opts.report_gc {
+            let fmt = |m: Mean| {
+                m.get()
+                    .map_or("NA".to_string(), |gc| format!("{:.2}%", gc * 100.0))
+            };
+            eprintln!(
+                "Mean GC content: {} in reads with UMI, {} in reads without UMI",
+                fmt(self.gc.0),
+                fmt(self.gc.1)
+            );
+        }
+        if self.baseline.is_some() {
+            let flips = self.flips;
+            eprintln!(
+                "Verdict flips vs baseline: {} found->not-found, {} not-found->found ({} reads not in baseline)",
+                flips.found_to_not_found, flips.not_found_to_found, flips.missing_from_baseline
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<R> RunState<R> {
+    /// Write a read to the `--quarantine` output, if any, with `reason`
+    /// appended to its header.
+    fn quarantine_read(
+        &mut self,
+        head: &[u8],
+        seq: &[u8],
+        qual: Option<&[u8]>,
+        reason: &str,
+    ) -> Result<()> {
+        let Some(ref mut w) = self.quarantine else {
+            return Ok(());
+        };
+        let mut head = head.to_vec();
+        head.extend_from_slice(b" quarantine:");
+        head.extend_from_slice(reason.as_bytes());
+        w.write_fastq(&head, seq, qual)
+    }
+
+    /// Quarantine the FASTQ records `rejected` as `malformed_record`.
+    fn quarantine_malformed(&mut self, rejected: Vec<FastqRecord>) -> Result<()> {
+        for rec in rejected {
+            self.quarantine_read(&rec.head, &rec.seq, rec.qual.as_deref(), "malformed_record")?;
+        }
+        Ok(())
+    }
+}
+
+/// Warn about read categories in `stats`, the counts of a whole input, that
+/// point to a wrong setting.
+fn warn_read_categories(stats: &ProcessStats, opts: &ProcessOptions) {
+    if stats.shorter_than_umi as f64 > UMI_LONGER_THAN_READ_WARN_FRACTION * stats.total as f64 {
+        eprintln!(
+            "Warning: the UMI is longer than the read in {} of {} reads ({:.2}%), which can never contain it; check --umi-length",
+            stats.shorter_than_umi,
+            stats.total,
+            crate::percentage(stats.shorter_than_umi, stats.total)
+        );
+    }
+    if stats.malformed > 0 {
+        eprintln!(
+            "Warning: skipped {} malformed FASTQ records whose quality and sequence lengths differ",
+            stats.malformed
+        );
+    }
+    if stats.low_complexity_umi > 0 {
+        eprintln!(
+            "Warning: {} reads have a low-complexity UMI (entropy < {} bit){}",
+            stats.low_complexity_umi,
+            LOW_COMPLEXITY_ENTROPY,
+            if opts.skip_low_complexity {
+                "; they were not searched"
+            } else {
+                "; consider --skip-low-complexity"
+            }
+        );
+    }
+}
+
+/// Why a read with sequence length `seq_len` and search `outcome` belongs in
+/// the `--quarantine` output, if it does: an empty sequence, a header UMI of
+/// the wrong length, no UMI (e.g. an unparseable header) or a read shorter
+/// than its UMI, checked in that order. Malformed FASTQ records never reach
+/// the search and are quarantined as `malformed_record` when skipped.
+fn quarantine_reason(seq_len: usize, outcome: &Outcome) -> Option<&'static str> {
+    if seq_len == 0 {
+        Some("empty_sequence")
+    } else if outcome.wrong_umi_length {
+        Some("wrong_umi_length")
+    } else if outcome.no_umi {
+        Some("no_umi")
+    } else if outcome.umi_longer_than_read {
+        Some("shorter_than_umi")
+    } else {
+        None
+    }
+}
+
+/// Read ID used in per-read reports: the first whitespace-delimited token of
+/// the header.
+fn read_id(header: &[u8]) -> &[u8] {
+    header
+        .split(|b| b.is_ascii_whitespace())
+        .next()
+        .unwrap_or(header)
+}
+
+/// File of `--tag-output-by-mismatch` reads whose best match has
+/// `mismatches` mismatches: `<prefix>.mm<mismatches>.fq`.
+pub fn mismatch_output_path(prefix: &Path, mismatches: u32) -> PathBuf {
+    PathBuf::from(format!("{}.mm{}.fq", prefix.display(), mismatches))
+}
+
+/// Extract the UMI of `rec` and search its sequence for it.
+fn analyze_record<R: BioRecord>(
+    rec: &R,
+    opts: &ProcessOptions,
+) -> std::result::Result<Outcome, UmiParseError> {
+    rec.with_seq(|read| analyze_read(rec, read, opts))
+}
+
+/// Positions where `window` differs from `umi` as `(offset, umi base, read
+/// base)`, counted the way the search counts mismatches: left out for
+/// `ignore_positions` and wildcard 'N's, and 'N' as a mismatch except with
+/// `raw_alphabet`.
+fn substitutions(umi: &[u8], window: &[u8], opts: &ProcessOptions) -> Vec<(usize, u8, u8)> {
+    umi.iter()
+        .zip(window)
+        .enumerate()
+        .filter(|&(i, (u, w))| {
+            let (u, w) = (std::slice::from_ref(u), std::slice::from_ref(w));
+            if opts.ignore_positions.contains(&i) {
+                false
+            } else if opts.tolerate_n_in_umi {
+                wildcard_hamming_distance(u, w) > 0
+            } else if opts.raw_alphabet {
+                u != w
+            } else {
+                hamming_distance(u, w) > 0
+            }
+        })
+        .map(|(i, (&u, &w))| (i, u, w))
+        .collect()
+}
+
+/// `substitutions` as the `--error-table` column: `pos:from>to` entries
+/// separated by commas, or `NA` if there are none.
+fn substitution_list(subs: &[(usize, u8, u8)]) -> String {
+    if subs.is_empty() {
+        return "NA".to_string();
+    }
+    subs.iter()
+        .map(|&(i, from, to)| format!("{}:{}>{}", i, char::from(from), char::from(to)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Whether `read` is an adapter dimer: `adapter` occurs within the first
+/// `ADAPTER_DIMER_MAX_INSERT_FRACTION` of it, so the adapter and whatever
+/// was read through after it make up most of the read.
+fn is_adapter_dimer(read: &[u8], adapter: &CompiledUmi) -> bool {
+    let max_insert = (read.len() as f64 * ADAPTER_DIMER_MAX_INSERT_FRACTION) as usize;
+    // An occurrence starting within `max_insert` ends within this prefix
+    let prefix = (max_insert + adapter.umi().len()).min(read.len());
+    adapter.matches(&read[..prefix])
+}
+
+/// Extract the UMI of `rec` and search `read`, its sequence, for it.
+fn analyze_read<R: BioRecord>(
+    rec: &R,
+    read: &[u8],
+    opts: &ProcessOptions,
+) -> std::result::Result<Outcome, UmiParseError> {
+    let group = opts
+        .count_by_tag
+        .and_then(|tag| rec.tag(&tag).map(<[u8]>::to_vec));
+    let adapter = opts.adapter.as_ref().is_some_and(|a| a.matches(read));
+    let adapter_dimer = adapter
+        && opts
+            .adapter
+            .as_ref()
+            .is_some_and(|a| is_adapter_dimer(read, a));
+    // Outside strict mode a read without a usable UMI is counted as without it
+    let umi = match record_umi(rec, opts) {
+        Ok(umi) => umi,
+        Err(e) if opts.strict => return Err(e),
+        Err(e) => {
+            return Ok(Outcome {
+                group,
+                adapter,
+                adapter_dimer,
+                no_umi: true,
+                wrong_umi_length: matches!(
+                    e,
+                    UmiParseError::LengthMismatch { .. } | UmiParseError::LengthOutOfRange { .. }
+                ),
+                ..Default::default()
+            })
+        }
+    };
+    // The shortest candidate, or the whole template the UMI is filled into
+    let umi_len = match (opts.umi_candidates_delim, &opts.umi_template) {
+        (Some(delim), _) => umi.split(|&b| b == delim).map(<[u8]>::len).min(),
+        (None, Some(template)) => Some(template.len()),
+        (None, None) => Some(umi.len()),
+    };
+    // The window next to an anchor must fit the longest candidate
+    let anchored_len = match opts.umi_candidates_delim {
+        Some(delim) => umi.split(|&b| b == delim).map(<[u8]>::len).max(),
+        None => umi_len,
+    };
+    let umi_longer_than_read = umi_len.is_some_and(|len| len > read.len());
+    let low_complexity = shannon_entropy(&umi) < LOW_COMPLEXITY_ENTROPY;
+    if low_complexity && opts.skip_low_complexity {
+        return Ok(Outcome {
+            group,
+            adapter,
+            adapter_dimer,
+            low_complexity,
+            ..Default::default()
+        });
+    }
+    let (region_start, seq) = search_region(read, anchored_len.unwrap_or(0), opts);
+    if opts.dump_windows.as_deref() == Some(read_id(rec.header())) {
+        eprint!(
+            "{}",
+            window_dump(read_id(rec.header()), &umi, seq, region_start, opts)
+        );
+    }
+    let (mut outcome, mut found_umi) = search_umi(&umi, seq, opts);
+    if opts.check_revcomp && !outcome.matched {
+        let (reverse, reverse_umi) = search_umi(&umi, &reverse_complement(seq), opts);
+        if reverse.matched {
+            (outcome, found_umi) = (reverse, reverse_umi);
+            outcome.reverse = true;
+        }
+    }
+    if let Some(ref best) = outcome.best {
+        let len = opts.umi_template.as_ref().map_or(found_umi.len(), Vec::len);
+        // Windows are kept in forward read coordinates whatever the strand
+        let offset = if outcome.reverse {
+            seq.len() - best.position - len
+        } else {
+            best.position
+        };
+        let start = region_start + offset;
+        outcome.window = Some(start..start + len);
+    }
+    if let (Some(min), Some(window)) = (opts.min_match_complexity, &outcome.window) {
+        let region = &read[window.start.saturating_sub(MATCH_COMPLEXITY_FLANK)
+            ..(window.end + MATCH_COMPLEXITY_FLANK).min(read.len())];
+        if dinucleotide_entropy(region) < min {
+            outcome.matched = false;
+            outcome.best = None;
+            outcome.window = None;
+            outcome.low_complexity_match = true;
+        }
+    }
+    if opts.umi_candidates_delim.is_some() && outcome.matched {
+        outcome.candidate = Some(found_umi.to_vec());
+    }
+    if let (true, Some(window)) = (opts.error_table.is_some(), &outcome.window) {
+        let window = &read[window.clone()];
+        let observed = if outcome.reverse {
+            reverse_complement(window)
+        } else {
+            window.to_vec()
+        };
+        outcome.compared = Some((found_umi.to_vec(), observed));
+    }
+    if opts.scan_only.is_some()
+        || opts.qc_table.is_some()
+        || opts.estimate_dup_rate.is_some()
+        || opts.top_umis.is_some()
+        || opts.collapse_output.is_some()
+        || (opts.unmatched_umi_table.is_some() && !outcome.matched)
+    {
+        outcome.umi = Some(umi.into_owned());
+    }
+    outcome.group = group;
+    outcome.adapter = adapter;
+    outcome.adapter_dimer = adapter_dimer;
+    outcome.low_complexity = low_complexity;
+    outcome.umi_longer_than_read = umi_longer_than_read;
+    Ok(outcome)
+}
+
+/// Process a batch of records: perform parallel matching then serial writes.
+///
+/// The function runs the expensive UMI matching in parallel (with Rayon) and
+/// then performs outputs serially to avoid interleaved writes. Returns the
+/// `ProcessStats` of the batch, describing how many reads were routed to each
+/// output writer.
+///
+/// `first_record` is the zero-based index of the first record of the batch in
+/// the input and is only used to report the offending record in strict mode.
+fn process_batch<R: BioRecord>(
+    batch: Vec<R>,
+    state: &mut RunState<R>,
+    opts: &ProcessOptions,
+    first_record: usize,
+) -> Result<ProcessStats> {
+    if batch.is_empty() {
+        return Ok(ProcessStats::default());
+    }
+
+    #[cfg(feature = "profiling")]
+    let started = Instant::now();
+
+    // 1. Parallel compute
+    let results: Vec<std::result::Result<Outcome, UmiParseError>> = batch
+        .par_iter()
+        .map(|rec| {
+            let started = opts.warn_slow.map(|threshold| (threshold, Instant::now()));
+            let mut result = analyze_record(rec, opts);
+            if let (Ok(outcome), Some((threshold, started))) = (&mut result, started) {
+                let elapsed = started.elapsed();
+                outcome.slow = (elapsed > threshold).then_some(elapsed);
+            }
+            result
+        })
+        .collect();
+
+    #[cfg(feature = "profiling")]
+    let matched = {
+        let now = Instant::now();
+        state.timing.matching += now - started;
+        now
+    };
+
+    // 2. Serial write
+    let mut stats = ProcessStats {
+        total: batch.len(),
+        ..Default::default()
+    };
+    for (i, (mut rec, result)) in batch.into_iter().zip(results).enumerate() {
+        let outcome = result.map_err(|e| {
+            anyhow!(
+                "No parseable UMI in header of read '{}' (record {}): {}",
+                String::from_utf8_lossy(rec.header()),
+                first_record + i + 1,
+                e
+            )
+        })?;
+        if opts.check_duplicate_ids && !state.seen_ids.insert(read_id(rec.header()).to_vec()) {
+            if opts.strict {
+                anyhow::bail!(
+                    "Duplicate read ID '{}' (record {})",
+                    String::from_utf8_lossy(read_id(rec.header())),
+                    first_record + i + 1
+                );
+            }
+            stats.duplicate_id += 1;
+        }
+        if let Some(elapsed) = outcome.slow {
+            eprintln!(
+                "Warning: read '{}' ({} bp) took {:.1} ms to match",
+                String::from_utf8_lossy(read_id(rec.header())),
+                rec.seq_len(),
+                elapsed.as_secs_f64() * 1000.0
+            );
+        }
+        let strand = outcome.window.as_ref().map(|window| {
+            if outcome.reverse {
+                ('-', rec.seq_len() - window.end)
+            } else {
+                ('+', window.start)
+            }
+        });
+        state.record_verdict(
+            rec.header(),
+            outcome.matched,
+            outcome.candidate.as_deref(),
+            strand,
+        )?;
+        if let (Some(filter), Some(umi)) = (&mut state.umi_filter, &outcome.umi) {
+            state.umi_repeats.0 += 1;
+            state.umi_repeats.1 += usize::from(filter.insert(umi));
+        }
+        if let (Some(top), Some(umi)) = (&mut state.top_umis, &outcome.umi) {
+            top.insert(umi);
+        }
+        if let Some(ref mut w) = state.qc_table {
+            w.write_all(read_id(rec.header()))?;
+            w.write_all(b"\t")?;
+            w.write_all(outcome.umi.as_deref().unwrap_or(b"NA"))?;
+            let mismatches = outcome
+                .best
+                .map_or("NA".to_string(), |best| best.mismatches.to_string());
+            writeln!(
+                w,
+                "\t{}\t{}\t{}",
+                outcome.matched,
+                mismatches,
+                rec.seq_len()
+            )?;
+        }
+        if let (Some(w), Some((umi, window))) = (&mut state.error_table, &outcome.compared) {
+            w.write_all(read_id(rec.header()))?;
+            w.write_all(b"\t")?;
+            w.write_all(umi)?;
+            w.write_all(b"\t")?;
+            w.write_all(window)?;
+            let subs = substitutions(umi, window, opts);
+            writeln!(w, "\t{}\t{}", subs.len(), substitution_list(&subs))?;
+        }
+        if let Some(name) = rec.mate_name() {
+            match state.pending_mates.remove(name) {
+                Some(mate_matched) => {
+                    stats.pairs += 1;
+                    stats.pairs_with_umi += usize::from(mate_matched || outcome.matched);
+                }
+                None => {
+                    state.pending_mates.insert(name.to_vec(), outcome.matched);
+                }
+            }
+        }
+        state.reads += 1;
+        stats.adapter_present += usize::from(outcome.adapter);
+        state.adapter_dimers += usize::from(outcome.adapter_dimer);
+        stats.low_complexity_umi += usize::from(outcome.low_complexity);
+        state.low_complexity_matches += usize::from(outcome.low_complexity_match);
+        state.window_capped += usize::from(outcome.window_capped);
+        stats.no_umi += usize::from(outcome.no_umi);
+        stats.shorter_than_umi += usize::from(outcome.umi_longer_than_read);
+        if let Some(gc) = opts.report_gc.then(|| rec.with_seq(gc_fraction)).flatten() {
+            if outcome.matched {
+                state.gc.0.add(gc);
+            } else {
+                state.gc.1.add(gc);
+            }
+        }
+        if let (Some(w), Some(umi), Some(n)) = (&mut state.scan, &outcome.umi, outcome.occurrences)
+        {
+            w.write_all(read_id(rec.header()))?;
+            w.write_all(b"\t")?;
+            w.write_all(umi)?;
+            writeln!(w, "\t{}", n)?;
+        }
+        if let Some(reason) = quarantine_reason(rec.seq_len(), &outcome) {
+            rec.with_seq(|seq| state.quarantine_read(rec.header(), seq, rec.qual(), reason))?;
+        }
+        if let (true, Some(window)) = (opts.position_histogram.is_some(), &outcome.window) {
+            *state.match_offsets.entry(window.start).or_default() += 1;
+        }
+        if let (Some(groups), Some(umi)) = (&mut state.consensus, &outcome.umi) {
+            rec.with_seq(|seq| groups.add(umi, rec.header(), seq, rec.qual()));
+        }
+        if let (true, Some(min_run)) = (opts.trim_polyg_output, opts.trim_polyg) {
+            let len = rec.seq_len() - rec.with_seq(|seq| polyg_tail_len(seq, min_run));
+            rec.truncate(len);
+        }
+        if let Some(group) = outcome.group {
+            let counts = state.group_counts.entry(group).or_default();
+            if outcome.matched {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+        }
+        if opts.normalize_output_headers {
+            rec.strip_header_umi(opts.umi_field, opts.keep_header_umi)?;
+        }
+        if outcome.matched {
+            stats.removed += 1;
+            if let (Some(w), Some(best), Some(window)) =
+                (&mut state.match_bed, outcome.best, &outcome.window)
+            {
+                if let Some(iv) = rec.reference_interval(window.start, window.end) {
+                    w.write_all(&state.target_names[iv.tid as usize])?;
+                    write!(w, "\t{}\t{}\t", iv.start, iv.end)?;
+                    w.write_all(read_id(rec.header()))?;
+                    let strand = if iv.reverse { '-' } else { '+' };
+                    writeln!(w, "\t{}\t{}", best.mismatches, strand)?;
+                }
+            }
+            if let (true, Some(best)) = (opts.annotate_mismatches, outcome.best) {
+                rec.annotate_mismatches(best.mismatches)?;
+            }
+            if let (true, Some(window)) = (opts.window_quality, &outcome.window) {
+                if let Some(quality) = rec.window_quality(window.start, window.end) {
+                    state.window_quality.add(quality);
+                    rec.annotate_window_quality(quality)?;
+                }
+            }
+            if let (false, Some(best)) = (state.mismatch_w.is_empty(), outcome.best) {
+                rec.write_to(&mut state.mismatch_w[best.mismatches as usize])?;
+            } else if let (true, Some(best)) = (opts.sort_by_mismatches, outcome.best) {
+                state.held_removed.push((best.mismatches, rec));
+                if state.held_removed.len() == SORT_WARN_RECORDS {
+                    eprintln!(
+                        "Warning: --sort-by-mismatches is holding {} reads in memory",
+                        SORT_WARN_RECORDS
+                    );
+                }
+            } else {
+                rec.write_to(&mut state.rem_w)?;
+            }
+        } else {
+            stats.kept += 1;
+            if let (true, Some(umi)) = (opts.unmatched_umi_table.is_some(), outcome.umi) {
+                *state.unmatched_umis.entry(umi).or_default() += 1;
+            }
+            match opts.downsample {
+                Some(n) => state.sample_kept(n, rec),
+                None => rec.write_to(&mut state.kept_w)?,
+            }
+        }
+    }
+    #[cfg(feature = "profiling")]
+    {
+        state.timing.writing += matched.elapsed();
+        state.timing.batches += 1;
+    }
+    Ok(stats)
+}
+
+/// Process an input FASTQ (or gzipped FASTQ) file, separating reads
+/// into two outputs: reads containing the UMI (kept) and reads where the UMI
+/// was found inside the sequence (removed). Returns the read counts.
+///
+/// `opts` controls allowed mismatches and how the UMI is extracted from the
+/// read header.
+pub fn process_fastq(
+    input: &Path,
+    kept_out: Option<&Path>,
+    rem_out: Option<&Path>,
+    opts: &ProcessOptions,
+) -> Result<ProcessStats> {
+    if opts.umi_tag.is_some() || opts.count_by_tag.is_some() {
+        anyhow::bail!(
+            "SAM/BAM tags (--umi-tag, --count-by-tag, --single-cell) require BAM or SAM input"
+        );
+    }
+    if opts.region.is_some() {
+        anyhow::bail!("--region requires indexed BAM input");
+    }
+    if opts.require_flags != 0 || opts.exclude_flags != 0 {
+        anyhow::bail!("--require-flags and --exclude-flags require BAM or SAM input");
+    }
+    if opts.match_bed.is_some() {
+        anyhow::bail!("--match-bed requires aligned BAM or SAM input");
+    }
+
+    // Check for 0-byte file BEFORE parsing to avoid parser errors/panics
+    if fs::metadata(input)?.len() == 0 {
+        // Create empty output if requested, then return
+        if let (false, Some(p)) = (opts.no_empty_output, kept_out) {
+            let _ = create_fastq_writer(p)?;
+        }
+        return Ok(ProcessStats::default());
+    }
+
+    // Restore progress from an earlier, interrupted run if requested
+    let resume_from = match opts.checkpoint {
+        Some(ref p) if opts.resume && p.exists() => Some(Checkpoint::load(p)?),
+        _ => None,
+    };
+    let checkpoint = resume_from.unwrap_or_default();
+
+    let Some(mut source) = FastqSource::open_at(input, opts, checkpoint.input_offset)? else {
+        return Ok(ProcessStats {
+            total: checkpoint.records,
+            removed: checkpoint.removed,
+            kept: checkpoint.kept,
+            ..Default::default()
+        });
+    };
+    let shard = match opts.shard {
+        Some(shard) => {
+            let mut counter = FastqSource::open(input, opts)?.expect("input is not empty");
+            let mut records = 0;
+            while counter.next_record()?.is_some() {
+                records += 1;
+            }
+            shard_range(shard, records)
+        }
+        None => 0..usize::MAX,
+    };
+
+    let level = opts
+        .gzip_level
+        .map_or(Compression::default(), Compression::new);
+    let open_writer = |p: &Path, resume_len: u64| {
+        if opts.checkpoint.is_some() {
+            checkpointed_fastq_writer(p, level, resume_from.map(|_| resume_len))
+        } else {
+            create_fastq_writer_with_level(p, level)
+        }
+    };
+
+    // Initialize writers immediately, unless empty outputs should not exist
+    let open = |out: Option<&Path>, resume_len: u64| -> Result<GenericWriter> {
+        Ok(match out {
+            Some(p) if opts.no_empty_output => {
+                GenericWriter::Deferred(DeferredOutput::Fastq(p.to_path_buf()))
+            }
+            Some(p) => GenericWriter::Fastq(open_writer(p, resume_len)?),
+            None => GenericWriter::Sink,
+        })
+    };
+    let kept_w = open(kept_out, checkpoint.kept_bytes)?;
+    let rem_w = open(rem_out, checkpoint.removed_bytes)?;
+    let mut state = RunState::new(kept_w, rem_w).with_reports(opts)?;
+
+    let mut stats = ProcessStats {
+        total: checkpoint.records,
+        removed: checkpoint.removed,
+        kept: checkpoint.kept,
+        ..Default::default()
+    };
+    let outputs = (kept_out, rem_out);
+    let mut batch = Vec::with_capacity(opts.batch_size);
+    let mut sizer = BatchSizer::new(opts.max_memory).with_limit(opts.batch_size);
+    let mut index = 0;
+
+    while let Some(rec) = source.next_record()? {
+        index += 1;
+        if index <= shard.start {
+            continue;
+        }
+        if index > shard.end {
+            break;
+        }
+
+        // The parser checks this too, but a mismatch must never reach the
+        // writers, which would emit an unreadable record
+        state.quarantine_malformed(std::mem::take(&mut source.rejected))?;
+        if rec.qual.as_ref().is_some_and(|q| q.len() != rec.seq.len()) {
+            stats.malformed += 1;
+            state.quarantine_malformed(vec![rec])?;
+            continue;
+        }
+        sizer.observe(rec.head.len() + rec.seq.len() + rec.qual.as_ref().map_or(0, Vec::len));
+        batch.push(rec);
+
+        if batch.len() >= sizer.capacity() {
+            stats += process_batch(batch, &mut state, opts, stats.total)?;
+            batch = Vec::with_capacity(sizer.capacity());
+            save_checkpoint(opts, &mut state, &stats, source.offset, outputs)?;
+        }
+    }
+    stats.malformed += source.malformed;
+    state.quarantine_malformed(std::mem::take(&mut source.rejected))?;
+
+    // Final flush
+    stats += process_batch(batch, &mut state, opts, stats.total)?;
+    state.finish(opts)?;
+    stats.gc = state.gc;
+    stats.top_umis = state.top_umi_list(opts);
+    warn_read_categories(&stats, opts);
+    save_checkpoint(opts, &mut state, &stats, source.offset, outputs)?;
+
+    Ok(stats)
+}
+
+/// Count the UMI in the mate files `r1` and `r2` of paired FASTQ input, read
+/// in lockstep. Every read is searched on its own like in `process_fastq`,
+/// and a pair whose UMI (taken from the R1 header) is in neither mate is also
+/// searched across the end of R1 and the start of R2 with
+/// `is_umi_spanning_mates`, which catches UMIs split by overlapping mates.
+/// Each mate is first narrowed to its search region (see `search_region`) as
+/// sequenced, and R2 is then reverse complemented and joined to R1 where the
+/// mates overlap. No output is written.
+pub fn process_fastq_mates(r1: &Path, r2: &Path, opts: &ProcessOptions) -> Result<ProcessStats> {
+    let different_lengths = || {
+        anyhow!(
+            "{} and {} have different numbers of reads",
+            r1.display(),
+            r2.display()
+        )
+    };
+    let (mut mates1, mut mates2) =
+        match (FastqSource::open(r1, opts)?, FastqSource::open(r2, opts)?) {
+            (Some(mates1), Some(mates2)) => (mates1, mates2),
+            (None, None) => return Ok(ProcessStats::default()),
+            _ => return Err(different_lengths()),
+        };
+
+    let mut stats = ProcessStats::default();
+    // Pairs whose UMI was only found across the junction
+    let mut spanning_pairs = 0;
+    loop {
+        let mut batch = Vec::with_capacity(opts.batch_size);
+        while batch.len() < opts.batch_size {
+            match (mates1.next_record()?, mates2.next_record()?) {
+                (Some(m1), Some(m2)) => {
+                    if mate_id(&m1.head) != mate_id(&m2.head) {
+                        anyhow::bail!(
+                            "Read '{}' of {} is paired with read '{}' of {}; the mate files are out of sync",
+                            String::from_utf8_lossy(read_id(&m1.head)),
+                            r1.display(),
+                            String::from_utf8_lossy(read_id(&m2.head)),
+                            r2.display()
+                        );
+                    }
+                    batch.push((m1, m2));
+                }
+                (None, None) => break,
+                _ => return Err(different_lengths()),
+            }
+        }
+        if batch.is_empty() {
+            break;
+        }
+
+        let results: Vec<std::result::Result<(bool, bool, bool), UmiParseError>> = batch
+            .par_iter()
+            .map(|(m1, m2)| {
+                let found = (
+                    analyze_record(m1, opts)?.matched,
+                    analyze_record(m2, opts)?.matched,
+                );
+                let spanning = !found.0
+                    && !found.1
+                    && record_umi(m1, opts).is_ok_and(|umi| {
+                        let (_, region1) = search_region(&m1.seq, umi.len(), opts);
+                        let (_, region2) = search_region(&m2.seq, umi.len(), opts);
+                        is_umi_spanning_mates(&umi, region1, region2, opts.max_mismatches)
+                    });
+                Ok((found.0, found.1, spanning))
+            })
+            .collect();
+        for ((m1, _), result) in batch.iter().zip(results) {
+            let (found1, found2, spanning) = result.map_err(|e| {
+                anyhow!(
+                    "No parseable UMI in header of read '{}' (record {}): {}",
+                    String::from_utf8_lossy(&m1.head),
+                    stats.pairs + 1,
+                    e
+                )
+            })?;
+            let found = usize::from(found1) + usize::from(found2);
+            stats.total += 2;
+            stats.removed += found;
+            stats.kept += 2 - found;
+            stats.pairs += 1;
+            stats.pairs_with_umi += usize::from(found > 0 || spanning);
+            spanning_pairs += usize::from(spanning);
+        }
+    }
+    eprintln!(
+        "Pairs with the UMI only across the mate junction: {} ({:.2}%)",
+        spanning_pairs,
+        crate::percentage(spanning_pairs, stats.pairs)
+    );
+
+    Ok(stats)
+}
+
+/// Read ID of a mate without its `/1` or `/2` suffix, shared by both mates.
+fn mate_id(header: &[u8]) -> &[u8] {
+    let id = read_id(header);
+    id.strip_suffix(b"/1")
+        .or_else(|| id.strip_suffix(b"/2"))
+        .unwrap_or(id)
+}
+
+/// Records `shard.0` of `shard.1` contiguous, near-equal ranges of `records`
+/// records.
+fn shard_range((i, n): (usize, usize), records: usize) -> Range<usize> {
+    records * i / n..records * (i + 1) / n
+}
 
-const BATCH_SIZE: usize = 10_000;
+/// Owned records of a FASTQ (or gzipped FASTQ) file. Outside strict mode,
+/// records whose quality and sequence lengths differ are skipped and
+/// counted instead of ending the input.
+struct FastqSource<'a> {
+    input: &'a Path,
+    reader: Box<dyn FastxReader>,
+    opts: &'a ProcessOptions,
+    /// Decompressed input bytes before `reader`, once it was reopened to
+    /// resume from a checkpoint or to continue past a malformed record.
+    start: u64,
+    /// Decompressed input bytes up to the end of the last record returned.
+    offset: u64,
+    malformed: usize,
+    /// Skipped malformed records, kept for `quarantine`.
+    rejected: Vec<FastqRecord>,
+}
 
-/// Process a batch of records: perform parallel matching then serial writes.
-///
-/// The function runs the expensive UMI matching in parallel (with Rayon) and
-/// then performs outputs serially to avoid interleaved writes. Returns a tuple
-/// `(removed_count, kept_count)` describing how many reads were routed to each
-/// output writer.
-fn process_batch<R: BioRecord>(
-    batch: Vec<R>,
-    kept_writer: &mut GenericWriter,
-    removed_writer: &mut GenericWriter,
-    max_mismatches: u32,
-    umi_len: usize,
-) -> Result<(usize, usize)> {
-    if batch.is_empty() {
-        return Ok((0, 0));
+impl<'a> FastqSource<'a> {
+    /// Open `input`, or `None` if it is empty.
+    fn open(input: &'a Path, opts: &'a ProcessOptions) -> Result<Option<Self>> {
+        Self::open_at(input, opts, 0)
     }
 
-    // 1. Parallel compute
-    let results: Vec<bool> = batch
-        .par_iter()
-        .map(|rec| {
-            if let Some(umi) = crate::extract_umi_from_header(rec.header(), umi_len) {
-                is_umi_in_read(&umi, rec.seq(), max_mismatches)
-            } else {
-                false
+    /// Open `input` at the record starting at decompressed byte `offset`, or
+    /// `None` if nothing follows it.
+    fn open_at(input: &'a Path, opts: &'a ProcessOptions, offset: u64) -> Result<Option<Self>> {
+        let reader = if offset == 0 {
+            match parse_fastx_file(input) {
+                Ok(r) => r,
+                // If the file is empty the parser returns ParseErrorKind::EmptyFile
+                Err(e) if e.kind == ParseErrorKind::EmptyFile => return Ok(None),
+                // Any other parse error is fatal
+                Err(e) => return Err(e).context("Failed to parse FASTX file"),
             }
-        })
-        .collect();
-
-    // 2. Serial write
-    let mut removed = 0;
-    let mut kept = 0;
-    for (rec, matched) in batch.into_iter().zip(results) {
-        if matched {
-            removed += 1;
-            rec.write_to(removed_writer)?;
         } else {
-            kept += 1;
-            rec.write_to(kept_writer)?;
+            match reopen_fastq_after(input, offset, 0)? {
+                Some((r, _)) => r,
+                None => return Ok(None),
+            }
+        };
+        Ok(Some(Self {
+            input,
+            reader,
+            opts,
+            start: offset,
+            offset,
+            malformed: 0,
+            rejected: Vec::new(),
+        }))
+    }
+
+    /// The next record, with missing qualities filled in if requested.
+    fn next_record(&mut self) -> Result<Option<FastqRecord>> {
+        loop {
+            let line = match self.reader.next() {
+                None => return Ok(None),
+                Some(Ok(r)) => {
+                    // `all` stops just before the newline ending the record
+                    self.offset = self.start + r.position().byte() + r.all().len() as u64 + 1;
+                    return Ok(Some(FastqRecord {
+                        head: r.id().to_vec(),
+                        seq: r.seq().to_vec(),
+                        qual: r
+                            .qual()
+                            .map(|q| q.to_vec())
+                            .or_else(|| self.opts.fill_quality.map(|c| vec![c; r.num_bases()])),
+                    }));
+                }
+                Some(Err(e)) if e.kind == ParseErrorKind::UnequalLengths && !self.opts.strict => {
+                    e.position.line
+                }
+                Some(Err(e)) => return Err(e.into()),
+            };
+            // The parser stops at a malformed record; continue after its
+            // four lines (error lines are 1-based)
+            self.malformed += 1;
+            if self.opts.quarantine.is_some() {
+                self.rejected
+                    .extend(raw_fastq_record(self.input, self.start, line)?);
+            }
+            match reopen_fastq_after(self.input, self.start, line + 3)? {
+                Some((r, start)) => {
+                    self.reader = r;
+                    self.start = start;
+                    self.offset = start;
+                }
+                None => return Ok(None),
+            }
         }
     }
-    Ok((removed, kept))
 }
 
-/// Process an input FASTQ (or gzipped FASTQ) file, separating reads
-/// into two outputs: reads containing the UMI (kept) and reads where the UMI
-/// was found inside the sequence (removed). Returns `(total, removed, kept)`.
-///
-/// `max_m` controls allowed mismatches and `umi_len` is the expected UMI length
-/// used when extracting the UMI from the read header.
-pub fn process_fastq(
+/// Reopen the FASTQ (or gzipped FASTQ) file `input` after its first `offset`
+/// decompressed bytes and the `lines` lines that follow them, to resume from
+/// a checkpoint or continue past a record the parser rejected. Plain files
+/// are seeked to `offset`; gzip streams cannot be, so they are decompressed
+/// up to it. Returns the reader and the decompressed byte it starts at, or
+/// `None` if nothing follows.
+fn reopen_fastq_after(
     input: &Path,
-    kept_out: Option<&Path>,
-    rem_out: Option<&Path>,
-    max_m: u32,
-    umi_len: usize,
-) -> Result<(usize, usize, usize)> {
-    // Check for 0-byte file BEFORE parsing to avoid parser errors/panics
-    if fs::metadata(input)?.len() == 0 {
-        // Create empty output if requested, then return
-        if let Some(p) = kept_out {
-            let _ = create_fastq_writer(p)?;
+    offset: u64,
+    lines: u64,
+) -> Result<Option<(Box<dyn FastxReader>, u64)>> {
+    let mut stream = open_decompressed_at(input, offset)?;
+    let mut start = offset;
+    let mut line = Vec::new();
+    for _ in 0..lines {
+        line.clear();
+        match stream.read_until(b'\n', &mut line)? {
+            0 => return Ok(None),
+            n => start += n as u64,
         }
-        return Ok((0, 0, 0));
     }
+    match parse_fastx_reader(stream) {
+        Ok(reader) => Ok(Some((reader, start))),
+        Err(e) if e.kind == ParseErrorKind::EmptyFile => Ok(None),
+        Err(e) => Err(e).context("Failed to parse FASTX file"),
+    }
+}
+
+/// The FASTQ (or gzipped FASTQ) file `input` from decompressed byte `offset`
+/// on. Plain files are seeked; gzip streams cannot be, so they are
+/// decompressed up to it.
+fn open_decompressed_at(input: &Path, offset: u64) -> Result<Box<dyn BufRead + Send>> {
+    let mut file =
+        fs::File::open(input).with_context(|| format!("Failed to open {}", input.display()))?;
+    let mut magic = [0; 2];
+    let gzipped = file.read(&mut magic)? == 2 && magic == [0x1f, 0x8b];
+    Ok(if gzipped {
+        file.seek(SeekFrom::Start(0))?;
+        let mut stream = BufReader::new(MultiGzDecoder::new(file));
+        io::copy(&mut (&mut stream).take(offset), &mut io::sink())?;
+        Box::new(stream)
+    } else {
+        file.seek(SeekFrom::Start(offset))?;
+        Box::new(BufReader::new(file))
+    })
+}
+
+/// The record starting at line `line` (1-based) after the first `offset`
+/// decompressed bytes of `input`, taken line by line without validation, for
+/// quarantining a record the parser rejected. `None` if the input ends early.
+fn raw_fastq_record(input: &Path, offset: u64, line: u64) -> Result<Option<FastqRecord>> {
+    let stream = open_decompressed_at(input, offset)?;
+    let lines = stream
+        .split(b'\n')
+        .skip(line.saturating_sub(1) as usize)
+        .take(4)
+        .map(|l| {
+            l.map(|mut l| {
+                if l.last() == Some(&b'\r') {
+                    l.pop();
+                }
+                l
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let [head, seq, _, qual] = &lines[..] else {
+        return Ok(None);
+    };
+    Ok(Some(FastqRecord {
+        head: head.strip_prefix(b"@").unwrap_or(head).to_vec(),
+        seq: seq.clone(),
+        qual: Some(qual.clone()),
+    }))
+}
 
+/// Flush both FASTQ writers and record `stats`, the `input_offset` the input
+/// continues at and the lengths of the `(kept, removed)` outputs in the
+/// checkpoint file, if checkpointing is enabled.
+fn save_checkpoint<R>(
+    opts: &ProcessOptions,
+    state: &mut RunState<R>,
+    stats: &ProcessStats,
+    input_offset: u64,
+    (kept_out, rem_out): (Option<&Path>, Option<&Path>),
+) -> Result<()> {
+    if let Some(ref path) = opts.checkpoint {
+        state.kept_w.flush()?;
+        state.rem_w.flush()?;
+        // Deferred outputs do not exist until their first record
+        let output_len = |out: Option<&Path>| {
+            out.and_then(|p| fs::metadata(p).ok())
+                .map_or(0, |m| m.len())
+        };
+        Checkpoint {
+            records: stats.total,
+            removed: stats.removed,
+            kept: stats.kept,
+            input_offset,
+            kept_bytes: output_len(kept_out),
+            removed_bytes: output_len(rem_out),
+        }
+        .save(path)?;
+    }
+    Ok(())
+}
+
+/// Headers of the first `n` records of a FASTQ (or gzipped FASTQ) file.
+pub fn sample_fastq_headers(input: &Path, n: usize) -> Result<Vec<Vec<u8>>> {
+    if fs::metadata(input)?.len() == 0 {
+        return Ok(Vec::new());
+    }
     let mut reader = match parse_fastx_file(input) {
         Ok(r) => r,
-        // If the file is empty the parser returns ParseErrorKind::EmptyFile
         Err(e) if e.kind == needletail::errors::ParseErrorKind::EmptyFile => {
-            return Ok((0, 0, 0));
-        }
-        Err(e) => {
-            // Any other parse error is fatal
-            return Err(e).context("Failed to parse FASTX file");
+            return Ok(Vec::new());
         }
+        Err(e) => return Err(e).context("Failed to parse FASTX file"),
     };
-
-    // Initialize writers immediately
-    let mut kept_w = match kept_out {
-        Some(p) => GenericWriter::Fastq(create_fastq_writer(p)?),
-        None => GenericWriter::Sink,
-    };
-    let mut rem_w = match rem_out {
-        Some(p) => GenericWriter::Fastq(create_fastq_writer(p)?),
-        None => GenericWriter::Sink,
-    };
-
-    let mut stats = (0, 0, 0); // total, removed, kept
-    let mut batch = Vec::with_capacity(BATCH_SIZE);
-
-    // Standard loop: no need to peek at the first record manually
-    while let Some(record) = reader.next() {
-        let r = record?;
-        stats.0 += 1;
-
-        // Own the data
-        batch.push(FastqRecord {
-            head: r.id().to_vec(),
-            seq: r.seq().to_vec(),
-            qual: r.qual().map(|q| q.to_vec()),
-        });
-
-        if batch.len() >= BATCH_SIZE {
-            let (r_inc, k_inc) = process_batch(batch, &mut kept_w, &mut rem_w, max_m, umi_len)?;
-            stats.1 += r_inc;
-            stats.2 += k_inc;
-            batch = Vec::with_capacity(BATCH_SIZE);
+    let mut headers = Vec::new();
+    while headers.len() < n {
+        match reader.next() {
+            Some(record) => headers.push(record?.id().to_vec()),
+            None => break,
         }
     }
+    Ok(headers)
+}
 
-    // Final flush
-    let (r_inc, k_inc) = process_batch(batch, &mut kept_w, &mut rem_w, max_m, umi_len)?;
-    stats.1 += r_inc;
-    stats.2 += k_inc;
+// --- BAM PROCESSOR ---
 
-    Ok(stats)
+/// Read names of the first `n` records of a BAM (or SAM) file.
+pub fn sample_bam_headers(input: &Path, n: usize) -> Result<Vec<Vec<u8>>> {
+    use bam::Read;
+    let mut reader = bam::Reader::from_path(input).context("Failed to open BAM file")?;
+    reader
+        .records()
+        .take(n)
+        .map(|r| Ok(r?.qname().to_vec()))
+        .collect()
 }
 
-// --- BAM PROCESSOR ---
+/// UMI length recorded in the header comments of the BAM (or SAM) file
+/// `input`, see `umi_length_from_sam_header`.
+pub fn bam_header_umi_length(input: &Path) -> Result<Option<usize>> {
+    use bam::Read;
+    let reader = bam::Reader::from_path(input).context("Failed to open BAM file")?;
+    Ok(crate::umi_length_from_sam_header(
+        reader.header().as_bytes(),
+    ))
+}
 
 /// Process an input BAM (or SAM) file, separating reads into `kept_out` and
 /// `rem_out` files similarly to `process_fastq`. Uses the BAM header from the
-/// input when creating output BAM writers.
+/// input when creating output BAM writers. With `opts.region` only the reads
+/// overlapping it are read, which requires a BAM index. A BAM without the
+/// BGZF end-of-file marker is reported as possibly truncated, which is an
+/// error in strict mode.
 pub fn process_bam(
     input: &Path,
     kept_out: Option<&Path>,
     rem_out: Option<&Path>,
-    max_m: u32,
-    umi_len: usize,
-) -> Result<(usize, usize, usize)> {
-    let mut reader = bam::Reader::from_path(input).context("Failed to open BAM file")?;
+    opts: &ProcessOptions,
+) -> Result<ProcessStats> {
+    if opts.checkpoint.is_some() {
+        anyhow::bail!("Checkpointing and --resume are only supported for FASTQ input");
+    }
+    if opts.trim_polyg_output {
+        anyhow::bail!("Trimming poly-G tails from the output is only supported for FASTQ input");
+    }
+    if opts.check_duplicate_ids {
+        anyhow::bail!("Checking for duplicate read IDs is only supported for FASTQ input");
+    }
+    if opts.collapse_output.is_some() {
+        anyhow::bail!("--collapse-output is only supported for FASTQ input");
+    }
+    if opts.quarantine.is_some() {
+        anyhow::bail!("--quarantine is only supported for FASTQ input");
+    }
+    if opts.tag_output_by_mismatch.is_some() {
+        anyhow::bail!("--tag-output-by-mismatch is only supported for FASTQ input");
+    }
+
+    if crate::io::is_truncated_bgzf(input)? {
+        let message = format!(
+            "{} has no BGZF end-of-file marker and may be truncated; counts may be incomplete",
+            input.display()
+        );
+        if opts.strict {
+            anyhow::bail!(message);
+        }
+        eprintln!("Warning: {}", message);
+    }
+
+    match opts.region {
+        Some(ref region) => {
+            let mut reader = bam::IndexedReader::from_path(input).with_context(|| {
+                format!(
+                    "Failed to open {} with its index; --region requires a .bai or .csi index next to the BAM file",
+                    input.display()
+                )
+            })?;
+            let fetch = |reader: &mut bam::IndexedReader| {
+                reader
+                    .fetch(region.as_str())
+                    .with_context(|| format!("Invalid region '{}'", region))
+            };
+            fetch(&mut reader)?;
+            let shard = match opts.shard {
+                Some(shard) => {
+                    let mut counter =
+                        bam::IndexedReader::from_path(input).context("Failed to open BAM file")?;
+                    fetch(&mut counter)?;
+                    shard_range(shard, count_bam_records(counter)?)
+                }
+                None => 0..usize::MAX,
+            };
+            process_bam_reader(reader, kept_out, rem_out, shard, opts)
+        }
+        None => {
+            let open = || bam::Reader::from_path(input).context("Failed to open BAM file");
+            let shard = match opts.shard {
+                Some(shard) => shard_range(shard, count_bam_records(open()?)?),
+                None => 0..usize::MAX,
+            };
+            process_bam_reader(open()?, kept_out, rem_out, shard, opts)
+        }
+    }
+}
+
+/// Number of records `reader` yields.
+fn count_bam_records<B: bam::Read>(mut reader: B) -> Result<usize> {
+    let mut record = bam::Record::new();
+    let mut records = 0;
+    while let Some(result) = reader.read(&mut record) {
+        result.context("Failed to read BAM record")?;
+        records += 1;
+    }
+    Ok(records)
+}
 
+/// Body of `process_bam` for an opened (and possibly region-restricted)
+/// `reader`, processing only its records with indices in `shard`.
+fn process_bam_reader<B: bam::Read>(
+    mut reader: B,
+    kept_out: Option<&Path>,
+    rem_out: Option<&Path>,
+    shard: Range<usize>,
+    opts: &ProcessOptions,
+) -> Result<ProcessStats> {
     // Read header immediately to setup output writers
     let header = bam::Header::from_template(reader.header());
-
-    // Note: header is used to initialize writers (if provided)
-    let mut kept_w = match kept_out {
-        Some(p) => GenericWriter::Bam(create_bam_writer(p, &header)?),
-        None => GenericWriter::Sink,
+    let format = if opts.sam_output {
+        bam::Format::Sam
+    } else {
+        bam::Format::Bam
     };
-    let mut rem_w = match rem_out {
-        Some(p) => GenericWriter::Bam(create_bam_writer(p, &header)?),
-        None => GenericWriter::Sink,
+
+    // Note: header is used to initialize writers (if provided); each writer
+    // takes its own copy, so kept and removed outputs never share a header
+    let open = |out: Option<&Path>| -> Result<GenericWriter> {
+        Ok(match out {
+            Some(p) if opts.fastq_output => {
+                GenericWriter::Mates(Box::new(MateWriters::new(p, opts.no_empty_output)?))
+            }
+            Some(p) if opts.no_empty_output => GenericWriter::Deferred(DeferredOutput::Bam(
+                p.to_path_buf(),
+                header.clone(),
+                format,
+            )),
+            Some(p) => GenericWriter::Bam(create_bam_writer(p, &header, format)?),
+            None => GenericWriter::Sink,
+        })
     };
+    let kept_w = open(kept_out)?;
+    let rem_w = open(rem_out)?;
+    let mut state = RunState::new(kept_w, rem_w).with_reports(opts)?;
+    state.target_names = reader
+        .header()
+        .target_names()
+        .into_iter()
+        .map(<[u8]>::to_vec)
+        .collect();
 
-    let mut stats = (0, 0, 0); // total, removed, kept
-    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut stats = ProcessStats::default();
+    let mut batch = Vec::with_capacity(opts.batch_size);
+    let mut sizer = BatchSizer::new(opts.max_memory).with_limit(opts.batch_size);
 
     // Iterate directly. If file is empty (has header but no records),
     // this loop simply won't run, and we flow to the empty final flush.
-    for result in reader.records() {
+    for (index, result) in reader.records().enumerate() {
+        if index < shard.start {
+            continue;
+        }
+        if index >= shard.end {
+            break;
+        }
         let r = result?;
-        stats.0 += 1;
-        let seq = r.seq().as_bytes();
-        batch.push(BamRecord { rec: r, seq });
+        let flags = r.flags();
+        if flags & opts.require_flags != opts.require_flags || flags & opts.exclude_flags != 0 {
+            continue;
+        }
+        let len = r.seq().len();
+        // The record holds the packed sequence and qualities next to our copy
+        let copy = if opts.no_seq_copy { 0 } else { len };
+        sizer.observe(r.qname().len() + len + copy + len.div_ceil(2));
+        batch.push(if opts.no_seq_copy {
+            BamRecord::without_seq_copy(r)
+        } else {
+            BamRecord::new(r)
+        });
 
-        if batch.len() >= BATCH_SIZE {
-            let (r_inc, k_inc) = process_batch(batch, &mut kept_w, &mut rem_w, max_m, umi_len)?;
-            stats.1 += r_inc;
-            stats.2 += k_inc;
-            batch = Vec::with_capacity(BATCH_SIZE);
+        if batch.len() >= sizer.capacity() {
+            stats += process_batch(batch, &mut state, opts, stats.total)?;
+            batch = Vec::with_capacity(sizer.capacity());
         }
     }
 
     // Final flush
-    let (r_inc, k_inc) = process_batch(batch, &mut kept_w, &mut rem_w, max_m, umi_len)?;
-    stats.1 += r_inc;
-    stats.2 += k_inc;
+    stats += process_batch(batch, &mut state, opts, stats.total)?;
+    state.finish(opts)?;
+    stats.gc = state.gc;
+    stats.top_umis = state.top_umi_list(opts);
+    warn_read_categories(&stats, opts);
 
     Ok(stats)
 }
@@ -206,28 +2563,45 @@ mod tests {
         }
     }
 
+    /// FASTQ record without qualities.
+    fn fastq_read(head: &[u8], seq: &[u8]) -> FastqRecord {
+        FastqRecord {
+            head: head.to_vec(),
+            seq: seq.to_vec(),
+            qual: None,
+        }
+    }
+
+    /// Process `batch` on a fresh state that discards every read, returning
+    /// the batch stats and the state holding the run counters.
+    fn run_batch<R: BioRecord>(
+        batch: Vec<R>,
+        opts: &ProcessOptions,
+    ) -> (ProcessStats, RunState<R>) {
+        let mut state = RunState::new(GenericWriter::Sink, GenericWriter::Sink);
+        let stats = process_batch(batch, &mut state, opts, 0).unwrap();
+        (stats, state)
+    }
+
     #[test]
     fn test_process_batch_fastq_routing() {
         let batch = vec![
-            FastqRecord {
-                head: b"r1:ACGT".to_vec(),
-                seq: b"XXXXACGTYYYY".to_vec(),
-                qual: None,
-            },
-            FastqRecord {
-                head: b"r2:TTTT".to_vec(),
-                seq: b"AAAAAAAA".to_vec(),
-                qual: None,
-            },
+            fastq_read(b"r1:ACGT", b"XXXXACGTYYYY"),
+            fastq_read(b"r2:TTTT", b"AAAAAAAA"),
         ];
 
         let kept_buf = Arc::new(Mutex::new(Vec::new()));
         let rem_buf = Arc::new(Mutex::new(Vec::new()));
-        let mut kept_writer = GenericWriter::Fastq(Box::new(SharedWriter(kept_buf.clone())));
-        let mut rem_writer = GenericWriter::Fastq(Box::new(SharedWriter(rem_buf.clone())));
+        let kept_writer = GenericWriter::Fastq(Box::new(SharedWriter(kept_buf.clone())));
+        let rem_writer = GenericWriter::Fastq(Box::new(SharedWriter(rem_buf.clone())));
+        let mut state = RunState::new(kept_writer, rem_writer);
 
-        let (removed, kept) =
-            process_batch(batch, &mut kept_writer, &mut rem_writer, 0, 4).unwrap();
+        let opts = ProcessOptions {
+            umi_len: 4,
+            ..Default::default()
+        };
+        let ProcessStats { removed, kept, .. } =
+            process_batch(batch, &mut state, &opts, 0).unwrap();
         assert_eq!(removed, 1);
         assert_eq!(kept, 1);
 
@@ -238,4 +2612,750 @@ mod tests {
         // Check the removed writer contains the expected FASTQ header
         assert!(String::from_utf8_lossy(&r).contains("@r1:ACGT"));
     }
+
+    #[test]
+    fn test_sort_by_mismatches_orders_removed_reads() {
+        let batch = vec![
+            fastq_read(b"mm2:ACGTACGT", b"GGACCTACCTGG"),
+            fastq_read(b"none:ACGTACGT", b"TTTTTTTTTTTT"),
+            fastq_read(b"mm0:ACGTACGT", b"GGACGTACGTGG"),
+            fastq_read(b"mm1:ACGTACGT", b"GGACGTACCTGG"),
+        ];
+
+        let rem_buf = Arc::new(Mutex::new(Vec::new()));
+        let rem_writer = GenericWriter::Fastq(Box::new(SharedWriter(rem_buf.clone())));
+        let mut state = RunState::new(GenericWriter::Sink, rem_writer);
+        let opts = ProcessOptions {
+            max_mismatches: 2,
+            umi_len: 8,
+            sort_by_mismatches: true,
+            ..Default::default()
+        };
+
+        let ProcessStats { removed, kept, .. } =
+            process_batch(batch, &mut state, &opts, 0).unwrap();
+        assert_eq!((removed, kept), (3, 1));
+        // Nothing is written until the input is exhausted
+        assert!(rem_buf.lock().unwrap().is_empty());
+
+        state.finish(&opts).unwrap();
+        let out = String::from_utf8(rem_buf.lock().unwrap().clone()).unwrap();
+        let headers: Vec<&str> = out.lines().filter(|l| l.starts_with('@')).collect();
+        assert_eq!(headers, ["@mm0:ACGTACGT", "@mm1:ACGTACGT", "@mm2:ACGTACGT"]);
+    }
+
+    #[test]
+    fn test_tag_output_by_mismatch_splits_removed_reads() {
+        let batch = vec![
+            fastq_read(b"mm1:ACGTACGT", b"GGACGTACCTGG"),
+            fastq_read(b"none:ACGTACGT", b"TTTTTTTTTTTT"),
+            fastq_read(b"mm0:ACGTACGT", b"GGACGTACGTGG"),
+        ];
+        let tmp = tempfile::tempdir().unwrap();
+        let prefix = tmp.path().join("split");
+        let rem_buf = Arc::new(Mutex::new(Vec::new()));
+        let rem_writer = GenericWriter::Fastq(Box::new(SharedWriter(rem_buf.clone())));
+        let opts = ProcessOptions {
+            max_mismatches: 2,
+            umi_len: 8,
+            tag_output_by_mismatch: Some(prefix.clone()),
+            ..Default::default()
+        };
+        let mut state = RunState::new(GenericWriter::Sink, rem_writer)
+            .with_reports(&opts)
+            .unwrap();
+
+        let ProcessStats { removed, kept, .. } =
+            process_batch(batch, &mut state, &opts, 0).unwrap();
+        assert_eq!((removed, kept), (2, 1));
+        state.finish(&opts).unwrap();
+        drop(state);
+
+        // Routed reads are not also written to the removed output
+        assert!(rem_buf.lock().unwrap().is_empty());
+        let headers = |mismatches| {
+            let out = fs::read_to_string(mismatch_output_path(&prefix, mismatches)).unwrap();
+            out.lines()
+                .filter(|l| l.starts_with('@'))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(headers(0), ["@mm0:ACGTACGT"]);
+        assert_eq!(headers(1), ["@mm1:ACGTACGT"]);
+        assert!(headers(2).is_empty());
+        assert!(!mismatch_output_path(&prefix, 3).exists());
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn test_batch_timing_covers_the_batch() {
+        // Enough mismatch searches over long reads to take measurable time
+        let mut state: u64 = 1;
+        let mut next_base = || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            b"ACGT"[(state >> 33) as usize % 4]
+        };
+        let batch: Vec<FastqRecord> = (0..5_000)
+            .map(|_| FastqRecord {
+                head: b"r:ACGTACGTACGT".to_vec(),
+                seq: (0..300).map(|_| next_base()).collect(),
+                qual: None,
+            })
+            .collect();
+        let opts = ProcessOptions {
+            max_mismatches: 3,
+            ..Default::default()
+        };
+        let mut run = RunState::new(GenericWriter::Sink, GenericWriter::Sink);
+
+        let started = Instant::now();
+        process_batch(batch, &mut run, &opts, 0).unwrap();
+        let elapsed = started.elapsed();
+
+        let BatchTiming {
+            batches,
+            matching,
+            writing,
+        } = run.timing;
+        assert_eq!(batches, 1);
+        assert!(matching + writing <= elapsed);
+        assert!((matching + writing).as_secs_f64() >= 0.9 * elapsed.as_secs_f64());
+    }
+
+    #[test]
+    fn test_counting_sinks_see_every_routed_read() {
+        let batch = vec![
+            fastq_read(b"r1:ACGTACGT", b"GGACGTACGTGG"),
+            fastq_read(b"r2:ACGTACGT", b"GGGGGGGGGGGG"),
+            fastq_read(b"r3:TTTTGGGG", b"AATTTTGGGGAA"),
+            // Held back for --sort-by-mismatches until the end of the run
+            fastq_read(b"r4:ACGTACGT", b"GGACGTACCTGG"),
+        ];
+        let opts = ProcessOptions {
+            umi_len: 8,
+            max_mismatches: 1,
+            sort_by_mismatches: true,
+            ..Default::default()
+        };
+        let mut state = RunState::new(GenericWriter::Counting(0), GenericWriter::Counting(0));
+
+        let stats = process_batch(batch, &mut state, &opts, 0).unwrap();
+        assert_eq!(state.kept_w.records_written(), Some(1));
+        assert_eq!(state.rem_w.records_written(), Some(0));
+        state.finish(&opts).unwrap();
+        assert_eq!(state.kept_w.records_written(), Some(stats.kept));
+        assert_eq!(state.rem_w.records_written(), Some(stats.removed));
+        assert_eq!(stats.removed, 3);
+        assert_eq!(GenericWriter::Sink.records_written(), None);
+    }
+
+    #[test]
+    fn test_unmatched_umi_table_lists_most_common_first() {
+        let tmp = tempfile::tempdir().unwrap();
+        let table = tmp.path().join("unmatched.tsv");
+        let batch = vec![
+            fastq_read(b"r1:TTTTGGGG", b"AAAAAAAAAAAA"),
+            fastq_read(b"r2:CCCCAAAA", b"AAAAAAAAAAAA"),
+            fastq_read(b"r3:TTTTGGGG", b"AAAAAAAAAAAA"),
+            // Found in its read, so not part of the table
+            fastq_read(b"r4:ACGTACGT", b"GGACGTACGTGG"),
+        ];
+        let opts = ProcessOptions {
+            umi_len: 8,
+            unmatched_umi_table: Some(table.clone()),
+            ..Default::default()
+        };
+        let (_, mut state) = run_batch(batch, &opts);
+        state.finish(&opts).unwrap();
+
+        let content = std::fs::read_to_string(&table).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines, ["umi\tcount", "TTTTGGGG\t2", "CCCCAAAA\t1"]);
+    }
+
+    #[test]
+    fn test_skip_prefix_ignores_umi_in_primer() {
+        let batch = vec![
+            // UMI inside the skipped 8bp prefix
+            fastq_read(b"prefix:ACGTACGT", b"ACGTACGTTTTTTTTTTTTT"),
+            // UMI after the prefix
+            fastq_read(b"body:ACGTACGT", b"TTTTTTTTACGTACGTTTTT"),
+            // UMI inside the skipped 4bp suffix
+            fastq_read(b"suffix:TTTTACGT", b"GGGGGGGGGGGGGGGGTTTTACGT"),
+        ];
+        let opts = ProcessOptions {
+            umi_len: 8,
+            skip_prefix: 8,
+            skip_suffix: 4,
+            ..Default::default()
+        };
+        let (ProcessStats { removed, kept, .. }, _) = run_batch(batch, &opts);
+        assert_eq!((removed, kept), (1, 2));
+    }
+
+    #[test]
+    fn test_search_region_handles_short_reads() {
+        let opts = ProcessOptions {
+            skip_prefix: 3,
+            skip_suffix: 3,
+            ..Default::default()
+        };
+        assert_eq!(search_region(b"AAACCCGGG", 0, &opts), (3, &b"CCC"[..]));
+        assert!(search_region(b"AAAC", 0, &opts).1.is_empty());
+    }
+
+    #[test]
+    fn test_quarantine_collects_problematic_reads() {
+        let read = |head: &[u8], seq: &[u8], qual: &[u8]| FastqRecord {
+            head: head.to_vec(),
+            seq: seq.to_vec(),
+            qual: Some(qual.to_vec()),
+        };
+        let batch = vec![
+            read(b"good:ACGT:1", b"GGACGTGG", b"IIIIIIII"),
+            // No second-to-last field to take the UMI from
+            read(b"malformed", b"GGACGTGG", b"IIIIIIII"),
+            read(b"empty:ACGT:1", b"", b""),
+            read(b"short:ACGT:1", b"ACG", b"III"),
+            read(b"r:ACG:1", b"GGACGTGG", b"IIIIIIII"),
+        ];
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("quarantine.fq");
+        let opts = ProcessOptions {
+            umi_len: 4,
+            umi_field: -2,
+            quarantine: Some(path.clone()),
+            ..Default::default()
+        };
+        let mut state = RunState::new(GenericWriter::Sink, GenericWriter::Sink)
+            .with_reports(&opts)
+            .unwrap();
+        let stats = process_batch(batch, &mut state, &opts, 0).unwrap();
+        // Quarantined reads still count as without UMI
+        assert_eq!((stats.removed, stats.kept), (1, 4));
+        assert_eq!((stats.no_umi, stats.shorter_than_umi), (2, 2));
+        state.finish(&opts).unwrap();
+        drop(state);
+
+        let out = fs::read_to_string(&path).unwrap();
+        let headers: Vec<&str> = out.lines().filter(|l| l.starts_with('@')).collect();
+        assert_eq!(
+            headers,
+            [
+                "@malformed quarantine:no_umi",
+                "@empty:ACGT:1 quarantine:empty_sequence",
+                "@short:ACGT:1 quarantine:shorter_than_umi",
+                "@r:ACG:1 quarantine:wrong_umi_length",
+            ]
+        );
+        assert!(out.contains("@short:ACGT:1 quarantine:shorter_than_umi\nACG\n+\nIII\n"));
+    }
+
+    #[test]
+    fn test_anchor_motif_restricts_search_region() {
+        // The UMI ACGTAC sits right after TATA; an earlier copy is not anchored
+        let read = b"ACGTACGGGGTATAACGTACCC";
+        let mut opts = ProcessOptions {
+            umi_len: 6,
+            anchor_motif: Some(b"TATA".to_vec()),
+            ..Default::default()
+        };
+        assert_eq!(search_region(read, 6, &opts), (14, &b"ACGTAC"[..]));
+        opts.anchor_gap = 2;
+        assert_eq!(search_region(read, 6, &opts), (14, &b"ACGTACCC"[..]));
+        opts.anchor_before = true;
+        assert_eq!(search_region(read, 6, &opts), (2, &b"GTACGGGG"[..]));
+        assert!(search_region(b"ACGTACGGGG", 6, &opts).1.is_empty());
+    }
+
+    #[test]
+    fn test_anchor_motif_finds_umi_only_next_to_motif() {
+        // r1 holds its UMI right after TATA, r2 away from any TATA
+        let batch = || {
+            vec![
+                fastq_read(b"r1:ACGTAC", b"GGGGTATAACGTACGG"),
+                fastq_read(b"r2:ACGTAC", b"ACGTACGGGGTATAGG"),
+            ]
+        };
+        let unanchored = ProcessOptions {
+            umi_len: 6,
+            ..Default::default()
+        };
+        let (stats, _) = run_batch(batch(), &unanchored);
+        assert_eq!(stats.removed, 2);
+
+        let anchored = ProcessOptions {
+            anchor_motif: Some(b"TATA".to_vec()),
+            ..unanchored
+        };
+        let (ProcessStats { removed, kept, .. }, _) = run_batch(batch(), &anchored);
+        assert_eq!((removed, kept), (1, 1));
+
+        // The window after the anchor fits the longest candidate, not the shortest
+        let candidates = ProcessOptions {
+            umi_len: 2,
+            umi_len_max: Some(6),
+            umi_candidates_delim: Some(b'+'),
+            ..anchored
+        };
+        let batch = vec![fastq_read(b"r3:GG+ACGTAC", b"GGGGTATAACGTACGG")];
+        let (stats, _) = run_batch(batch, &candidates);
+        assert_eq!(stats.removed, 1);
+    }
+
+    #[test]
+    fn test_batch_sizer_shrinks_for_long_reads() {
+        let unlimited = BatchSizer::new(None);
+        assert_eq!(unlimited.capacity(), BATCH_SIZE);
+
+        // 4 MB budget with ~1 MB reads leaves room for 4 records per batch
+        let mut sizer = BatchSizer::new(Some(4 << 20));
+        assert_eq!(sizer.capacity(), BATCH_SIZE);
+        for _ in 0..3 {
+            sizer.observe(1 << 20);
+        }
+        assert_eq!(sizer.capacity(), 4);
+
+        // A single read larger than the budget still makes progress
+        let mut tight = BatchSizer::new(Some(1024));
+        tight.observe(1 << 20);
+        assert_eq!(tight.capacity(), 1);
+
+        // Short reads never grow the batch beyond BATCH_SIZE
+        let mut short = BatchSizer::new(Some(4 << 20));
+        short.observe(10);
+        assert_eq!(short.capacity(), BATCH_SIZE);
+    }
+
+    /// Minimal tagged record standing in for a BAM record with aux tags.
+    struct TaggedRecord {
+        seq: Vec<u8>,
+        tags: Vec<([u8; 2], Vec<u8>)>,
+    }
+    impl BioRecord for TaggedRecord {
+        fn seq(&self) -> &[u8] {
+            &self.seq
+        }
+        fn header(&self) -> &[u8] {
+            b"read"
+        }
+        fn write_to(self, _writer: &mut GenericWriter) -> Result<()> {
+            Ok(())
+        }
+        fn tag(&self, tag: &[u8]) -> Option<&[u8]> {
+            self.tags
+                .iter()
+                .find(|(t, _)| t == tag)
+                .map(|(_, v)| v.as_slice())
+        }
+    }
+
+    #[test]
+    fn test_single_cell_counts_per_cell_barcode() {
+        let tmp = tempfile::tempdir().unwrap();
+        let table = tmp.path().join("cells.tsv");
+        let read = |cb: &[u8], ub: &[u8], seq: &[u8]| TaggedRecord {
+            seq: seq.to_vec(),
+            tags: vec![(*b"CB", cb.to_vec()), (*b"UB", ub.to_vec())],
+        };
+        let batch = vec![
+            read(b"AAACCC-1", b"ACGTACGT", b"GGACGTACGTGG"),
+            read(b"AAACCC-1", b"ACGTACGT", b"TTTTTTTTTTTT"),
+            read(b"GGGTTT-1", b"TTGGCCAA", b"CCTTGGCCAACC"),
+            // Without a UB tag the read cannot contain its UMI
+            TaggedRecord {
+                seq: b"ACGTACGT".to_vec(),
+                tags: vec![(*b"CB", b"GGGTTT-1".to_vec())],
+            },
+        ];
+        let opts = ProcessOptions {
+            umi_tag: Some(*b"UB"),
+            count_by_tag: Some(*b"CB"),
+            tag_counts: Some(table.clone()),
+            ..Default::default()
+        };
+        let (ProcessStats { removed, kept, .. }, mut state) = run_batch(batch, &opts);
+        assert_eq!((removed, kept), (2, 2));
+        state.finish(&opts).unwrap();
+
+        let content = std::fs::read_to_string(&table).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "CB\ttotal\twith_umi\tperc_with\twithout_umi\tperc_without",
+                "AAACCC-1\t2\t1\t50.00\t1\t50.00",
+                "GGGTTT-1\t2\t1\t50.00\t1\t50.00",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trim_polyg_changes_match_outcome() {
+        // The UMI only occurs by overlapping the poly-G tail
+        let batch = || {
+            vec![FastqRecord {
+                head: b"read:ACGTGGGG".to_vec(),
+                seq: b"TTTTACGTGGGGGGGGGGGG".to_vec(),
+                qual: Some(b"IIIIIIIIIIIIIIIIIIII".to_vec()),
+            }]
+        };
+        let mut opts = ProcessOptions {
+            umi_len: 8,
+            ..Default::default()
+        };
+        let (stats, _) = run_batch(batch(), &opts);
+        assert_eq!((stats.removed, stats.kept), (1, 0));
+
+        opts.trim_polyg = Some(10);
+        opts.trim_polyg_output = true;
+        let kept_buf = Arc::new(Mutex::new(Vec::new()));
+        let kept_writer = GenericWriter::Fastq(Box::new(SharedWriter(kept_buf.clone())));
+        let mut state = RunState::new(kept_writer, GenericWriter::Sink);
+        let stats = process_batch(batch(), &mut state, &opts, 0).unwrap();
+        assert_eq!((stats.removed, stats.kept), (0, 1));
+        let out = String::from_utf8(kept_buf.lock().unwrap().clone()).unwrap();
+        assert_eq!(out, "@read:ACGTGGGG\nTTTTACGT\n+\nIIIIIIII\n");
+    }
+
+    #[test]
+    fn test_polyg_tail_len_respects_threshold() {
+        assert_eq!(polyg_tail_len(b"ACGTGGGGG", 5), 5);
+        assert_eq!(polyg_tail_len(b"ACGTGGGG", 5), 0);
+        assert_eq!(polyg_tail_len(b"GGGG", 2), 4);
+        assert_eq!(polyg_tail_len(b"ACGT", 1), 0);
+    }
+
+    #[test]
+    fn test_gc_content_of_matched_and_unmatched_reads() {
+        let batch = vec![
+            // With UMI: 50% and 100% GC
+            fastq_read(b"a:ACGT", b"ACGTAT"),
+            fastq_read(b"b:GGCC", b"GGCCGC"),
+            // Without UMI: 0% and 50% GC
+            fastq_read(b"c:ACGT", b"TTTTTTTT"),
+            fastq_read(b"d:ACGT", b"GGGGAAAA"),
+        ];
+        let opts = ProcessOptions {
+            umi_len: 4,
+            report_gc: true,
+            ..Default::default()
+        };
+        let (_, state) = run_batch(batch, &opts);
+        // ACGTAT has 2/6 GC bases, GGCCGC 6/6
+        assert_eq!(state.gc.0.get(), Some((2.0 / 6.0 + 1.0) / 2.0));
+        assert_eq!(state.gc.1.get(), Some(0.25));
+        assert_eq!(Mean::default().get(), None);
+    }
+
+    #[test]
+    fn test_adapter_present_is_counted_independently_of_umi() {
+        let batch = vec![
+            fastq_read(b"umi_and_adapter:ACGT", b"ACGTAGATCGGAAGAGC"),
+            fastq_read(b"adapter_only:ACGT", b"TTTTAGATCGGAAGAGC"),
+            fastq_read(b"neither:ACGT", b"TTTTTTTTTTTTTTTTT"),
+            // The adapter follows two bases of insert and runs to the end
+            fastq_read(b"dimer:ACGT", b"GGAGATCGGAAGAGC"),
+        ];
+        let opts = ProcessOptions {
+            umi_len: 4,
+            adapter: Some(CompiledUmi::new(b"AGATCGGAAGAGC", 0)),
+            ..Default::default()
+        };
+
+        let (stats, state) = run_batch(batch, &opts);
+        assert_eq!((stats.removed, stats.kept), (1, 3));
+        assert_eq!(stats.adapter_present, 3);
+        // The other adapters follow a 4bp insert, too long for a dimer
+        assert_eq!(state.adapter_dimers, 1);
+    }
+
+    #[test]
+    fn test_process_stats_merge() {
+        let mean = |values: &[f64]| {
+            let mut mean = Mean::default();
+            values.iter().for_each(|&v| mean.add(v));
+            mean
+        };
+        let mut stats = ProcessStats {
+            total: 3,
+            removed: 2,
+            kept: 1,
+            pairs: 1,
+            pairs_with_umi: 1,
+            gc: (mean(&[0.5, 0.25]), mean(&[0.5])),
+            top_umis: None,
+            no_umi: 1,
+            shorter_than_umi: 0,
+            adapter_present: 2,
+            low_complexity_umi: 1,
+            duplicate_id: 1,
+            malformed: 0,
+        };
+        stats += ProcessStats {
+            total: 2,
+            removed: 0,
+            kept: 2,
+            pairs: 1,
+            pairs_with_umi: 0,
+            gc: (Mean::default(), mean(&[0.25, 0.0])),
+            top_umis: None,
+            no_umi: 1,
+            shorter_than_umi: 2,
+            adapter_present: 1,
+            low_complexity_umi: 0,
+            duplicate_id: 1,
+            malformed: 2,
+        };
+        assert_eq!(
+            stats,
+            ProcessStats {
+                total: 5,
+                removed: 2,
+                kept: 3,
+                pairs: 2,
+                pairs_with_umi: 1,
+                gc: (mean(&[0.5, 0.25]), mean(&[0.5, 0.25, 0.0])),
+                top_umis: None,
+                no_umi: 2,
+                shorter_than_umi: 2,
+                adapter_present: 3,
+                low_complexity_umi: 1,
+                duplicate_id: 2,
+                malformed: 2,
+            }
+        );
+        assert_eq!(stats.gc.1.get(), Some(0.25));
+    }
+
+    #[test]
+    fn test_low_complexity_umis_are_counted_and_optionally_skipped() {
+        let batch = || {
+            vec![
+                fastq_read(b"polya:AAAAAAAA", b"CCAAAAAAAAAACC"),
+                fastq_read(b"mixed:ACGTACGT", b"CCACGTACGTCC"),
+            ]
+        };
+        let mut opts = ProcessOptions {
+            umi_len: 8,
+            ..Default::default()
+        };
+
+        let (stats, _) = run_batch(batch(), &opts);
+        assert_eq!((stats.removed, stats.kept), (2, 0));
+        assert_eq!(stats.low_complexity_umi, 1);
+
+        opts.skip_low_complexity = true;
+        let (stats, _) = run_batch(batch(), &opts);
+        assert_eq!((stats.removed, stats.kept), (1, 1));
+        assert_eq!(stats.low_complexity_umi, 1);
+    }
+
+    #[test]
+    fn test_annotate_mismatches_on_removed_reads() {
+        let batch = vec![
+            FastqRecord {
+                head: b"exact:ACGTACGT".to_vec(),
+                seq: b"TTACGTACGTTT".to_vec(),
+                qual: Some(b"IIIIIIIIIIII".to_vec()),
+            },
+            FastqRecord {
+                head: b"two:ACGTACGT".to_vec(),
+                seq: b"TTACCTACCTTT".to_vec(),
+                qual: Some(b"IIIIIIIIIIII".to_vec()),
+            },
+            FastqRecord {
+                head: b"none:ACGTACGT".to_vec(),
+                seq: b"TTTTTTTTTTTT".to_vec(),
+                qual: Some(b"IIIIIIIIIIII".to_vec()),
+            },
+        ];
+        let opts = ProcessOptions {
+            max_mismatches: 2,
+            umi_len: 8,
+            annotate_mismatches: true,
+            ..Default::default()
+        };
+        let kept_buf = Arc::new(Mutex::new(Vec::new()));
+        let rem_buf = Arc::new(Mutex::new(Vec::new()));
+        let mut state = RunState::new(
+            GenericWriter::Fastq(Box::new(SharedWriter(kept_buf.clone()))),
+            GenericWriter::Fastq(Box::new(SharedWriter(rem_buf.clone()))),
+        );
+        process_batch(batch, &mut state, &opts, 0).unwrap();
+
+        let removed = String::from_utf8(rem_buf.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            removed,
+            "@exact:ACGTACGT mm:0\nTTACGTACGTTT\n+\nIIIIIIIIIIII\n\
+             @two:ACGTACGT mm:2\nTTACCTACCTTT\n+\nIIIIIIIIIIII\n"
+        );
+        // Reads without their UMI have no match to annotate
+        let kept = String::from_utf8(kept_buf.lock().unwrap().clone()).unwrap();
+        assert_eq!(kept, "@none:ACGTACGT\nTTTTTTTTTTTT\n+\nIIIIIIIIIIII\n");
+    }
+
+    #[test]
+    fn test_window_quality_of_removed_reads() {
+        // UMI at offset 2; its window has qualities 5 ('&') and 35 ('D')
+        let batch = vec![FastqRecord {
+            head: b"r1:ACGTACGT".to_vec(),
+            seq: b"TTACGTACGTTT".to_vec(),
+            qual: Some(b"II&&&&DDDDII".to_vec()),
+        }];
+        let opts = ProcessOptions {
+            umi_len: 8,
+            skip_prefix: 1,
+            window_quality: true,
+            ..Default::default()
+        };
+        let rem_buf = Arc::new(Mutex::new(Vec::new()));
+        let mut state = RunState::new(
+            GenericWriter::Sink,
+            GenericWriter::Fastq(Box::new(SharedWriter(rem_buf.clone()))),
+        );
+        process_batch(batch, &mut state, &opts, 0).unwrap();
+
+        let removed = String::from_utf8(rem_buf.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            removed,
+            "@r1:ACGTACGT wq:20.0\nTTACGTACGTTT\n+\nII&&&&DDDDII\n"
+        );
+        assert_eq!(state.window_quality.get(), Some(20.0));
+    }
+
+    #[test]
+    fn test_any_umi_candidate_counts_as_found() {
+        let batch = vec![
+            fastq_read(b"second:AAAACCCC,ACGTACGT", b"TTACGTACGTTT"),
+            fastq_read(b"neither:AAAACCCC,GGGGTTTT", b"TTACGTACGTTT"),
+        ];
+        let opts = ProcessOptions {
+            umi_len: 8,
+            umi_candidates_delim: Some(b','),
+            ..Default::default()
+        };
+        let report_buf = Arc::new(Mutex::new(Vec::new()));
+        let mut state = RunState::new(GenericWriter::Sink, GenericWriter::Sink);
+        state.report = Some(Box::new(SharedWriter(report_buf.clone())));
+        state.report_candidates = true;
+        let stats = process_batch(batch, &mut state, &opts, 0).unwrap();
+        assert_eq!((stats.removed, stats.kept), (1, 1));
+
+        let report = String::from_utf8(report_buf.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            report,
+            "second:AAAACCCC,ACGTACGT\ttrue\tACGTACGT\nneither:AAAACCCC,GGGGTTTT\tfalse\tNA\n"
+        );
+    }
+
+    #[test]
+    fn test_per_read_report_shows_reverse_strand_matches() {
+        // The UMI ACGGTTCA only occurs as its reverse complement TGAACCGT
+        let batch = vec![
+            fastq_read(b"fwd:ACGGTTCA", b"TTACGGTTCATTTT"),
+            fastq_read(b"rev:ACGGTTCA", b"CCTGAACCGTCCCCCC"),
+            fastq_read(b"none:ACGGTTCA", b"CCCCCCCCCCCC"),
+        ];
+        let opts = ProcessOptions {
+            umi_len: 8,
+            check_revcomp: true,
+            per_read_report: Some(PathBuf::from("unused")),
+            ..Default::default()
+        };
+        let report_buf = Arc::new(Mutex::new(Vec::new()));
+        let mut state = RunState::new(GenericWriter::Sink, GenericWriter::Sink);
+        state.report = Some(Box::new(SharedWriter(report_buf.clone())));
+        state.report_strand = true;
+        let stats = process_batch(batch, &mut state, &opts, 0).unwrap();
+        assert_eq!((stats.removed, stats.kept), (2, 1));
+
+        // Read offsets 2..10 on the forward strand are 6..14 on the reverse
+        let report = String::from_utf8(report_buf.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            report,
+            "fwd:ACGGTTCA\ttrue\t+\t2\nrev:ACGGTTCA\ttrue\t-\t6\nnone:ACGGTTCA\tfalse\tNA\tNA\n"
+        );
+
+        let without = ProcessOptions {
+            check_revcomp: false,
+            ..opts
+        };
+        let batch = vec![fastq_read(b"rev:ACGGTTCA", b"CCTGAACCGTCCCCCC")];
+        let (stats, _) = run_batch(batch, &without);
+        assert_eq!(stats.removed, 0);
+    }
+
+    #[test]
+    fn test_estimated_umi_duplication_rate() {
+        // 2000 reads carrying 800 distinct UMIs: 1200 repeats (60%)
+        let umi = |i: usize| -> String {
+            (0..12)
+                .map(|k| ['A', 'C', 'G', 'T'][(i >> (2 * k)) & 3])
+                .collect()
+        };
+        let batch: Vec<FastqRecord> = (0..2000)
+            .map(|i| {
+                fastq_read(
+                    format!("r{}:{}", i, umi(i % 800)).as_bytes(),
+                    b"TTTTTTTTTTTTTTTT",
+                )
+            })
+            .collect();
+        let opts = ProcessOptions {
+            estimate_dup_rate: Some(0.01),
+            ..Default::default()
+        };
+        let mut state = RunState::new(GenericWriter::Sink, GenericWriter::Sink);
+        state.umi_filter = Some(BloomFilter::new(10_000, 0.01));
+        process_batch(batch, &mut state, &opts, 0).unwrap();
+
+        let (extracted, repeated) = state.umi_repeats;
+        assert_eq!(extracted, 2000);
+        // False positives can only add repeats, at about 1% of the new UMIs
+        assert!((1200..=1220).contains(&repeated), "{} repeats", repeated);
+    }
+
+    #[test]
+    fn test_duplicate_read_ids_are_counted() {
+        let batch = || {
+            ["dup:ACGTACGT 1:N", "other:ACGTACGT", "dup:ACGTACGT 2:N"]
+                .iter()
+                .map(|head| fastq_read(head.as_bytes(), b"TTACGTACGTTT"))
+                .collect::<Vec<_>>()
+        };
+        let mut opts = ProcessOptions {
+            umi_len: 8,
+            check_duplicate_ids: true,
+            ..Default::default()
+        };
+
+        let (stats, _) = run_batch(batch(), &opts);
+        assert_eq!(stats.duplicate_id, 1);
+
+        opts.strict = true;
+        let mut state = RunState::new(GenericWriter::Sink, GenericWriter::Sink);
+        let err = process_batch(batch(), &mut state, &opts, 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Duplicate read ID 'dup:ACGTACGT' (record 3)"
+        );
+    }
+
+    #[test]
+    fn test_tolerate_n_in_umi() {
+        let batch = || vec![fastq_read(b"read:ACGTNCGT", b"TTACGTACGTTT")];
+        let mut opts = ProcessOptions {
+            umi_len: 8,
+            ..Default::default()
+        };
+        let (stats, _) = run_batch(batch(), &opts);
+        assert_eq!((stats.removed, stats.kept), (0, 1));
+
+        opts.tolerate_n_in_umi = true;
+        let (stats, _) = run_batch(batch(), &opts);
+        assert_eq!((stats.removed, stats.kept), (1, 0));
+    }
 }
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+/// Approximate counts of the most frequent byte strings in a stream, kept in
+/// memory bounded by the number of counters rather than the number of
+/// distinct items (the Misra-Gries algorithm).
+///
+/// Every item seen more than `seen / (counters + 1)` times is guaranteed to
+/// have a counter. Counts are lower bounds, short by at most
+/// `max_undercount`.
+#[derive(Debug, Clone)]
+pub struct TopK {
+    counts: HashMap<Vec<u8>, usize>,
+    counters: usize,
+    seen: usize,
+}
+
+impl TopK {
+    /// Create a tracker with at most `counters` counters.
+    pub fn new(counters: usize) -> Self {
+        Self {
+            counts: HashMap::with_capacity(counters + 1),
+            counters: counters.max(1),
+            seen: 0,
+        }
+    }
+
+    /// Count one occurrence of `item`.
+    pub fn insert(&mut self, item: &[u8]) {
+        self.seen += 1;
+        if let Some(count) = self.counts.get_mut(item) {
+            *count += 1;
+        } else if self.counts.len() < self.counters {
+            self.counts.insert(item.to_vec(), 1);
+        } else {
+            // The new item and one occurrence of every tracked item cancel
+            // out; each such step removes `counters + 1` occurrences, so
+            // there are few of them
+            self.counts.retain(|_, count| {
+                *count -= 1;
+                *count > 0
+            });
+        }
+    }
+
+    /// Number of items counted.
+    pub fn seen(&self) -> usize {
+        self.seen
+    }
+
+    /// Upper bound on how far any reported count is below the true count.
+    pub fn max_undercount(&self) -> usize {
+        let tracked: usize = self.counts.values().sum();
+        (self.seen - tracked) / (self.counters + 1)
+    }
+
+    /// The `k` items with the highest counts, most frequent first; ties are
+    /// ordered by item.
+    pub fn top(&self, k: usize) -> Vec<(&[u8], usize)> {
+        let mut top: Vec<(&[u8], usize)> = self
+            .counts
+            .iter()
+            .map(|(item, &count)| (item.as_slice(), count))
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        top.truncate(k);
+        top
+    }
+
+    /// An owned copy of the `k` most frequent items, to report once counting
+    /// is done.
+    pub fn snapshot(&self, k: usize) -> TopItems {
+        TopItems {
+            items: self
+                .top(k)
+                .into_iter()
+                .map(|(item, count)| (item.to_vec(), count))
+                .collect(),
+            seen: self.seen,
+            max_undercount: self.max_undercount(),
+        }
+    }
+}
+
+/// The most frequent items of a `TopK`, taken with `TopK::snapshot`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TopItems {
+    /// Items and their approximate counts, most frequent first.
+    pub items: Vec<(Vec<u8>, usize)>,
+    /// Number of items counted.
+    pub seen: usize,
+    /// Upper bound on how far any count is below the true count.
+    pub max_undercount: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_top_k_finds_dominant_item() {
+        let mut top = TopK::new(4);
+        for i in 0..1000u32 {
+            // Every third item is the dominant one, the rest are all distinct
+            if i % 3 == 0 {
+                top.insert(b"ACGTACGT");
+            } else {
+                top.insert(format!("other{}", i).as_bytes());
+            }
+        }
+        assert_eq!(top.seen(), 1000);
+        let best = top.top(2);
+        assert_eq!(best[0].0, b"ACGTACGT");
+        let true_count = 334;
+        assert!(best[0].1 <= true_count && best[0].1 + top.max_undercount() >= true_count);
+    }
+
+    #[test]
+    fn test_top_k_is_exact_below_capacity() {
+        let mut top = TopK::new(10);
+        for item in [&b"AA"[..], b"CC", b"AA", b"GG", b"CC", b"AA"] {
+            top.insert(item);
+        }
+        assert_eq!(top.max_undercount(), 0);
+        assert_eq!(top.top(2), [(&b"AA"[..], 3), (&b"CC"[..], 2)]);
+        assert_eq!(
+            top.snapshot(1),
+            TopItems {
+                items: vec![(b"AA".to_vec(), 3)],
+                seen: 6,
+                max_undercount: 0,
+            }
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use std::path::Path;
 use tempfile::tempdir;
 use tempfile::NamedTempFile;
+use umi_checker::processing::{ProcessOptions, ProcessStats};
 
 #[test]
 fn test_process_fastq_integration() {
@@ -9,12 +10,20 @@ fn test_process_fastq_integration() {
     let removed_tmp = NamedTempFile::new().expect("create temp file");
 
     // Call processing function
-    let (total, with_umi, without_umi) = umi_checker::processing::process_fastq(
+    let ProcessStats {
+        total,
+        removed: with_umi,
+        kept: without_umi,
+        ..
+    } = umi_checker::processing::process_fastq(
         &data_path,
         Some(matched_tmp.path()),
         Some(removed_tmp.path()),
-        1, // allow 1 mismatch
-        12,
+        &ProcessOptions {
+            max_mismatches: 1, // allow 1 mismatch
+            umi_len: 12,
+            ..Default::default()
+        },
     )
     .expect("processing failed");
 
@@ -31,12 +40,20 @@ fn test_process_bam_integration() {
     let removed_tmp = NamedTempFile::new().expect("create temp file");
 
     // Call processing function
-    let (total, with_umi, without_umi) = umi_checker::processing::process_bam(
+    let ProcessStats {
+        total,
+        removed: with_umi,
+        kept: without_umi,
+        ..
+    } = umi_checker::processing::process_bam(
         &data_path,
         Some(matched_tmp.path()),
         Some(removed_tmp.path()),
-        2, // allow 2 mismatches
-        12,
+        &ProcessOptions {
+            max_mismatches: 2, // allow 2 mismatches
+            umi_len: 12,
+            ..Default::default()
+        },
     )
     .expect("processing failed");
 
@@ -196,9 +213,17 @@ fn test_process_fastq_empty_input_creates_empty_kept() -> Result<(), Box<dyn std
     let matched = tmp.path().join("matched.fq");
     let removed = tmp.path().join("removed.fq");
 
-    let (total, with_umi, without_umi) =
-        umi_checker::processing::process_fastq(input.path(), Some(&matched), Some(&removed), 1, 12)
-            .expect("processing failed");
+    let opts = ProcessOptions {
+        max_mismatches: 1,
+        ..Default::default()
+    };
+    let ProcessStats {
+        total,
+        removed: with_umi,
+        kept: without_umi,
+        ..
+    } = umi_checker::processing::process_fastq(input.path(), Some(&matched), Some(&removed), &opts)
+        .expect("processing failed");
 
     assert_eq!(total, 0);
     assert_eq!(with_umi, 0);
@@ -220,9 +245,17 @@ fn test_process_bam_empty_input_creates_kept() -> Result<(), Box<dyn std::error:
     let matched = tmp.path().join("matched.bam");
     let removed = tmp.path().join("removed.bam");
 
-    let (total, with_umi, without_umi) =
-        umi_checker::processing::process_bam(&input_path, Some(&matched), Some(&removed), 1, 12)
-            .expect("processing failed");
+    let opts = ProcessOptions {
+        max_mismatches: 1,
+        ..Default::default()
+    };
+    let ProcessStats {
+        total,
+        removed: with_umi,
+        kept: without_umi,
+        ..
+    } = umi_checker::processing::process_bam(&input_path, Some(&matched), Some(&removed), &opts)
+        .expect("processing failed");
 
     assert_eq!(total, 0);
     assert_eq!(with_umi, 0);
@@ -237,6 +270,89 @@ fn test_process_bam_empty_input_creates_kept() -> Result<(), Box<dyn std::error:
     Ok(())
 }
 
+#[test]
+fn test_process_bam_outputs_have_independent_headers() -> Result<(), Box<dyn std::error::Error>> {
+    use rust_htslib::bam::{self, Read};
+    let data_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/example.bam");
+    let tmp = tempdir()?;
+    let matched = tmp.path().join("matched.bam");
+    let removed = tmp.path().join("removed.bam");
+
+    let opts = ProcessOptions {
+        max_mismatches: 2,
+        umi_len: 12,
+        ..Default::default()
+    };
+    let stats =
+        umi_checker::processing::process_bam(&data_path, Some(&matched), Some(&removed), &opts)?;
+    assert!(stats.removed > 0 && stats.kept > 0);
+
+    let input = bam::Reader::from_path(&data_path)?;
+    let expected = input.header().as_bytes().to_vec();
+    // Records were routed to both writers; each file carries a complete copy
+    // of the input header and decodes to its own share of the records
+    for (path, records) in [(&removed, stats.removed), (&matched, stats.kept)] {
+        let mut reader = bam::Reader::from_path(path)?;
+        assert_eq!(reader.header().as_bytes(), &expected[..]);
+        assert_eq!(
+            reader.header().target_count(),
+            input.header().target_count()
+        );
+        assert_eq!(
+            reader.records().collect::<Result<Vec<_>, _>>()?.len(),
+            records
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_process_bam_counts_reads_and_pairs_separately() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // Only the first mate of each pair holds its UMI; a secondary alignment
+    // of a mate is not a third mate
+    let input = tmp.path().join("pairs.sam");
+    std::fs::write(
+        &input,
+        "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100\n\
+         p1:ACGTACGTAC\t65\tchr1\t1\t60\t16M\tchr1\t50\t0\tTTACGTACGTACTTTT\tIIIIIIIIIIIIIIII\n\
+         p1:ACGTACGTAC\t129\tchr1\t50\t60\t16M\tchr1\t1\t0\tTTTTTTTTTTTTTTTT\tIIIIIIIIIIIIIIII\n\
+         p2:GGCCAATTGG\t65\tchr1\t5\t60\t16M\tchr1\t60\t0\tAAGGCCAATTGGAAAA\tIIIIIIIIIIIIIIII\n\
+         p2:GGCCAATTGG\t385\tchr1\t70\t0\t16M\tchr1\t5\t0\tTTTTTTTTTTTTTTTT\tIIIIIIIIIIIIIIII\n\
+         p2:GGCCAATTGG\t129\tchr1\t60\t60\t16M\tchr1\t5\t0\tTTTTTTTTTTTTTTTT\tIIIIIIIIIIIIIIII\n",
+    )?;
+
+    let stats = umi_checker::processing::process_bam(
+        &input,
+        None,
+        None,
+        &ProcessOptions {
+            umi_len: 10,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!((stats.total, stats.removed), (5, 2));
+    assert_eq!((stats.pairs, stats.pairs_with_umi), (2, 2));
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("--umi-length")
+        .arg("10")
+        .arg("--verbose");
+    cmd.assert().success().stdout(predicate::str::contains(
+        "pairs: UMI found in 40.00% of reads and in at least one mate of 100.00% of 2 pairs",
+    ));
+
+    Ok(())
+}
+
 #[test]
 fn test_main_cli_custom_threads() -> Result<(), Box<dyn std::error::Error>> {
     use assert_cmd::assert::OutputAssertExt;
@@ -284,3 +400,2511 @@ fn test_main_cli_custom_umi_length() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[test]
+fn test_main_cli_umi_field_with_trailing_count() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("counted.fastq");
+    std::fs::write(
+        &input,
+        b"@read1:ACGTACGTACGT:3\nGGGGACGTACGTACGTGGGG\n+\nIIIIIIIIIIIIIIIIIIII\n\
+          @read2:TTTTCCCCGGGG:7\nACACACACACACACACACAC\n+\nIIIIIIIIIIIIIIIIIIII\n",
+    )?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&input).arg("--umi-field").arg("-2");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("counted.fastq\t2\t1"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_strict_fails_on_malformed_header() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("malformed.fastq");
+    std::fs::write(
+        &input,
+        b"@read1:ACGTACGTACGT\nGGGGACGTACGTACGTGGGG\n+\nIIIIIIIIIIIIIIIIIIII\n\
+          @read2:ACGT\nACACACACACACACACACAC\n+\nIIIIIIIIIIIIIIIIIIII\n",
+    )?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&input).arg("--strict");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("read2:ACGT").and(predicate::str::contains("record 2")));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_wrong_umi_length_counts_as_without_umi() -> Result<(), Box<dyn std::error::Error>>
+{
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("malformed.fastq");
+    let out_prefix = tmp.path().join("out");
+    std::fs::write(
+        &input,
+        b"@read1:ACGTACGTACGT\nGGGGACGTACGTACGTGGGG\n+\nIIIIIIIIIIIIIIIIIIII\n\
+          @read2:ACGT\nACACACACACACACACACAC\n+\nIIIIIIIIIIIIIIIIIIII\n",
+    )?;
+
+    // Outside --strict the 4bp UMI of read2 is skipped, not fatal
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&input).arg("-o").arg(&out_prefix);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "malformed.fastq\t2\t1\t50.00\t1\t50.00",
+        ))
+        .stderr(predicate::str::contains("panicked").not());
+
+    let kept = std::fs::read_to_string(tmp.path().join("out.fq"))?;
+    assert_eq!(kept.lines().next(), Some("@read2:ACGT"));
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&input).arg("--verbose");
+    cmd.assert().success().stdout(predicate::str::contains(
+        "malformed read categories: no_umi 1, shorter_than_umi 0",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_reports_duplicate_read_ids() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("dups.fastq");
+    std::fs::write(
+        &input,
+        "@r1:ACGTACGT 1:N\nTTACGTACGTTT\n+\nIIIIIIIIIIII\n\
+         @r2:ACGTACGT\nTTTTTTTTTTTT\n+\nIIIIIIIIIIII\n\
+         @r1:ACGTACGT 2:N\nTTACGTACGTTT\n+\nIIIIIIIIIIII\n",
+    )?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("--check-duplicate-ids")
+        .arg("--verbose");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("dups.fastq\t3\t2\t"))
+        .stdout(predicate::str::contains("duplicate_id 1"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_resume_after_interrupted_run() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::io::Read;
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+    use umi_checker::processing::Checkpoint;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("reads.fq");
+    let records = 20_000;
+    // Every other read carries the UMI from its header
+    let fastq: String = (0..records)
+        .map(|i| {
+            let seq = if i % 2 == 0 {
+                "GGGGACGTACGTACGTGGGG"
+            } else {
+                "ACACACACACACACACACAC"
+            };
+            format!("@read{}:ACGTACGTACGT\n{}\n+\n{}\n", i, seq, "I".repeat(20))
+        })
+        .collect();
+    std::fs::write(&input, fastq)?;
+    let run = |prefix: &Path| {
+        let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+        cmd.arg("-i")
+            .arg(&input)
+            .arg("-o")
+            .arg(prefix)
+            .args(["--output-compression", "gzip"]);
+        cmd
+    };
+    let gunzip = |path: &Path| -> std::io::Result<String> {
+        let mut content = String::new();
+        flate2::read::MultiGzDecoder::new(std::fs::File::open(path)?)
+            .read_to_string(&mut content)?;
+        Ok(content)
+    };
+
+    // Uninterrupted reference run
+    run(&tmp.path().join("full")).assert().success();
+
+    // Kill a checkpointed run once it wrote its first checkpoint; with
+    // --max-memory 0 every batch holds a single read, so it is far from done
+    let prefix = tmp.path().join("out");
+    let checkpoint = tmp.path().join("out.checkpoint");
+    let mut child = run(&prefix)
+        .args(["--checkpoint", "--max-memory", "0"])
+        .spawn()?;
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !checkpoint.exists() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    child.kill()?;
+    child.wait()?;
+    let interrupted = Checkpoint::load(&checkpoint)?;
+    assert!(interrupted.records > 0 && interrupted.records < records);
+
+    run(&prefix).arg("--resume").assert().success();
+
+    for (resumed, full) in [
+        ("out.fq.gz", "full.fq.gz"),
+        ("out.removed.fq.gz", "full.removed.fq.gz"),
+    ] {
+        assert_eq!(
+            gunzip(&tmp.path().join(resumed))?,
+            gunzip(&tmp.path().join(full))?
+        );
+    }
+    let done = Checkpoint::load(&checkpoint)?;
+    assert_eq!(
+        (done.records, done.removed, done.kept),
+        (records, records / 2, records / 2)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_rejects_checkpoint_for_bam_input() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("reads.sam");
+    std::fs::write(&input, "@HD\tVN:1.6\n")?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-o")
+        .arg(tmp.path().join("out"))
+        .arg("--resume");
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "--checkpoint and --resume only support FASTQ input",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_fixed_umi() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("no_umi_headers.fastq");
+    // Headers carry no UMI at all; the barcode is given on the command line
+    std::fs::write(
+        &input,
+        b"@read1\nGGGGACGTACGTACGTGGGG\n+\nIIIIIIIIIIIIIIIIIIII\n\
+          @read2\nACACACACACACACACACAC\n+\nIIIIIIIIIIIIIIIIIIII\n\
+          @read3\nACGTACGTACGTTTTTTTTT\n+\nIIIIIIIIIIIIIIIIIIII\n",
+    )?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&input).arg("--umi").arg("ACGTACGTACGT");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("no_umi_headers.fastq\t3\t2\t"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_mixed_umi_lengths() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("mixed.fastq");
+    // A 10bp and a 12bp UMI that are present, and a 12bp UMI that is not
+    std::fs::write(
+        &input,
+        b"@read1:ACGTACGTAC\nGGGGACGTACGTACGGGG\n+\nIIIIIIIIIIIIIIIIII\n\
+          @read2:TTGGCCAATTGG\nAATTGGCCAATTGGAA\n+\nIIIIIIIIIIIIIIII\n\
+          @read3:CCCCCCCCCCCC\nAAAAAAAAAAAAAAAA\n+\nIIIIIIIIIIIIIIII\n",
+    )?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("10")
+        .arg("--umi-length-max")
+        .arg("12");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("mixed.fastq\t3\t2\t"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_compare_baseline_counts_flips() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("flips.fastq");
+    // read1 matches exactly, read2 only with one mismatch, read3 never
+    std::fs::write(
+        &input,
+        b"@read1:ACGTACGT\nGGACGTACGTGG\n+\nIIIIIIIIIIII\n\
+          @read2:ACGTACGT\nGGACGTACCTGG\n+\nIIIIIIIIIIII\n\
+          @read3:ACGTACGT\nTTTTTTTTTTTT\n+\nIIIIIIIIIIII\n",
+    )?;
+    let baseline = tmp.path().join("baseline.tsv");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("--per-read-report")
+        .arg(&baseline);
+    cmd.assert().success();
+    assert_eq!(
+        std::fs::read_to_string(&baseline)?,
+        "read_id\tfound\nread1:ACGTACGT\ttrue\nread2:ACGTACGT\tfalse\nread3:ACGTACGT\tfalse\n"
+    );
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("-m")
+        .arg("1")
+        .arg("--compare-baseline")
+        .arg(&baseline);
+    cmd.assert().success().stderr(predicate::str::contains(
+        "0 found->not-found, 1 not-found->found",
+    ));
+
+    // A gzipped baseline is decompressed when loaded
+    let gz_baseline = tmp.path().join("baseline.tsv.gz");
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("--per-read-report")
+        .arg(&gz_baseline);
+    cmd.assert().success();
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("-m")
+        .arg("1")
+        .arg("--compare-baseline")
+        .arg(&gz_baseline);
+    cmd.assert().success().stderr(predicate::str::contains(
+        "0 found->not-found, 1 not-found->found",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_single_cell_counts_per_cell() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let table = tmp.path().join("cells.tsv");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg("tests/data/single_cell.sam")
+        .arg("--single-cell")
+        .arg("--tag-counts")
+        .arg(&table);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("single_cell.sam\t4\t3\t"));
+
+    let content = std::fs::read_to_string(&table)?;
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "CB\ttotal\twith_umi\tperc_with\twithout_umi\tperc_without",
+            "AAACCC-1\t2\t1\t50.00\t1\t50.00",
+            "GGGTTT-1\t2\t2\t100.00\t0\t0.00",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_mixed_fastq_and_bam_inputs() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let out_prefix = tmp.path().join("mixed");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg("tests/data/example.fastq")
+        .arg("tests/data/example.bam")
+        .arg("-m")
+        .arg("2")
+        .arg("-o")
+        .arg(&out_prefix);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("example.fastq\t3\t2\t"))
+        .stdout(predicate::str::contains("example.bam\t17619\t76\t"));
+
+    // Each input is written in its own format under its own prefix
+    let fastq = tmp.path().join("mixed.example.removed.fq");
+    assert!(std::fs::read_to_string(&fastq)?.starts_with('@'));
+    let bam = tmp.path().join("mixed.example.removed.bam");
+    let mut magic = [0u8; 2];
+    std::io::Read::read_exact(&mut std::fs::File::open(&bam)?, &mut magic)?;
+    assert_eq!(magic, [0x1f, 0x8b]);
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_scan_only_counts_occurrences() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("planted.fastq");
+    // The UMI is planted three, one and zero times
+    std::fs::write(
+        &input,
+        b"@three:ACGTACGT\nACGTACGTTTACGTACGTTTACGTACGT\n+\nIIIIIIIIIIIIIIIIIIIIIIIIIIII\n\
+          @one:ACGTACGT\nTTTTTTTTTTACGTACGTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIIIIIIIIIIIIIII\n\
+          @zero:ACGTACGT\nTTTTTTTTTTTTTTTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIIIIIIIIIIIIIII\n",
+    )?;
+    let table = tmp.path().join("scan.tsv");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("--scan-only")
+        .arg(&table);
+    cmd.assert().success();
+
+    assert_eq!(
+        std::fs::read_to_string(&table)?,
+        "read_id\tumi\toccurrences_in_read\n\
+         three:ACGTACGT\tACGTACGT\t3\n\
+         one:ACGTACGT\tACGTACGT\t1\n\
+         zero:ACGTACGT\tACGTACGT\t0\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_output_compression_follows_input_or_prefix(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::io::Write;
+    use std::process::Command;
+
+    let is_gzip =
+        |p: &Path| -> std::io::Result<bool> { Ok(std::fs::read(p)?.starts_with(&[0x1f, 0x8b])) };
+    let tmp = tempdir()?;
+
+    // Gzipped input -> gzipped output
+    let gz_input = tmp.path().join("input.fastq.gz");
+    let mut enc = flate2::write::GzEncoder::new(
+        std::fs::File::create(&gz_input)?,
+        flate2::Compression::default(),
+    );
+    enc.write_all(&std::fs::read("tests/data/example.fastq")?)?;
+    enc.finish()?;
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&gz_input)
+        .arg("-o")
+        .arg(tmp.path().join("from_gz"));
+    cmd.assert().success();
+    assert!(is_gzip(&tmp.path().join("from_gz.fq.gz"))?);
+    assert!(is_gzip(&tmp.path().join("from_gz.removed.fq.gz"))?);
+
+    // Plain input with a .gz output prefix -> gzipped output
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg("tests/data/example.fastq")
+        .arg("-o")
+        .arg(tmp.path().join("from_plain.fq.gz"));
+    cmd.assert().success();
+    assert!(is_gzip(&tmp.path().join("from_plain.fq.gz"))?);
+    assert!(is_gzip(&tmp.path().join("from_plain.removed.fq.gz"))?);
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_list_supported_formats() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("--list-supported-formats");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("fastq\t.fq,.fastq\t.fq"))
+        .stdout(predicate::str::contains(
+            "fastq.gz\t.fq.gz,.fastq.gz\t.fq.gz",
+        ))
+        .stdout(predicate::str::contains("bam\t.bam\t.bam"))
+        .stdout(predicate::str::contains("sam\t.sam\t.sam"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_output_format_sam() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let out_prefix = tmp.path().join("as_sam");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg("tests/data/example.bam")
+        .arg("-m")
+        .arg("2")
+        .arg("-o")
+        .arg(&out_prefix)
+        .arg("--output-format")
+        .arg("sam");
+    cmd.assert().success();
+
+    // Plain text: header lines first, then one line per record
+    let removed = std::fs::read_to_string(tmp.path().join("as_sam.removed.sam"))?;
+    assert!(removed.starts_with("@HD") || removed.starts_with("@SQ"));
+    assert_eq!(removed.lines().filter(|l| !l.starts_with('@')).count(), 76);
+    let kept = std::fs::read_to_string(tmp.path().join("as_sam.sam"))?;
+    assert_eq!(kept.lines().filter(|l| !l.starts_with('@')).count(), 17543);
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_summary_to_file() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let summary = tmp.path().join("summary.tsv");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg("tests/data/example.fastq")
+        .arg("-m")
+        .arg("1")
+        .arg("--summary-to")
+        .arg(&summary);
+    cmd.assert().success().stdout(predicate::str::is_empty());
+
+    assert!(std::fs::read_to_string(&summary)?.starts_with("example.fastq\t3\t2\t"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_region_counts_only_overlapping_reads() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use rust_htslib::bam::{self, Read};
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("example.bam");
+    std::fs::copy("tests/data/example.bam", &input)?;
+
+    // Without an index the region cannot be fetched
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("--region")
+        .arg("NC_038709.1:1000-2000");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("requires a .bai or .csi index"));
+
+    bam::index::build(&input, None, bam::index::Type::Bai, 1)?;
+
+    // Reads overlapping the (1-based, inclusive) region
+    let mut expected = 0;
+    let mut reader = bam::Reader::from_path(&input)?;
+    for rec in reader.records() {
+        let rec = rec?;
+        let end = rec.cigar().end_pos().max(rec.pos() + 1);
+        if rec.tid() == 0 && rec.pos() < 2000 && end > 999 {
+            expected += 1;
+        }
+    }
+    assert!(expected > 0 && expected < 17619);
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("--region")
+        .arg("NC_038709.1:1000-2000");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "example.bam\t{}\t",
+            expected
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_match_bed_reports_reference_interval() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("aligned.sam");
+    let bed = tmp.path().join("matches.bed");
+    let qual = "I".repeat(32);
+    let sam = format!(
+        "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+         r1:ACGTACGTACGT\t0\tchr1\t101\t60\t12S20M\t*\t0\t0\tACGTACGTACGT{g20}\t{qual}\n\
+         r2:CCCCAAAATTTT\t16\tchr1\t201\t60\t10M2D22M\t*\t0\t0\t{g14}CCCCAAAATTTT{g6}\t{qual}\n\
+         r3:ACACACACACAC\t0\tchr1\t301\t60\t32M\t*\t0\t0\t{g32}\t{qual}\n",
+        g20 = "G".repeat(20),
+        g14 = "G".repeat(14),
+        g6 = "G".repeat(6),
+        g32 = "G".repeat(32),
+        qual = qual,
+    );
+    std::fs::write(&input, sam)?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&input).arg("--match-bed").arg(&bed);
+    cmd.assert().success();
+
+    // r1: the UMI fills the soft clip in front of position 100 (0-based);
+    // r2: the UMI starts 4 bases after the 2 bp deletion
+    let content = std::fs::read_to_string(&bed)?;
+    assert_eq!(
+        content.lines().collect::<Vec<_>>(),
+        [
+            "chr1\t88\t100\tr1:ACGTACGTACGT\t0\t+",
+            "chr1\t216\t228\tr2:CCCCAAAATTTT\t0\t-",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_closed_stdout_exits_cleanly() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::cargo;
+    use std::process::{Command, Stdio};
+
+    let data_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/example.fastq");
+    let mut child = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")))
+        .arg("-i")
+        .arg(&data_path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Close the read end before the summary is written, like `head -0`
+    drop(child.stdout.take());
+
+    let output = child.wait_with_output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert!(!stderr.contains("panicked"), "stderr: {}", stderr);
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_diagnose_headers() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let data_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/example.fastq");
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&data_path).arg("--diagnose-headers");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("Sampled headers: 3"))
+        .stderr(predicate::str::contains(
+            "Inferred UMI: field -1 in 3 headers (100.00%), length 12",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_warns_about_slow_reads() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("huge.fastq");
+    // Every window of the huge read matches a pigeonhole chunk of the UMI, so
+    // each one needs the full distance check
+    let huge = "A".repeat(2_000_000);
+    std::fs::write(
+        &input,
+        format!(
+            "@short:AAAAAACCCCCC\nAAAAAACCCCCC\n+\n{}\n@huge:AAAAAACCCCCC\n{}\n+\n{}\n",
+            "I".repeat(12),
+            huge,
+            "I".repeat(huge.len())
+        ),
+    )?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-m")
+        .arg("1")
+        .arg("--warn-slow-threshold")
+        .arg("1");
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: read 'huge:AAAAAACCCCCC' (2000000 bp) took",
+        ))
+        .stderr(predicate::str::contains("'short:").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_flag_filters_restrict_bam_records() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use rust_htslib::bam::{self, Read};
+    use std::process::Command;
+
+    let mut total = 0;
+    let mut primary = 0;
+    let mut proper_primary = 0;
+    let mut reader = bam::Reader::from_path("tests/data/example.bam")?;
+    for rec in reader.records() {
+        let flags = rec?.flags();
+        total += 1;
+        if flags & 0x900 == 0 {
+            primary += 1;
+            if flags & 0x2 != 0 {
+                proper_primary += 1;
+            }
+        }
+    }
+    assert!(proper_primary < primary && primary < total);
+
+    let run = |args: &[&str], expected: usize| {
+        let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+        cmd.arg("-i").arg("tests/data/example.bam").args(args);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(format!(
+                "example.bam\t{}\t",
+                expected
+            )));
+    };
+    // No secondary reads in the example: excluding them changes nothing
+    run(&["-F", "0x100"], total);
+    // Excluding secondary and supplementary reads
+    run(&["-F", "0x900"], primary);
+    run(&["-f", "2", "--exclude-flags", "2304"], proper_primary);
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_check_install() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("--check-install");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("PASS\tmatcher"))
+        .stdout(predicate::str::contains("PASS\tthreads"))
+        .stdout(predicate::str::contains("PASS\tfastq"))
+        .stdout(predicate::str::contains("PASS\thtslib"))
+        .stdout(predicate::str::contains("FAIL").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_qc_table() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let table = tmp.path().join("reads.tsv");
+    let data_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/example.fastq");
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&data_path).arg("--qc-table").arg(&table);
+    cmd.assert().success();
+
+    let content = std::fs::read_to_string(&table)?;
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(lines[0], "read_id\tumi\tfound\tmismatches\tread_length");
+    // One row per read of the example file, 2 of 3 with their UMI
+    assert_eq!(lines.len(), 4);
+    assert!(lines[1..].iter().all(|l| l.split('\t').count() == 5));
+    assert_eq!(lines[1], "read1:ACGTACGTACGT\tACGTACGTACGT\ttrue\t0\t16");
+    assert_eq!(
+        lines[1..].iter().filter(|l| l.contains("\ttrue\t")).count(),
+        2
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_error_table() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // r1 holds its UMI with A->G at UMI position 2, r2 exactly, r3 not at all
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(
+        &input,
+        "@r1:CTAGCTAG\nGGCTGGCTAGGG\n+\nIIIIIIIIIIII\n\
+         @r2:CTAGCTAG\nGGCTAGCTAGGG\n+\nIIIIIIIIIIII\n\
+         @r3:CTAGCTAG\nTTTTTTTTTTTT\n+\nIIIIIIIIIIII\n",
+    )?;
+    let table = tmp.path().join("errors.tsv");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("-m")
+        .arg("1")
+        .arg("--error-table")
+        .arg(&table);
+    cmd.assert().success();
+
+    let content = std::fs::read_to_string(&table)?;
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "read_id\tumi\twindow\tmismatches\tsubstitutions",
+            "r1:CTAGCTAG\tCTAGCTAG\tCTGGCTAG\t1\t2:A>G",
+            "r2:CTAGCTAG\tCTAGCTAG\tCTAGCTAG\t0\tNA",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_fill_missing_quality() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // FASTA records parse fine but carry no qualities
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(
+        &input,
+        ">r1:ACGTACGTACGT\nTTACGTACGTACGTTT\n>r2:ACGTACGTACGT\nTTTTTTTTTT\n",
+    )?;
+    let out = tmp.path().join("out");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-o")
+        .arg(&out)
+        .arg("--fill-missing-quality");
+    cmd.assert().success();
+
+    for path in [tmp.path().join("out.fq"), tmp.path().join("out.removed.fq")] {
+        let content = std::fs::read_to_string(&path)?;
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", path.display());
+        assert_eq!(lines[3].len(), lines[1].len());
+        assert!(lines[3].bytes().all(|q| q == b'I'));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_umi_whole_header() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // The read name is exactly the 12bp UMI
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(
+        &input,
+        "@ACGTACGTACGT 1:N:0\nTTACGTACGTACGTTT\n+\nIIIIIIIIIIIIIIII\n@GGGGCCCCAAAA\nTTTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIII\n",
+    )?;
+    let out = tmp.path().join("out");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-o")
+        .arg(&out)
+        .arg("--umi-whole-header");
+    cmd.assert().success();
+
+    let removed = std::fs::read_to_string(tmp.path().join("out.removed.fq"))?;
+    assert!(removed.starts_with("@ACGTACGTACGT"));
+    assert_eq!(removed.lines().count(), 4);
+    let kept = std::fs::read_to_string(tmp.path().join("out.fq"))?;
+    assert!(kept.starts_with("@GGGGCCCCAAAA"));
+
+    Ok(())
+}
+
+#[cfg(feature = "ipc")]
+#[test]
+fn test_main_cli_ipc_socket() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let socket = tmp.path().join("summary.sock");
+    let listener = UnixListener::bind(&socket)?;
+    let server = std::thread::spawn(move || -> std::io::Result<Vec<String>> {
+        let (stream, _) = listener.accept()?;
+        BufReader::new(stream).lines().collect()
+    });
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg("tests/data/example.fastq")
+        .arg("--ipc-socket")
+        .arg(&socket);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::starts_with("example.fastq\t"));
+
+    let lines = server.join().unwrap()?;
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with("{\"file\":\"example.fastq\",\"total\":"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_truncated_bam() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // Drop the 28-byte BGZF end-of-file block
+    let bam = std::fs::read("tests/data/example.bam")?;
+    let input = tmp.path().join("truncated.bam");
+    std::fs::write(&input, &bam[..bam.len() - 28])?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&input);
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("may be truncated"));
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&input).arg("--strict");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("no BGZF end-of-file marker"));
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg("tests/data/example.bam");
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("truncated").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_downsample_to() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // 50 reads without their UMI and one with it
+    let mut fastq = String::from("@hit:ACGTACGTACGT\nTTACGTACGTACGTTT\n+\nIIIIIIIIIIIIIIII\n");
+    for i in 0..50 {
+        fastq.push_str(&format!(
+            "@r{}:ACGTACGTACGT\nTTTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIII\n",
+            i
+        ));
+    }
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(&input, fastq)?;
+
+    let sample = |out: &str, seed: u64| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+        cmd.arg("-i")
+            .arg(&input)
+            .arg("-o")
+            .arg(tmp.path().join(out))
+            .arg("--downsample-to")
+            .arg("10")
+            .arg("--seed")
+            .arg(seed.to_string());
+        // Counts still cover every read
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("reads.fastq\t51\t1"));
+        let kept = std::fs::read_to_string(tmp.path().join(format!("{}.fq", out)))?;
+        Ok(kept.lines().step_by(4).map(str::to_string).collect())
+    };
+
+    let first = sample("a", 7)?;
+    assert_eq!(first.len(), 10);
+    assert!(first.iter().all(|h| h.starts_with("@r")));
+    assert_eq!(sample("b", 7)?, first);
+    assert_ne!(sample("c", 8)?, first);
+    let removed = std::fs::read_to_string(tmp.path().join("a.removed.fq"))?;
+    assert_eq!(removed.lines().count(), 4);
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_umi_length_per_input() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // R1 carries an 8bp UMI, R2 a 10bp UMI; only the R2 UMI is in its read
+    let r1 = tmp.path().join("r1.fastq");
+    std::fs::write(
+        &r1,
+        "@p1:ACGTACGT\nTTTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIII\n@p2:ACGTACGT\nTTTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIII\n",
+    )?;
+    let r2 = tmp.path().join("r2.fastq");
+    std::fs::write(
+        &r2,
+        "@p1:GGCCAATTGG\nTTGGCCAATTGGTTTT\n+\nIIIIIIIIIIIIIIII\n@p2:GGCCAATTGG\nTTTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIII\n",
+    )?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&r1).arg(&r2).arg("-l").arg("8,10");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("r1.fastq\t2\t0\t"))
+        .stdout(predicate::str::contains("r2.fastq\t2\t1\t"));
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&r1).arg(&r2).arg("-l").arg("8,10,12");
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "--umi-length has 3 values for 2 inputs",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_manifest_per_input_parameters() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // An 8bp UMI assay and a 10bp UMI assay whose only match has a mismatch
+    std::fs::write(
+        tmp.path().join("a.fastq"),
+        "@p1:ACGTACGT\nTTACGTACGTTTTTTT\n+\nIIIIIIIIIIIIIIII\n@p2:ACGTACGT\nTTTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIII\n",
+    )?;
+    std::fs::write(
+        tmp.path().join("b.fastq"),
+        "@p1:GGCCAATTGG\nTTGGCCTATTGGTTTT\n+\nIIIIIIIIIIIIIIII\n@p2:GGCCAATTGG\nTTTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIII\n",
+    )?;
+    let manifest = tmp.path().join("manifest.tsv");
+    std::fs::write(
+        &manifest,
+        "# path\tumi_length\tmismatches\na.fastq\t8\nb.fastq\t10\t1\n",
+    )?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("--manifest").arg(&manifest);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("a.fastq\t2\t1\t"))
+        .stdout(predicate::str::contains("b.fastq\t2\t1\t"));
+
+    // Without the override b.fastq's match is beyond the mismatch budget
+    std::fs::write(&manifest, "a.fastq\t8\nb.fastq\t10\n")?;
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("--manifest").arg(&manifest);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("b.fastq\t2\t0\t"));
+
+    std::fs::write(&manifest, "a.fastq\t8\t4\n")?;
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("--manifest").arg(&manifest);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Maximum allowed mismatches is 3"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_collapse_output() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // Three reads of one molecule, the second with a sequencing error, and
+    // a read of another molecule
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(
+        &input,
+        "@r1:ACGTACGTACGT\nGATTACAGATTACA\n+\nIIIIIIIIIIIIII\n\
+         @r2:ACGTACGTACGT\nGATTACCGATTACA\n+\nIIIIII#IIIIIII\n\
+         @r3:ACGTACGTACGT\nGATTACAGATTACA\n+\nIIIIIIIIIIIIII\n\
+         @r4:GGGGCCCCAAAA\nTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIII\n",
+    )?;
+    let collapsed = tmp.path().join("consensus.fq");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("--collapse-output")
+        .arg(&collapsed);
+    cmd.assert().success();
+
+    let content = std::fs::read_to_string(&collapsed)?;
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "@r1:ACGTACGTACGT umi:ACGTACGTACGT reads:3",
+            "GATTACAGATTACA",
+            "+",
+            "IIIIIIIIIIIIII",
+            "@r4:GGGGCCCCAAAA umi:GGGGCCCCAAAA reads:1",
+            "TTTTTTTTTTTTTT",
+            "+",
+            "IIIIIIIIIIIIII",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_piped_summary_is_plain_tsv() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    // assert_cmd captures stdout through a pipe, so it is not a terminal
+    let output = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")))
+        .arg("-i")
+        .arg("tests/data/example.fastq")
+        .output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(!stdout.contains('\x1b'));
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0].split('\t').count(), 6);
+    assert!(lines[0].starts_with("example.fastq\t"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_umi_template() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // The header carries the 8 variable bases of an NNNNGGNNNN UMI; r2 has
+    // a C instead of the fixed G
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(
+        &input,
+        "@r1:ACGTACGT\nTTACGTGGACGTTT\n+\nIIIIIIIIIIIIII\n\
+         @r2:ACGTACGT\nTTACGTGCACGTTT\n+\nIIIIIIIIIIIIII\n",
+    )?;
+    let out = tmp.path().join("out");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-o")
+        .arg(&out)
+        .arg("--umi-template")
+        .arg("NNNNGGNNNN")
+        .arg("-m")
+        .arg("1");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("reads.fastq\t2\t1\t"));
+    let removed = std::fs::read_to_string(tmp.path().join("out.removed.fq"))?;
+    assert!(removed.starts_with("@r1:ACGTACGT\n"));
+    let kept = std::fs::read_to_string(tmp.path().join("out.fq"))?;
+    assert!(kept.starts_with("@r2:ACGTACGT\n"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_position_histogram() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // Three UMIs planted at offset 4, one at offset 0 and one read without its UMI
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(
+        &input,
+        "@r1:ACGTACGTACGT\nTTTTACGTACGTACGTTT\n+\nIIIIIIIIIIIIIIIIII\n\
+         @r2:GGGGCCCCAAAA\nCATGGGGGCCCCAAAATT\n+\nIIIIIIIIIIIIIIIIII\n\
+         @r3:TTGGCCAATTGG\nAAAATTGGCCAATTGGAA\n+\nIIIIIIIIIIIIIIIIII\n\
+         @r4:ACGTACGTACGT\nACGTACGTACGTTTTTTT\n+\nIIIIIIIIIIIIIIIIII\n\
+         @r5:ACGTACGTACGT\nTTTTTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIIIII\n",
+    )?;
+    let histogram = tmp.path().join("positions.tsv");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("--position-histogram")
+        .arg(&histogram);
+    cmd.assert().success();
+
+    assert_eq!(
+        std::fs::read_to_string(&histogram)?,
+        "offset\treads\n0\t1\n4\t3\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_min_match_complexity() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // r1's UMI only matches inside a poly-A stretch, r2's in a complex region
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(
+        &input,
+        "@r1:AAAAAAAAAAAA\nCAAAAAAAAAAAAAAAAAAAAAAG\n+\nIIIIIIIIIIIIIIIIIIIIIIII\n\
+         @r2:ACGTTGCAGTCA\nGATCACGTTGCAGTCACCTAGG\n+\nIIIIIIIIIIIIIIIIIIIIII\n",
+    )?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&input);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("reads.fastq\t2\t2\t"));
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("--min-match-complexity")
+        .arg("1.5");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("reads.fastq\t2\t1\t"))
+        .stderr(predicate::str::contains(
+            "UMI matches rejected in low-complexity regions (dinucleotide entropy < 1.5 bit): 1",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_umi_length_from_header() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // The header records a 10bp UMI; extracting 12 bases would fail
+    let sam = |comment: &str| {
+        format!(
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100\n{}\
+             r1:ACGTACGTAC\t0\tchr1\t1\t60\t16M\t*\t0\t0\tTTACGTACGTACTTTT\tIIIIIIIIIIIIIIII\n\
+             r2:ACGTACGTAC\t0\tchr1\t1\t60\t16M\t*\t0\t0\tTTTTTTTTTTTTTTTT\tIIIIIIIIIIIIIIII\n",
+            comment
+        )
+    };
+    let input = tmp.path().join("reads.sam");
+    std::fs::write(&input, sam("@CO\tumi_length=10\n"))?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("--umi-length")
+        .arg("from-header");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("reads.sam\t2\t1\t"));
+
+    // Without a recognizable comment the explicit fallback length is used
+    let bare = tmp.path().join("bare.sam");
+    std::fs::write(&bare, sam(""))?;
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&bare)
+        .arg("--umi-length")
+        .arg("from-header:10");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("bare.sam\t2\t1\t50.00\t1\t50.00"))
+        .stderr(predicate::str::contains("no UMI length found").not());
+
+    // or, without one, the default length with a warning
+    let default = tmp.path().join("default.sam");
+    std::fs::write(
+        &default,
+        "@HD\tVN:1.6\n\
+         r1:ACGTACGTACGT\t4\t*\t0\t0\t*\t*\t0\t0\tTTACGTACGTACGTTT\tIIIIIIIIIIIIIIII\n",
+    )?;
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&default)
+        .arg("--umi-length")
+        .arg("from-header");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("default.sam\t1\t1\t100.00"))
+        .stderr(predicate::str::contains(
+            "no UMI length found in the header of",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_no_empty_output() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // No read contains its UMI, so nothing is removed
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(
+        &input,
+        "@r1:ACGTACGTACGT\nTTTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIII\n",
+    )?;
+    let out = tmp.path().join("out");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-o")
+        .arg(&out)
+        .arg("--no-empty-output");
+    cmd.assert().success();
+    assert!(tmp.path().join("out.fq").exists());
+    assert!(!tmp.path().join("out.removed.fq").exists());
+
+    // An empty input produces no files at all
+    let empty = tmp.path().join("empty.fastq");
+    std::fs::write(&empty, "")?;
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&empty)
+        .arg("-o")
+        .arg(tmp.path().join("none"))
+        .arg("--no-empty-output");
+    cmd.assert().success();
+    assert!(!tmp.path().join("none.fq").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_auto_profile() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg("tests/data/example.fastq")
+        .arg("-o")
+        .arg(tmp.path().join("out.fq.gz"))
+        .arg("--auto")
+        .arg("-v");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("example.fastq\t3\t2"))
+        .stderr(predicate::str::is_match(
+            r"Auto profile: \d+ CPUs, \d+ matching threads, batch size \d+, gzip level \d",
+        )?);
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_require_both_umis_same_position() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // Both reads contain both UMIs, but only the first has them flanking it
+    let input = tmp.path().join("dual.fastq");
+    std::fs::write(
+        &input,
+        "@r1:AAAACCCC+GGGGTTGT\nAAAACCCCTTTTTTGGGGTTGT\n+\nIIIIIIIIIIIIIIIIIIIIII\n\
+         @r2:AAAACCCC+GGGGTTGT\nTTTAAAACCCCGGGGTTGTTTT\n+\nIIIIIIIIIIIIIIIIIIIIII\n",
+    )?;
+    let run = |anchored: bool| {
+        let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+        cmd.arg("-i")
+            .arg(&input)
+            .arg("-l")
+            .arg("8")
+            .arg("--umi-candidates-delim")
+            .arg("+");
+        if anchored {
+            cmd.arg("--require-both-umis-same-position");
+        }
+        cmd.assert()
+    };
+
+    run(false)
+        .success()
+        .stdout(predicate::str::contains("dual.fastq\t2\t2\t"));
+    run(true)
+        .success()
+        .stdout(predicate::str::contains("dual.fastq\t2\t1\t"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_bam_to_fastq_split_by_mate() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // Pair p1 has its UMI in both mates (R2 on the reverse strand), pair p2
+    // in neither; the unpaired read s1 has its UMI
+    let input = tmp.path().join("paired.sam");
+    std::fs::write(
+        &input,
+        "@HD\tVN:1.6\n\
+         p1:ACGTACGT\t77\t*\t0\t0\t*\t*\t0\t0\tACGTACGTTT\tIIIIIIIII5\n\
+         p1:ACGTACGT\t157\t*\t0\t0\t*\t*\t0\t0\tAAACGTACGT\t5IIIIIIIII\n\
+         p2:ACGTACGT\t77\t*\t0\t0\t*\t*\t0\t0\tTTTTTTTTTT\tIIIIIIIIII\n\
+         p2:ACGTACGT\t141\t*\t0\t0\t*\t*\t0\t0\tGGGGGGGGGG\tIIIIIIIIII\n\
+         s1:ACGTACGT\t4\t*\t0\t0\t*\t*\t0\t0\tACGTACGTCC\tIIIIIIIIII\n",
+    )?;
+    let output = tmp.path().join("out");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-o")
+        .arg(&output)
+        .arg("-l")
+        .arg("8")
+        .arg("--output-format")
+        .arg("fastq");
+    cmd.assert().success();
+
+    let read = |name: &str| std::fs::read_to_string(tmp.path().join(name));
+    let records = |name: &str| read(name).map(|s| s.lines().count() / 4);
+    assert_eq!(records("out.R1.fq")?, 1);
+    assert_eq!(records("out.R2.fq")?, 1);
+    assert_eq!(records("out.removed.R1.fq")?, 1);
+    assert_eq!(records("out.removed.R2.fq")?, 1);
+    assert_eq!(records("out.removed.fq")?, 1);
+    // No unpaired read is without its UMI
+    assert!(!tmp.path().join("out.fq").exists());
+    assert!(read("out.R1.fq")?.starts_with("@p2:ACGTACGT\nTTTTTTTTTT\n"));
+    // The reverse-strand mate is written in sequencing orientation
+    assert_eq!(
+        read("out.removed.R2.fq")?,
+        "@p1:ACGTACGT\nACGTACGTTT\n+\nIIIIIIIII5\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_skips_records_with_unequal_quality_length(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::io::Write;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // r2 has one quality character too few
+    let fastq = "@r1:ACGTACGT\nTTACGTACGTTTTTTT\n+\nIIIIIIIIIIIIIIII\n\
+                 @r2:ACGTACGT\nTTACGTACGTTTTTTT\n+\nIIIIIIIIIIIIIII\n\
+                 @r3:ACGTACGT\nTTTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIII\n";
+    let plain = tmp.path().join("bad.fastq");
+    std::fs::write(&plain, fastq)?;
+    let gzipped = tmp.path().join("bad_gz.fastq.gz");
+    let mut enc = flate2::write::GzEncoder::new(
+        std::fs::File::create(&gzipped)?,
+        flate2::Compression::default(),
+    );
+    enc.write_all(fastq.as_bytes())?;
+    enc.finish()?;
+
+    for (input, output) in [(&plain, "out"), (&gzipped, "out_gz")] {
+        let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+        cmd.arg("-i")
+            .arg(input)
+            .arg("-o")
+            .arg(tmp.path().join(output))
+            .arg("-l")
+            .arg("8")
+            .arg("--output-compression")
+            .arg("none")
+            .arg("--quarantine")
+            .arg(tmp.path().join(format!("{}.quarantine.fq", output)))
+            .arg("--verbose");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("\t2\t1\t50.00\t1\t50.00"))
+            .stdout(predicate::str::contains("duplicate_id 0, malformed 1"));
+        // The skipped record is quarantined as it was in the input
+        assert_eq!(
+            std::fs::read_to_string(tmp.path().join(format!("{}.quarantine.fq", output)))?,
+            "@r2:ACGTACGT quarantine:malformed_record\nTTACGTACGTTTTTTT\n+\nIIIIIIIIIIIIIII\n"
+        );
+        let first_header = |name: String| -> std::io::Result<Option<String>> {
+            let content = std::fs::read_to_string(tmp.path().join(name))?;
+            Ok(content.lines().next().map(str::to_string))
+        };
+        assert_eq!(
+            first_header(format!("{}.removed.fq", output))?.as_deref(),
+            Some("@r1:ACGTACGT")
+        );
+        assert_eq!(
+            first_header(format!("{}.fq", output))?.as_deref(),
+            Some("@r3:ACGTACGT")
+        );
+
+        let opts = ProcessOptions {
+            umi_len: 8,
+            ..Default::default()
+        };
+        let stats = umi_checker::processing::process_fastq(input, None, None, &opts)?;
+        assert_eq!((stats.total, stats.malformed), (2, 1));
+    }
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&plain).arg("-l").arg("8").arg("--strict");
+    cmd.assert().failure();
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_max_window_search() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // A 20 kb read with its UMI half-way in, and a short read with it up front
+    let long = format!("{}ACGTACGTACGT{}", "T".repeat(10_000), "T".repeat(10_000));
+    let input = tmp.path().join("long.fastq");
+    std::fs::write(
+        &input,
+        format!(
+            "@long:ACGTACGTACGT\n{}\n+\n{}\n@short:ACGTACGTACGT\nTACGTACGTACGTT\n+\nIIIIIIIIIIIIII\n",
+            long,
+            "I".repeat(long.len())
+        ),
+    )?;
+    let run = |cap: Option<&str>| {
+        let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+        cmd.arg("-i").arg(&input);
+        if let Some(cap) = cap {
+            cmd.arg("--max-window-search").arg(cap);
+        }
+        cmd.assert()
+    };
+
+    run(None)
+        .success()
+        .stdout(predicate::str::contains("long.fastq\t2\t2\t"))
+        .stderr(predicate::str::contains("UMI search stopped").not());
+    // Only the first 100 windows of the long read are examined
+    run(Some("100"))
+        .success()
+        .stdout(predicate::str::contains("long.fastq\t2\t1\t"))
+        .stderr(predicate::str::contains(
+            "UMI search stopped after 100 windows in 1 reads",
+        ));
+    run(Some("0")).failure();
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_shards_sum_to_whole_input() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let bam = tmp.path().join("reads.sam");
+    let mut sam = String::from("@HD\tVN:1.6\n");
+    for i in 0..5 {
+        let seq = if i % 2 == 0 {
+            "TTACGTACGTACGTTT"
+        } else {
+            "TTTTTTTTTTTTTTTT"
+        };
+        sam.push_str(&format!(
+            "r{}:ACGTACGTACGT\t4\t*\t0\t0\t*\t*\t0\t0\t{}\tIIIIIIIIIIIIIIII\n",
+            i, seq
+        ));
+    }
+    std::fs::write(&bam, sam)?;
+
+    // Total and with-UMI counts of the summary line for `shard`
+    let counts =
+        |input: &Path, shard: Option<&str>| -> Result<(usize, usize), Box<dyn std::error::Error>> {
+            let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+            cmd.arg("-i").arg(input);
+            if let Some(shard) = shard {
+                cmd.arg("--shard").arg(shard);
+            }
+            let output = cmd.output()?;
+            assert!(output.status.success());
+            let line = String::from_utf8(output.stdout)?;
+            let columns: Vec<&str> = line.trim().split('\t').collect();
+            Ok((columns[1].parse()?, columns[2].parse()?))
+        };
+
+    for input in [Path::new("tests/data/example.fastq"), &bam] {
+        let (total, with_umi) = counts(input, None)?;
+        let first = counts(input, Some("0/2"))?;
+        let second = counts(input, Some("1/2"))?;
+        assert!(first.0 > 0 && second.0 > 0);
+        assert_eq!(first.0 + second.0, total);
+        assert_eq!(first.1 + second.1, with_umi);
+    }
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&bam).arg("--shard").arg("2/2");
+    assert!(!cmd.output()?.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_warns_when_umi_is_longer_than_reads() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let short = tmp.path().join("short.fastq");
+    std::fs::write(
+        &short,
+        "@r1:ACGTACGTACGT\nACGTACGT\n+\nIIIIIIII\n@r2:ACGTACGTACGT\nTTTTTTTT\n+\nIIIIIIII\n",
+    )?;
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&short);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("short.fastq\t2\t0\t"))
+        .stderr(predicate::str::contains(
+            "the UMI is longer than the read in 2 of 2 reads (100.00%)",
+        ));
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg("tests/data/example.fastq");
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("longer than the read").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_multiqc_schema() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("sample_a.fastq");
+    std::fs::write(
+        &input,
+        "@r1:ACGTACGT\nTTACGTACGTTT\n+\nIIIIIIIIIIII\n@r2:ACGTACGT\nTTTTTTTTTTTT\n+\nIIIIIIIIIIII\n",
+    )?;
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("--schema")
+        .arg("multiqc");
+    let output = cmd.output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "Sample\ttotal_reads\treads_with_umi\tpercent_with_umi\treads_without_umi\tpercent_without_umi",
+            "sample_a\t2\t1\t50.00\t1\t50.00",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_multiqc_json() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("sample_a.fastq");
+    std::fs::write(
+        &input,
+        "@r1:ACGTACGT\nTTACGTACGTTT\n+\nIIIIIIIIIIII\n@r2:ACGTACGT\nTTTTTTTTTTTT\n+\nIIIIIIIIIIII\n@r3:ACGTACGT\nTTTTTTTTTTTT\n+\nIIIIIIIIIIII\n@r4:ACGTACGT\nTTTTTTTTTTTT\n+\nIIIIIIIIIIII\n",
+    )?;
+    let mqc = tmp.path().join("umi_checker_mqc.json");
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("--multiqc")
+        .arg(&mqc);
+    cmd.assert().success();
+
+    let json = std::fs::read_to_string(&mqc)?;
+    assert!(json.starts_with("{\"id\":\"umi_checker\","));
+    assert!(json.contains("\"plot_type\":\"generalstats\""));
+    assert!(json
+        .contains("\"data\":{\"sample_a\":{\"total\":4,\"with_umi\":1,\"with_umi_pct\":25.00}}"));
+
+    // MultiQC ignores files without the _mqc suffix, so reject them
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("--multiqc")
+        .arg(tmp.path().join("umi_checker.json"));
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("_mqc.json"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_no_seq_copy_keeps_counts() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let run =
+        |extra: &[&str], out: &str| -> Result<(String, Vec<u8>), Box<dyn std::error::Error>> {
+            let prefix = tmp.path().join(out);
+            let output = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")))
+                .arg("-i")
+                .arg("tests/data/example.bam")
+                .arg("-m")
+                .arg("2")
+                .arg("-o")
+                .arg(&prefix)
+                .arg("--verbose")
+                .args(extra)
+                .output()?;
+            assert!(output.status.success());
+            // Drop the elapsed time, which differs between runs
+            let stdout = String::from_utf8(output.stdout)?
+                .lines()
+                .filter(|l| !l.starts_with("Elapsed"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            let removed = std::fs::read(tmp.path().join(format!("{}.removed.bam", out)))?;
+            Ok((stdout, removed))
+        };
+
+    let (copy, copy_removed) = run(&[], "copy")?;
+    let (no_copy, no_copy_removed) = run(&["--no-seq-copy"], "no_copy")?;
+    assert!(copy.contains("example.bam\t17619\t76\t"));
+    assert_eq!(no_copy, copy);
+    assert_eq!(no_copy_removed, copy_removed);
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_missing_input() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let missing = tmp.path().join("missing.fastq");
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg("tests/data/example.fastq").arg(&missing);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "Input file not found: {}",
+            missing.display()
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_conflicting_options() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    for (first, second) in [
+        (&["--chunks", "3"][..], &["--scan-only", "scan.tsv"][..]),
+        (&["--long-read"], &["--chunks", "3"]),
+        (&["--long-read"], &["--spacer-motif", "GG"]),
+        (&["--estimate-dup-rate"], &["--umi", "ACGT"]),
+    ] {
+        let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+        cmd.arg("-i").arg("tests/data/example.fastq");
+        cmd.args(first).args(second);
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("cannot be used with"))
+            .stderr(predicate::str::contains(first[0]))
+            .stderr(predicate::str::contains(second[0]));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_internal_linker() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // The header UMI ACGTTGCA sits in the read as ACG + linker CCTT + TTGCA
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(
+        &input,
+        "@r1:ACGTTGCA\nGGACGCCTTTTGCAGG\n+\nIIIIIIIIIIIIIIII\n",
+    )?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&input).arg("-l").arg("8");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("reads.fastq\t1\t0\t"));
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("--internal-linker")
+        .arg("CCTT")
+        .arg("--linker-split")
+        .arg("3");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("reads.fastq\t1\t1\t"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_dump_windows() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // r2 holds its UMI with one mismatch (T->A) at offset 3
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(
+        &input,
+        "@r1:ACGTACGT\nTTACGTACGTTT\n+\nIIIIIIIIIIII\n@r2:ACGTACGT\nGGGACGAACGTGG\n+\nIIIIIIIIIIIII\n",
+    )?;
+    let output = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")))
+        .arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("--dump-windows")
+        .arg("r2:ACGTACGT")
+        .output()?;
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("Windows of read 'r2:ACGTACGT':\nUMI ACGTACGT\n"));
+    assert!(!stderr.contains("r1:ACGTACGT"));
+    // One line per window of the 13 bp read
+    let rows: Vec<&str> = stderr
+        .lines()
+        .filter(|l| l.split('\t').count() == 3 && !l.starts_with("offset"))
+        .collect();
+    assert_eq!(rows.len(), 6);
+    assert!(rows.contains(&"3\tACGAACGT\t1"));
+    assert!(stderr.contains("minimum distance 1\n"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_long_read_matches_scanning() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let mut state: u64 = 0x5eed;
+    let mut bases = |n: usize| -> String {
+        (0..n)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                ['A', 'C', 'G', 'T'][(state % 4) as usize]
+            })
+            .collect()
+    };
+    // 2 kb reads with two candidate UMIs each; every other read carries its
+    // second candidate with two substitutions
+    let mut fastq = String::new();
+    for i in 0..20 {
+        let (first, second) = (bases(12), bases(12));
+        let mut read = bases(2_000);
+        if i % 2 == 0 {
+            let planted = format!("TT{}", &second[2..]);
+            read.replace_range(900..912, &planted);
+        }
+        fastq.push_str(&format!(
+            "@r{}:{}+{}\n{}\n+\n{}\n",
+            i,
+            first,
+            second,
+            read,
+            "I".repeat(read.len())
+        ));
+    }
+    let input = tmp.path().join("long.fastq");
+    std::fs::write(&input, fastq)?;
+
+    let run = |long_read: bool| -> Result<String, Box<dyn std::error::Error>> {
+        let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+        cmd.arg("-i")
+            .arg(&input)
+            .arg("-m")
+            .arg("2")
+            .arg("--umi-candidates-delim")
+            .arg("+");
+        if long_read {
+            cmd.arg("--long-read");
+        }
+        let output = cmd.output()?;
+        assert!(output.status.success());
+        Ok(String::from_utf8(output.stdout)?)
+    };
+    let scanned = run(false)?;
+    assert!(scanned.starts_with("long.fastq\t20\t"));
+    assert!(!scanned.starts_with("long.fastq\t20\t0\t"));
+    assert_eq!(run(true)?, scanned);
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_tag_output_by_mismatch() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // r0 holds its UMI exactly, r1 with one mismatch (T->C) and r2 not at all
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(
+        &input,
+        "@r0:ACGTACGT\nGGACGTACGTGG\n+\nIIIIIIIIIIII\n\
+         @r1:ACGTACGT\nGGACGCACGTGG\n+\nIIIIIIIIIIII\n\
+         @r2:ACGTACGT\nTTTTTTTTTTTT\n+\nIIIIIIIIIIII\n",
+    )?;
+    let prefix = tmp.path().join("split");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("-m")
+        .arg("1")
+        .arg("--tag-output-by-mismatch")
+        .arg(&prefix);
+    cmd.assert().success();
+
+    let mm0 = std::fs::read_to_string(tmp.path().join("split.mm0.fq"))?;
+    let mm1 = std::fs::read_to_string(tmp.path().join("split.mm1.fq"))?;
+    assert_eq!(mm0, "@r0:ACGTACGT\nGGACGTACGTGG\n+\nIIIIIIIIIIII\n");
+    assert_eq!(mm1, "@r1:ACGTACGT\nGGACGCACGTGG\n+\nIIIIIIIIIIII\n");
+    // One file per allowed mismatch count
+    assert!(!tmp.path().join("split.mm2.fq").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_mates_find_umi_across_junction() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let r1 = tmp.path().join("r1.fq");
+    let r2 = tmp.path().join("r2.fq");
+    // p1 has its UMI split across the mates (R2 continues the insert on the
+    // reverse strand), p2 nowhere, p3 whole in R2
+    std::fs::write(
+        &r1,
+        "@p1:ACGTACGTACGT 1:N:0\nTTTTTTTTTTTTACGTAC\n+\nIIIIIIIIIIIIIIIIII\n\
+         @p2:ACGTACGTACGT 1:N:0\nTTTTTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIIIII\n\
+         @p3:ACGTACGTACGT 1:N:0\nTTTTTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIIIIIII\n",
+    )?;
+    std::fs::write(
+        &r2,
+        "@p1:ACGTACGTACGT 2:N:0\nCCCCCCCCCCCCACGTAC\n+\nIIIIIIIIIIIIIIIIII\n\
+         @p2:ACGTACGTACGT 2:N:0\nGGGGGGGGGGGGGGGGGG\n+\nIIIIIIIIIIIIIIIIII\n\
+         @p3:ACGTACGTACGT 2:N:0\nACGTACGTACGTGGGGGG\n+\nIIIIIIIIIIIIIIIIII\n",
+    )?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&r1)
+        .arg(&r2)
+        .arg("--mates")
+        .arg("--verbose");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("r1.fq\t6\t1\t16.67\t5\t83.33"))
+        .stdout(predicate::str::contains(
+            "r1: UMI found in 16.67% of reads and in at least one mate of 66.67% of 3 pairs",
+        ))
+        .stderr(predicate::str::contains(
+            "Pairs with the UMI only across the mate junction: 1 (33.33%)",
+        ));
+
+    // Skipping the last bases of each mate leaves p1 without the junction
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&r1)
+        .arg(&r2)
+        .arg("--mates")
+        .arg("--skip-suffix")
+        .arg("6");
+    cmd.assert().success().stderr(predicate::str::contains(
+        "Pairs with the UMI only across the mate junction: 0 (0.00%)",
+    ));
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&r1).arg("--mates");
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "--mates requires exactly two FASTQ inputs",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_normalize_output_headers() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(
+        &input,
+        "@r0:ACGTACGT 1:N:0\nGGACGTACGTGG\n+\nIIIIIIIIIIII\n\
+         @r1:ACGTACGT 1:N:0\nTTTTTTTTTTTT\n+\nIIIIIIIIIIII\n",
+    )?;
+    let out = tmp.path().join("out");
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("-o")
+        .arg(&out)
+        .arg("--normalize-output-headers")
+        .arg("--keep-umi-comment");
+    cmd.assert().success();
+
+    // Both outputs lose the UMI from the read name but keep it as a comment
+    let kept = std::fs::read_to_string(tmp.path().join("out.fq"))?;
+    let removed = std::fs::read_to_string(tmp.path().join("out.removed.fq"))?;
+    assert_eq!(
+        kept,
+        "@r1 1:N:0 umi:ACGTACGT\nTTTTTTTTTTTT\n+\nIIIIIIIIIIII\n"
+    );
+    assert_eq!(
+        removed,
+        "@r0 1:N:0 umi:ACGTACGT\nGGACGTACGTGG\n+\nIIIIIIIIIIII\n"
+    );
+
+    // Without --keep-umi-comment the UMI is dropped
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("-o")
+        .arg(&out)
+        .arg("--normalize-output-headers");
+    cmd.assert().success();
+    let kept = std::fs::read_to_string(tmp.path().join("out.fq"))?;
+    assert!(kept.starts_with("@r1 1:N:0\n"));
+
+    // SAM records get the trimmed read name and keep the UMI in an RX tag
+    let sam = tmp.path().join("reads.sam");
+    std::fs::write(
+        &sam,
+        "@HD\tVN:1.6\n\
+         r0:ACGTACGT\t4\t*\t0\t0\t*\t*\t0\t0\tGGACGTACGTGG\tIIIIIIIIIIII\n\
+         r1:ACGTACGT\t4\t*\t0\t0\t*\t*\t0\t0\tTTTTTTTTTTTT\tIIIIIIIIIIII\n",
+    )?;
+    let sam_out = tmp.path().join("sam_out");
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&sam)
+        .arg("-l")
+        .arg("8")
+        .arg("-o")
+        .arg(&sam_out)
+        .arg("--normalize-output-headers")
+        .arg("--keep-umi-comment");
+    cmd.assert().success();
+    let records = |name: &str| -> std::io::Result<Vec<String>> {
+        Ok(std::fs::read_to_string(tmp.path().join(name))?
+            .lines()
+            .filter(|l| !l.starts_with('@'))
+            .map(str::to_string)
+            .collect())
+    };
+    for (name, read) in [("sam_out.sam", "r1"), ("sam_out.removed.sam", "r0")] {
+        let records = records(name)?;
+        assert_eq!(records.len(), 1, "{}", name);
+        let fields: Vec<&str> = records[0].split('\t').collect();
+        assert_eq!(fields[0], read);
+        assert!(fields[11..].contains(&"RX:Z:ACGTACGT"), "{}", records[0]);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_ci_columns() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let output = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")))
+        .arg("-i")
+        .arg("tests/data/example.fastq")
+        .arg("--ci")
+        .output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    let fields: Vec<&str> = stdout.trim_end().split('\t').collect();
+    assert_eq!(fields.len(), 8);
+    let rate: f64 = fields[3].parse()?;
+    let (lower, upper): (f64, f64) = (fields[6].parse()?, fields[7].parse()?);
+    assert!(lower <= rate && rate <= upper && lower < upper);
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_detect_umi_length() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let data_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/example.umi10.fastq");
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&data_path).arg("--detect-umi-length");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("  10\t"))
+        .stdout(predicate::str::ends_with("Recommended: --umi-length 10\n"))
+        // Only the headers are read: no summary row is printed
+        .stdout(predicate::str::contains("example.umi10.fastq\t").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_anchor_motif() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // r1 holds its UMI just after TATA; r2 only far from the motif
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(
+        &input,
+        "@r1:ACGTTGCA\nGGGGTATAACGTTGCAGG\n+\nIIIIIIIIIIIIIIIIII\n\
+         @r2:ACGTTGCA\nACGTTGCAGGGGTATAGG\n+\nIIIIIIIIIIIIIIIIII\n",
+    )?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i").arg(&input).arg("-l").arg("8");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("reads.fastq\t2\t2\t"));
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("--anchor-motif")
+        .arg("TATA");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("reads.fastq\t2\t1\t"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_top_umis() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::cargo;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // GGGGCCCC tags half of the reads, every other UMI a single read
+    let input = tmp.path().join("reads.fastq");
+    let mut reads = String::new();
+    for (i, umi) in [
+        "AAAACCCC", "ACACACAC", "AGAGAGAG", "ATATATAT", "CACACACA", "CGCGCGCG",
+    ]
+    .iter()
+    .enumerate()
+    {
+        reads.push_str(&format!("@u{}:{}\nTTTTTTTT\n+\nIIIIIIII\n", i, umi));
+        reads.push_str(&format!("@d{}:GGGGCCCC\nTTTTTTTT\n+\nIIIIIIII\n", i));
+    }
+    std::fs::write(&input, reads)?;
+
+    let run = |verbose: bool| {
+        let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+        cmd.arg("-i")
+            .arg(&input)
+            .arg("-l")
+            .arg("8")
+            .arg("--top-umis")
+            .arg("3");
+        if verbose {
+            cmd.arg("--verbose");
+        }
+        cmd.output()
+    };
+
+    // The list is only printed with --verbose
+    let output = run(false)?;
+    assert!(output.status.success());
+    assert!(!String::from_utf8(output.stderr)?.contains("Most common"));
+
+    let output = run(true)?;
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    let mut lines = stderr
+        .lines()
+        .skip_while(|l| !l.starts_with("Most common of 12 extracted UMIs"));
+    assert!(lines.next().is_some(), "{}", stderr);
+    assert_eq!(lines.next(), Some("  GGGGCCCC\t6"));
+    assert_eq!(lines.take_while(|l| l.starts_with("  ")).count(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_ignore_positions() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // The UMI sits at offset 2 with a wrong base at window offset 3
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(&input, "@r1:ACGTACGT\nGGACGAACGTGG\n+\nIIIIIIIIIIII\n")?;
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("-m")
+        .arg("0");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("reads.fastq\t1\t0\t"));
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("-m")
+        .arg("0")
+        .arg("--ignore-positions")
+        .arg("3,7");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("reads.fastq\t1\t1\t"));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_raw_alphabet() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // An amino-acid barcode holding an N, found with one substitution (R->S)
+    let input = tmp.path().join("reads.fastq");
+    std::fs::write(&input, "@r1:WKLMNPQR\nGGWKLMNPQSGG\n+\nIIIIIIIIIIII\n")?;
+
+    // As DNA the N is a second mismatch
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("-m")
+        .arg("1");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("reads.fastq\t1\t0\t"));
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("-m")
+        .arg("1")
+        .arg("--alphabet")
+        .arg("raw");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("reads.fastq\t1\t1\t"));
+
+    // Raw symbols keep their case: a lowercase header UMI only matches the
+    // same lowercase symbols in the read
+    let lower = tmp.path().join("lower.fastq");
+    std::fs::write(
+        &lower,
+        "@r1:wklmnpqr\nGGwklmnpqrGG\n+\nIIIIIIIIIIII\n@r2:wklmnpqr\nGGWKLMNPQRGG\n+\nIIIIIIIIIIII\n",
+    )?;
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&lower)
+        .arg("-l")
+        .arg("8")
+        .arg("--alphabet")
+        .arg("raw");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("lower.fastq\t2\t1\t"));
+
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("-l")
+        .arg("8")
+        .arg("--alphabet")
+        .arg("raw")
+        .arg("--check-revcomp");
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "--check-revcomp cannot be combined with --alphabet raw",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_main_cli_output_prefix_extensions() -> Result<(), Box<dyn std::error::Error>> {
+    use assert_cmd::assert::OutputAssertExt;
+    use assert_cmd::cargo;
+    use predicates::prelude::*;
+    use std::process::Command;
+
+    let tmp = tempdir()?;
+    // A non-sequence extension stays part of the prefix, without a warning
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg("tests/data/example.fastq")
+        .arg("-o")
+        .arg(tmp.path().join("out.txt"));
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("--output").not());
+    assert!(tmp.path().join("out.txt.fq").exists());
+    assert!(tmp.path().join("out.txt.removed.fq").exists());
+
+    // Another format's extension is replaced, with a warning
+    let mut cmd = Command::new(cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.arg("-i")
+        .arg("tests/data/example.fastq")
+        .arg("-o")
+        .arg(tmp.path().join("other.bam"));
+    cmd.assert().success().stderr(predicate::str::contains(
+        "ends in .bam, but fastq is written",
+    ));
+    assert!(tmp.path().join("other.fq").exists());
+    assert!(tmp.path().join("other.removed.fq").exists());
+    assert!(!tmp.path().join("other.bam.fq").exists());
+
+    Ok(())
+}